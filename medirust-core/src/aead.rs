@@ -0,0 +1,56 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use rand_core::{OsRng, RngCore};
+use anyhow::{Result, anyhow};
+
+// AES-256-GCM; ciphertexts carry the 16-byte tag at the end
+pub const KEY_SIZE: usize = 32; // 256 bits
+pub const NONCE_SIZE: usize = 12; // 96 bits for GCM
+
+// Generates a new AES key
+pub fn generate_key() -> Vec<u8> {
+    let mut key = vec![0u8; KEY_SIZE];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// Encrypts under a fresh random nonce, returning (ciphertext, nonce)
+pub fn encrypt(data: &[u8], key: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut nonce = vec![0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = encrypt_with_nonce(data, key, &nonce, aad)?;
+    Ok((ciphertext, nonce))
+}
+
+// Encrypts under a caller-chosen nonce, which must never repeat for a key;
+// `encrypt` is the safe entry point, this one exists for test vectors
+pub fn encrypt_with_nonce(data: &[u8], key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != KEY_SIZE {
+        return Err(anyhow!("Invalid AES key size"));
+    }
+    if nonce.len() != NONCE_SIZE {
+        return Err(anyhow!("Invalid Nonce size"));
+    }
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| anyhow!("Failed to create AES cipher: {}", e))?;
+    cipher.encrypt(Nonce::from_slice(nonce), Payload { msg: data, aad })
+        .map_err(|e| anyhow!("Failed to encrypt data: {}", e))
+}
+
+// Decrypts; fails unless `aad` matches what was bound at encryption
+pub fn decrypt(ciphertext: &[u8], key: &[u8], nonce: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != KEY_SIZE {
+        return Err(anyhow!("Invalid AES key size"));
+    }
+    if nonce.len() != NONCE_SIZE {
+        return Err(anyhow!("Invalid Nonce size"));
+    }
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|e| anyhow!("Failed to create AES cipher: {}", e))?;
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|e| anyhow!("Failed to decrypt data: {}", e))
+}
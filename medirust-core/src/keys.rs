@@ -0,0 +1,231 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use rsa::{
+    RsaPrivateKey, RsaPublicKey,
+    pkcs8::{der::pem, DecodePrivateKey, EncodePrivateKey, LineEnding},
+};
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519Secret};
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+use crate::{hpke, pkcs1};
+
+// DER prefixes of X25519 keys (RFC 8410); the raw 32-byte key follows
+const X25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x03, 0x21, 0x00];
+const X25519_PKCS8_PREFIX: [u8; 16] = [0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x04, 0x22, 0x04, 0x20];
+// HPKE info string for wrapping record keys
+const RECORD_KEY_INFO: &[u8] = b"medirust record key";
+
+// Key-encapsulation backend of a patient key pair, stored per patient
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+pub enum KeyAlgorithm {
+    // RSA with PKCS#1 v1.5 key wrapping
+    #[default]
+    #[cfg_attr(feature = "serde", serde(rename = "rsa-pkcs1v15"))]
+    Rsa,
+    // HPKE base mode: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-256-GCM
+    #[cfg_attr(feature = "serde", serde(rename = "hpke-x25519-sha256-aes256gcm"))]
+    X25519Hpke,
+}
+
+impl KeyAlgorithm {
+    pub fn id(self) -> &'static str {
+        match self {
+            KeyAlgorithm::Rsa => "rsa-pkcs1v15",
+            KeyAlgorithm::X25519Hpke => "hpke-x25519-sha256-aes256gcm",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Result<Self> {
+        id.parse().map_err(|_| anyhow!("Unknown key algorithm {}", id))
+    }
+
+    // Guesses the algorithm of a client-supplied PEM from its label
+    pub fn detect(pem: &str) -> Self {
+        if pem.contains("-----BEGIN RSA ") { KeyAlgorithm::Rsa } else { KeyAlgorithm::X25519Hpke }
+    }
+
+    pub fn wrap_operation(self) -> &'static str {
+        match self {
+            KeyAlgorithm::Rsa => "rsa_wrap_key",
+            KeyAlgorithm::X25519Hpke => "hpke_wrap_key",
+        }
+    }
+
+    pub fn unwrap_operation(self) -> &'static str {
+        match self {
+            KeyAlgorithm::Rsa => "rsa_unwrap_key",
+            KeyAlgorithm::X25519Hpke => "hpke_unwrap_key",
+        }
+    }
+}
+
+impl core::str::FromStr for KeyAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rsa-pkcs1v15" => Ok(KeyAlgorithm::Rsa),
+            "hpke-x25519-sha256-aes256gcm" => Ok(KeyAlgorithm::X25519Hpke),
+            _ => Err(()),
+        }
+    }
+}
+
+// Public half of a patient key pair, which record keys are wrapped to
+#[derive(Clone, PartialEq)]
+pub enum PatientPublicKey {
+    Rsa(RsaPublicKey),
+    X25519(X25519PublicKey),
+}
+
+impl PatientPublicKey {
+    // RSA keys are PKCS1 PEM, X25519 keys SPKI PEM
+    pub fn from_pem(algorithm: KeyAlgorithm, pem_str: &str) -> Result<Self> {
+        match algorithm {
+            KeyAlgorithm::Rsa => pkcs1::public_key_from_pem(pem_str).map(PatientPublicKey::Rsa),
+            KeyAlgorithm::X25519Hpke => {
+                let (label, der) = pem::decode_vec(pem_str.as_bytes())
+                    .map_err(|e| anyhow!("Failed to import public key from PEM: {}", e))?;
+                match der.strip_prefix(&X25519_SPKI_PREFIX[..]) {
+                    Some(raw) if label == "PUBLIC KEY" => {
+                        let raw: [u8; 32] = raw.try_into().map_err(|_| anyhow!("Invalid X25519 public key length"))?;
+                        Ok(PatientPublicKey::X25519(X25519PublicKey::from(raw)))
+                    }
+                    _ => Err(anyhow!("PEM does not hold an X25519 public key")),
+                }
+            }
+        }
+    }
+
+    pub fn to_pem(&self) -> Result<String> {
+        match self {
+            PatientPublicKey::Rsa(key) => pkcs1::public_key_to_pem(key),
+            PatientPublicKey::X25519(key) => {
+                let mut der = X25519_SPKI_PREFIX.to_vec();
+                der.extend_from_slice(key.as_bytes());
+                pem::encode_string("PUBLIC KEY", LineEnding::LF, &der)
+                    .map_err(|e| anyhow!("Failed to export public key to PEM: {}", e))
+            }
+        }
+    }
+
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            PatientPublicKey::Rsa(_) => KeyAlgorithm::Rsa,
+            PatientPublicKey::X25519(_) => KeyAlgorithm::X25519Hpke,
+        }
+    }
+
+    pub fn fingerprint(&self) -> Result<String> {
+        match self {
+            PatientPublicKey::Rsa(key) => pkcs1::public_key_fingerprint(key),
+            PatientPublicKey::X25519(key) => {
+                let mut der = X25519_SPKI_PREFIX.to_vec();
+                der.extend_from_slice(key.as_bytes());
+                Ok(pkcs1::sha256_hex(&der))
+            }
+        }
+    }
+
+    // Wraps a record's AES key to this public key
+    pub fn wrap_key(&self, aes_key: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            PatientPublicKey::Rsa(key) => pkcs1::wrap_key(aes_key, key),
+            PatientPublicKey::X25519(key) => hpke::seal(key, RECORD_KEY_INFO, b"", aes_key),
+        }
+    }
+}
+
+// Private half of a patient key pair
+#[derive(Clone)]
+pub enum PatientPrivateKey {
+    Rsa(Box<RsaPrivateKey>),
+    X25519(X25519Secret),
+}
+
+impl PatientPrivateKey {
+    pub fn generate(algorithm: KeyAlgorithm, rsa_key_bits: usize) -> Result<Self> {
+        match algorithm {
+            KeyAlgorithm::Rsa => pkcs1::generate_key_pair(rsa_key_bits).map(|(key, _)| PatientPrivateKey::Rsa(Box::new(key))),
+            KeyAlgorithm::X25519Hpke => Ok(PatientPrivateKey::X25519(hpke::generate_key_pair().0)),
+        }
+    }
+
+    pub fn public_key(&self) -> PatientPublicKey {
+        match self {
+            PatientPrivateKey::Rsa(key) => PatientPublicKey::Rsa(RsaPublicKey::from(key.as_ref())),
+            PatientPrivateKey::X25519(key) => PatientPublicKey::X25519(X25519PublicKey::from(key)),
+        }
+    }
+
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            PatientPrivateKey::Rsa(_) => KeyAlgorithm::Rsa,
+            PatientPrivateKey::X25519(_) => KeyAlgorithm::X25519Hpke,
+        }
+    }
+
+    // RSA keys are PKCS1 PEM, X25519 keys PKCS8 PEM
+    pub fn from_pem(algorithm: KeyAlgorithm, pem_str: &str) -> Result<Self> {
+        match algorithm {
+            KeyAlgorithm::Rsa => pkcs1::private_key_from_pem(pem_str).map(|key| PatientPrivateKey::Rsa(Box::new(key))),
+            KeyAlgorithm::X25519Hpke => {
+                let (label, der) = pem::decode_vec(pem_str.as_bytes())
+                    .map_err(|e| anyhow!("Failed to import private key from PEM: {}", e))?;
+                let der = Zeroizing::new(der);
+                if label != "PRIVATE KEY" || !der.starts_with(&X25519_PKCS8_PREFIX) {
+                    return Err(anyhow!("PEM does not hold an X25519 private key"));
+                }
+                Self::from_pkcs8_der(&der)
+            }
+        }
+    }
+
+    pub fn to_pem(&self) -> Result<String> {
+        match self {
+            PatientPrivateKey::Rsa(key) => pkcs1::private_key_to_pem(key),
+            PatientPrivateKey::X25519(_) => {
+                let der = self.to_pkcs8_der()?;
+                pem::encode_string("PRIVATE KEY", LineEnding::LF, &der)
+                    .map_err(|e| anyhow!("Failed to export private key to PEM: {}", e))
+            }
+        }
+    }
+
+    // PKCS8 DER of either algorithm, the plaintext of passphrase-protected exports
+    pub fn to_pkcs8_der(&self) -> Result<Zeroizing<Vec<u8>>> {
+        match self {
+            PatientPrivateKey::Rsa(key) => key.to_pkcs8_der()
+                .map(|der| Zeroizing::new(der.as_bytes().to_vec()))
+                .map_err(|e| anyhow!("Failed to encode private key as PKCS8: {}", e)),
+            PatientPrivateKey::X25519(key) => {
+                let mut der = Zeroizing::new(X25519_PKCS8_PREFIX.to_vec());
+                der.extend_from_slice(key.as_bytes());
+                Ok(der)
+            }
+        }
+    }
+
+    pub fn from_pkcs8_der(der: &[u8]) -> Result<Self> {
+        if let Some(raw) = der.strip_prefix(&X25519_PKCS8_PREFIX[..]) {
+            let raw: [u8; 32] = raw.try_into().map_err(|_| anyhow!("Invalid X25519 private key length"))?;
+            return Ok(PatientPrivateKey::X25519(X25519Secret::from(raw)));
+        }
+        RsaPrivateKey::from_pkcs8_der(der)
+            .map(|key| PatientPrivateKey::Rsa(Box::new(key)))
+            .map_err(|e| anyhow!("Failed to decode PKCS8 private key: {}", e))
+    }
+
+    // Unwraps a record's AES key
+    pub fn unwrap_key(&self, wrapped_key: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            PatientPrivateKey::Rsa(key) => pkcs1::unwrap_key(wrapped_key, key),
+            PatientPrivateKey::X25519(key) => hpke::open(key, RECORD_KEY_INFO, b"", wrapped_key),
+        }
+    }
+}
@@ -0,0 +1,17 @@
+#![no_std]
+
+// Record encryption shared by the MediRust server and its clients. Every
+// format here is stored or sent over the wire, so any change must keep
+// tests/vectors.json passing on both the native and the wasm32 target.
+// Browser bindings live in the medirust-wasm crate.
+
+extern crate alloc;
+
+pub mod aead;
+pub mod hpke;
+pub mod keys;
+pub mod pkcs1;
+pub mod record;
+
+pub use keys::{KeyAlgorithm, PatientPrivateKey, PatientPublicKey};
+pub use record::{open_record, record_aad, seal_record, RecordBinding, SealedRecord, RECORD_AAD_VERSION};
@@ -0,0 +1,68 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use rand_core::OsRng;
+use rsa::{
+    RsaPrivateKey, RsaPublicKey, Pkcs1v15Encrypt,
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPublicKey},
+    pkcs8::LineEnding,
+};
+use sha2::{Digest, Sha256};
+use anyhow::{Result, anyhow};
+
+// RSA key pairs with PKCS#1 v1.5 key wrapping, stored as PKCS1 PEM
+
+// Generates a new RSA key pair of the given modulus size
+pub fn generate_key_pair(bits: usize) -> Result<(RsaPrivateKey, RsaPublicKey)> {
+    let private_key = RsaPrivateKey::new(&mut OsRng, bits)
+        .map_err(|e| anyhow!("Failed to generate RSA private key: {}", e))?;
+    let public_key = RsaPublicKey::from(&private_key);
+    Ok((private_key, public_key))
+}
+
+// Encrypts an AES key using RSA public key
+pub fn wrap_key(aes_key: &[u8], public_key: &RsaPublicKey) -> Result<Vec<u8>> {
+    public_key.encrypt(&mut OsRng, Pkcs1v15Encrypt, aes_key)
+        .map_err(|e| anyhow!("Failed to encrypt AES key with RSA: {}", e))
+}
+
+// Decrypts an AES key using RSA private key
+pub fn unwrap_key(wrapped_key: &[u8], private_key: &RsaPrivateKey) -> Result<Vec<u8>> {
+    private_key.decrypt(Pkcs1v15Encrypt, wrapped_key)
+        .map_err(|e| anyhow!("Failed to decrypt AES key with RSA: {}", e))
+}
+
+pub fn public_key_to_pem(public_key: &RsaPublicKey) -> Result<String> {
+    public_key.to_pkcs1_pem(LineEnding::LF)
+        .map_err(|e| anyhow!("Failed to export public key to PEM: {}", e))
+}
+
+pub fn public_key_from_pem(pem: &str) -> Result<RsaPublicKey> {
+    RsaPublicKey::from_pkcs1_pem(pem)
+        .map_err(|e| anyhow!("Failed to import public key from PEM: {}", e))
+}
+
+pub fn private_key_to_pem(private_key: &RsaPrivateKey) -> Result<String> {
+    private_key.to_pkcs1_pem(LineEnding::LF)
+        .map_err(|e| anyhow!("Failed to export private key to PEM: {}", e))
+        .map(|pem| pem.to_string())
+}
+
+pub fn private_key_from_pem(pem: &str) -> Result<RsaPrivateKey> {
+    RsaPrivateKey::from_pkcs1_pem(pem)
+        .map_err(|e| anyhow!("Failed to import private key from PEM: {}", e))
+}
+
+// SHA-256 fingerprint (hex) of the PKCS1 DER encoding of a public key
+pub fn public_key_fingerprint(public_key: &RsaPublicKey) -> Result<String> {
+    let der = public_key.to_pkcs1_der()
+        .map_err(|e| anyhow!("Failed to encode public key: {}", e))?;
+    Ok(sha256_hex(der.as_bytes()))
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
@@ -0,0 +1,64 @@
+use alloc::vec::Vec;
+use zeroize::Zeroizing;
+use anyhow::Result;
+
+use crate::aead;
+use crate::keys::{PatientPrivateKey, PatientPublicKey};
+
+// Domain separator and current version of record content associated data
+const RECORD_AAD_DOMAIN: &[u8] = b"medirust-record-aad";
+pub const RECORD_AAD_VERSION: i32 = 1;
+
+// Associated data binding a record's content ciphertext to its row, so
+// ciphertexts cannot be swapped between records or relabelled.
+// Version 0 is the legacy format, encrypted without associated data.
+pub fn record_aad(record_id: &[u8], patient_id: &[u8], record_type: &str, version: i32) -> Vec<u8> {
+    if version == 0 {
+        return Vec::new();
+    }
+    let mut aad = RECORD_AAD_DOMAIN.to_vec();
+    aad.extend_from_slice(&version.to_be_bytes());
+    for field in [record_id, patient_id, record_type.as_bytes()] {
+        aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        aad.extend_from_slice(field);
+    }
+    aad
+}
+
+// The row a record's content belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct RecordBinding<'a> {
+    pub record_id: &'a [u8],
+    pub patient_id: &'a [u8],
+    pub record_type: &'a str,
+    pub aad_version: i32,
+}
+
+impl RecordBinding<'_> {
+    pub fn aad(&self) -> Vec<u8> {
+        record_aad(self.record_id, self.patient_id, self.record_type, self.aad_version)
+    }
+}
+
+// Encrypted record content as stored by the server: the ciphertext goes to
+// IPFS, nonce and wrapped key to the health_records row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SealedRecord {
+    pub ciphertext: Vec<u8>,
+    pub nonce: Vec<u8>,
+    pub wrapped_key: Vec<u8>,
+}
+
+// Encrypts content under a fresh record key wrapped to the patient's key,
+// as the server does when creating a record
+pub fn seal_record(public_key: &PatientPublicKey, binding: &RecordBinding<'_>, content: &[u8]) -> Result<SealedRecord> {
+    let aes_key = Zeroizing::new(aead::generate_key());
+    let (ciphertext, nonce) = aead::encrypt(content, &aes_key, &binding.aad())?;
+    let wrapped_key = public_key.wrap_key(&aes_key)?;
+    Ok(SealedRecord { ciphertext, nonce, wrapped_key })
+}
+
+pub fn open_record(private_key: &PatientPrivateKey, binding: &RecordBinding<'_>, sealed: &SealedRecord) -> Result<Zeroizing<Vec<u8>>> {
+    let aes_key = Zeroizing::new(private_key.unwrap_key(&sealed.wrapped_key)?);
+    aead::decrypt(&sealed.ciphertext, &aes_key, &sealed.nonce, &binding.aad()).map(Zeroizing::new)
+}
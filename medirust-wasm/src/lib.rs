@@ -0,0 +1,118 @@
+use medirust_core::record::{self, RecordBinding, SealedRecord, RECORD_AAD_VERSION};
+use medirust_core::{KeyAlgorithm, PatientPrivateKey, PatientPublicKey};
+use wasm_bindgen::prelude::*;
+
+// JavaScript bindings, so a browser can hold the patient key and encrypt
+// before upload or decrypt after download. Binary values are Uint8Arrays;
+// keys are PEM strings in the same formats the server stores. Build with
+// `wasm-pack build --target web medirust-wasm`.
+
+fn js_error(e: anyhow::Error) -> JsError {
+    JsError::new(&format!("{:#}", e))
+}
+
+#[wasm_bindgen]
+pub struct KeyPair {
+    private_key_pem: String,
+    public_key_pem: String,
+    fingerprint: String,
+}
+
+#[wasm_bindgen]
+impl KeyPair {
+    #[wasm_bindgen(getter, js_name = privateKeyPem)]
+    pub fn private_key_pem(&self) -> String {
+        self.private_key_pem.clone()
+    }
+
+    #[wasm_bindgen(getter, js_name = publicKeyPem)]
+    pub fn public_key_pem(&self) -> String {
+        self.public_key_pem.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn fingerprint(&self) -> String {
+        self.fingerprint.clone()
+    }
+}
+
+// Record content encrypted to a patient key, field for field what the
+// server stores: `ciphertext` is the IPFS object, the rest the record row
+#[wasm_bindgen]
+pub struct EncryptedRecord {
+    sealed: SealedRecord,
+    aad_version: i32,
+}
+
+#[wasm_bindgen]
+impl EncryptedRecord {
+    // Wraps values received from the server for `decryptRecord`
+    #[wasm_bindgen(constructor)]
+    pub fn new(ciphertext: Vec<u8>, nonce: Vec<u8>, wrapped_key: Vec<u8>, aad_version: i32) -> EncryptedRecord {
+        EncryptedRecord { sealed: SealedRecord { ciphertext, nonce, wrapped_key }, aad_version }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn ciphertext(&self) -> Vec<u8> {
+        self.sealed.ciphertext.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn nonce(&self) -> Vec<u8> {
+        self.sealed.nonce.clone()
+    }
+
+    #[wasm_bindgen(getter, js_name = wrappedKey)]
+    pub fn wrapped_key(&self) -> Vec<u8> {
+        self.sealed.wrapped_key.clone()
+    }
+
+    #[wasm_bindgen(getter, js_name = aadVersion)]
+    pub fn aad_version(&self) -> i32 {
+        self.aad_version
+    }
+}
+
+// Generates a patient key pair; `algorithm` is a key algorithm id such as
+// "hpke-x25519-sha256-aes256gcm", `rsa_key_bits` only applies to RSA
+#[wasm_bindgen(js_name = generateKeyPair)]
+pub fn generate_key_pair(algorithm: &str, rsa_key_bits: usize) -> Result<KeyPair, JsError> {
+    let algorithm = KeyAlgorithm::from_id(algorithm).map_err(js_error)?;
+    let private_key = PatientPrivateKey::generate(algorithm, rsa_key_bits).map_err(js_error)?;
+    let public_key = private_key.public_key();
+    Ok(KeyPair {
+        private_key_pem: private_key.to_pem().map_err(js_error)?,
+        public_key_pem: public_key.to_pem().map_err(js_error)?,
+        fingerprint: public_key.fingerprint().map_err(js_error)?,
+    })
+}
+
+// Encrypts record content to a patient's public key under the current
+// associated data version
+#[wasm_bindgen(js_name = encryptRecord)]
+pub fn encrypt_record(
+    public_key_pem: &str,
+    record_id: &[u8],
+    patient_id: &[u8],
+    record_type: &str,
+    content: &[u8],
+) -> Result<EncryptedRecord, JsError> {
+    let public_key = PatientPublicKey::from_pem(KeyAlgorithm::detect(public_key_pem), public_key_pem).map_err(js_error)?;
+    let binding = RecordBinding { record_id, patient_id, record_type, aad_version: RECORD_AAD_VERSION };
+    let sealed = record::seal_record(&public_key, &binding, content).map_err(js_error)?;
+    Ok(EncryptedRecord { sealed, aad_version: RECORD_AAD_VERSION })
+}
+
+#[wasm_bindgen(js_name = decryptRecord)]
+pub fn decrypt_record(
+    private_key_pem: &str,
+    record_id: &[u8],
+    patient_id: &[u8],
+    record_type: &str,
+    record: &EncryptedRecord,
+) -> Result<Vec<u8>, JsError> {
+    let private_key = PatientPrivateKey::from_pem(KeyAlgorithm::detect(private_key_pem), private_key_pem).map_err(js_error)?;
+    let binding = RecordBinding { record_id, patient_id, record_type, aad_version: record.aad_version };
+    let content = record::open_record(&private_key, &binding, &record.sealed).map_err(js_error)?;
+    Ok(content.to_vec())
+}
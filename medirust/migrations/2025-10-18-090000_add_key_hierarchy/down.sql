@@ -0,0 +1,11 @@
+ALTER TABLE health_records
+DROP COLUMN data_key_id;
+
+ALTER TABLE patients
+DROP COLUMN escrow_key_id;
+
+ALTER TABLE patients
+DROP COLUMN escrowed_private_key;
+
+DROP INDEX data_keys_tenant_idx;
+DROP TABLE data_keys;
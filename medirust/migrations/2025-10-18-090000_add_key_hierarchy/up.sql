@@ -0,0 +1,19 @@
+CREATE TABLE data_keys (
+    id VARCHAR(64) PRIMARY KEY NOT NULL,
+    tenant VARCHAR(255) NOT NULL,
+    root_key_id VARCHAR(64) NOT NULL, -- root key the data key is currently wrapped under
+    wrapped_key TEXT NOT NULL, -- base64(nonce || AES-GCM ciphertext)
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX data_keys_tenant_idx ON data_keys (tenant, created_at);
+
+ALTER TABLE patients
+ADD COLUMN escrowed_private_key TEXT;
+
+ALTER TABLE patients
+ADD COLUMN escrow_key_id VARCHAR(64);
+
+ALTER TABLE health_records
+ADD COLUMN data_key_id VARCHAR(64);
@@ -0,0 +1,5 @@
+DROP TABLE patient_key_history;
+DROP TABLE key_rotation_jobs;
+
+ALTER TABLE health_records
+DROP COLUMN key_fingerprint;
@@ -0,0 +1,29 @@
+ALTER TABLE health_records
+ADD COLUMN key_fingerprint VARCHAR(64); -- patient key wrapping encrypted_aes_key; NULL for legacy records
+
+CREATE TABLE key_rotation_jobs (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    patient_id BLOB NOT NULL,
+    old_key_fingerprint VARCHAR(64) NOT NULL,
+    new_key_fingerprint VARCHAR(64) NOT NULL,
+    status VARCHAR(32) NOT NULL,
+    total_records BIGINT NOT NULL,
+    rewrapped_records BIGINT NOT NULL DEFAULT 0,
+    error TEXT,
+    old_escrowed_private_key TEXT, -- kept until the job completes so it can be resumed
+    old_escrow_key_id VARCHAR(64),
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    completed_at DATETIME,
+    FOREIGN KEY (patient_id) REFERENCES patients(id) ON DELETE CASCADE
+);
+
+CREATE TABLE patient_key_history (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    patient_id BLOB NOT NULL,
+    key_fingerprint VARCHAR(64) NOT NULL,
+    public_key_pem TEXT NOT NULL,
+    rotation_job_id BLOB,
+    retired_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (patient_id) REFERENCES patients(id) ON DELETE CASCADE
+);
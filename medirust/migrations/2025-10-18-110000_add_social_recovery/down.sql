@@ -0,0 +1,4 @@
+DROP TABLE recovery_submissions;
+DROP TABLE recovery_requests;
+DROP TABLE recovery_guardians;
+DROP TABLE recovery_configs;
@@ -0,0 +1,43 @@
+CREATE TABLE recovery_configs (
+    patient_id BLOB PRIMARY KEY NOT NULL,
+    threshold INTEGER NOT NULL,
+    share_count INTEGER NOT NULL,
+    key_fingerprint VARCHAR(64) NOT NULL, -- patient key protected by this configuration
+    encrypted_private_key TEXT NOT NULL, -- base64(nonce || AES-GCM ciphertext) under the recovery key
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (patient_id) REFERENCES patients(id) ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_guardians (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    patient_id BLOB NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    public_key_pem TEXT NOT NULL,
+    encrypted_share TEXT NOT NULL, -- share encrypted to the guardian's public key
+    share_hash VARCHAR(64) NOT NULL, -- SHA-256 of the share, to validate submissions
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (patient_id) REFERENCES patients(id) ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_requests (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    patient_id BLOB NOT NULL,
+    status VARCHAR(32) NOT NULL,
+    verified_by VARCHAR(255),
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL,
+    completed_at DATETIME,
+    FOREIGN KEY (patient_id) REFERENCES patients(id) ON DELETE CASCADE
+);
+
+CREATE TABLE recovery_submissions (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    request_id BLOB NOT NULL,
+    guardian_id BLOB NOT NULL,
+    share TEXT NOT NULL, -- sealed under the tenant data key when one is configured
+    submitted_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (request_id, guardian_id),
+    FOREIGN KEY (request_id) REFERENCES recovery_requests(id) ON DELETE CASCADE,
+    FOREIGN KEY (guardian_id) REFERENCES recovery_guardians(id) ON DELETE CASCADE
+);
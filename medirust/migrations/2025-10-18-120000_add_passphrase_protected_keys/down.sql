@@ -0,0 +1,2 @@
+ALTER TABLE patients
+DROP COLUMN passphrase_protected_key;
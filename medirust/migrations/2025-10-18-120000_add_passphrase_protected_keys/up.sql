@@ -0,0 +1,2 @@
+ALTER TABLE patients
+ADD COLUMN passphrase_protected_key TEXT;
@@ -0,0 +1,8 @@
+ALTER TABLE patient_key_history
+DROP COLUMN key_algorithm;
+
+ALTER TABLE key_rotation_jobs
+DROP COLUMN old_key_algorithm;
+
+ALTER TABLE patients
+DROP COLUMN key_algorithm;
@@ -0,0 +1,9 @@
+-- Key-encapsulation backend of the patient key pair; existing keys are RSA
+ALTER TABLE patients
+ADD COLUMN key_algorithm VARCHAR(64) NOT NULL DEFAULT 'rsa-pkcs1v15';
+
+ALTER TABLE key_rotation_jobs
+ADD COLUMN old_key_algorithm VARCHAR(64) NOT NULL DEFAULT 'rsa-pkcs1v15';
+
+ALTER TABLE patient_key_history
+ADD COLUMN key_algorithm VARCHAR(64) NOT NULL DEFAULT 'rsa-pkcs1v15';
@@ -0,0 +1,12 @@
+DROP INDEX delegations_patient_clinician_idx;
+DROP TABLE delegations;
+DROP TABLE clinician_keys;
+
+ALTER TABLE health_records
+DROP COLUMN pre_wrapped_key;
+
+ALTER TABLE health_records
+DROP COLUMN pre_capsule;
+
+ALTER TABLE patients
+DROP COLUMN pre_public_key;
@@ -0,0 +1,27 @@
+ALTER TABLE patients
+ADD COLUMN pre_public_key TEXT; -- base64 Ristretto point; enables delegated access
+
+ALTER TABLE health_records
+ADD COLUMN pre_capsule TEXT; -- base64 capsule encapsulating pre_wrapped_key's key
+
+ALTER TABLE health_records
+ADD COLUMN pre_wrapped_key TEXT; -- base64(nonce || AES-GCM ciphertext of the record AES key)
+
+CREATE TABLE clinician_keys (
+    principal VARCHAR(255) PRIMARY KEY NOT NULL,
+    pre_public_key TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE delegations (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    patient_id BLOB NOT NULL,
+    clinician VARCHAR(255) NOT NULL,
+    re_key TEXT, -- cleared on revocation
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at DATETIME,
+    FOREIGN KEY (patient_id) REFERENCES patients(id) ON DELETE CASCADE
+);
+
+CREATE INDEX delegations_patient_clinician_idx ON delegations (patient_id, clinician);
@@ -0,0 +1,8 @@
+ALTER TABLE health_records
+DROP COLUMN signing_key_id;
+
+ALTER TABLE health_records
+DROP COLUMN signature;
+
+DROP INDEX signing_keys_principal_idx;
+DROP TABLE signing_keys;
@@ -0,0 +1,15 @@
+CREATE TABLE signing_keys (
+    id VARCHAR(64) PRIMARY KEY NOT NULL, -- SHA-256 fingerprint of the SPKI DER
+    principal VARCHAR(255) NOT NULL,
+    algorithm VARCHAR(64) NOT NULL,
+    public_key_pem TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX signing_keys_principal_idx ON signing_keys (principal);
+
+ALTER TABLE health_records
+ADD COLUMN signature TEXT; -- base64 detached signature by the authoring clinician
+
+ALTER TABLE health_records
+ADD COLUMN signing_key_id VARCHAR(64);
@@ -0,0 +1,2 @@
+ALTER TABLE health_records
+DROP COLUMN aad_version;
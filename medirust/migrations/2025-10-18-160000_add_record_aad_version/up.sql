@@ -0,0 +1,2 @@
+ALTER TABLE health_records
+ADD COLUMN aad_version INTEGER NOT NULL DEFAULT 0; -- 0: legacy content encrypted without associated data
@@ -0,0 +1,2 @@
+DROP INDEX credentials_patient_idx;
+DROP TABLE credentials;
@@ -0,0 +1,13 @@
+-- No foreign keys: rows outlive deleted records so revoked credentials stay revoked
+CREATE TABLE credentials (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    patient_id BLOB NOT NULL,
+    record_id BLOB NOT NULL,
+    credential_type VARCHAR(64) NOT NULL,
+    status_index INTEGER NOT NULL UNIQUE, -- bit in the revocation status list
+    issued_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL,
+    revoked_at DATETIME
+);
+
+CREATE INDEX credentials_patient_idx ON credentials (patient_id);
@@ -0,0 +1,4 @@
+DROP INDEX audit_events_batch_idx;
+DROP INDEX audit_events_patient_idx;
+DROP TABLE audit_events;
+DROP TABLE audit_batches;
@@ -0,0 +1,27 @@
+-- No foreign keys: the audit trail must outlive the patients and records it mentions
+CREATE TABLE audit_batches (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    merkle_root VARCHAR(64) NOT NULL, -- hex SHA-256 over the leaf hashes (RFC 9162)
+    leaf_count INTEGER NOT NULL,
+    ledger VARCHAR(64) NOT NULL,
+    anchor_ref VARCHAR(255), -- ledger reference, set once the root is anchored
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    anchored_at DATETIME
+);
+
+CREATE TABLE audit_events (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    occurred_at DATETIME NOT NULL,
+    actor VARCHAR(255) NOT NULL,
+    action VARCHAR(255) NOT NULL,
+    patient_id BLOB, -- UUID as BLOB
+    record_id BLOB, -- UUID as BLOB
+    record_cid VARCHAR(255),
+    status INTEGER NOT NULL,
+    leaf_hash VARCHAR(64) NOT NULL,
+    batch_id BLOB, -- set when the event is sealed into a batch
+    leaf_index INTEGER
+);
+
+CREATE INDEX audit_events_patient_idx ON audit_events (patient_id);
+CREATE INDEX audit_events_batch_idx ON audit_events (batch_id, leaf_index);
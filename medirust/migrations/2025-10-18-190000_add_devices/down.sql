@@ -0,0 +1,4 @@
+DROP INDEX health_records_patient_updated_idx;
+DROP TABLE device_record_keys;
+DROP INDEX devices_patient_idx;
+DROP TABLE devices;
@@ -0,0 +1,24 @@
+CREATE TABLE devices (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    patient_id BLOB NOT NULL REFERENCES patients(id),
+    name VARCHAR(255) NOT NULL,
+    key_algorithm VARCHAR(64) NOT NULL,
+    public_key_pem TEXT NOT NULL,
+    key_fingerprint VARCHAR(64) NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    revoked_at DATETIME
+);
+
+CREATE INDEX devices_patient_idx ON devices (patient_id);
+
+-- Record AES keys wrapped to each device's public key
+CREATE TABLE device_record_keys (
+    device_id BLOB NOT NULL REFERENCES devices(id),
+    record_id BLOB NOT NULL REFERENCES health_records(id),
+    wrapped_key TEXT NOT NULL, -- base64
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (device_id, record_id)
+);
+
+-- Change feed cursors page through a patient's records in (updated_at, id) order
+CREATE INDEX health_records_patient_updated_idx ON health_records (patient_id, updated_at, id);
@@ -0,0 +1,9 @@
+-- Operators allowed to administer the vault, authenticated like clinicians
+-- by the SHA-256 fingerprint of their TLS client certificate
+CREATE TABLE admin_users (
+    id BLOB PRIMARY KEY NOT NULL, -- UUID as BLOB
+    name VARCHAR(255) NOT NULL UNIQUE,
+    certificate_fingerprint VARCHAR(64) NOT NULL UNIQUE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    disabled_at DATETIME
+);
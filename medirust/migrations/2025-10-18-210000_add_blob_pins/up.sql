@@ -0,0 +1,13 @@
+-- Pin state of every blob the deployment stores, reconciled against the IPFS node
+CREATE TABLE blob_pins (
+    cid VARCHAR(255) PRIMARY KEY NOT NULL,
+    status VARCHAR(32) NOT NULL, -- pending, pinned, missing or released
+    error TEXT, -- last pin or repair failure
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    verified_at DATETIME -- last time the node reported the pin
+);
+
+-- Blobs stored before pins were tracked; the first reconciliation confirms them
+INSERT INTO blob_pins (cid, status)
+SELECT DISTINCT ipfs_cid, 'pending' FROM health_records;
@@ -0,0 +1,2 @@
+ALTER TABLE health_records
+DROP COLUMN content_sha256;
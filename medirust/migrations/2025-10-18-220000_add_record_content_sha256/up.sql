@@ -0,0 +1,2 @@
+ALTER TABLE health_records
+ADD COLUMN content_sha256 VARCHAR(64); -- hex SHA-256 of the encrypted content; NULL for records stored before it was kept
@@ -0,0 +1,24 @@
+-- Dropping the sealed columns would lose the titles and types of sealed
+-- records. Write them back in plaintext with `medirust-admin
+-- unseal-metadata` first; until then this refuses to run.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM health_records WHERE metadata_key_id IS NOT NULL) THEN
+        RAISE EXCEPTION 'health_records has sealed titles and types; run medirust-admin unseal-metadata before reverting';
+    END IF;
+END
+$$;
+
+DROP INDEX health_records_record_type_index_idx;
+
+ALTER TABLE health_records
+DROP COLUMN record_type_index;
+
+ALTER TABLE health_records
+DROP COLUMN sealed_title;
+
+ALTER TABLE health_records
+DROP COLUMN sealed_record_type;
+
+ALTER TABLE health_records
+DROP COLUMN metadata_key_id;
@@ -0,0 +1,19 @@
+-- Record titles and types move into columns sealed under a tenant data key;
+-- the plaintext columns are left empty for sealed records
+ALTER TABLE health_records
+ADD COLUMN metadata_key_id VARCHAR(64); -- data key sealing the columns below; NULL while record_type and title are plaintext
+
+ALTER TABLE health_records
+ADD COLUMN sealed_record_type TEXT; -- base64(nonce || AES-GCM ciphertext)
+
+ALTER TABLE health_records
+ADD COLUMN sealed_title TEXT;
+
+ALTER TABLE health_records
+ADD COLUMN record_type_index VARCHAR(64); -- hex HMAC-SHA256 of the record type under a key derived from metadata_key_id
+
+CREATE INDEX health_records_record_type_index_idx ON health_records (patient_id, record_type_index);
+
+-- SQL alone cannot encrypt existing rows. `medirust-admin migrate` seals
+-- them right after applying this, and refuses to finish without a root key;
+-- the server, which needs the root key to start, seals any it still finds.
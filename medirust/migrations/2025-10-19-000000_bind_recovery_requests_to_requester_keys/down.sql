@@ -0,0 +1,2 @@
+ALTER TABLE recovery_requests
+DROP COLUMN recovery_public_key_pem;
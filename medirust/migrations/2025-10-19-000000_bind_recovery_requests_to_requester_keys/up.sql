@@ -0,0 +1,11 @@
+-- Recovery requests carry the requester's public key: guardian shares are
+-- wrapped to it as they are handed in and the rebuilt private key is returned
+-- encrypted to it. Open requests cannot be bound after the fact, so they are
+-- dropped together with the shares held for them.
+DELETE FROM recovery_submissions;
+
+DELETE FROM recovery_requests
+WHERE status <> 'completed';
+
+ALTER TABLE recovery_requests
+ADD COLUMN recovery_public_key_pem TEXT; -- NULL only for requests completed before this migration
@@ -0,0 +1,2 @@
+ALTER TABLE recovery_requests
+DROP COLUMN access_token_hash;
@@ -0,0 +1,11 @@
+-- Only the requester can follow a recovery request: opening one returns a
+-- token whose SHA-256 is kept here. Requests opened before have no token and
+-- can no longer be read or completed, so open ones are dropped.
+DELETE FROM recovery_submissions
+WHERE request_id IN (SELECT id FROM recovery_requests WHERE status <> 'completed');
+
+DELETE FROM recovery_requests
+WHERE status <> 'completed';
+
+ALTER TABLE recovery_requests
+ADD COLUMN access_token_hash TEXT; -- NULL only for requests completed before this migration
@@ -0,0 +1,542 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    rt, web, HttpResponse, Responder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{SubsecRound, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Duration;
+use uuid::Uuid;
+use anyhow::{Result, anyhow};
+
+use crate::auth::{self, Principal};
+use crate::config::AppConfig;
+use crate::ledger::Ledger;
+use crate::merkle::{self, Hash};
+use crate::models::{AuditBatch, AuditEvent};
+use crate::schema::{audit_batches, audit_events};
+use crate::DbPool;
+
+// Tamper-evident audit trail. Each access to patient data is stored with the
+// hash of its canonical encoding; the anchoring task seals unbatched events
+// into RFC 9162 Merkle trees and anchors each root to the configured ledger.
+// An inclusion proof then ties a single event to an anchored root.
+
+const LEAF_DOMAIN: &[u8] = b"medirust-audit-v1";
+const ANONYMOUS_ACTOR: &str = "anonymous";
+
+// Action recorded when a record's content is stored on IPFS
+pub const RECORD_CREATE_ACTION: &str = "record.create";
+// Action recorded when a record's content fails its integrity check on read
+pub const RECORD_INTEGRITY_FAILURE_ACTION: &str = "record.integrity_failure";
+
+// Who an audit event is attributed to
+pub fn actor(principal: Option<&Principal>) -> String {
+    principal.map(|p| p.name.clone()).unwrap_or_else(|| ANONYMOUS_ACTOR.to_string())
+}
+
+// New, not yet recorded event; the leaf hash is set by `record_event`
+pub fn new_event(actor: String, action: String, status: u16) -> AuditEvent {
+    AuditEvent {
+        id: Uuid::new_v4().as_bytes().to_vec(),
+        // The database keeps microseconds; truncate so the leaf hash survives a round trip
+        occurred_at: Utc::now().naive_utc().trunc_subsecs(6),
+        actor,
+        action,
+        patient_id: None,
+        record_id: None,
+        record_cid: None,
+        status: i32::from(status),
+        leaf_hash: String::new(),
+        batch_id: None,
+        leaf_index: None,
+    }
+}
+
+fn push_field(out: &mut Vec<u8>, field: Option<&[u8]>) {
+    match field {
+        Some(bytes) => {
+            out.push(1);
+            out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            out.extend_from_slice(bytes);
+        }
+        None => out.push(0),
+    }
+}
+
+// Canonical encoding of an event committed to by its Merkle leaf
+pub fn leaf_data(event: &AuditEvent) -> Vec<u8> {
+    let mut out = LEAF_DOMAIN.to_vec();
+    push_field(&mut out, Some(&event.id));
+    push_field(&mut out, Some(&event.occurred_at.and_utc().timestamp_micros().to_be_bytes()));
+    push_field(&mut out, Some(event.actor.as_bytes()));
+    push_field(&mut out, Some(event.action.as_bytes()));
+    push_field(&mut out, event.patient_id.as_deref());
+    push_field(&mut out, event.record_id.as_deref());
+    push_field(&mut out, event.record_cid.as_deref().map(str::as_bytes));
+    push_field(&mut out, Some(&event.status.to_be_bytes()));
+    out
+}
+
+pub fn record_event(conn: &mut PgConnection, mut event: AuditEvent) -> Result<AuditEvent> {
+    event.leaf_hash = merkle::to_hex(&merkle::leaf_hash(&leaf_data(&event)));
+    diesel::insert_into(audit_events::table)
+        .values(&event)
+        .execute(conn)?;
+    Ok(event)
+}
+
+fn path_uuid(req: &ServiceRequest, name: &str) -> Option<Vec<u8>> {
+    req.match_info()
+        .get(name)
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .map(|uuid| uuid.as_bytes().to_vec())
+}
+
+// Middleware recording every request to a patient route, including rejected ones
+pub async fn record_access(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let config = req.app_data::<web::Data<AppConfig>>().cloned();
+    let pool = req.app_data::<web::Data<DbPool>>().cloned();
+    let pattern = req.match_pattern().filter(|pattern| pattern.starts_with("/patients"));
+    let (pool, pattern) = match (config, pool, pattern) {
+        (Some(config), Some(pool), Some(pattern)) if config.audit.enabled => (pool, pattern),
+        _ => return next.call(req).await,
+    };
+
+    let principal = auth::resolve(req.request()).await.unwrap_or_else(|e| {
+        tracing::error!(error = %e, "failed to resolve principal for audit event");
+        None
+    });
+    let mut event = new_event(actor(principal.as_ref()), format!("{} {}", req.method(), pattern), 0);
+    event.patient_id = path_uuid(&req, "patient_id");
+    event.record_id = path_uuid(&req, "record_id");
+
+    let res = next.call(req).await?;
+    event.status = i32::from(res.status().as_u16());
+    let written = web::block(move || -> Result<AuditEvent> {
+        let mut conn = pool.get()?;
+        record_event(&mut conn, event)
+    })
+    .await;
+    match written {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => tracing::error!(error = %e, "failed to record audit event"),
+        Err(e) => tracing::error!(error = %e, "failed to record audit event"),
+    }
+    Ok(res)
+}
+
+// Seals up to `max_batch_size` unbatched events, oldest first, into a new batch
+fn seal_batch(conn: &mut PgConnection, ledger_id: &str, max_batch_size: usize) -> Result<Option<AuditBatch>> {
+    conn.transaction(|conn| {
+        let pending: Vec<(Vec<u8>, String)> = audit_events::table
+            .filter(audit_events::batch_id.is_null())
+            .order((audit_events::occurred_at.asc(), audit_events::id.asc()))
+            .limit(max_batch_size as i64)
+            .select((audit_events::id, audit_events::leaf_hash))
+            .for_update()
+            .skip_locked()
+            .load(conn)?;
+        if pending.is_empty() {
+            return Ok(None);
+        }
+
+        let leaves = pending
+            .iter()
+            .map(|(_, leaf_hash)| merkle::from_hex(leaf_hash).ok_or_else(|| anyhow!("Corrupt leaf hash {}", leaf_hash)))
+            .collect::<Result<Vec<Hash>>>()?;
+        let batch = AuditBatch {
+            id: Uuid::new_v4().as_bytes().to_vec(),
+            merkle_root: merkle::to_hex(&merkle::root(&leaves)),
+            leaf_count: leaves.len() as i32,
+            ledger: ledger_id.to_string(),
+            anchor_ref: None,
+            created_at: Utc::now().naive_utc(),
+            anchored_at: None,
+        };
+        diesel::insert_into(audit_batches::table)
+            .values(&batch)
+            .execute(conn)?;
+        for (index, (event_id, _)) in pending.into_iter().enumerate() {
+            diesel::update(audit_events::table.find(event_id))
+                .set((
+                    audit_events::batch_id.eq(Some(batch.id.clone())),
+                    audit_events::leaf_index.eq(Some(index as i32)),
+                ))
+                .execute(conn)?;
+        }
+        Ok(Some(batch))
+    })
+}
+
+// Seals all pending events and anchors every batch without an anchor. A crash
+// between anchoring and storing the reference anchors that root again on the
+// next run, which is harmless.
+pub async fn anchor_pending(pool: &DbPool, ledger: &Ledger, max_batch_size: usize) -> Result<usize> {
+    loop {
+        let pool = pool.clone();
+        let ledger_id = ledger.id();
+        let sealed = web::block(move || -> Result<Option<AuditBatch>> {
+            let mut conn = pool.get()?;
+            seal_batch(&mut conn, ledger_id, max_batch_size)
+        })
+        .await??;
+        if sealed.is_none() {
+            break;
+        }
+    }
+
+    let unanchored_pool = pool.clone();
+    let unanchored = web::block(move || -> Result<Vec<AuditBatch>> {
+        let mut conn = unanchored_pool.get()?;
+        Ok(audit_batches::table
+            .filter(audit_batches::anchor_ref.is_null())
+            .order(audit_batches::created_at.asc())
+            .select(AuditBatch::as_select())
+            .load(&mut conn)?)
+    })
+    .await??;
+
+    let mut anchored = 0;
+    for batch in unanchored {
+        let root = merkle::from_hex(&batch.merkle_root).ok_or_else(|| anyhow!("Corrupt Merkle root {}", batch.merkle_root))?;
+        let anchor_ref = ledger.anchor(&root).await?;
+        let pool = pool.clone();
+        web::block(move || -> Result<usize> {
+            let mut conn = pool.get()?;
+            Ok(diesel::update(audit_batches::table.find(batch.id))
+                .set((
+                    audit_batches::anchor_ref.eq(Some(anchor_ref)),
+                    audit_batches::anchored_at.eq(Some(Utc::now().naive_utc())),
+                ))
+                .execute(&mut conn)?)
+        })
+        .await??;
+        anchored += 1;
+    }
+    Ok(anchored)
+}
+
+// Runs `anchor_pending` every `audit.anchor_interval_secs` on the server runtime
+pub fn spawn_anchoring(pool: DbPool, ledger: web::Data<Option<Ledger>>, config: &AppConfig) {
+    let interval_secs = config.audit.anchor_interval_secs;
+    let max_batch_size = config.audit.max_batch_size;
+    rt::spawn(async move {
+        let Some(ledger) = ledger.get_ref().as_ref() else {
+            return;
+        };
+        let mut interval = rt::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            match anchor_pending(&pool, ledger, max_batch_size).await {
+                Ok(0) => {}
+                Ok(anchored) => tracing::info!(anchored, ledger = ledger.id(), "anchored audit batches"),
+                Err(e) => tracing::error!(error = %e, "failed to anchor audit batches"),
+            }
+        }
+    });
+}
+
+fn uuid_string(id: &[u8]) -> String {
+    Uuid::from_slice(id).unwrap_or_default().to_string()
+}
+
+fn event_json(event: &AuditEvent) -> Value {
+    json!({
+        "id": uuid_string(&event.id),
+        "occurred_at": event.occurred_at,
+        "actor": event.actor,
+        "action": event.action,
+        "patient_id": event.patient_id.as_deref().map(uuid_string),
+        "record_id": event.record_id.as_deref().map(uuid_string),
+        "record_cid": event.record_cid,
+        "status": event.status,
+        "leaf_hash": event.leaf_hash,
+        "batch_id": event.batch_id.as_deref().map(uuid_string),
+        "leaf_index": event.leaf_index,
+    })
+}
+
+fn batch_json(batch: &AuditBatch) -> Value {
+    json!({
+        "id": uuid_string(&batch.id),
+        "merkle_root": batch.merkle_root,
+        "leaf_count": batch.leaf_count,
+        "ledger": batch.ledger,
+        "anchor_ref": batch.anchor_ref,
+        "created_at": batch.created_at,
+        "anchored_at": batch.anchored_at,
+    })
+}
+
+fn load_batch_leaves(conn: &mut PgConnection, batch_id: &[u8]) -> Result<Vec<Hash>> {
+    let leaf_hashes: Vec<String> = audit_events::table
+        .filter(audit_events::batch_id.eq(batch_id))
+        .order(audit_events::leaf_index.asc())
+        .select(audit_events::leaf_hash)
+        .load(conn)?;
+    leaf_hashes
+        .iter()
+        .map(|leaf_hash| merkle::from_hex(leaf_hash).ok_or_else(|| anyhow!("Corrupt leaf hash {}", leaf_hash)))
+        .collect()
+}
+
+// True if the batch's leaves rebuild the root stored for it
+fn root_matches(batch: &AuditBatch, leaves: &[Hash]) -> bool {
+    leaves.len() == batch.leaf_count as usize && merkle::to_hex(&merkle::root(leaves)) == batch.merkle_root
+}
+
+fn event_is_intact(event: &AuditEvent) -> bool {
+    merkle::to_hex(&merkle::leaf_hash(&leaf_data(event))) == event.leaf_hash
+}
+
+// The root the ledger holds for a batch. None until the root is anchored and
+// final, or when the batch was anchored to a ledger other than the configured one.
+pub async fn ledger_root(ledger: Option<&Ledger>, batch: &AuditBatch) -> Result<Option<Hash>> {
+    match (ledger, &batch.anchor_ref) {
+        (Some(ledger), Some(anchor_ref)) if ledger.id() == batch.ledger => ledger.lookup(anchor_ref).await,
+        _ => Ok(None),
+    }
+}
+
+// Outcome of `verify_trail`; ids are UUID strings
+#[derive(Debug, Default)]
+pub struct TrailReport {
+    pub events: usize,
+    pub unsealed_events: usize,
+    pub batches: usize,
+    // Batches not anchored yet, or anchored to a ledger that is not configured
+    pub unverified_anchors: usize,
+    // Events whose contents no longer match their leaf hash
+    pub altered_events: Vec<String>,
+    // Batches whose leaves no longer rebuild the stored root
+    pub broken_batches: Vec<String>,
+    // Anchored batches whose ledger entry is missing or holds another root
+    pub unconfirmed_anchors: Vec<String>,
+}
+
+impl TrailReport {
+    pub fn is_intact(&self) -> bool {
+        self.altered_events.is_empty() && self.broken_batches.is_empty() && self.unconfirmed_anchors.is_empty()
+    }
+}
+
+// Checks the whole audit trail: every event against its leaf hash, every
+// batch root against its leaves, and every anchored root against the ledger
+pub async fn verify_trail(conn: &mut PgConnection, ledger: Option<&Ledger>) -> Result<TrailReport> {
+    let mut report = TrailReport::default();
+    let batches = audit_batches::table
+        .order(audit_batches::created_at.asc())
+        .select(AuditBatch::as_select())
+        .load(conn)?;
+    for batch in &batches {
+        let events = audit_events::table
+            .filter(audit_events::batch_id.eq(&batch.id))
+            .order(audit_events::leaf_index.asc())
+            .select(AuditEvent::as_select())
+            .load(conn)?;
+        report.events += events.len();
+        report.altered_events.extend(events.iter().filter(|event| !event_is_intact(event)).map(|event| uuid_string(&event.id)));
+        let leaves = load_batch_leaves(conn, &batch.id)?;
+        if !root_matches(batch, &leaves) {
+            report.broken_batches.push(uuid_string(&batch.id));
+        }
+        match ledger_root(ledger, batch).await? {
+            Some(root) if merkle::to_hex(&root) == batch.merkle_root => {}
+            Some(_) => report.unconfirmed_anchors.push(uuid_string(&batch.id)),
+            None if batch.anchor_ref.is_some() && ledger.is_some_and(|ledger| ledger.id() == batch.ledger) => {
+                report.unconfirmed_anchors.push(uuid_string(&batch.id));
+            }
+            None => report.unverified_anchors += 1,
+        }
+    }
+    report.batches = batches.len();
+
+    let unsealed = audit_events::table
+        .filter(audit_events::batch_id.is_null())
+        .select(AuditEvent::as_select())
+        .load(conn)?;
+    report.events += unsealed.len();
+    report.unsealed_events = unsealed.len();
+    report.altered_events.extend(unsealed.iter().filter(|event| !event_is_intact(event)).map(|event| uuid_string(&event.id)));
+    Ok(report)
+}
+
+// Handler listing the audit trail of a patient, oldest first
+pub async fn list_patient_events(
+    pool: web::Data<DbPool>,
+    patient_id: web::Path<String>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+
+    match web::block(move || -> Result<Vec<AuditEvent>> {
+        let mut conn = pool.get()?;
+        Ok(audit_events::table
+            .filter(audit_events::patient_id.eq(patient_id_bytes))
+            .order((audit_events::occurred_at.asc(), audit_events::id.asc()))
+            .select(AuditEvent::as_select())
+            .load(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(events)) => HttpResponse::Ok().json(events.iter().map(event_json).collect::<Vec<_>>()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error listing audit events: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// An event with its batch and the batch's leaf hashes in leaf order
+type SealedEvent = (AuditEvent, AuditBatch, Vec<Hash>);
+
+enum Rejection {
+    NotFound,
+    NotSealed,
+}
+
+impl Rejection {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Rejection::NotFound => HttpResponse::NotFound().body("Audit event not found"),
+            Rejection::NotSealed => HttpResponse::Conflict().body("Audit event has not been sealed into a batch yet"),
+        }
+    }
+}
+
+// Handler returning the inclusion proof of an event in its anchored batch
+pub async fn get_event_proof(
+    pool: web::Data<DbPool>,
+    event_id: web::Path<String>,
+) -> impl Responder {
+    let event_id_bytes = match Uuid::parse_str(&event_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid event ID"),
+    };
+
+    let loaded = web::block(move || -> Result<Result<SealedEvent, Rejection>> {
+        let mut conn = pool.get()?;
+        let Some(event) = audit_events::table
+            .find(event_id_bytes)
+            .select(AuditEvent::as_select())
+            .first(&mut conn)
+            .optional()?
+        else {
+            return Ok(Err(Rejection::NotFound));
+        };
+        let Some(batch_id) = event.batch_id.clone() else {
+            return Ok(Err(Rejection::NotSealed));
+        };
+        let batch = audit_batches::table
+            .find(&batch_id)
+            .select(AuditBatch::as_select())
+            .first(&mut conn)?;
+        let leaves = load_batch_leaves(&mut conn, &batch_id)?;
+        Ok(Ok((event, batch, leaves)))
+    })
+    .await;
+    let (event, batch, leaves) = match loaded {
+        Ok(Ok(Ok(loaded))) => loaded,
+        Ok(Ok(Err(rejection))) => return rejection.into_response(),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error loading audit event: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    let leaf_index = event.leaf_index.unwrap_or_default() as usize;
+    let Some(audit_path) = merkle::inclusion_proof(leaf_index, &leaves) else {
+        return HttpResponse::InternalServerError().body("Audit event is missing from its batch");
+    };
+    let data = leaf_data(&event);
+    // False if the stored event no longer matches the hash sealed into the tree
+    let event_intact = event_is_intact(&event);
+
+    HttpResponse::Ok().json(json!({
+        "event": event_json(&event),
+        "event_intact": event_intact,
+        "leaf_data": STANDARD.encode(&data),
+        "leaf_hash": event.leaf_hash,
+        "leaf_index": leaf_index,
+        "tree_size": leaves.len(),
+        "audit_path": audit_path.iter().map(|hash| merkle::to_hex(hash)).collect::<Vec<_>>(),
+        "merkle_root": batch.merkle_root,
+        "batch": batch_json(&batch),
+    }))
+}
+
+// Handler returning a batch, with its root recomputed from the stored events
+// and checked against what the ledger recorded
+pub async fn get_batch(
+    pool: web::Data<DbPool>,
+    ledger: web::Data<Option<Ledger>>,
+    batch_id: web::Path<String>,
+) -> impl Responder {
+    let batch_id_bytes = match Uuid::parse_str(&batch_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid batch ID"),
+    };
+
+    let loaded = web::block(move || -> Result<Option<(AuditBatch, Vec<Hash>)>> {
+        let mut conn = pool.get()?;
+        let Some(batch) = audit_batches::table
+            .find(&batch_id_bytes)
+            .select(AuditBatch::as_select())
+            .first(&mut conn)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let leaves = load_batch_leaves(&mut conn, &batch_id_bytes)?;
+        Ok(Some((batch, leaves)))
+    })
+    .await;
+    let (batch, leaves) = match loaded {
+        Ok(Ok(Some(loaded))) => loaded,
+        Ok(Ok(None)) => return HttpResponse::NotFound().body("Audit batch not found"),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error loading audit batch: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    let root_matches_events = root_matches(&batch, &leaves);
+    let ledger_root = match ledger_root(ledger.get_ref().as_ref(), &batch).await {
+        Ok(root) => root,
+        Err(e) => return HttpResponse::BadGateway().body(format!("Error querying ledger: {:?}", e)),
+    };
+
+    HttpResponse::Ok().json(json!({
+        "batch": batch_json(&batch),
+        "root_matches_events": root_matches_events,
+        "ledger_root": ledger_root.map(|root| merkle::to_hex(&root)),
+        "anchor_confirmed": ledger_root.map(|root| merkle::to_hex(&root) == batch.merkle_root),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InclusionProof {
+    pub leaf_hash: String,
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    pub audit_path: Vec<String>,
+    pub merkle_root: String,
+}
+
+// Handler checking an inclusion proof; needs no access to the audit tables
+pub async fn verify_proof(proof: web::Json<InclusionProof>) -> impl Responder {
+    let leaf = merkle::from_hex(&proof.leaf_hash);
+    let root = merkle::from_hex(&proof.merkle_root);
+    let path: Option<Vec<Hash>> = proof.audit_path.iter().map(|hash| merkle::from_hex(hash)).collect();
+    match (leaf, root, path) {
+        (Some(leaf), Some(root), Some(path)) => HttpResponse::Ok().json(json!({
+            "valid": merkle::verify_inclusion(&leaf, proof.leaf_index, proof.tree_size, &path, &root),
+        })),
+        _ => HttpResponse::BadRequest().body("Hashes must be 32-byte hex strings"),
+    }
+}
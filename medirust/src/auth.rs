@@ -0,0 +1,294 @@
+use actix_web::{dev::Payload, error, http::header, web, FromRequest, HttpRequest, HttpResponse, Responder};
+use anyhow::Result;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use futures::future::{ready, LocalBoxFuture, Ready};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::config::AppConfig;
+use crate::crypto::CryptoUtils;
+use crate::models::{AdminUser, Patient};
+use crate::schema::{admin_users, patients};
+use crate::tls::ClientCertificate;
+use crate::DbPool;
+
+// How long a patient has to answer a challenge, and how long the session lasts
+const CHALLENGE_TTL: Duration = Duration::from_secs(120);
+const SESSION_TTL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PrincipalKind {
+    // A clinician system authenticated by a mutual-TLS client certificate
+    Clinician,
+    // A vault operator listed in admin_users, added with medirust-admin
+    Admin,
+}
+
+// The authenticated caller of a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Principal {
+    pub name: String,
+    pub kind: PrincipalKind,
+}
+
+impl Principal {
+    // Resolves a client certificate to a principal using tls.client_principals
+    pub fn from_client_certificate(config: &AppConfig, cert: &ClientCertificate) -> Option<Self> {
+        let fingerprint = cert.fingerprint();
+        config.tls.client_principals
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(&fingerprint))
+            .map(|(_, name)| Principal {
+                name: name.clone(),
+                kind: PrincipalKind::Clinician,
+            })
+    }
+
+    // Resolves a client certificate to an enabled admin user
+    pub fn admin_from_client_certificate(conn: &mut PgConnection, cert: &ClientCertificate) -> QueryResult<Option<Self>> {
+        let admin = admin_users::table
+            .filter(admin_users::certificate_fingerprint.eq(cert.fingerprint()))
+            .filter(admin_users::disabled_at.is_null())
+            .select(AdminUser::as_select())
+            .first(conn)
+            .optional()?;
+        Ok(admin.map(|admin| Principal { name: admin.name, kind: PrincipalKind::Admin }))
+    }
+}
+
+// Resolves the caller of a request: clinicians from tls.client_principals
+// first, then admin users from the database. None without a known certificate.
+pub async fn resolve(req: &HttpRequest) -> Result<Option<Principal>, actix_web::Error> {
+    let config = req.app_data::<web::Data<AppConfig>>()
+        .ok_or_else(|| error::ErrorInternalServerError("Configuration not available"))?;
+    let Some(cert) = req.conn_data::<ClientCertificate>() else {
+        return Ok(None);
+    };
+    if let Some(principal) = Principal::from_client_certificate(config, cert) {
+        return Ok(Some(principal));
+    }
+    let Some(pool) = req.app_data::<web::Data<DbPool>>().cloned() else {
+        return Ok(None);
+    };
+    let cert = cert.clone();
+    web::block(move || -> anyhow::Result<Option<Principal>> {
+        let mut conn = pool.get()?;
+        Ok(Principal::admin_from_client_certificate(&mut conn, &cert)?)
+    })
+    .await?
+    .map_err(|e| error::ErrorInternalServerError(format!("Error resolving admin user: {:?}", e)))
+}
+
+impl FromRequest for Principal {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            if req.conn_data::<ClientCertificate>().is_none() {
+                return Err(error::ErrorUnauthorized("Client certificate required"));
+            }
+            resolve(&req)
+                .await?
+                .ok_or_else(|| error::ErrorForbidden("Client certificate is not mapped to a principal"))
+        })
+    }
+}
+
+// Patient sessions. A patient proves they hold their private key by
+// unwrapping a random challenge the server wrapped to their public key, and
+// gets a short-lived bearer token for the endpoints acting on their keys.
+// Challenges and tokens are kept in memory, hashed, and die with the process.
+#[derive(Default)]
+pub struct PatientSessions {
+    state: Mutex<SessionState>,
+}
+
+#[derive(Default)]
+struct SessionState {
+    // Challenge ID -> patient and SHA-256 of the challenge secret
+    challenges: HashMap<String, (Vec<u8>, String, Instant)>,
+    // SHA-256 of the token -> patient
+    sessions: HashMap<String, (Vec<u8>, Instant)>,
+}
+
+impl PatientSessions {
+    // Stores a challenge for the patient, returning its ID and the secret to wrap
+    pub fn issue_challenge(&self, patient_id: &[u8]) -> (String, Vec<u8>) {
+        let challenge_id = Uuid::new_v4().to_string();
+        let secret = CryptoUtils::generate_aes_key();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.challenges.retain(|_, (_, _, expires)| *expires > now);
+        state.challenges.insert(challenge_id.clone(), (patient_id.to_vec(), sha256_hex(&secret), now + CHALLENGE_TTL));
+        (challenge_id, secret)
+    }
+
+    // Checks a challenge answer, which can only be tried once, and opens a
+    // session for the patient. None if the answer is wrong or too late.
+    pub fn answer_challenge(&self, patient_id: &[u8], challenge_id: &str, secret: &[u8]) -> Option<String> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if !take_challenge(&mut state, patient_id, challenge_id, secret, now) {
+            return None;
+        }
+        Some(open_session(&mut state, patient_id, now))
+    }
+
+    // Checks a challenge answer without opening a session, for key holders
+    // other than the patient such as recovery guardians
+    pub fn verify_challenge(&self, subject_id: &[u8], challenge_id: &str, secret: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        take_challenge(&mut state, subject_id, challenge_id, secret, Instant::now())
+    }
+
+    // Opens a session for a patient authenticated some other way, e.g. by passphrase
+    pub fn open(&self, patient_id: &[u8]) -> String {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        open_session(&mut state, patient_id, Instant::now())
+    }
+
+    // Patient of a live session token
+    pub fn patient(&self, token: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.sessions
+            .get(&sha256_hex(token.as_bytes()))
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(patient_id, _)| patient_id.clone())
+    }
+}
+
+// Removes the challenge and reports whether the answer was right and in time
+fn take_challenge(state: &mut SessionState, subject_id: &[u8], challenge_id: &str, secret: &[u8], now: Instant) -> bool {
+    match state.challenges.remove(challenge_id) {
+        Some((challenged, secret_hash, expires)) => {
+            challenged == subject_id && expires > now && secret_hash == sha256_hex(secret)
+        }
+        None => false,
+    }
+}
+
+fn open_session(state: &mut SessionState, patient_id: &[u8], now: Instant) -> String {
+    let token = CryptoUtils::encode_base64(&CryptoUtils::generate_aes_key());
+    state.sessions.retain(|_, (_, expires)| *expires > now);
+    state.sessions.insert(sha256_hex(token.as_bytes()), (patient_id.to_vec(), now + SESSION_TTL));
+    token
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The patient named by the `{patient_id}` route segment, authenticated by a
+// session token in `Authorization: Bearer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedPatient {
+    pub patient_id: Vec<u8>,
+}
+
+impl FromRequest for AuthenticatedPatient {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authenticated_patient(req))
+    }
+}
+
+fn authenticated_patient(req: &HttpRequest) -> Result<AuthenticatedPatient, actix_web::Error> {
+    let sessions = req.app_data::<web::Data<PatientSessions>>()
+        .ok_or_else(|| error::ErrorInternalServerError("Patient sessions not available"))?;
+    let token = bearer_token(req).ok_or_else(|| error::ErrorUnauthorized("Patient session required"))?;
+    let patient_id = sessions.patient(token)
+        .ok_or_else(|| error::ErrorUnauthorized("Invalid or expired patient session"))?;
+    let route_patient = req.match_info()
+        .get("patient_id")
+        .and_then(|id| Uuid::parse_str(id).ok())
+        .ok_or_else(|| error::ErrorBadRequest("Invalid patient ID"))?;
+    if route_patient.as_bytes().as_slice() != patient_id.as_slice() {
+        return Err(error::ErrorForbidden("Session belongs to another patient"));
+    }
+    Ok(AuthenticatedPatient { patient_id })
+}
+
+// Token sent in `Authorization: Bearer`
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionRequest {
+    pub challenge_id: String,
+    // Base64 challenge secret, unwrapped with the patient's private key
+    pub challenge: String,
+}
+
+// Handler issuing a challenge wrapped to the patient's current public key
+pub async fn create_session_challenge(
+    pool: web::Data<DbPool>,
+    sessions: web::Data<PatientSessions>,
+    patient_id: web::Path<String>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let patient = match web::block(move || -> Result<Option<Patient>> {
+        let mut conn = pool.get()?;
+        Ok(patients::table
+            .find(&patient_id_bytes)
+            .select(Patient::as_select())
+            .first(&mut conn)
+            .optional()?)
+    })
+    .await
+    {
+        Ok(Ok(Some(patient))) => patient,
+        Ok(Ok(None)) => return HttpResponse::NotFound().body("Patient not found"),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error getting patient: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    let (challenge_id, secret) = sessions.issue_challenge(&patient.id);
+    match patient.public_key().and_then(|public_key| public_key.wrap_key(&secret)) {
+        Ok(wrapped) => HttpResponse::Ok().json(json!({
+            "challenge_id": challenge_id,
+            "wrapped_challenge": CryptoUtils::encode_base64(&wrapped),
+            "key_algorithm": patient.key_algorithm,
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error wrapping challenge: {:?}", e)),
+    }
+}
+
+// Handler exchanging an unwrapped challenge for a session token
+pub async fn create_session(
+    sessions: web::Data<PatientSessions>,
+    patient_id: web::Path<String>,
+    request: web::Json<SessionRequest>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let secret = CryptoUtils::decode_base64(&request.challenge).unwrap_or_default();
+    match sessions.answer_challenge(&patient_id_bytes, &request.challenge_id, &secret) {
+        Some(token) => HttpResponse::Ok().json(json!({
+            "token": token,
+            "expires_in": SESSION_TTL.as_secs(),
+        })),
+        // Counted as an authentication failure by the rate limiter
+        None => HttpResponse::Unauthorized().body("Challenge answer is wrong or expired"),
+    }
+}
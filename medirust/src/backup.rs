@@ -0,0 +1,665 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel_migrations::MigrationHarness;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow, Context};
+
+use medirust_core::aead;
+
+use crate::blobstore::BlobStore;
+use crate::crypto::CryptoUtils;
+use crate::keys::RootKey;
+use crate::schema::{self, health_records};
+use crate::AnyConnection;
+
+// Archive layout:
+//
+//   magic | u32 header length | header (JSON) | segments... | SHA-256 trailer
+//
+// The header is plaintext and names the archive, its parent and the wrapped
+// archive key. The payload is split into segments of at most SEGMENT_SIZE
+// bytes, each sealed with AES-256-GCM under the archive key with a counter
+// nonce; the header and a final-segment flag are bound as associated data,
+// so segments cannot be reordered, dropped or moved between archives. The
+// trailer checksums everything before it and can be checked without a key.
+//
+// Decrypted, the payload is a sequence of frames (u8 tag, u64 length, body):
+// the manifest first, then table rows, then blobs.
+const MAGIC: &[u8; 8] = b"MDRBAK01";
+const FORMAT_VERSION: u32 = 1;
+const SEGMENT_SIZE: usize = 64 * 1024;
+const LAST_SEGMENT: u32 = 1 << 31;
+const CHECKSUM_SIZE: usize = 32;
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+// Rows per table frame, so huge tables do not become a single frame
+const ROWS_PER_FRAME: usize = 1000;
+
+const TAG_MANIFEST: u8 = 1;
+const TAG_ROWS: u8 = 2;
+const TAG_BLOB: u8 = 3;
+
+// Snapshot of every table, in an order that satisfies foreign keys on
+// restore. Columns follow schema.rs, so rows load with `SELECT *`; a column
+// added to the schema without being listed here fails to compile.
+macro_rules! backup_tables {
+    ($($table:ident { $($column:ident: $ty:ty),* $(,)? })*) => {
+        mod rows {
+            $(
+                pub mod $table {
+                    use chrono::NaiveDateTime;
+                    use diesel::{Insertable, Queryable};
+                    use serde::{Deserialize, Serialize};
+
+                    #[derive(Debug, Queryable, Insertable, Serialize, Deserialize)]
+                    #[diesel(table_name = crate::schema::$table)]
+                    pub struct Row {
+                        $(pub $column: $ty,)*
+                    }
+                }
+            )*
+        }
+
+        const TABLES: &[&str] = &[$(stringify!($table)),*];
+
+        fn dump_table(conn: &mut AnyConnection, table: &str) -> Result<Vec<Value>> {
+            match table {
+                $(stringify!($table) => schema::$table::table
+                    .load::<rows::$table::Row>(conn)
+                    .with_context(|| format!("Failed to read table {}", table))?
+                    .iter()
+                    .map(|row| serde_json::to_value(row).map_err(|e| anyhow!("Failed to encode a {} row: {}", table, e)))
+                    .collect(),)*
+                _ => Err(anyhow!("Unknown table {}", table)),
+            }
+        }
+
+        fn restore_rows(conn: &mut AnyConnection, table: &str, values: Vec<Value>) -> Result<()> {
+            match table {
+                $(stringify!($table) => {
+                    for value in values {
+                        let row: rows::$table::Row = serde_json::from_value(value)
+                            .map_err(|e| anyhow!("Invalid {} row in archive: {}", table, e))?;
+                        diesel::insert_into(schema::$table::table)
+                            .values(&row)
+                            .execute(conn)
+                            .with_context(|| format!("Failed to restore a {} row", table))?;
+                    }
+                    Ok(())
+                })*
+                _ => Err(anyhow!("Archive holds unknown table {}", table)),
+            }
+        }
+
+        fn count_rows(conn: &mut AnyConnection) -> Result<i64> {
+            let mut total = 0;
+            $(total += schema::$table::table.count().get_result::<i64>(conn)?;)*
+            Ok(total)
+        }
+    };
+}
+
+backup_tables! {
+    admin_users {
+        id: Vec<u8>, name: String, certificate_fingerprint: String, created_at: NaiveDateTime,
+        disabled_at: Option<NaiveDateTime>,
+    }
+    signing_keys {
+        id: String, principal: String, algorithm: String, public_key_pem: String, created_at: NaiveDateTime,
+    }
+    clinician_keys {
+        principal: String, pre_public_key: String, created_at: NaiveDateTime, updated_at: NaiveDateTime,
+    }
+    data_keys {
+        id: String, tenant: String, root_key_id: String, wrapped_key: String, created_at: NaiveDateTime,
+        updated_at: NaiveDateTime,
+    }
+    patients {
+        id: Vec<u8>, health_id: String, name: String, created_at: NaiveDateTime, updated_at: NaiveDateTime,
+        public_key_pem: String, escrowed_private_key: Option<String>, escrow_key_id: Option<String>,
+        passphrase_protected_key: Option<String>, key_algorithm: String, pre_public_key: Option<String>,
+    }
+    health_records {
+        id: Vec<u8>, patient_id: Vec<u8>, ipfs_cid: String, record_type: String, title: String,
+        encryption_key_cid: String, created_at: NaiveDateTime, updated_at: NaiveDateTime,
+        encrypted_aes_key: String, nonce: String, data_key_id: Option<String>, key_fingerprint: Option<String>,
+        pre_capsule: Option<String>, pre_wrapped_key: Option<String>, signature: Option<String>,
+        signing_key_id: Option<String>, aad_version: i32, content_sha256: Option<String>,
+        metadata_key_id: Option<String>, sealed_record_type: Option<String>, sealed_title: Option<String>,
+        record_type_index: Option<String>,
+    }
+    key_rotation_jobs {
+        id: Vec<u8>, patient_id: Vec<u8>, old_key_fingerprint: String, new_key_fingerprint: String,
+        status: String, total_records: i64, rewrapped_records: i64, error: Option<String>,
+        old_escrowed_private_key: Option<String>, old_escrow_key_id: Option<String>,
+        created_at: NaiveDateTime, updated_at: NaiveDateTime, completed_at: Option<NaiveDateTime>,
+        old_key_algorithm: String,
+    }
+    patient_key_history {
+        id: Vec<u8>, patient_id: Vec<u8>, key_fingerprint: String, public_key_pem: String,
+        rotation_job_id: Option<Vec<u8>>, retired_at: NaiveDateTime, key_algorithm: String,
+    }
+    delegations {
+        id: Vec<u8>, patient_id: Vec<u8>, clinician: String, re_key: Option<String>, created_at: NaiveDateTime,
+        revoked_at: Option<NaiveDateTime>,
+    }
+    devices {
+        id: Vec<u8>, patient_id: Vec<u8>, name: String, key_algorithm: String, public_key_pem: String,
+        key_fingerprint: String, created_at: NaiveDateTime, revoked_at: Option<NaiveDateTime>,
+    }
+    device_record_keys {
+        device_id: Vec<u8>, record_id: Vec<u8>, wrapped_key: String, created_at: NaiveDateTime,
+    }
+    credentials {
+        id: Vec<u8>, patient_id: Vec<u8>, record_id: Vec<u8>, credential_type: String, status_index: i32,
+        issued_at: NaiveDateTime, expires_at: NaiveDateTime, revoked_at: Option<NaiveDateTime>,
+    }
+    recovery_configs {
+        patient_id: Vec<u8>, threshold: i32, share_count: i32, key_fingerprint: String,
+        encrypted_private_key: String, created_at: NaiveDateTime, updated_at: NaiveDateTime,
+    }
+    recovery_guardians {
+        id: Vec<u8>, patient_id: Vec<u8>, name: String, public_key_pem: String, encrypted_share: String,
+        share_hash: String, created_at: NaiveDateTime,
+    }
+    recovery_requests {
+        id: Vec<u8>, patient_id: Vec<u8>, status: String, verified_by: Option<String>, created_at: NaiveDateTime,
+        expires_at: NaiveDateTime, completed_at: Option<NaiveDateTime>, recovery_public_key_pem: Option<String>,
+        access_token_hash: Option<String>,
+    }
+    recovery_submissions {
+        id: Vec<u8>, request_id: Vec<u8>, guardian_id: Vec<u8>, share: String, submitted_at: NaiveDateTime,
+    }
+    audit_batches {
+        id: Vec<u8>, merkle_root: String, leaf_count: i32, ledger: String, anchor_ref: Option<String>,
+        created_at: NaiveDateTime, anchored_at: Option<NaiveDateTime>,
+    }
+    blob_pins {
+        cid: String, status: String, error: Option<String>, created_at: NaiveDateTime, updated_at: NaiveDateTime,
+        verified_at: Option<NaiveDateTime>,
+    }
+    audit_events {
+        id: Vec<u8>, occurred_at: NaiveDateTime, actor: String, action: String, patient_id: Option<Vec<u8>>,
+        record_id: Option<Vec<u8>>, record_cid: Option<String>, status: i32, leaf_hash: String,
+        batch_id: Option<Vec<u8>>, leaf_index: Option<i32>,
+    }
+}
+
+// Plaintext archive header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveHeader {
+    pub format: u32,
+    pub archive_id: Uuid,
+    // Archive this one is incremental to; None for full backups
+    pub parent: Option<Uuid>,
+    pub created_at: NaiveDateTime,
+    // Backup key wrapping the archive key
+    pub key_id: String,
+    pub wrapped_key: String,
+}
+
+// First frame of every archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    // Newest migration applied to the source database
+    pub schema_version: Option<String>,
+    pub tables: BTreeMap<String, usize>,
+    // Every blob the rows reference, with the archive holding its content.
+    // Incremental archives carry a full row snapshot but only the blobs
+    // missing from their parent chain.
+    pub blobs: BTreeMap<String, Uuid>,
+}
+
+pub struct ArchiveInfo {
+    pub header: ArchiveHeader,
+    pub manifest: Manifest,
+}
+
+#[derive(Debug)]
+pub struct BackupSummary {
+    pub archive_id: Uuid,
+    pub rows: usize,
+    pub blobs_written: usize,
+    pub blobs_inherited: usize,
+}
+
+#[derive(Debug)]
+pub struct RestoreSummary {
+    pub archives: usize,
+    pub rows: usize,
+    pub blobs: usize,
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn segment_nonce(counter: u64) -> [u8; aead::NONCE_SIZE] {
+    let mut nonce = [0u8; aead::NONCE_SIZE];
+    nonce[aead::NONCE_SIZE - 8..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn segment_aad(header: &[u8], last: bool) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.push(last as u8);
+    aad
+}
+
+// Streams frames into an archive
+struct ArchiveWriter<W: Write> {
+    out: HashingWriter<W>,
+    header: Vec<u8>,
+    key: Zeroizing<Vec<u8>>,
+    counter: u64,
+    buffer: Zeroizing<Vec<u8>>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(out: W, header: &ArchiveHeader, key: Zeroizing<Vec<u8>>) -> Result<Self> {
+        let header = serde_json::to_vec(header)?;
+        let mut out = HashingWriter { inner: out, hasher: Sha256::new() };
+        out.write_all(MAGIC)?;
+        out.write_all(&(header.len() as u32).to_be_bytes())?;
+        out.write_all(&header)?;
+        Ok(ArchiveWriter { out, header, key, counter: 0, buffer: Zeroizing::new(Vec::new()) })
+    }
+
+    fn write_frame(&mut self, tag: u8, parts: &[&[u8]]) -> Result<()> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        self.buffer.push(tag);
+        self.buffer.extend_from_slice(&(len as u64).to_be_bytes());
+        for part in parts {
+            self.buffer.extend_from_slice(part);
+        }
+        while self.buffer.len() > SEGMENT_SIZE {
+            let rest = self.buffer.split_off(SEGMENT_SIZE);
+            let segment = std::mem::replace(&mut *self.buffer, rest);
+            self.seal_segment(&Zeroizing::new(segment), false)?;
+        }
+        Ok(())
+    }
+
+    fn seal_segment(&mut self, plaintext: &[u8], last: bool) -> Result<()> {
+        let ciphertext = aead::encrypt_with_nonce(plaintext, &self.key, &segment_nonce(self.counter), &segment_aad(&self.header, last))?;
+        self.counter += 1;
+        let len = ciphertext.len() as u32 | if last { LAST_SEGMENT } else { 0 };
+        self.out.write_all(&len.to_be_bytes())?;
+        self.out.write_all(&ciphertext)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        let rest = std::mem::take(&mut *self.buffer);
+        self.seal_segment(&Zeroizing::new(rest), true)?;
+        let checksum = self.out.hasher.finalize_reset();
+        self.out.write_all(&checksum)?;
+        self.out.flush()?;
+        Ok(self.out.inner)
+    }
+}
+
+fn read_header<R: Read>(input: &mut R) -> Result<(ArchiveHeader, Vec<u8>)> {
+    let mut magic = [0u8; MAGIC.len()];
+    input.read_exact(&mut magic).context("Failed to read archive")?;
+    if &magic != MAGIC {
+        return Err(anyhow!("Not a MediRust backup archive"));
+    }
+    let mut len = [0u8; 4];
+    input.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_HEADER_SIZE {
+        return Err(anyhow!("Archive header is too large"));
+    }
+    let mut raw = vec![0u8; len];
+    input.read_exact(&mut raw)?;
+    let header: ArchiveHeader = serde_json::from_slice(&raw).map_err(|e| anyhow!("Invalid archive header: {}", e))?;
+    if header.format != FORMAT_VERSION {
+        return Err(anyhow!("Unsupported archive format {}", header.format));
+    }
+    Ok((header, raw))
+}
+
+// Checks the trailing checksum of a whole archive and returns its header.
+// Needs no key, so damaged archives can be found before a restore.
+pub fn verify_archive<R: Read + Seek>(input: &mut R) -> Result<ArchiveHeader> {
+    let len = input.seek(SeekFrom::End(0))?;
+    input.rewind()?;
+    let (header, _) = read_header(input)?;
+    let body = len.checked_sub(CHECKSUM_SIZE as u64).ok_or_else(|| anyhow!("Archive is truncated"))?;
+    input.rewind()?;
+    let mut hasher = Sha256::new();
+    let copied = io::copy(&mut input.by_ref().take(body), &mut hasher)?;
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    input.read_exact(&mut checksum)?;
+    if copied != body || hasher.finalize().as_slice() != checksum {
+        return Err(anyhow!("Checksum mismatch in archive {}: it is damaged or truncated", header.archive_id));
+    }
+    Ok(header)
+}
+
+// Decrypts the frames of an archive in order
+struct ArchiveReader<R: Read> {
+    input: R,
+    header: ArchiveHeader,
+    raw_header: Vec<u8>,
+    key: Zeroizing<Vec<u8>>,
+    counter: u64,
+    plaintext: Zeroizing<Vec<u8>>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    fn open(mut input: R, backup_key: &RootKey) -> Result<Self> {
+        let (header, raw_header) = read_header(&mut input)?;
+        if header.key_id != backup_key.id() {
+            return Err(anyhow!(
+                "Archive {} is encrypted under backup key {}, but backup key {} is loaded",
+                header.archive_id, header.key_id, backup_key.id()
+            ));
+        }
+        let key = Zeroizing::new(backup_key.open(&header.wrapped_key)
+            .with_context(|| format!("Failed to unwrap the key of archive {}", header.archive_id))?);
+        Ok(ArchiveReader {
+            input,
+            header,
+            raw_header,
+            key,
+            counter: 0,
+            plaintext: Zeroizing::new(Vec::new()),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    fn next_segment(&mut self) -> Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+        let mut len = [0u8; 4];
+        self.input.read_exact(&mut len).context("Archive is truncated")?;
+        let len = u32::from_be_bytes(len);
+        let last = len & LAST_SEGMENT != 0;
+        let mut ciphertext = vec![0u8; (len & !LAST_SEGMENT) as usize];
+        self.input.read_exact(&mut ciphertext).context("Archive is truncated")?;
+        let plaintext = aead::decrypt(&ciphertext, &self.key, &segment_nonce(self.counter), &segment_aad(&self.raw_header, last))
+            .with_context(|| format!("Segment {} of archive {} failed authentication", self.counter, self.header.archive_id))?;
+        self.counter += 1;
+        self.finished = last;
+        self.plaintext.drain(..self.position);
+        self.position = 0;
+        self.plaintext.extend_from_slice(&plaintext);
+        Ok(true)
+    }
+
+    fn take(&mut self, len: usize) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        while self.plaintext.len() - self.position < len {
+            if !self.next_segment()? {
+                return Ok(None);
+            }
+        }
+        let bytes = self.plaintext[self.position..self.position + len].to_vec();
+        self.position += len;
+        Ok(Some(Zeroizing::new(bytes)))
+    }
+
+    fn next_frame(&mut self) -> Result<Option<(u8, Zeroizing<Vec<u8>>)>> {
+        let Some(prefix) = self.take(9)? else {
+            if self.plaintext.len() > self.position {
+                return Err(anyhow!("Archive {} ends inside a frame", self.header.archive_id));
+            }
+            return Ok(None);
+        };
+        let len = u64::from_be_bytes(prefix[1..9].try_into().expect("8 byte length")) as usize;
+        let body = self.take(len)?
+            .ok_or_else(|| anyhow!("Archive {} ends inside a frame", self.header.archive_id))?;
+        Ok(Some((prefix[0], body)))
+    }
+
+    fn manifest(&mut self) -> Result<Manifest> {
+        match self.next_frame()? {
+            Some((TAG_MANIFEST, body)) => serde_json::from_slice(&body).map_err(|e| anyhow!("Invalid archive manifest: {}", e)),
+            _ => Err(anyhow!("Archive {} does not start with a manifest", self.header.archive_id)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RowsFrame {
+    table: String,
+    rows: Vec<Value>,
+}
+
+fn blob_frame(cid: &str, content: &[u8]) -> Vec<u8> {
+    let mut head = Vec::with_capacity(2 + cid.len() + CHECKSUM_SIZE);
+    head.extend_from_slice(&(cid.len() as u16).to_be_bytes());
+    head.extend_from_slice(cid.as_bytes());
+    head.extend_from_slice(&Sha256::digest(content));
+    head
+}
+
+// Splits a blob frame into CID, checksum and content
+fn parse_blob_frame(body: &[u8]) -> Result<(&str, &[u8], &[u8])> {
+    let cid_len = body.get(..2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+        .ok_or_else(|| anyhow!("Truncated blob frame"))?;
+    let cid = body.get(2..2 + cid_len).ok_or_else(|| anyhow!("Truncated blob frame"))?;
+    let checksum = body.get(2 + cid_len..2 + cid_len + CHECKSUM_SIZE).ok_or_else(|| anyhow!("Truncated blob frame"))?;
+    let cid = std::str::from_utf8(cid).map_err(|_| anyhow!("Invalid CID in blob frame"))?;
+    Ok((cid, checksum, &body[2 + cid_len + CHECKSUM_SIZE..]))
+}
+
+// Newest migration applied to a database, as recorded by diesel
+fn schema_version(conn: &mut AnyConnection) -> Result<Option<String>> {
+    let versions = match conn {
+        AnyConnection::Postgresql(conn) => conn.applied_migrations(),
+        AnyConnection::Sqlite(conn) => conn.applied_migrations(),
+    }
+    .map_err(|e| anyhow!("Failed to read applied migrations: {}", e))?;
+    Ok(versions.iter().map(|version| version.to_string()).max())
+}
+
+// Reads the header and manifest of an archive, e.g. the parent of an
+// incremental backup
+pub fn read_info<R: Read>(input: R, backup_key: &RootKey) -> Result<ArchiveInfo> {
+    let mut reader = ArchiveReader::open(input, backup_key)?;
+    let manifest = reader.manifest()?;
+    Ok(ArchiveInfo { header: reader.header, manifest })
+}
+
+// Writes an archive of every table and every blob the health records
+// reference. With a parent, blobs already held by the parent chain are
+// referenced instead of copied.
+pub async fn backup<W: Write>(
+    conn: &mut AnyConnection,
+    store: &BlobStore,
+    backup_key: &RootKey,
+    parent: Option<&ArchiveInfo>,
+    out: W,
+) -> Result<BackupSummary> {
+    let archive_id = Uuid::new_v4();
+    let archive_key = Zeroizing::new(CryptoUtils::generate_aes_key());
+    let header = ArchiveHeader {
+        format: FORMAT_VERSION,
+        archive_id,
+        parent: parent.map(|parent| parent.header.archive_id),
+        created_at: Utc::now().naive_utc(),
+        key_id: backup_key.id().to_string(),
+        wrapped_key: backup_key.seal(&archive_key)?,
+    };
+
+    // Rows are read in one transaction so the snapshot is consistent
+    let (schema_version, tables) = conn.transaction(|conn| -> Result<_> {
+        let tables = TABLES.iter()
+            .map(|table| Ok((*table, dump_table(conn, table)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((schema_version(conn)?, tables))
+    })?;
+    let cids = health_records::table
+        .select(health_records::ipfs_cid)
+        .distinct()
+        .load::<String>(conn)
+        .context("Failed to list record CIDs")?;
+
+    let mut blobs = BTreeMap::new();
+    let mut to_copy = Vec::new();
+    for cid in cids {
+        match parent.and_then(|parent| parent.manifest.blobs.get(&cid)) {
+            Some(holder) => {
+                blobs.insert(cid, *holder);
+            }
+            None => {
+                blobs.insert(cid.clone(), archive_id);
+                to_copy.push(cid);
+            }
+        }
+    }
+    let manifest = Manifest {
+        schema_version,
+        tables: tables.iter().map(|(table, rows)| (table.to_string(), rows.len())).collect(),
+        blobs,
+    };
+
+    let mut writer = ArchiveWriter::new(out, &header, archive_key)?;
+    writer.write_frame(TAG_MANIFEST, &[&serde_json::to_vec(&manifest)?])?;
+    let mut row_count = 0;
+    for (table, rows) in tables {
+        row_count += rows.len();
+        for chunk in rows.chunks(ROWS_PER_FRAME) {
+            let frame = RowsFrame { table: table.to_string(), rows: chunk.to_vec() };
+            writer.write_frame(TAG_ROWS, &[&serde_json::to_vec(&frame)?])?;
+        }
+    }
+    for cid in &to_copy {
+        let content = store.get(cid).await.with_context(|| format!("Failed to back up blob {}", cid))?;
+        writer.write_frame(TAG_BLOB, &[&blob_frame(cid, &content), &content])?;
+    }
+    writer.finish()?;
+
+    Ok(BackupSummary {
+        archive_id,
+        rows: row_count,
+        blobs_written: to_copy.len(),
+        blobs_inherited: manifest.blobs.len() - to_copy.len(),
+    })
+}
+
+// Restores a backup into an empty, migrated database and a blob store.
+// `archives` is the newest archive plus every archive of its parent chain,
+// in any order. Blobs are checked against their checksum and the CID the
+// store assigns, then pinned; rows are inserted in a single transaction.
+pub async fn restore<R: Read + Seek>(
+    conn: &mut AnyConnection,
+    store: &BlobStore,
+    backup_key: &RootKey,
+    mut archives: Vec<R>,
+) -> Result<RestoreSummary> {
+    let mut headers = Vec::with_capacity(archives.len());
+    for archive in archives.iter_mut() {
+        headers.push(verify_archive(archive)?);
+        archive.rewind()?;
+    }
+
+    // The newest archive is the one no other archive builds on
+    let parents: HashSet<Uuid> = headers.iter().filter_map(|header| header.parent).collect();
+    let newest = match headers.iter().filter(|header| !parents.contains(&header.archive_id)).collect::<Vec<_>>()[..] {
+        [newest] => newest.archive_id,
+        [] => return Err(anyhow!("The archives' parent links form a cycle")),
+        _ => return Err(anyhow!("The archives belong to more than one backup chain")),
+    };
+    let by_id: HashMap<Uuid, usize> = headers.iter().enumerate().map(|(i, header)| (header.archive_id, i)).collect();
+    let mut chain = vec![by_id[&newest]];
+    while let Some(parent) = headers[*chain.last().expect("chain is never empty")].parent {
+        let index = *by_id.get(&parent).ok_or_else(|| anyhow!("Parent archive {} is missing", parent))?;
+        chain.push(index);
+    }
+    if chain.len() != archives.len() {
+        return Err(anyhow!("Some archives are not part of the chain of archive {}", newest));
+    }
+
+    if count_rows(conn)? > 0 {
+        return Err(anyhow!("Restore needs an empty database"));
+    }
+    let mut readers = Vec::with_capacity(chain.len());
+    let mut archives: Vec<Option<R>> = archives.into_iter().map(Some).collect();
+    for index in &chain {
+        let archive = archives[*index].take().expect("each archive is in the chain once");
+        readers.push(ArchiveReader::open(archive, backup_key)?);
+    }
+    let manifest = readers[0].manifest()?;
+    let current_version = schema_version(conn)?;
+    if manifest.schema_version != current_version {
+        return Err(anyhow!(
+            "Archive was taken at schema version {}, but the database is at {}; run the matching migrations first",
+            manifest.schema_version.as_deref().unwrap_or("none"),
+            current_version.as_deref().unwrap_or("none")
+        ));
+    }
+
+    let mut tables = Vec::new();
+    let mut restored = HashSet::new();
+    for (position, reader) in readers.iter_mut().enumerate() {
+        if position > 0 {
+            reader.manifest()?;
+        }
+        let archive_id = reader.header.archive_id;
+        while let Some((tag, body)) = reader.next_frame()? {
+            match tag {
+                TAG_ROWS if position == 0 => {
+                    let frame: RowsFrame = serde_json::from_slice(&body).map_err(|e| anyhow!("Invalid row frame: {}", e))?;
+                    tables.push(frame);
+                }
+                TAG_BLOB => {
+                    let (cid, checksum, content) = parse_blob_frame(&body)?;
+                    if manifest.blobs.get(cid) != Some(&archive_id) || restored.contains(cid) {
+                        continue;
+                    }
+                    if Sha256::digest(content).as_slice() != checksum {
+                        return Err(anyhow!("Blob {} in archive {} does not match its checksum", cid, archive_id));
+                    }
+                    store.put_as(cid, content.to_vec()).await?;
+                    store.pin(cid).await?;
+                    restored.insert(cid.to_string());
+                }
+                TAG_ROWS => {}
+                other => return Err(anyhow!("Unknown frame type {} in archive {}", other, archive_id)),
+            }
+        }
+    }
+    if let Some(missing) = manifest.blobs.keys().find(|cid| !restored.contains(*cid)) {
+        return Err(anyhow!("Blob {} is referenced but not held by any of the archives", missing));
+    }
+
+    let expected: usize = manifest.tables.values().sum();
+    let rows = conn.transaction(|conn| -> Result<usize> {
+        let mut rows = 0;
+        for frame in tables {
+            rows += frame.rows.len();
+            restore_rows(conn, &frame.table, frame.rows)?;
+        }
+        if rows != expected {
+            return Err(anyhow!("Archive holds {} rows but its manifest lists {}", rows, expected));
+        }
+        Ok(rows)
+    })?;
+
+    Ok(RestoreSummary { archives: chain.len(), rows, blobs: restored.len() })
+}
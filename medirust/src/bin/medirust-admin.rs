@@ -0,0 +1,484 @@
+extern crate dotenvy;
+
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+use diesel::PgConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use dotenvy::dotenv;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Context, Result, anyhow};
+
+use medirust::audit;
+use medirust::backup::{self, ArchiveInfo};
+use medirust::blobstore::{BlobStore, FetchError};
+use medirust::config::AppConfig;
+use medirust::crypto::{CryptoUtils, PatientPrivateKey};
+use medirust::keys::{self, KeyHierarchy, RootKey};
+use medirust::ledger::Ledger;
+use medirust::metrics;
+use medirust::pins;
+use medirust::models::{AdminUser, HealthRecord, Patient};
+use medirust::schema::{admin_users, health_records, patients};
+use medirust::tls::ClientCertificate;
+use medirust::{AnyConnection, DbPool};
+
+// Operator tool for a MediRust deployment. It reads the same configuration
+// as the server (medirust.toml, MEDIRUST_* overrides) and talks to the
+// database and IPFS directly, so it also works while the server is down.
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+#[derive(Parser)]
+#[command(name = "medirust-admin", about = "Operate a MediRust vault")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Apply pending database migrations and seal plaintext record metadata
+    Migrate,
+    /// Write sealed record titles and types back in plaintext, before
+    /// reverting the migration that seals them
+    UnsealMetadata,
+    /// Allow a client certificate to act as an admin user
+    CreateAdmin {
+        #[arg(long)]
+        name: String,
+        /// PEM file holding the admin's client certificate
+        #[arg(long, conflicts_with = "fingerprint", required_unless_present = "fingerprint")]
+        certificate: Option<PathBuf>,
+        /// Hex SHA-256 fingerprint of the certificate's DER encoding
+        #[arg(long)]
+        fingerprint: Option<String>,
+    },
+    /// Stop accepting an admin user's certificate
+    DisableAdmin {
+        #[arg(long)]
+        name: String,
+    },
+    /// List patients with their key setup and record counts
+    ListPatients,
+    /// Check audit events, batch roots and ledger anchors
+    VerifyAudit,
+    /// Check that every record's content can be fetched from IPFS, matches
+    /// its CID and stored hash and, where a key is available, decrypts
+    CheckRecords {
+        /// Only check this patient's records
+        #[arg(long)]
+        patient: Option<Uuid>,
+        /// Directory of patient private keys named <patient-id>.pem; escrowed
+        /// keys are used for patients without a file
+        #[arg(long)]
+        keys_dir: Option<PathBuf>,
+    },
+    /// Generate a new root key and re-wrap all data keys under it
+    RotateRootKey {
+        /// Where to write the new key (base64); must not exist yet
+        #[arg(long)]
+        new_key_file: PathBuf,
+    },
+    /// Write an encrypted archive of the database and every record blob
+    Backup {
+        /// Archive to create; must not exist yet
+        #[arg(long)]
+        output: PathBuf,
+        /// Earlier archive to make an incremental backup of; only blobs
+        /// missing from its chain are copied
+        #[arg(long)]
+        parent: Option<PathBuf>,
+    },
+    /// Restore an archive, plus the archives it is incremental to, into an
+    /// empty deployment whose migrations have been run
+    Restore {
+        #[arg(required = true)]
+        archives: Vec<PathBuf>,
+    },
+    /// Re-pin record blobs the IPFS node lost and unpin unreferenced ones, as
+    /// the server does every blob_store.reconcile_interval_secs
+    ReconcilePins,
+    /// Re-upload record blobs the IPFS node no longer holds from another node
+    RepairBlobs {
+        /// RPC API URL of the IPFS node to copy blobs from
+        #[arg(long)]
+        from: String,
+    },
+    /// Print vault inventory metrics in the Prometheus text format
+    ExportMetrics {
+        /// Write to this file instead of stdout, replacing it atomically
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    dotenv().ok();
+    let cli = Cli::parse();
+
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(cli.command, &config).await {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Runs a command; Ok(false) means it ran but found problems
+async fn run(command: Command, config: &AppConfig) -> Result<bool> {
+    let mut conn = PgConnection::establish(&config.database.url)
+        .with_context(|| format!("Failed to connect to {}", config.database.url))?;
+    match command {
+        Command::Migrate => migrate(&mut conn, config),
+        Command::UnsealMetadata => unseal_metadata(&mut conn, config),
+        Command::CreateAdmin { name, certificate, fingerprint } => create_admin(&mut conn, name, certificate.as_deref(), fingerprint),
+        Command::DisableAdmin { name } => disable_admin(&mut conn, &name),
+        Command::ListPatients => list_patients(&mut conn),
+        Command::VerifyAudit => verify_audit(&mut conn, config).await,
+        Command::CheckRecords { patient, keys_dir } => check_records(&mut conn, config, patient, keys_dir.as_deref()).await,
+        Command::RotateRootKey { new_key_file } => rotate_root_key(&mut conn, config, &new_key_file),
+        Command::Backup { output, parent } => backup(AnyConnection::Postgresql(conn), config, &output, parent.as_deref()).await,
+        Command::Restore { archives } => restore(AnyConnection::Postgresql(conn), config, &archives).await,
+        Command::ReconcilePins => reconcile_pins(config).await,
+        Command::RepairBlobs { from } => repair_blobs(config, &from).await,
+        Command::ExportMetrics { output } => export_metrics(&mut conn, output.as_deref()),
+    }
+}
+
+fn uuid_string(id: &[u8]) -> String {
+    Uuid::from_slice(id).unwrap_or_default().to_string()
+}
+
+fn migrate(conn: &mut PgConnection, config: &AppConfig) -> Result<bool> {
+    let applied = conn.run_pending_migrations(MIGRATIONS).map_err(|e| anyhow!("Migration failed: {}", e))?;
+    if applied.is_empty() {
+        println!("Database is up to date");
+    }
+    for version in applied {
+        println!("Applied migration {}", version);
+    }
+    // Existing records are part of the migration: their titles and types are
+    // sealed here, which needs the root key
+    let plaintext: i64 = health_records::table
+        .filter(health_records::metadata_key_id.is_null())
+        .count()
+        .get_result(conn)?;
+    if plaintext == 0 {
+        return Ok(true);
+    }
+    let keys = KeyHierarchy::from_config(&config.key_management)?.ok_or_else(|| {
+        anyhow!("{} records have plaintext titles and types; configure a root key and run migrate again to seal them", plaintext)
+    })?;
+    let sealed = keys::seal_plaintext_record_metadata(&keys, conn)?;
+    println!("Sealed the metadata of {} records", sealed);
+    Ok(true)
+}
+
+fn unseal_metadata(conn: &mut PgConnection, config: &AppConfig) -> Result<bool> {
+    let keys = KeyHierarchy::from_config(&config.key_management)?
+        .ok_or_else(|| anyhow!("Sealed record metadata can only be opened with the root key configured"))?;
+    let opened = keys::unseal_record_metadata(&keys, conn)?;
+    println!("Wrote the metadata of {} records back in plaintext", opened);
+    Ok(true)
+}
+
+fn create_admin(conn: &mut PgConnection, name: String, certificate: Option<&Path>, fingerprint: Option<String>) -> Result<bool> {
+    let fingerprint = match (certificate, fingerprint) {
+        (Some(path), _) => ClientCertificate::from_pem_file(path)?.fingerprint(),
+        (None, Some(fingerprint)) => fingerprint.to_ascii_lowercase(),
+        (None, None) => return Err(anyhow!("Pass --certificate or --fingerprint")),
+    };
+    if fingerprint.len() != 64 || !fingerprint.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow!("Fingerprint must be 64 hex characters"));
+    }
+    let admin = AdminUser {
+        id: Uuid::new_v4().as_bytes().to_vec(),
+        name,
+        certificate_fingerprint: fingerprint,
+        created_at: Utc::now().naive_utc(),
+        disabled_at: None,
+    };
+    diesel::insert_into(admin_users::table)
+        .values(&admin)
+        .execute(conn)
+        .with_context(|| format!("Failed to create admin user {}", admin.name))?;
+    println!("Created admin user {} ({}) for certificate {}", admin.name, uuid_string(&admin.id), admin.certificate_fingerprint);
+    Ok(true)
+}
+
+fn disable_admin(conn: &mut PgConnection, name: &str) -> Result<bool> {
+    let updated = diesel::update(admin_users::table.filter(admin_users::name.eq(name)).filter(admin_users::disabled_at.is_null()))
+        .set(admin_users::disabled_at.eq(Utc::now().naive_utc()))
+        .execute(conn)?;
+    if updated == 0 {
+        return Err(anyhow!("No enabled admin user named {}", name));
+    }
+    println!("Disabled admin user {}", name);
+    Ok(true)
+}
+
+fn list_patients(conn: &mut PgConnection) -> Result<bool> {
+    let patients = patients::table
+        .order(patients::created_at.asc())
+        .select(Patient::as_select())
+        .load(conn)?;
+    println!("{:<36}  {:<20}  {:<28}  {:<7}  {:>7}  created_at", "id", "health_id", "key_algorithm", "escrow", "records");
+    for patient in &patients {
+        let records: i64 = health_records::table
+            .filter(health_records::patient_id.eq(&patient.id))
+            .count()
+            .get_result(conn)?;
+        println!(
+            "{:<36}  {:<20}  {:<28}  {:<7}  {:>7}  {}",
+            uuid_string(&patient.id),
+            patient.health_id,
+            patient.key_algorithm,
+            if patient.escrowed_private_key.is_some() { "yes" } else { "no" },
+            records,
+            patient.created_at,
+        );
+    }
+    println!("{} patients", patients.len());
+    Ok(true)
+}
+
+async fn verify_audit(conn: &mut PgConnection, config: &AppConfig) -> Result<bool> {
+    let ledger = if config.audit.enabled { Some(Ledger::from_config(&config.audit)?) } else { None };
+    let report = audit::verify_trail(conn, ledger.as_ref()).await?;
+    println!("{} events ({} not sealed yet), {} batches", report.events, report.unsealed_events, report.batches);
+    for id in &report.altered_events {
+        println!("ALTERED event {}: contents do not match its leaf hash", id);
+    }
+    for id in &report.broken_batches {
+        println!("BROKEN batch {}: events do not rebuild the stored root", id);
+    }
+    for id in &report.unconfirmed_anchors {
+        println!("UNCONFIRMED batch {}: ledger does not hold the stored root", id);
+    }
+    if report.unverified_anchors > 0 {
+        println!("{} batches are not anchored to the configured ledger yet", report.unverified_anchors);
+    }
+    println!("{}", if report.is_intact() { "Audit trail intact" } else { "Audit trail FAILED verification" });
+    Ok(report.is_intact())
+}
+
+// Private key for a patient from --keys-dir, falling back to escrow
+fn patient_private_key(
+    conn: &mut PgConnection,
+    keys: Option<&KeyHierarchy>,
+    keys_dir: Option<&Path>,
+    patient: &Patient,
+) -> Result<Option<PatientPrivateKey>> {
+    if let Some(path) = keys_dir.map(|dir| dir.join(format!("{}.pem", uuid_string(&patient.id))))
+        && path.exists()
+    {
+        let pem = Zeroizing::new(fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?);
+        return PatientPrivateKey::from_pem(patient.algorithm()?, &pem).map(Some);
+    }
+    keys::escrowed_private_key(keys, conn, patient)
+}
+
+fn decrypt_record(conn: &mut PgConnection, keys: Option<&KeyHierarchy>, private_key: &PatientPrivateKey, record: &HealthRecord, ciphertext: &[u8]) -> Result<()> {
+    let (encrypted_aes_key, nonce) = keys::record_key_material(keys, conn, record)?;
+    let aes_key = Zeroizing::new(private_key.unwrap_key(&CryptoUtils::decode_base64(&encrypted_aes_key)?)?);
+    let mut record = record.clone();
+    keys::open_record_metadata(keys, conn, &mut record)?;
+    let aad = CryptoUtils::record_aad(&record.id, &record.patient_id, &record.record_type, record.aad_version);
+    CryptoUtils::decrypt_data_with_aad(ciphertext, &aes_key, &CryptoUtils::decode_base64(&nonce)?, &aad)?;
+    Ok(())
+}
+
+async fn check_records(conn: &mut PgConnection, config: &AppConfig, patient: Option<Uuid>, keys_dir: Option<&Path>) -> Result<bool> {
+    let keys = KeyHierarchy::from_config(&config.key_management)?;
+    let store = BlobStore::from_config(&config.blob_store)?;
+    let mut query = patients::table.order(patients::created_at.asc()).into_boxed();
+    if let Some(patient) = patient {
+        query = query.filter(patients::id.eq(patient.as_bytes().to_vec()));
+    }
+    let patients = query.select(Patient::as_select()).load(conn)?;
+
+    let (mut checked, mut retrieved, mut decrypted, mut failed) = (0, 0, 0, 0);
+    for patient in &patients {
+        let private_key = patient_private_key(conn, keys.as_ref(), keys_dir, patient)
+            .with_context(|| format!("Failed to load the private key of patient {}", uuid_string(&patient.id)))?;
+        let records = health_records::table
+            .filter(health_records::patient_id.eq(&patient.id))
+            .select(HealthRecord::as_select())
+            .load(conn)?;
+        for record in &records {
+            checked += 1;
+            let ciphertext = match store.fetch_verified(&record.ipfs_cid, record.content_sha256.as_deref()).await {
+                Ok(ciphertext) => ciphertext,
+                Err(FetchError::Integrity(reason)) => {
+                    failed += 1;
+                    println!("CORRUPT record {} ({}): {}", uuid_string(&record.id), record.ipfs_cid, reason);
+                    continue;
+                }
+                Err(FetchError::Unverifiable(reason)) => {
+                    failed += 1;
+                    println!("UNVERIFIABLE record {} ({}): {}", uuid_string(&record.id), record.ipfs_cid, reason);
+                    continue;
+                }
+                Err(FetchError::Unavailable(e)) => {
+                    failed += 1;
+                    println!("MISSING record {} ({}): {:#}", uuid_string(&record.id), record.ipfs_cid, e);
+                    continue;
+                }
+            };
+            retrieved += 1;
+            let Some(private_key) = &private_key else {
+                continue;
+            };
+            match decrypt_record(conn, keys.as_ref(), private_key, record, &ciphertext) {
+                Ok(()) => decrypted += 1,
+                Err(e) => {
+                    failed += 1;
+                    println!("UNREADABLE record {} ({}): {:#}", uuid_string(&record.id), record.ipfs_cid, e);
+                }
+            }
+        }
+    }
+    println!(
+        "{} records checked: {} retrievable, {} decrypted, {} failed",
+        checked, retrieved, decrypted, failed
+    );
+    Ok(failed == 0)
+}
+
+fn rotate_root_key(conn: &mut PgConnection, config: &AppConfig, new_key_file: &Path) -> Result<bool> {
+    let current = RootKey::load(&config.key_management.root_key)?
+        .ok_or_else(|| anyhow!("No root key is configured; envelope encryption is disabled"))?;
+    let new_key = Zeroizing::new(CryptoUtils::generate_aes_key());
+    let new_root = RootKey::from_bytes(new_key.to_vec())?;
+
+    // Persist the key before any data key depends on it
+    let mut file = create_private_file(new_key_file)?;
+    file.write_all(CryptoUtils::encode_base64(&new_key).as_bytes())?;
+    file.sync_all()?;
+
+    let hierarchy = KeyHierarchy::new(new_root, config.key_management.tenant.clone());
+    let rewrapped = hierarchy.rotate_root_key(conn, &current)?;
+    println!("Re-wrapped {} data keys from root key {} to {}", rewrapped, current.id(), hierarchy.root_key_id());
+    println!(
+        "Set key_management.root_key.path to {} and move the old key source to key_management.previous_root_key, then restart the servers",
+        new_key_file.display()
+    );
+    Ok(true)
+}
+
+fn backup_key(config: &AppConfig) -> Result<RootKey> {
+    RootKey::load(&config.backup.key)?.ok_or_else(|| anyhow!("backup.key is not configured"))
+}
+
+// Creates a file only the operator can read
+fn create_private_file(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path).with_context(|| format!("Failed to create {}", path.display()))
+}
+
+async fn backup(mut conn: AnyConnection, config: &AppConfig, output: &Path, parent: Option<&Path>) -> Result<bool> {
+    let key = backup_key(config)?;
+    let store = BlobStore::from_config(&config.blob_store)?;
+    let parent: Option<ArchiveInfo> = match parent {
+        Some(path) => {
+            let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+            Some(backup::read_info(file, &key)?)
+        }
+        None => None,
+    };
+
+    // Written under a temporary name so an interrupted run never leaves
+    // something that looks like a complete archive
+    let partial = output.with_extension("partial");
+    let file = create_private_file(&partial)?;
+    let result = backup::backup(&mut conn, &store, &key, parent.as_ref(), file).await;
+    let summary = match result.and_then(|summary| fs::rename(&partial, output).map(|_| summary).map_err(Into::into)) {
+        Ok(summary) => summary,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    println!(
+        "Wrote archive {} to {}: {} rows, {} blobs copied, {} blobs held by earlier archives",
+        summary.archive_id, output.display(), summary.rows, summary.blobs_written, summary.blobs_inherited
+    );
+    Ok(true)
+}
+
+async fn restore(mut conn: AnyConnection, config: &AppConfig, paths: &[PathBuf]) -> Result<bool> {
+    let key = backup_key(config)?;
+    let store = BlobStore::from_config(&config.blob_store)?;
+    let archives = paths.iter()
+        .map(|path| File::open(path).with_context(|| format!("Failed to open {}", path.display())))
+        .collect::<Result<Vec<_>>>()?;
+    let summary = backup::restore(&mut conn, &store, &key, archives).await?;
+    println!(
+        "Restored {} rows and {} pinned blobs from {} archives",
+        summary.rows, summary.blobs, summary.archives
+    );
+    Ok(true)
+}
+
+// The pin functions run their queries through a pool, like the server
+fn db_pool(config: &AppConfig) -> Result<DbPool> {
+    r2d2::Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::<PgConnection>::new(config.database.url.clone()))
+        .with_context(|| format!("Failed to connect to {}", config.database.url))
+}
+
+async fn reconcile_pins(config: &AppConfig) -> Result<bool> {
+    let store = BlobStore::from_config(&config.blob_store)?;
+    let report = pins::reconcile(&db_pool(config)?, &store).await?;
+    println!(
+        "Checked {} blobs: {} re-pinned, {} missing, {} released",
+        report.checked, report.repinned, report.missing, report.released
+    );
+    if report.missing > 0 {
+        println!("Run repair-blobs --from <node> to restore missing blobs");
+    }
+    Ok(report.missing == 0)
+}
+
+async fn repair_blobs(config: &AppConfig, from: &str) -> Result<bool> {
+    let store = BlobStore::from_config(&config.blob_store)?;
+    let secondary = BlobStore::ipfs(from)?;
+    let report = pins::repair(&db_pool(config)?, &store, &secondary).await?;
+    println!("Repaired {} blobs, {} could not be repaired", report.repaired, report.failed);
+    Ok(report.failed == 0)
+}
+
+fn export_metrics(conn: &mut PgConnection, output: Option<&Path>) -> Result<bool> {
+    let rendered = metrics::render_inventory(conn)?;
+    let Some(output) = output else {
+        print!("{}", rendered);
+        return Ok(true);
+    };
+    // Textfile collectors may read at any moment, so never expose a partial file
+    let partial = output.with_extension("prom.tmp");
+    fs::write(&partial, rendered).with_context(|| format!("Failed to write {}", partial.display()))?;
+    fs::rename(&partial, output).with_context(|| format!("Failed to replace {}", output.display()))?;
+    Ok(true)
+}
@@ -0,0 +1,546 @@
+use cid::multihash::MultihashGeneric;
+use cid::{Cid, Version};
+use futures::TryStreamExt;
+use ipfs_api_backend_hyper::{request, IpfsApi, IpfsClient, TryFromUri};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use uuid::Uuid;
+
+use crate::config::{BlobStoreConfig, ReplicaKind};
+use crate::replication::ReplicatedBlobStore;
+
+// Multicodecs of raw binary content and of dag-pb nodes, and the multihash
+// code of SHA-256
+const RAW_CODEC: u64 = 0x55;
+const DAG_PB_CODEC: u64 = 0x70;
+const SHA2_256_CODE: u64 = 0x12;
+
+// Chunk size of a default `ipfs add`; smaller content is a single block
+const IPFS_DEFAULT_CHUNK: usize = 262_144;
+
+// Verified fetches try this often, each attempt bounded by FETCH_TIMEOUT,
+// doubling the pause between attempts from FETCH_BACKOFF
+const FETCH_ATTEMPTS: u32 = 3;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const FETCH_BACKOFF: Duration = Duration::from_millis(200);
+
+// Largest chunk IPFS accepts. Blobs up to this size added with raw leaves
+// get the same CID as `raw_cid` computes; larger ones the `replica_cid` of
+// their chunks.
+const IPFS_RAW_CHUNKER: &str = "size-1048576";
+const IPFS_RAW_CHUNK: usize = 1_048_576;
+
+// Links per node of the balanced DAG `ipfs add` builds over a file's chunks
+const IPFS_MAX_LINKS: usize = 174;
+
+// Content-addressed store holding encrypted record content. Blobs are
+// identified by the CID the store assigns when they are added.
+pub enum BlobStore {
+    Ipfs(Box<IpfsClient>),
+    Fs(FsBlobStore),
+    Memory(MemoryBlobStore),
+    Replicated(Box<ReplicatedBlobStore>),
+}
+
+impl BlobStore {
+    // The IPFS node at ipfs_api_url, replicated to blob_store.replicas if any
+    pub fn from_config(config: &BlobStoreConfig) -> Result<Self> {
+        if config.replicas.is_empty() {
+            return Self::ipfs(&config.ipfs_api_url);
+        }
+        let mut replicas = vec![(format!("ipfs:{}", config.ipfs_api_url), Self::ipfs(&config.ipfs_api_url)?)];
+        for replica in &config.replicas {
+            replicas.push(match (replica.kind, &replica.api_url, &replica.path) {
+                (ReplicaKind::Ipfs, Some(url), _) => (format!("ipfs:{}", url), Self::ipfs(url)?),
+                (ReplicaKind::Fs, _, Some(path)) => (format!("fs:{}", path.display()), BlobStore::Fs(FsBlobStore::new(path)?)),
+                (kind, ..) => return Err(anyhow!("Incomplete {:?} replica configuration", kind)),
+            });
+        }
+        let write_quorum = config.write_quorum.unwrap_or(replicas.len() / 2 + 1);
+        ReplicatedBlobStore::new(replicas, write_quorum).map(|store| BlobStore::Replicated(Box::new(store)))
+    }
+
+    // Store backed by the Kubo RPC API at `api_url`
+    pub fn ipfs(api_url: &str) -> Result<Self> {
+        IpfsClient::from_str(api_url)
+            .map(|client| BlobStore::Ipfs(Box::new(client)))
+            .map_err(|e| anyhow!("Invalid IPFS API URL {}: {}", api_url, e))
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            BlobStore::Ipfs(_) => "ipfs",
+            BlobStore::Fs(_) => "fs",
+            BlobStore::Memory(_) => "memory",
+            BlobStore::Replicated(_) => "replicated",
+        }
+    }
+
+    // Adds content, returning its CID. Every backend names a blob by its
+    // `replica_cid`: IPFS is asked for raw CIDv1 leaves, the others compute it.
+    pub async fn put(&self, content: Vec<u8>) -> Result<String> {
+        match self {
+            BlobStore::Ipfs(client) => {
+                let options = request::Add {
+                    cid_version: Some(1),
+                    raw_leaves: Some(true),
+                    chunker: Some(IPFS_RAW_CHUNKER),
+                    ..Default::default()
+                };
+                ipfs_add(client, content, options).await
+            }
+            BlobStore::Fs(store) => store.put(content).await,
+            BlobStore::Memory(store) => store.available().map(|_| store.put(content)),
+            BlobStore::Replicated(store) => store.put(content).await,
+        }
+    }
+
+    // Adds content that must come back under `cid`, as when restoring or
+    // copying a blob. CIDv0 blobs, from before IPFS was asked for raw leaves,
+    // are added to IPFS the way they first were.
+    pub async fn put_as(&self, cid: &str, content: Vec<u8>) -> Result<()> {
+        let legacy = Cid::try_from(cid).is_ok_and(|cid| cid.version() == Version::V0);
+        let stored = match self {
+            BlobStore::Ipfs(client) if legacy => ipfs_add(client, content, request::Add::default()).await?,
+            _ => self.put(content).await?,
+        };
+        if stored != cid {
+            // Not the blob asked for, so nothing should keep it
+            if let Err(e) = self.unpin(&stored).await {
+                tracing::warn!(cid = %stored, error = %e, "failed to unpin a blob stored under an unexpected CID");
+            }
+            return Err(anyhow!("Blob {} was stored as {}; the blob store does not reproduce its CID", cid, stored));
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, cid: &str) -> Result<Vec<u8>> {
+        match self {
+            BlobStore::Ipfs(client) => client.cat(cid)
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat()
+                .await
+                .map_err(|e| anyhow!("Failed to fetch blob {} from IPFS: {}", cid, e)),
+            BlobStore::Fs(store) => store.get(cid).await,
+            BlobStore::Memory(store) => {
+                store.available()?;
+                store.get(cid).ok_or_else(|| anyhow!("Blob {} not found", cid))
+            }
+            BlobStore::Replicated(store) => store.get(cid, None).await.map_err(anyhow::Error::from),
+        }
+    }
+
+    // One fetch attempt. Replicated stores check each replica's copy, so a
+    // replica serving bad content does not hide an intact one.
+    async fn fetch(&self, cid: &str, sha256: Option<&str>) -> Result<Vec<u8>, FetchError> {
+        match self {
+            BlobStore::Replicated(store) => store.get(cid, sha256).await,
+            _ => self.get(cid).await.map_err(FetchError::Unavailable),
+        }
+    }
+
+    // Fetches a blob and checks it against its CID and, when known, the
+    // SHA-256 recorded when it was stored. Unavailable stores are retried
+    // with backoff.
+    pub async fn fetch_verified(&self, cid: &str, sha256: Option<&str>) -> Result<Vec<u8>, FetchError> {
+        let mut backoff = FETCH_BACKOFF;
+        let mut verification_failure = None;
+        let mut last_error = None;
+        for attempt in 1..=FETCH_ATTEMPTS {
+            if attempt > 1 {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            let content = match tokio::time::timeout(FETCH_TIMEOUT, self.fetch(cid, sha256)).await {
+                Ok(Ok(content)) => content,
+                Ok(Err(FetchError::Unavailable(e))) => {
+                    tracing::warn!(cid = %cid, attempt, error = %e, "blob fetch failed");
+                    last_error = Some(e);
+                    continue;
+                }
+                Ok(Err(failure)) => {
+                    tracing::warn!(cid = %cid, attempt, reason = %failure, "blob failed verification");
+                    verification_failure = Some(failure);
+                    continue;
+                }
+                Err(_) => {
+                    tracing::warn!(cid = %cid, attempt, "blob fetch timed out");
+                    last_error = Some(anyhow!("Timed out after {}s fetching blob {}", FETCH_TIMEOUT.as_secs(), cid));
+                    continue;
+                }
+            };
+            match verify_content(cid, sha256, &content) {
+                Ok(()) => return Ok(content),
+                Err(failure) => {
+                    // A truncated or garbled transfer may succeed on retry; if
+                    // it never does, report the failed check over any later error
+                    tracing::warn!(cid = %cid, attempt, reason = %failure, "blob failed verification");
+                    verification_failure = Some(failure);
+                }
+            }
+        }
+        Err(verification_failure.unwrap_or_else(|| {
+            FetchError::Unavailable(last_error.unwrap_or_else(|| anyhow!("Blob {} was not fetched", cid)))
+        }))
+    }
+
+    // Protects a blob from the store's garbage collection
+    pub async fn pin(&self, cid: &str) -> Result<()> {
+        match self {
+            BlobStore::Ipfs(client) => client.pin_add(cid, true)
+                .await
+                .map(|_| ())
+                .map_err(|e| anyhow!("Failed to pin blob {}: {}", cid, e)),
+            BlobStore::Fs(store) => store.pin(cid).await,
+            BlobStore::Memory(store) => store.available().and_then(|_| store.pin(cid)),
+            BlobStore::Replicated(store) => store.pin(cid).await,
+        }
+    }
+
+    // Lets the store collect a blob nothing references anymore
+    pub async fn unpin(&self, cid: &str) -> Result<()> {
+        match self {
+            BlobStore::Ipfs(client) => client.pin_rm(cid, true)
+                .await
+                .map(|_| ())
+                .map_err(|e| anyhow!("Failed to unpin blob {}: {}", cid, e)),
+            BlobStore::Fs(store) => store.unpin(cid).await,
+            BlobStore::Memory(store) => {
+                store.available()?;
+                store.unpin(cid);
+                Ok(())
+            }
+            BlobStore::Replicated(store) => store.unpin(cid).await,
+        }
+    }
+
+    // CIDs the store currently keeps pinned
+    pub async fn pinned(&self) -> Result<HashSet<String>> {
+        match self {
+            BlobStore::Ipfs(client) => client.pin_ls(None, Some("recursive"))
+                .await
+                .map(|response| response.keys.into_keys().collect())
+                .map_err(|e| anyhow!("Failed to list pins: {}", e)),
+            BlobStore::Fs(store) => store.pinned().await,
+            BlobStore::Memory(store) => store.available().map(|_| store.pinned()),
+            BlobStore::Replicated(store) => store.pinned().await,
+        }
+    }
+}
+
+// Why a verified fetch returned no content
+#[derive(Debug)]
+pub enum FetchError {
+    // No attempt got an answer from the store
+    Unavailable(anyhow::Error),
+    // The store returned content that does not match the blob's CID or hash
+    Integrity(String),
+    // Neither the CID nor a recorded hash can vouch for the content, so it is
+    // not handed out
+    Unverifiable(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::Unavailable(e) => write!(f, "{:#}", e),
+            FetchError::Integrity(reason) => write!(f, "Integrity check failed: {}", reason),
+            FetchError::Unverifiable(reason) => write!(f, "Cannot verify content: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+// Blobs as files named by CID under a directory. Nothing is collected, so
+// every stored blob counts as pinned.
+#[derive(Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root).map_err(|e| anyhow!("Failed to create blob directory {}: {}", root.display(), e))?;
+        Ok(FsBlobStore { root: root.to_path_buf() })
+    }
+
+    // Only well-formed CIDs map to a file, so a CID cannot escape the directory
+    fn path(&self, cid: &str) -> Result<PathBuf> {
+        Cid::try_from(cid).map_err(|e| anyhow!("Invalid CID {}: {}", cid, e))?;
+        Ok(self.root.join(cid))
+    }
+
+    pub async fn put(&self, content: Vec<u8>) -> Result<String> {
+        let cid = replica_cid(&content);
+        let path = self.path(&cid)?;
+        // Written aside and renamed so readers never see a partial blob
+        let partial = self.root.join(format!(".{}.{}", cid, Uuid::new_v4()));
+        tokio::fs::write(&partial, content).await.map_err(|e| anyhow!("Failed to write blob {}: {}", cid, e))?;
+        tokio::fs::rename(&partial, &path).await.map_err(|e| anyhow!("Failed to store blob {}: {}", cid, e))?;
+        Ok(cid)
+    }
+
+    pub async fn get(&self, cid: &str) -> Result<Vec<u8>> {
+        match tokio::fs::read(self.path(cid)?).await {
+            Ok(content) => Ok(content),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(anyhow!("Blob {} not found", cid)),
+            Err(e) => Err(anyhow!("Failed to read blob {}: {}", cid, e)),
+        }
+    }
+
+    pub async fn pin(&self, cid: &str) -> Result<()> {
+        match tokio::fs::try_exists(self.path(cid)?).await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(anyhow!("Cannot pin missing blob {}", cid)),
+            Err(e) => Err(anyhow!("Failed to check blob {}: {}", cid, e)),
+        }
+    }
+
+    pub async fn unpin(&self, cid: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(cid)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(anyhow!("Failed to remove blob {}: {}", cid, e)),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn pinned(&self) -> Result<HashSet<String>> {
+        let mut entries = tokio::fs::read_dir(&self.root).await.map_err(|e| anyhow!("Failed to list blobs: {}", e))?;
+        let mut cids = HashSet::new();
+        while let Some(entry) = entries.next_entry().await.map_err(|e| anyhow!("Failed to list blobs: {}", e))? {
+            if let Some(name) = entry.file_name().to_str()
+                && Cid::try_from(name).is_ok()
+            {
+                cids.insert(name.to_string());
+            }
+        }
+        Ok(cids)
+    }
+}
+
+// Process-local store for tests and tooling. CIDs are the ones IPFS assigns
+// with raw leaves, see `replica_cid`.
+#[derive(Clone, Default)]
+pub struct MemoryBlobStore {
+    blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    pins: Arc<Mutex<HashSet<String>>>,
+    offline: Arc<AtomicBool>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        MemoryBlobStore::default()
+    }
+
+    pub fn put(&self, content: Vec<u8>) -> String {
+        let cid = replica_cid(&content);
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner()).insert(cid.clone(), content);
+        cid
+    }
+
+    pub fn get(&self, cid: &str) -> Option<Vec<u8>> {
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner()).get(cid).cloned()
+    }
+
+    pub fn pin(&self, cid: &str) -> Result<()> {
+        if !self.blobs.lock().unwrap_or_else(|e| e.into_inner()).contains_key(cid) {
+            return Err(anyhow!("Cannot pin missing blob {}", cid));
+        }
+        self.pins.lock().unwrap_or_else(|e| e.into_inner()).insert(cid.to_string());
+        Ok(())
+    }
+
+    // Unpinning also drops the content, as a garbage collection would
+    pub fn unpin(&self, cid: &str) {
+        self.pins.lock().unwrap_or_else(|e| e.into_inner()).remove(cid);
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner()).remove(cid);
+    }
+
+    pub fn pinned(&self) -> HashSet<String> {
+        self.pins.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Makes every call through BlobStore fail, as an unreachable backend would
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::SeqCst);
+    }
+
+    fn available(&self) -> Result<()> {
+        match self.offline.load(Ordering::SeqCst) {
+            true => Err(anyhow!("Blob store is offline")),
+            false => Ok(()),
+        }
+    }
+
+    // Flips a bit of a stored blob, as a failing disk or a lying node would
+    pub fn corrupt(&self, cid: &str) {
+        if let Some(content) = self.blobs.lock().unwrap_or_else(|e| e.into_inner()).get_mut(cid)
+            && let Some(byte) = content.first_mut()
+        {
+            *byte ^= 1;
+        }
+    }
+
+    pub fn is_pinned(&self, cid: &str) -> bool {
+        self.pins.lock().unwrap_or_else(|e| e.into_inner()).contains(cid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+async fn ipfs_add(client: &IpfsClient, content: Vec<u8>, options: request::Add<'_>) -> Result<String> {
+    client.add_with_options(Cursor::new(content), options)
+        .await
+        .map(|added| added.hash)
+        .map_err(|e| anyhow!("Failed to add blob to IPFS: {}", e))
+}
+
+// CIDv1 (raw codec, SHA-256) of some content, in its base32 string form
+pub fn raw_cid(content: &[u8]) -> String {
+    sha256_cid(RAW_CODEC, content).to_string()
+}
+
+fn sha256_cid(codec: u64, block: &[u8]) -> Cid {
+    let digest = Sha256::digest(block);
+    let hash = MultihashGeneric::<64>::wrap(SHA2_256_CODE, &digest).expect("SHA-256 digests fit in a multihash");
+    Cid::new_v1(codec, hash)
+}
+
+// A node of a file's DAG: its CID, the file bytes under it, and the size of
+// all blocks under it including its own
+struct DagNode {
+    cid: Cid,
+    file_size: u64,
+    dag_size: u64,
+}
+
+// CID of content as `put` adds it to IPFS: a raw block if it fits one
+// chunk, otherwise the root of a balanced dag-pb tree over raw chunks. Stores
+// that name blobs themselves use it, so they agree with IPFS replicas.
+pub fn replica_cid(content: &[u8]) -> String {
+    if content.len() <= IPFS_RAW_CHUNK {
+        return raw_cid(content);
+    }
+    let mut level: Vec<DagNode> = content.chunks(IPFS_RAW_CHUNK)
+        .map(|chunk| DagNode { cid: sha256_cid(RAW_CODEC, chunk), file_size: chunk.len() as u64, dag_size: chunk.len() as u64 })
+        .collect();
+    while level.len() > 1 {
+        level = level.chunks(IPFS_MAX_LINKS)
+            .map(|children| {
+                let block = unixfs_file_node(children);
+                DagNode {
+                    cid: sha256_cid(DAG_PB_CODEC, &block),
+                    file_size: children.iter().map(|child| child.file_size).sum(),
+                    dag_size: block.len() as u64 + children.iter().map(|child| child.dag_size).sum::<u64>(),
+                }
+            })
+            .collect();
+    }
+    level[0].cid.to_string()
+}
+
+// Whether content hashes to its CID. Raw SHA-256 CIDs address the bytes
+// directly, and content that fits one default IPFS chunk is the data of a
+// single dag-pb block we can rebuild. Larger dag-pb files depend on how they
+// were chunked, so for those (and other hash functions) this returns None.
+pub fn matches_cid(cid: &str, content: &[u8]) -> Option<bool> {
+    let cid = Cid::try_from(cid).ok()?;
+    if cid.hash().code() != SHA2_256_CODE {
+        return None;
+    }
+    let block = match cid.codec() {
+        RAW_CODEC => Cow::Borrowed(content),
+        DAG_PB_CODEC if content.len() <= IPFS_DEFAULT_CHUNK => Cow::Owned(unixfs_file_block(content)),
+        _ => return None,
+    };
+    Some(cid.hash().digest() == Sha256::digest(&block).as_slice())
+}
+
+// Checks fetched content against its CID and, if given, its hex SHA-256.
+// Content that only a hash could vouch for is unverifiable without one; such
+// blobs predate the recorded hashes.
+pub fn verify_content(cid: &str, sha256: Option<&str>, content: &[u8]) -> Result<(), FetchError> {
+    let matches = matches_cid(cid, content);
+    if matches == Some(false) {
+        return Err(FetchError::Integrity(format!("content does not match CID {}", cid)));
+    }
+    let Some(expected) = sha256 else {
+        return match matches {
+            Some(_) => Ok(()),
+            None => Err(FetchError::Unverifiable(format!("no SHA-256 is recorded for {} and its CID cannot be checked locally", cid))),
+        };
+    };
+    let actual: String = Sha256::digest(content).iter().map(|b| format!("{:02x}", b)).collect();
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(FetchError::Integrity(format!("content of {} hashes to {}, expected {}", cid, actual, expected)));
+    }
+    Ok(())
+}
+
+// dag-pb block `ipfs add` stores for a single-chunk file: a PBNode whose Data
+// is a UnixFS File message holding the content and its size
+fn unixfs_file_block(content: &[u8]) -> Vec<u8> {
+    let mut unixfs = vec![0x08, 0x02];
+    if !content.is_empty() {
+        unixfs.push(0x12);
+        put_varint(&mut unixfs, content.len() as u64);
+        unixfs.extend_from_slice(content);
+    }
+    unixfs.push(0x18);
+    put_varint(&mut unixfs, content.len() as u64);
+
+    let mut block = vec![0x0a];
+    put_varint(&mut block, unixfs.len() as u64);
+    block.extend_from_slice(&unixfs);
+    block
+}
+
+// dag-pb block linking the chunks under part of a file: an unnamed link per
+// child, then a UnixFS File message with the total and per-child sizes
+fn unixfs_file_node(children: &[DagNode]) -> Vec<u8> {
+    let mut unixfs = vec![0x08, 0x02, 0x18];
+    put_varint(&mut unixfs, children.iter().map(|child| child.file_size).sum());
+    for child in children {
+        unixfs.push(0x20);
+        put_varint(&mut unixfs, child.file_size);
+    }
+
+    let mut block = Vec::new();
+    for child in children {
+        let hash = child.cid.to_bytes();
+        let mut link = vec![0x0a];
+        put_varint(&mut link, hash.len() as u64);
+        link.extend_from_slice(&hash);
+        link.extend_from_slice(&[0x12, 0x00, 0x18]);
+        put_varint(&mut link, child.dag_size);
+        block.push(0x12);
+        put_varint(&mut block, link.len() as u64);
+        block.extend_from_slice(&link);
+    }
+    block.push(0x0a);
+    put_varint(&mut block, unixfs.len() as u64);
+    block.extend_from_slice(&unixfs);
+    block
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
@@ -0,0 +1,821 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, anyhow, Context};
+
+use crate::crypto::KeyAlgorithm;
+
+// Default location of the configuration file, relative to the working directory.
+// Override with MEDIRUST_CONFIG.
+const DEFAULT_CONFIG_PATH: &str = "medirust.toml";
+
+// Prefix for environment overrides, e.g. MEDIRUST_SERVER_PORT=9090
+const ENV_PREFIX: &str = "MEDIRUST_";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub database: DatabaseConfig,
+    pub blob_store: BlobStoreConfig,
+    pub crypto: CryptoConfig,
+    pub logging: LoggingConfig,
+    pub rate_limit: RateLimitConfig,
+    pub key_management: KeyManagementConfig,
+    pub disclosure: DisclosureConfig,
+    pub audit: AuditConfig,
+    pub backup: BackupConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub workers: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    // CA bundle used to verify client certificates; enables mutual TLS when set
+    pub client_ca_path: Option<PathBuf>,
+    // Reject handshakes without a client certificate (otherwise it is optional)
+    pub require_client_cert: bool,
+    // Maps the SHA-256 fingerprint (hex) of a client certificate to a principal name
+    pub client_principals: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_size: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobStoreConfig {
+    pub ipfs_api_url: String,
+    // How often pins on the node are reconciled with the records referencing them
+    pub reconcile_interval_secs: u64,
+    // Further backends every blob is replicated to, besides ipfs_api_url
+    pub replicas: Vec<ReplicaConfig>,
+    // Backends that must accept a write; a majority when unset
+    pub write_quorum: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicaConfig {
+    pub kind: ReplicaKind,
+    // RPC API of an IPFS replica
+    pub api_url: Option<String>,
+    // Directory of a filesystem replica
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicaKind {
+    Ipfs,
+    Fs,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CryptoConfig {
+    pub rsa_key_bits: usize,
+    // Key-encapsulation backend for new patient key pairs
+    pub patient_key_algorithm: KeyAlgorithm,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // RSA key generation when registering patients
+    pub key_generation: BucketConfig,
+    // Record reads, which trigger RSA unwrapping and IPFS retrieval
+    pub record_read: BucketConfig,
+    // Record uploads and re-encryption, which encrypt and write to the blob store
+    pub record_write: BucketConfig,
+    // Authentication attempts
+    pub login: BucketConfig,
+    // Social recovery requests, guardian challenges and share hand-ins
+    pub recovery: BucketConfig,
+    // Authentication failures within lockout_window_secs that lock the account
+    pub lockout_threshold: u32,
+    pub lockout_window_secs: u64,
+    pub lockout_duration_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketConfig {
+    // Maximum burst size
+    pub capacity: u32,
+    // Sustained rate at which tokens are replenished
+    pub refill_per_minute: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyManagementConfig {
+    // Root key (KEK) wrapping the tenant data keys, from a file or a
+    // passphrase. Required: record titles and types are sealed under the data key.
+    pub root_key: KeySourceConfig,
+    // Former root key; data keys still wrapped under it are re-wrapped at startup
+    pub previous_root_key: KeySourceConfig,
+    // Tenant whose data key protects this deployment's records
+    pub tenant: String,
+    // Keep each patient's private key on the server, sealed under the data key
+    pub escrow_patient_keys: bool,
+    // Let patients store their private key encrypted under a passphrase and
+    // unlock it at login, instead of managing the key themselves
+    pub passphrase_unlock: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisclosureConfig {
+    // Ed25519 PKCS8 PEM key signing selective-disclosure credentials;
+    // issuance and verification are disabled when unset
+    pub issuer_key_path: Option<PathBuf>,
+    // `iss` claim of issued credentials
+    pub issuer: String,
+    // Externally reachable base URL, used in links embedded in credentials
+    pub public_url: String,
+    pub ttl_secs: u64,
+    // Ages for which `age_over_N` claims are derived from a record's birth_date
+    pub age_thresholds: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // Record access to patient data and anchor it to the ledger
+    pub enabled: bool,
+    pub anchor_interval_secs: u64,
+    // Most events sealed into one Merkle tree
+    pub max_batch_size: usize,
+    pub ledger: LedgerKind,
+    // Append-only file used by the local ledger
+    pub local_ledger_path: PathBuf,
+    // JSON-RPC endpoint and unlocked sender account for the EVM ledger
+    pub evm_rpc_url: String,
+    pub evm_from: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerKind {
+    // Hash-chained local file, for development and tests
+    #[default]
+    Local,
+    // Anchor transactions on an EVM chain through JSON-RPC
+    Evm,
+}
+
+impl std::str::FromStr for LedgerKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "local" => Ok(LedgerKind::Local),
+            "evm" => Ok(LedgerKind::Evm),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupConfig {
+    // Key wrapping the per-archive keys of `medirust-admin backup`; kept
+    // apart from the root key so backups can be restored after a rotation
+    pub key: KeySourceConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeySourceConfig {
+    // File holding 32 raw bytes or their base64 encoding
+    pub path: Option<PathBuf>,
+    // Environment variable holding a passphrase to derive the key from with Argon2id
+    pub passphrase_env: Option<String>,
+    // Base64 salt for passphrase derivation (at least 16 bytes)
+    pub salt: Option<String>,
+}
+
+impl KeySourceConfig {
+    pub fn is_configured(&self) -> bool {
+        self.path.is_some() || self.passphrase_env.is_some()
+    }
+
+    fn validate(&self, name: &str) -> Result<()> {
+        if self.path.is_some() && self.passphrase_env.is_some() {
+            return Err(anyhow!("{}: set either path or passphrase_env, not both", name));
+        }
+        if let Some(p) = &self.path
+            && !p.is_file()
+        {
+            return Err(anyhow!("{}.path points to a missing file: {}", name, p.display()));
+        }
+        if let Some(var) = &self.passphrase_env {
+            if env::var(var).map(|v| v.is_empty()).unwrap_or(true) {
+                return Err(anyhow!("{}.passphrase_env names an unset environment variable: {}", name, var));
+            }
+            match self.salt.as_deref().map(crate::crypto::CryptoUtils::decode_base64) {
+                Some(Ok(salt)) if salt.len() >= 16 => {}
+                Some(_) => return Err(anyhow!("{}.salt must be base64 encoding at least 16 bytes", name)),
+                None => return Err(anyhow!("{}.salt is required with passphrase_env", name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            workers: None,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: String::new(),
+            pool_size: 10,
+        }
+    }
+}
+
+impl Default for BlobStoreConfig {
+    fn default() -> Self {
+        BlobStoreConfig {
+            ipfs_api_url: "http://localhost:5001".to_string(),
+            reconcile_interval_secs: 3600,
+            replicas: Vec::new(),
+            write_quorum: None,
+        }
+    }
+}
+
+impl Default for CryptoConfig {
+    fn default() -> Self {
+        CryptoConfig {
+            rsa_key_bits: 2048,
+            patient_key_algorithm: KeyAlgorithm::default(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            key_generation: BucketConfig { capacity: 5, refill_per_minute: 5 },
+            record_read: BucketConfig { capacity: 60, refill_per_minute: 120 },
+            record_write: BucketConfig { capacity: 30, refill_per_minute: 60 },
+            login: BucketConfig { capacity: 10, refill_per_minute: 10 },
+            recovery: BucketConfig { capacity: 5, refill_per_minute: 2 },
+            lockout_threshold: 5,
+            lockout_window_secs: 900,
+            lockout_duration_secs: 900,
+        }
+    }
+}
+
+impl Default for KeyManagementConfig {
+    fn default() -> Self {
+        KeyManagementConfig {
+            root_key: KeySourceConfig::default(),
+            previous_root_key: KeySourceConfig::default(),
+            tenant: "default".to_string(),
+            escrow_patient_keys: false,
+            passphrase_unlock: false,
+        }
+    }
+}
+
+impl Default for DisclosureConfig {
+    fn default() -> Self {
+        DisclosureConfig {
+            issuer_key_path: None,
+            issuer: "medirust".to_string(),
+            public_url: "http://127.0.0.1:8080".to_string(),
+            ttl_secs: 30 * 24 * 3600,
+            age_thresholds: vec![18, 21, 65],
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: true,
+            anchor_interval_secs: 300,
+            max_batch_size: 4096,
+            ledger: LedgerKind::Local,
+            local_ledger_path: PathBuf::from("audit-ledger.jsonl"),
+            evm_rpc_url: "http://127.0.0.1:8545".to_string(),
+            evm_from: None,
+        }
+    }
+}
+
+impl AppConfig {
+    // Loads configuration from the TOML file (if present), applies environment
+    // overrides and validates the result.
+    pub fn load() -> Result<Self> {
+        let (path, explicit) = match env::var(format!("{}CONFIG", ENV_PREFIX)) {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let mut config = if path.exists() {
+            Self::from_file(&path)?
+        } else if explicit {
+            return Err(anyhow!("Configuration file {} does not exist", path.display()));
+        } else {
+            AppConfig::default()
+        };
+
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Parses a configuration file without applying overrides or validation
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read configuration file {}", path.display()))?;
+        Self::from_toml_str(&contents)
+            .with_context(|| format!("Invalid configuration file {}", path.display()))
+    }
+
+    pub fn from_toml_str(contents: &str) -> Result<Self> {
+        toml::from_str(contents).map_err(|e| anyhow!("{}", e))
+    }
+
+    // Environment variables take precedence over the file. DATABASE_URL is kept
+    // for compatibility with the diesel CLI and existing deployments.
+    fn apply_env_overrides(&mut self) -> Result<()> {
+        if let Ok(url) = env::var("DATABASE_URL") {
+            self.database.url = url;
+        }
+
+        if let Some(v) = env_override("SERVER_HOST") {
+            self.server.host = v;
+        }
+        if let Some(v) = env_override("SERVER_PORT") {
+            self.server.port = parse_override("SERVER_PORT", &v)?;
+        }
+        if let Some(v) = env_override("SERVER_WORKERS") {
+            self.server.workers = Some(parse_override("SERVER_WORKERS", &v)?);
+        }
+        if let Some(v) = env_override("TLS_ENABLED") {
+            self.tls.enabled = parse_override("TLS_ENABLED", &v)?;
+        }
+        if let Some(v) = env_override("TLS_CERT_PATH") {
+            self.tls.cert_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_override("TLS_KEY_PATH") {
+            self.tls.key_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_override("TLS_CLIENT_CA_PATH") {
+            self.tls.client_ca_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_override("TLS_REQUIRE_CLIENT_CERT") {
+            self.tls.require_client_cert = parse_override("TLS_REQUIRE_CLIENT_CERT", &v)?;
+        }
+        if let Some(v) = env_override("DATABASE_URL") {
+            self.database.url = v;
+        }
+        if let Some(v) = env_override("DATABASE_POOL_SIZE") {
+            self.database.pool_size = parse_override("DATABASE_POOL_SIZE", &v)?;
+        }
+        if let Some(v) = env_override("BLOB_STORE_IPFS_API_URL") {
+            self.blob_store.ipfs_api_url = v;
+        }
+        if let Some(v) = env_override("BLOB_STORE_RECONCILE_INTERVAL_SECS") {
+            self.blob_store.reconcile_interval_secs = parse_override("BLOB_STORE_RECONCILE_INTERVAL_SECS", &v)?;
+        }
+        if let Some(v) = env_override("BLOB_STORE_WRITE_QUORUM") {
+            self.blob_store.write_quorum = Some(parse_override("BLOB_STORE_WRITE_QUORUM", &v)?);
+        }
+        if let Some(v) = env_override("CRYPTO_RSA_KEY_BITS") {
+            self.crypto.rsa_key_bits = parse_override("CRYPTO_RSA_KEY_BITS", &v)?;
+        }
+        if let Some(v) = env_override("CRYPTO_PATIENT_KEY_ALGORITHM") {
+            self.crypto.patient_key_algorithm = parse_override("CRYPTO_PATIENT_KEY_ALGORITHM", &v)?;
+        }
+        if let Some(v) = env_override("LOGGING_LEVEL") {
+            self.logging.level = v;
+        }
+        if let Some(v) = env_override("LOGGING_FORMAT") {
+            self.logging.format = parse_override("LOGGING_FORMAT", &v)?;
+        }
+        if let Some(v) = env_override("RATE_LIMIT_ENABLED") {
+            self.rate_limit.enabled = parse_override("RATE_LIMIT_ENABLED", &v)?;
+        }
+        if let Some(v) = env_override("KEY_MANAGEMENT_ROOT_KEY_PATH") {
+            self.key_management.root_key.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_override("KEY_MANAGEMENT_TENANT") {
+            self.key_management.tenant = v;
+        }
+        if let Some(v) = env_override("KEY_MANAGEMENT_ESCROW_PATIENT_KEYS") {
+            self.key_management.escrow_patient_keys = parse_override("KEY_MANAGEMENT_ESCROW_PATIENT_KEYS", &v)?;
+        }
+        if let Some(v) = env_override("KEY_MANAGEMENT_PASSPHRASE_UNLOCK") {
+            self.key_management.passphrase_unlock = parse_override("KEY_MANAGEMENT_PASSPHRASE_UNLOCK", &v)?;
+        }
+        if let Some(v) = env_override("DISCLOSURE_ISSUER_KEY_PATH") {
+            self.disclosure.issuer_key_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_override("DISCLOSURE_ISSUER") {
+            self.disclosure.issuer = v;
+        }
+        if let Some(v) = env_override("DISCLOSURE_PUBLIC_URL") {
+            self.disclosure.public_url = v;
+        }
+        if let Some(v) = env_override("AUDIT_ENABLED") {
+            self.audit.enabled = parse_override("AUDIT_ENABLED", &v)?;
+        }
+        if let Some(v) = env_override("AUDIT_LEDGER") {
+            self.audit.ledger = parse_override("AUDIT_LEDGER", &v)?;
+        }
+        if let Some(v) = env_override("AUDIT_EVM_RPC_URL") {
+            self.audit.evm_rpc_url = v;
+        }
+        if let Some(v) = env_override("AUDIT_EVM_FROM") {
+            self.audit.evm_from = Some(v);
+        }
+        if let Some(v) = env_override("BACKUP_KEY_PATH") {
+            self.backup.key.path = Some(PathBuf::from(v));
+        }
+        Ok(())
+    }
+
+    // Rejects configurations that would otherwise fail later at runtime
+    pub fn validate(&self) -> Result<()> {
+        self.server.host.parse::<IpAddr>()
+            .map_err(|_| anyhow!("server.host must be an IP address, got '{}'", self.server.host))?;
+        if self.server.port == 0 {
+            return Err(anyhow!("server.port must be non-zero"));
+        }
+        if self.server.workers == Some(0) {
+            return Err(anyhow!("server.workers must be at least 1"));
+        }
+
+        if self.tls.enabled {
+            for (name, path) in [("tls.cert_path", &self.tls.cert_path), ("tls.key_path", &self.tls.key_path)] {
+                match path {
+                    Some(p) if p.is_file() => {}
+                    Some(p) => return Err(anyhow!("{} points to a missing file: {}", name, p.display())),
+                    None => return Err(anyhow!("{} is required when tls.enabled = true", name)),
+                }
+            }
+            if let Some(p) = &self.tls.client_ca_path
+                && !p.is_file()
+            {
+                return Err(anyhow!("tls.client_ca_path points to a missing file: {}", p.display()));
+            }
+            if self.tls.require_client_cert && self.tls.client_ca_path.is_none() {
+                return Err(anyhow!("tls.require_client_cert needs tls.client_ca_path"));
+            }
+            for fingerprint in self.tls.client_principals.keys() {
+                if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(anyhow!("tls.client_principals key '{}' is not a hex SHA-256 fingerprint", fingerprint));
+                }
+            }
+        } else if self.tls.client_ca_path.is_some() || self.tls.require_client_cert {
+            return Err(anyhow!("mutual TLS settings require tls.enabled = true"));
+        }
+
+        if self.database.url.is_empty() {
+            return Err(anyhow!("database.url must be set (or DATABASE_URL in the environment)"));
+        }
+        if self.database.pool_size == 0 {
+            return Err(anyhow!("database.pool_size must be at least 1"));
+        }
+
+        if !(self.blob_store.ipfs_api_url.starts_with("http://") || self.blob_store.ipfs_api_url.starts_with("https://")) {
+            return Err(anyhow!("blob_store.ipfs_api_url must be an http(s) URL, got '{}'", self.blob_store.ipfs_api_url));
+        }
+        if self.blob_store.reconcile_interval_secs == 0 {
+            return Err(anyhow!("blob_store.reconcile_interval_secs must be non-zero"));
+        }
+        for (index, replica) in self.blob_store.replicas.iter().enumerate() {
+            match (replica.kind, &replica.api_url, &replica.path) {
+                (ReplicaKind::Ipfs, Some(url), None) if url.starts_with("http://") || url.starts_with("https://") => {}
+                (ReplicaKind::Ipfs, Some(url), None) => {
+                    return Err(anyhow!("blob_store.replicas[{}].api_url must be an http(s) URL, got '{}'", index, url));
+                }
+                (ReplicaKind::Fs, None, Some(_)) => {}
+                (ReplicaKind::Ipfs, ..) => return Err(anyhow!("blob_store.replicas[{}] of kind ipfs takes api_url only", index)),
+                (ReplicaKind::Fs, ..) => return Err(anyhow!("blob_store.replicas[{}] of kind fs takes path only", index)),
+            }
+        }
+        let backends = self.blob_store.replicas.len() + 1;
+        if let Some(quorum) = self.blob_store.write_quorum
+            && !(1..=backends).contains(&quorum)
+        {
+            return Err(anyhow!("blob_store.write_quorum must be between 1 and {} (the number of backends), got {}", backends, quorum));
+        }
+
+        if !(2048..=8192).contains(&self.crypto.rsa_key_bits) || !self.crypto.rsa_key_bits.is_multiple_of(1024) {
+            return Err(anyhow!("crypto.rsa_key_bits must be a multiple of 1024 between 2048 and 8192, got {}", self.crypto.rsa_key_bits));
+        }
+
+        match self.logging.level.to_ascii_lowercase().as_str() {
+            "trace" | "debug" | "info" | "warn" | "error" => {}
+            other => return Err(anyhow!("logging.level must be one of trace, debug, info, warn, error; got '{}'", other)),
+        }
+
+        let limits = &self.rate_limit;
+        for (name, bucket) in [
+            ("rate_limit.key_generation", limits.key_generation),
+            ("rate_limit.record_read", limits.record_read),
+            ("rate_limit.record_write", limits.record_write),
+            ("rate_limit.login", limits.login),
+            ("rate_limit.recovery", limits.recovery),
+        ] {
+            if bucket.capacity == 0 || bucket.refill_per_minute == 0 {
+                return Err(anyhow!("{} needs a non-zero capacity and refill_per_minute", name));
+            }
+        }
+        if limits.lockout_threshold == 0 || limits.lockout_window_secs == 0 || limits.lockout_duration_secs == 0 {
+            return Err(anyhow!("rate_limit lockout settings must be non-zero"));
+        }
+
+        let keys = &self.key_management;
+        if !keys.root_key.is_configured() {
+            return Err(anyhow!(
+                "key_management.root_key must set path or passphrase_env (or MEDIRUST_KEY_MANAGEMENT_ROOT_KEY_PATH); record titles and types are sealed under it"
+            ));
+        }
+        keys.root_key.validate("key_management.root_key")?;
+        keys.previous_root_key.validate("key_management.previous_root_key")?;
+        if keys.tenant.is_empty() {
+            return Err(anyhow!("key_management.tenant must not be empty"));
+        }
+
+        let disclosure = &self.disclosure;
+        if let Some(p) = &disclosure.issuer_key_path
+            && !p.is_file()
+        {
+            return Err(anyhow!("disclosure.issuer_key_path points to a missing file: {}", p.display()));
+        }
+        if disclosure.issuer.is_empty() {
+            return Err(anyhow!("disclosure.issuer must not be empty"));
+        }
+        if !(disclosure.public_url.starts_with("http://") || disclosure.public_url.starts_with("https://")) {
+            return Err(anyhow!("disclosure.public_url must be an http(s) URL, got '{}'", disclosure.public_url));
+        }
+        if disclosure.ttl_secs == 0 {
+            return Err(anyhow!("disclosure.ttl_secs must be non-zero"));
+        }
+        if disclosure.age_thresholds.iter().any(|age| *age == 0 || *age > 150) {
+            return Err(anyhow!("disclosure.age_thresholds must be between 1 and 150"));
+        }
+
+        let audit = &self.audit;
+        if audit.anchor_interval_secs == 0 || audit.max_batch_size == 0 {
+            return Err(anyhow!("audit.anchor_interval_secs and audit.max_batch_size must be non-zero"));
+        }
+        if audit.enabled && audit.ledger == LedgerKind::Evm {
+            if !audit.evm_rpc_url.starts_with("http://") {
+                return Err(anyhow!("audit.evm_rpc_url must be an http URL, got '{}'", audit.evm_rpc_url));
+            }
+            match &audit.evm_from {
+                Some(from) if from.len() == 42 && from.starts_with("0x") && from[2..].chars().all(|c| c.is_ascii_hexdigit()) => {}
+                Some(from) => return Err(anyhow!("audit.evm_from is not an address: '{}'", from)),
+                None => return Err(anyhow!("audit.evm_from is required with audit.ledger = \"evm\"")),
+            }
+        }
+
+        self.backup.key.validate("backup.key")?;
+
+        Ok(())
+    }
+
+    pub fn bind_address(&self) -> (String, u16) {
+        (self.server.host.clone(), self.server.port)
+    }
+}
+
+fn env_override(key: &str) -> Option<String> {
+    env::var(format!("{}{}", ENV_PREFIX, key)).ok()
+}
+
+fn parse_override<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+    value.parse::<T>()
+        .map_err(|_| anyhow!("Invalid value '{}' for {}{}", value, ENV_PREFIX, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use uuid::Uuid;
+
+    // Tests that set environment variables hold this, as the process shares them
+    static ENV: Mutex<()> = Mutex::new(());
+
+    // A scratch directory holding a root key file, removed on drop
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new() -> Self {
+            let dir = env::temp_dir().join(format!("medirust-config-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("root.key"), [7u8; 32]).unwrap();
+            Scratch(dir)
+        }
+
+        fn root_key(&self) -> PathBuf {
+            self.0.join("root.key")
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn valid_config(scratch: &Scratch) -> AppConfig {
+        let mut config = AppConfig::default();
+        config.database.url = "postgres://localhost/medirust".to_string();
+        config.key_management.root_key.path = Some(scratch.root_key());
+        config
+    }
+
+    #[test]
+    fn a_root_key_is_required() {
+        let scratch = Scratch::new();
+        let mut config = valid_config(&scratch);
+        config.validate().unwrap();
+
+        config.key_management.root_key = KeySourceConfig::default();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("key_management.root_key"), "{}", err);
+
+        config.key_management.root_key.path = Some(scratch.0.join("missing.key"));
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.contains("missing file"), "{}", err);
+    }
+
+    #[test]
+    fn the_example_configuration_loads() {
+        let mut config = AppConfig::from_toml_str(include_str!("../medirust.example.toml")).unwrap();
+        assert_eq!(config.bind_address(), ("127.0.0.1".to_string(), 8080));
+        assert_eq!(config.crypto.patient_key_algorithm, KeyAlgorithm::Rsa);
+        assert_eq!((config.rate_limit.recovery.capacity, config.rate_limit.recovery.refill_per_minute), (5, 2));
+        assert_eq!(config.key_management.root_key.path, Some(PathBuf::from("secrets/root.key")));
+
+        let scratch = Scratch::new();
+        config.key_management.root_key.path = Some(scratch.root_key());
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn files_fill_in_defaults_and_reject_unknown_keys() {
+        let scratch = Scratch::new();
+        let path = scratch.0.join("medirust.toml");
+        fs::write(&path, "[server]\nport = 9090\n\n[logging]\nformat = \"json\"\n").unwrap();
+        let config = AppConfig::from_file(&path).unwrap();
+        assert_eq!(config.bind_address(), ("127.0.0.1".to_string(), 9090));
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.database.pool_size, DatabaseConfig::default().pool_size);
+
+        fs::write(&path, "[server]\nprot = 9090\n").unwrap();
+        let err = format!("{:#}", AppConfig::from_file(&path).unwrap_err());
+        assert!(err.contains("medirust.toml") && err.contains("prot"), "{}", err);
+
+        let err = format!("{:#}", AppConfig::from_file(&scratch.0.join("absent.toml")).unwrap_err());
+        assert!(err.contains("Failed to read"), "{}", err);
+    }
+
+    #[test]
+    fn environment_overrides_take_precedence() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let scratch = Scratch::new();
+        let path = scratch.0.join("medirust.toml");
+        fs::write(
+            &path,
+            format!(
+                "[server]\nport = 9090\n\n[database]\nurl = \"postgres://file/medirust\"\n\n[key_management.root_key]\npath = {:?}\n",
+                scratch.root_key()
+            ),
+        )
+        .unwrap();
+        let vars = [
+            ("MEDIRUST_CONFIG", path.to_str().unwrap()),
+            ("MEDIRUST_SERVER_PORT", "9191"),
+            ("DATABASE_URL", "postgres://legacy/medirust"),
+            ("MEDIRUST_RATE_LIMIT_ENABLED", "false"),
+            ("MEDIRUST_CRYPTO_PATIENT_KEY_ALGORITHM", "hpke-x25519-sha256-aes256gcm"),
+        ];
+        // SAFETY: every test touching the environment holds ENV
+        for (name, value) in vars {
+            unsafe { env::set_var(name, value) };
+        }
+        let config = AppConfig::load();
+        unsafe { env::set_var("MEDIRUST_DATABASE_URL", "postgres://prefixed/medirust") };
+        let prefixed = AppConfig::load();
+        unsafe { env::set_var("MEDIRUST_SERVER_PORT", "eighty") };
+        let invalid = AppConfig::load();
+        unsafe { env::set_var("MEDIRUST_CONFIG", scratch.0.join("absent.toml")) };
+        let absent = AppConfig::load();
+        for name in vars.iter().map(|(name, _)| *name).chain(["MEDIRUST_DATABASE_URL"]) {
+            unsafe { env::remove_var(name) };
+        }
+
+        let config = config.unwrap();
+        assert_eq!(config.server.port, 9191);
+        assert_eq!(config.database.url, "postgres://legacy/medirust");
+        assert!(!config.rate_limit.enabled);
+        assert_eq!(config.crypto.patient_key_algorithm, KeyAlgorithm::X25519Hpke);
+        // MEDIRUST_DATABASE_URL wins over the DATABASE_URL kept for the diesel CLI
+        assert_eq!(prefixed.unwrap().database.url, "postgres://prefixed/medirust");
+        let err = invalid.unwrap_err().to_string();
+        assert!(err.contains("MEDIRUST_SERVER_PORT"), "{}", err);
+        let err = absent.unwrap_err().to_string();
+        assert!(err.contains("does not exist"), "{}", err);
+    }
+
+    // Change that makes a valid configuration invalid
+    type Breakage = fn(&mut AppConfig);
+
+    #[test]
+    fn validation_rejects_settings_that_would_fail_at_runtime() {
+        let scratch = Scratch::new();
+        let cases: [(&str, Breakage); 8] = [
+            ("server.host", |config| config.server.host = "localhost".to_string()),
+            ("server.port", |config| config.server.port = 0),
+            ("tls.cert_path", |config| config.tls.enabled = true),
+            ("mutual TLS", |config| config.tls.require_client_cert = true),
+            ("blob_store.write_quorum", |config| config.blob_store.write_quorum = Some(2)),
+            ("crypto.rsa_key_bits", |config| config.crypto.rsa_key_bits = 3000),
+            ("rate_limit.recovery", |config| config.rate_limit.recovery.capacity = 0),
+            ("audit.evm_from", |config| config.audit.ledger = LedgerKind::Evm),
+        ];
+        for (expected, break_config) in cases {
+            let mut config = valid_config(&scratch);
+            break_config(&mut config);
+            let err = config.validate().unwrap_err().to_string();
+            assert!(err.contains(expected), "{}: {}", expected, err);
+        }
+    }
+}
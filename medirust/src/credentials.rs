@@ -0,0 +1,444 @@
+use actix_web::{web, HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use flate2::{read::ZlibDecoder, write::{GzEncoder, ZlibEncoder}, Compression};
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::io::{Read, Write};
+use uuid::Uuid;
+use anyhow::{Result, anyhow};
+
+use crate::auth::{AuthenticatedPatient, Principal};
+use crate::blobstore::BlobStore;
+use crate::config::AppConfig;
+use crate::disclosure::DisclosureIssuer;
+use crate::handlers;
+use crate::keys::KeyHierarchy;
+use crate::models::Credential;
+use crate::schema::credentials;
+use crate::signing::SignatureStatus;
+use crate::DbPool;
+
+// W3C Verifiable Credentials (data model 1.1) in the JWT encoding, signed
+// with the disclosure issuer key. Revocation uses a StatusList2021 bitstring.
+
+const VC_JWT_TYP: &str = "JWT";
+const VC_CONTEXT: &str = "https://www.w3.org/2018/credentials/v1";
+const STATUS_LIST_CONTEXT: &str = "https://w3id.org/vc/status-list/2021/v1";
+// 16 KiB of status bits, the minimum the status list spec recommends for herd privacy
+const STATUS_LIST_BITS: i32 = 131_072;
+// Status list credentials are short-lived so verifiers refetch revocations
+const STATUS_LIST_TTL_SECS: i64 = 24 * 3600;
+const STATUS_INDEX_ATTEMPTS: usize = 32;
+// QR payload: prefix, then base45 of the zlib-compressed JWT (QR alphanumeric mode)
+const QR_PREFIX: &str = "VC1:";
+const BASE45_ALPHABET: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+// Upper bound on a decompressed QR payload
+const MAX_CREDENTIAL_SIZE: u64 = 64 * 1024;
+
+// Kind of credential, chosen from the record type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialType {
+    Immunization,
+    LabResult,
+}
+
+impl CredentialType {
+    pub fn from_record_type(record_type: &str) -> Option<Self> {
+        match record_type.to_ascii_lowercase().as_str() {
+            "immunization" | "vaccination" => Some(CredentialType::Immunization),
+            "lab" | "lab_result" | "test_result" => Some(CredentialType::LabResult),
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> &'static str {
+        match self {
+            CredentialType::Immunization => "ImmunizationCredential",
+            CredentialType::LabResult => "LabResultCredential",
+        }
+    }
+}
+
+fn status_list_url(issuer: &DisclosureIssuer) -> String {
+    format!("{}/credentials/status", issuer.public_url())
+}
+
+// Base45 (RFC 9285), the QR alphanumeric alphabet packing two bytes into three characters
+pub fn base45_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3 / 2 + 1);
+    for chunk in bytes.chunks(2) {
+        let (mut value, digits) = match chunk {
+            [a, b] => (*a as usize * 256 + *b as usize, 3),
+            [a] => (*a as usize, 2),
+            _ => unreachable!(),
+        };
+        for _ in 0..digits {
+            encoded.push(BASE45_ALPHABET[value % 45] as char);
+            value /= 45;
+        }
+    }
+    encoded
+}
+
+pub fn base45_decode(encoded: &str) -> Result<Vec<u8>> {
+    let digits = encoded
+        .bytes()
+        .map(|c| BASE45_ALPHABET.iter().position(|a| *a == c).ok_or_else(|| anyhow!("Invalid base45 character")))
+        .collect::<Result<Vec<usize>>>()?;
+    let mut bytes = Vec::with_capacity(digits.len() * 2 / 3);
+    for chunk in digits.chunks(3) {
+        match chunk {
+            [c, d, e] => {
+                let value = c + d * 45 + e * 45 * 45;
+                if value > 0xffff {
+                    return Err(anyhow!("Invalid base45 triplet"));
+                }
+                bytes.extend_from_slice(&[(value / 256) as u8, (value % 256) as u8]);
+            }
+            [c, d] => {
+                let value = c + d * 45;
+                if value > 0xff {
+                    return Err(anyhow!("Invalid base45 pair"));
+                }
+                bytes.push(value as u8);
+            }
+            _ => return Err(anyhow!("Invalid base45 length")),
+        }
+    }
+    Ok(bytes)
+}
+
+// Encodes a credential for a QR code; offline verifiers reverse this and
+// check the JWT with the issuer's public key
+pub fn to_qr_payload(jwt: &str) -> Result<String> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(jwt.as_bytes())?;
+    Ok(format!("{}{}", QR_PREFIX, base45_encode(&encoder.finish()?)))
+}
+
+pub fn from_qr_payload(payload: &str) -> Result<String> {
+    let encoded = payload.strip_prefix(QR_PREFIX).ok_or_else(|| anyhow!("Not a credential QR payload"))?;
+    let compressed = base45_decode(encoded)?;
+    let mut jwt = String::new();
+    ZlibDecoder::new(compressed.as_slice())
+        .take(MAX_CREDENTIAL_SIZE)
+        .read_to_string(&mut jwt)
+        .map_err(|_| anyhow!("Invalid compressed credential"))?;
+    Ok(jwt)
+}
+
+// GZIP-compressed, multibase base64url bitstring with revoked indices set
+pub fn encode_status_list(revoked: &[i32]) -> Result<String> {
+    let mut bits = vec![0u8; STATUS_LIST_BITS as usize / 8];
+    for index in revoked.iter().filter(|index| (0..STATUS_LIST_BITS).contains(*index)) {
+        bits[*index as usize / 8] |= 0x80 >> (index % 8);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&bits)?;
+    Ok(format!("u{}", URL_SAFE_NO_PAD.encode(encoder.finish()?)))
+}
+
+fn credential_json(credential: &Credential) -> Value {
+    json!({
+        "id": Uuid::from_slice(&credential.id).unwrap_or_default().to_string(),
+        "record_id": Uuid::from_slice(&credential.record_id).unwrap_or_default().to_string(),
+        "credential_type": credential.credential_type,
+        "status_list_index": credential.status_index,
+        "issued_at": credential.issued_at,
+        "expires_at": credential.expires_at,
+        "revoked": credential.revoked_at.is_some(),
+        "revoked_at": credential.revoked_at,
+    })
+}
+
+// Handler issuing a verifiable credential for a signed immunization or lab
+// record. The record content (a JSON object) becomes the credential subject.
+pub async fn issue_credential(
+    pool: web::Data<DbPool>,
+    blob_store: web::Data<BlobStore>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    issuer: web::Data<Option<DisclosureIssuer>>,
+    principal: Option<Principal>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let Some(issuer) = issuer.get_ref().as_ref() else {
+        return HttpResponse::NotFound().body("Credential issuance is not configured");
+    };
+    let (patient_id, record_id) = path.into_inner();
+    let (patient_uuid, record_uuid) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&record_id)) {
+        (Ok(p), Ok(r)) => (p, r),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or record ID"),
+    };
+
+    let opened = match handlers::open_health_record(&pool, &blob_store, &config, &keys, principal.as_ref(), patient_uuid.as_bytes().to_vec(), record_uuid.as_bytes().to_vec()).await {
+        Ok(opened) => opened,
+        Err(response) => return response,
+    };
+    let Some(credential_type) = CredentialType::from_record_type(&opened.record.record_type) else {
+        return HttpResponse::UnprocessableEntity().body("Credentials are only issued for immunization and lab result records");
+    };
+    if opened.verification.status != SignatureStatus::Valid {
+        return HttpResponse::UnprocessableEntity().body("Only records with a valid author signature can be issued as credentials");
+    }
+    let mut subject = match serde_json::from_slice::<Value>(&opened.content) {
+        Ok(Value::Object(content)) => content,
+        _ => return HttpResponse::UnprocessableEntity().body("Record content is not a JSON object"),
+    };
+
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::seconds(issuer.ttl_secs() as i64);
+    let credential_id = Uuid::new_v4();
+    let record_credential = Credential {
+        id: credential_id.as_bytes().to_vec(),
+        patient_id: patient_uuid.as_bytes().to_vec(),
+        record_id: record_uuid.as_bytes().to_vec(),
+        credential_type: credential_type.id().to_string(),
+        status_index: 0,
+        issued_at: now.naive_utc(),
+        expires_at: expires_at.naive_utc(),
+        revoked_at: None,
+    };
+    let stored = match web::block(move || -> Result<Credential> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            // Random rather than sequential indices, so a status bit says
+            // nothing about when or for whom a credential was issued
+            let mut rng = rand::thread_rng();
+            for _ in 0..STATUS_INDEX_ATTEMPTS {
+                let index = rng.gen_range(0..STATUS_LIST_BITS);
+                let taken: i64 = credentials::table
+                    .filter(credentials::status_index.eq(index))
+                    .count()
+                    .get_result(conn)?;
+                if taken == 0 {
+                    let credential = Credential { status_index: index, ..record_credential };
+                    diesel::insert_into(credentials::table).values(&credential).execute(conn)?;
+                    return Ok(credential);
+                }
+            }
+            Err(anyhow!("No free status list index"))
+        })
+    })
+    .await
+    {
+        Ok(Ok(credential)) => credential,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error issuing credential: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    let status_url = status_list_url(issuer);
+    subject.insert("id".to_string(), json!(format!("urn:uuid:{}", patient_uuid)));
+    subject.insert("recordType".to_string(), json!(opened.record.record_type));
+    if let Some(signed_by) = &opened.verification.signed_by {
+        subject.insert("attestedBy".to_string(), json!(signed_by));
+    }
+    let mut claims = Map::new();
+    claims.insert("iss".to_string(), json!(issuer.issuer()));
+    claims.insert("sub".to_string(), json!(format!("urn:uuid:{}", patient_uuid)));
+    claims.insert("jti".to_string(), json!(format!("urn:uuid:{}", credential_id)));
+    claims.insert("iat".to_string(), json!(now.timestamp()));
+    claims.insert("nbf".to_string(), json!(now.timestamp()));
+    claims.insert("exp".to_string(), json!(expires_at.timestamp()));
+    claims.insert("vc".to_string(), json!({
+        "@context": [VC_CONTEXT, STATUS_LIST_CONTEXT],
+        "type": ["VerifiableCredential", credential_type.id()],
+        "issuer": issuer.issuer(),
+        "issuanceDate": now.to_rfc3339(),
+        "expirationDate": expires_at.to_rfc3339(),
+        "credentialSubject": subject,
+        "credentialStatus": {
+            "id": format!("{}#{}", status_url, stored.status_index),
+            "type": "StatusList2021Entry",
+            "statusPurpose": "revocation",
+            "statusListIndex": stored.status_index.to_string(),
+            "statusListCredential": status_url,
+        },
+    }));
+    let jwt = issuer.sign_jwt(VC_JWT_TYP, &claims);
+    let qr_payload = match to_qr_payload(&jwt) {
+        Ok(payload) => payload,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error encoding QR payload: {:?}", e)),
+    };
+
+    tracing::info!(credential_type = credential_type.id(), "verifiable credential issued");
+    let mut body = credential_json(&stored);
+    body["credential"] = json!(jwt);
+    body["qr_payload"] = json!(qr_payload);
+    HttpResponse::Created().json(body)
+}
+
+// Handler listing the credentials issued for the authenticated patient
+pub async fn list_credentials(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+
+    match web::block(move || -> Result<Vec<Credential>> {
+        let mut conn = pool.get()?;
+        Ok(credentials::table
+            .filter(credentials::patient_id.eq(patient_id_bytes))
+            .order(credentials::issued_at.asc())
+            .select(Credential::as_select())
+            .load(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(issued)) => HttpResponse::Ok().json(issued.iter().map(credential_json).collect::<Vec<_>>()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error listing credentials: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for the authenticated patient revoking one of their credentials;
+// the status list reflects it immediately
+pub async fn revoke_credential(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id, credential_id) = path.into_inner();
+    let (patient_id_bytes, credential_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&credential_id)) {
+        (Ok(p), Ok(c)) => (p.as_bytes().to_vec(), c.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or credential ID"),
+    };
+
+    match web::block(move || -> Result<usize> {
+        let mut conn = pool.get()?;
+        Ok(diesel::update(
+            credentials::table
+                .filter(credentials::id.eq(credential_id_bytes))
+                .filter(credentials::patient_id.eq(patient_id_bytes))
+                .filter(credentials::revoked_at.is_null()),
+        )
+        .set(credentials::revoked_at.eq(Some(Utc::now().naive_utc())))
+        .execute(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(0)) => HttpResponse::NotFound().body("Active credential not found"),
+        Ok(Ok(_)) => HttpResponse::NoContent().finish(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error revoking credential: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler serving the revocation status list as a signed StatusList2021 credential
+pub async fn get_status_list(
+    pool: web::Data<DbPool>,
+    issuer: web::Data<Option<DisclosureIssuer>>,
+) -> impl Responder {
+    let Some(issuer) = issuer.get_ref().as_ref() else {
+        return HttpResponse::NotFound().body("Credential issuance is not configured");
+    };
+
+    let revoked = match web::block(move || -> Result<Vec<i32>> {
+        let mut conn = pool.get()?;
+        Ok(credentials::table
+            .filter(credentials::revoked_at.is_not_null())
+            .select(credentials::status_index)
+            .load(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(revoked)) => revoked,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error loading revocations: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+    let encoded_list = match encode_status_list(&revoked) {
+        Ok(list) => list,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error encoding status list: {:?}", e)),
+    };
+
+    let now = Utc::now();
+    let status_url = status_list_url(issuer);
+    let mut claims = Map::new();
+    claims.insert("iss".to_string(), json!(issuer.issuer()));
+    claims.insert("sub".to_string(), json!(status_url));
+    claims.insert("iat".to_string(), json!(now.timestamp()));
+    claims.insert("exp".to_string(), json!(now.timestamp() + STATUS_LIST_TTL_SECS));
+    claims.insert("vc".to_string(), json!({
+        "@context": [VC_CONTEXT, STATUS_LIST_CONTEXT],
+        "id": status_url,
+        "type": ["VerifiableCredential", "StatusList2021Credential"],
+        "issuer": issuer.issuer(),
+        "issuanceDate": now.to_rfc3339(),
+        "credentialSubject": {
+            "id": format!("{}#list", status_url),
+            "type": "StatusList2021",
+            "statusPurpose": "revocation",
+            "encodedList": encoded_list,
+        },
+    }));
+    HttpResponse::Ok()
+        .content_type("application/jwt")
+        .insert_header(("Cache-Control", "max-age=300"))
+        .body(issuer.sign_jwt(VC_JWT_TYP, &claims))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyCredentialRequest {
+    // VC-JWT or QR payload
+    pub credential: String,
+}
+
+// Handler verifying a credential's signature, expiry and revocation status.
+// Verification failures are reported in the body.
+pub async fn verify_credential(
+    pool: web::Data<DbPool>,
+    issuer: web::Data<Option<DisclosureIssuer>>,
+    request: web::Json<VerifyCredentialRequest>,
+) -> impl Responder {
+    let Some(issuer) = issuer.get_ref().as_ref() else {
+        return HttpResponse::NotFound().body("Credential issuance is not configured");
+    };
+    let credential = request.into_inner().credential;
+    let verified = if credential.starts_with(QR_PREFIX) { from_qr_payload(&credential) } else { Ok(credential) }
+        .and_then(|jwt| issuer.verify_jwt(&jwt, VC_JWT_TYP))
+        .and_then(|claims| {
+            let vc = claims.get("vc").cloned().ok_or_else(|| anyhow!("Token is not a verifiable credential"))?;
+            let types = vc.get("type").and_then(Value::as_array).cloned().unwrap_or_default();
+            if !types.iter().any(|t| matches!(t.as_str(), Some("ImmunizationCredential" | "LabResultCredential"))) {
+                return Err(anyhow!("Unsupported credential type"));
+            }
+            let index = vc.pointer("/credentialStatus/statusListIndex")
+                .and_then(Value::as_str)
+                .and_then(|index| index.parse::<i32>().ok())
+                .ok_or_else(|| anyhow!("Credential has no status list entry"))?;
+            Ok((vc, index))
+        });
+    let (vc, status_index) = match verified {
+        Ok(verified) => verified,
+        Err(e) => return HttpResponse::Ok().json(json!({ "valid": false, "error": e.to_string() })),
+    };
+
+    match web::block(move || -> Result<Option<Option<chrono::NaiveDateTime>>> {
+        let mut conn = pool.get()?;
+        Ok(credentials::table
+            .filter(credentials::status_index.eq(status_index))
+            .select(credentials::revoked_at)
+            .first(&mut conn)
+            .optional()?)
+    })
+    .await
+    {
+        Ok(Ok(Some(revoked_at))) => HttpResponse::Ok().json(json!({
+            "valid": revoked_at.is_none(),
+            "revoked": revoked_at.is_some(),
+            "revoked_at": revoked_at.map(|at| DateTime::<Utc>::from_naive_utc_and_offset(at, Utc).to_rfc3339()),
+            "credential": vc,
+        })),
+        // A signed credential with no status row is treated as revoked
+        Ok(Ok(None)) => HttpResponse::Ok().json(json!({ "valid": false, "revoked": true, "credential": vc })),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error checking revocation: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
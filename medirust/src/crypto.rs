@@ -1,90 +1,86 @@
-use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
-    Aes256Gcm, Nonce,
-};
+use argon2::{Algorithm, Argon2, Params, Version};
 use rsa::{
-    RsaPrivateKey, RsaPublicKey, Pkcs1v15Encrypt,
-    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPublicKey},
-    pkcs8::{LineEnding}, // Only import LineEnding if needed
+    RsaPrivateKey, RsaPublicKey,
+    pkcs8::{der::pem, LineEnding},
 };
+use zeroize::Zeroizing;
 use base64::{engine::general_purpose, Engine as _};
 use rand::RngCore;
+use aes_gcm::aead::OsRng;
+use medirust_core::{aead, pkcs1, record};
 use anyhow::{Result, anyhow};
 
+// Key types and record formats live in medirust-core, shared with clients
+pub use medirust_core::{KeyAlgorithm, PatientPrivateKey, PatientPublicKey, RECORD_AAD_VERSION};
+
 // AES Key size for AES256-GCM
-const AES_KEY_SIZE: usize = 32; // 256 bits
-const NONCE_SIZE: usize = 12; // 96 bits for GCM
+const AES_KEY_SIZE: usize = aead::KEY_SIZE;
+const NONCE_SIZE: usize = aead::NONCE_SIZE;
+
+// Passphrase-protected private keys, in a medirust-specific format rather than
+// PKCS#8 EncryptedPrivateKeyInfo, whose PBES2 scheme has no Argon2 KDF. The
+// PEM block holds
+//   version (1) | Argon2id m_cost, t_cost, p_cost (u32 BE) | salt (16) | nonce (12) | ciphertext
+// where the ciphertext is the PKCS#8 DER private key (RSA or X25519) under
+// AES-256-GCM with the Argon2id-derived key. From version 2 the header up to
+// the nonce is authenticated as associated data. The KDF parameters travel
+// with the key so they can be raised later without breaking existing exports.
+const ENCRYPTED_KEY_PEM_LABEL: &str = "MEDIRUST ENCRYPTED PRIVATE KEY";
+const ENCRYPTED_KEY_VERSION: u8 = 2;
+// Exports from before the header was authenticated
+const LEGACY_ENCRYPTED_KEY_VERSION: u8 = 1;
+const PASSPHRASE_SALT_SIZE: usize = 16;
+const ENCRYPTED_KEY_HEADER_SIZE: usize = 1 + 3 * 4 + PASSPHRASE_SALT_SIZE + NONCE_SIZE;
+// Upper bounds on imported KDF parameters, so a crafted key cannot exhaust the server
+const MAX_KDF_MEMORY_KIB: u32 = 1 << 20;
+const MAX_KDF_ITERATIONS: u32 = 16;
 
 pub struct CryptoUtils;
 
 impl CryptoUtils {
     // Generates a new AES key
     pub fn generate_aes_key() -> Vec<u8> {
-        let mut key = vec![0u8; AES_KEY_SIZE];
-        OsRng.fill_bytes(&mut key);
-        key
+        aead::generate_key()
     }
 
     // Encrypts data using AES-GCM
     pub fn encrypt_data(data: &[u8], key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
-        if key.len() != AES_KEY_SIZE {
-            return Err(anyhow!("Invalid AES key size"));
-        }
-        let cipher = Aes256Gcm::new_from_slice(key)
-            .map_err(|e| anyhow!("Failed to create AES cipher: {}", e))?;
-
-        let mut nonce_bytes = vec![0u8; NONCE_SIZE];
-        OsRng.fill_bytes(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-
-        let ciphertext = cipher.encrypt(nonce, data)
-            .map_err(|e| anyhow!("Failed to encrypt data: {}", e))?;
+        Self::encrypt_data_with_aad(data, key, b"")
+    }
 
-        Ok((ciphertext, nonce_bytes))
+    // Encrypts data using AES-GCM, authenticating `aad` alongside the ciphertext
+    pub fn encrypt_data_with_aad(data: &[u8], key: &[u8], aad: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        aead::encrypt(data, key, aad)
     }
 
     // Decrypts data using AES-GCM
     pub fn decrypt_data(ciphertext: &[u8], key: &[u8], nonce_bytes: &[u8]) -> Result<Vec<u8>> {
-        if key.len() != AES_KEY_SIZE {
-            return Err(anyhow!("Invalid AES key size"));
-        }
-        if nonce_bytes.len() != NONCE_SIZE {
-            return Err(anyhow!("Invalid Nonce size"));
-        }
-
-        let cipher = Aes256Gcm::new_from_slice(key)
-            .map_err(|e| anyhow!("Failed to create AES cipher: {}", e))?;
-        let nonce = Nonce::from_slice(nonce_bytes);
+        Self::decrypt_data_with_aad(ciphertext, key, nonce_bytes, b"")
+    }
 
-        let plaintext = cipher.decrypt(nonce, ciphertext)
-            .map_err(|e| anyhow!("Failed to decrypt data: {}", e))?;
+    // Decrypts data using AES-GCM; fails unless `aad` matches what was bound at encryption
+    pub fn decrypt_data_with_aad(ciphertext: &[u8], key: &[u8], nonce_bytes: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        aead::decrypt(ciphertext, key, nonce_bytes, aad)
+    }
 
-        Ok(plaintext)
+    // Associated data binding a record's content ciphertext to its row
+    pub fn record_aad(record_id: &[u8], patient_id: &[u8], record_type: &str, version: i32) -> Vec<u8> {
+        record::record_aad(record_id, patient_id, record_type, version)
     }
 
-    // Generates a new RSA key pair
-    pub fn generate_rsa_key_pair() -> Result<(RsaPrivateKey, RsaPublicKey)> {
-        let mut rng = OsRng;
-        let bits = 2048;
-        let private_key = RsaPrivateKey::new(&mut rng, bits)
-            .map_err(|e| anyhow!("Failed to generate RSA private key: {}", e))?;
-        let public_key = RsaPublicKey::from(&private_key);
-        Ok((private_key, public_key))
+    // Generates a new RSA key pair of the given modulus size
+    pub fn generate_rsa_key_pair(bits: usize) -> Result<(RsaPrivateKey, RsaPublicKey)> {
+        pkcs1::generate_key_pair(bits)
     }
 
     // Encrypts an AES key using RSA public key
     pub fn encrypt_aes_key_with_rsa(aes_key: &[u8], public_key: &RsaPublicKey) -> Result<Vec<u8>> {
-        let mut rng = OsRng;
-        let encrypted_key = public_key.encrypt(&mut rng, Pkcs1v15Encrypt, aes_key)
-            .map_err(|e| anyhow!("Failed to encrypt AES key with RSA: {}", e))?;
-        Ok(encrypted_key)
+        pkcs1::wrap_key(aes_key, public_key)
     }
 
     // Decrypts an AES key using RSA private key
     pub fn decrypt_aes_key_with_rsa(encrypted_aes_key: &[u8], private_key: &RsaPrivateKey) -> Result<Vec<u8>> {
-        let decrypted_key = private_key.decrypt(Pkcs1v15Encrypt, encrypted_aes_key)
-            .map_err(|e| anyhow!("Failed to decrypt AES key with RSA: {}", e))?;
-        Ok(decrypted_key)
+        pkcs1::unwrap_key(encrypted_aes_key, private_key)
     }
 
     // Encode bytes to base64
@@ -100,27 +96,90 @@ impl CryptoUtils {
 
     // Export RSA Public Key to PKCS8 PEM format
     pub fn export_public_key_to_pem(public_key: &RsaPublicKey) -> Result<String> {
-        public_key.to_pkcs1_pem(LineEnding::LF)
-            .map_err(|e| anyhow!("Failed to export public key to PEM: {}", e))
-            .map(|pem| pem.to_string())
+        pkcs1::public_key_to_pem(public_key)
+    }
+
+    // SHA-256 fingerprint (hex) of the PKCS1 DER encoding of a public key
+    pub fn public_key_fingerprint(public_key: &RsaPublicKey) -> Result<String> {
+        pkcs1::public_key_fingerprint(public_key)
     }
 
     // Import RSA Public Key from PKCS1 PEM format
     pub fn import_public_key_from_pem(pem: &str) -> Result<RsaPublicKey> {
-        RsaPublicKey::from_pkcs1_pem(pem)
-            .map_err(|e| anyhow!("Failed to import public key from PEM: {}", e))
+        pkcs1::public_key_from_pem(pem)
     }
 
     // Export RSA Private Key to PKCS8 PEM format
     pub fn export_private_key_to_pem(private_key: &RsaPrivateKey) -> Result<String> {
-        private_key.to_pkcs1_pem(LineEnding::LF)
-            .map_err(|e| anyhow!("Failed to export private key to PEM: {}", e))
-            .map(|pem| pem.to_string())
+        pkcs1::private_key_to_pem(private_key)
     }
 
     // Import RSA Private Key from PKCS1 PEM format
     pub fn import_private_key_from_pem(pem: &str) -> Result<RsaPrivateKey> {
-        RsaPrivateKey::from_pkcs1_pem(pem)
-            .map_err(|e| anyhow!("Failed to import private key from PEM: {}", e))
+        pkcs1::private_key_from_pem(pem)
+    }
+
+    // Derives an AES key from a passphrase with Argon2id
+    pub fn derive_passphrase_key(passphrase: &[u8], salt: &[u8], params: Params) -> Result<Zeroizing<Vec<u8>>> {
+        let mut key = Zeroizing::new(vec![0u8; AES_KEY_SIZE]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, salt, &mut key)
+            .map_err(|e| anyhow!("Failed to derive key from passphrase: {}", e))?;
+        Ok(key)
+    }
+
+    // Export a patient private key as PKCS8, encrypted under a passphrase
+    pub fn export_encrypted_private_key_to_pem(private_key: &PatientPrivateKey, passphrase: &str) -> Result<String> {
+        Self::export_encrypted_private_key_with_params(private_key, passphrase, Params::default())
+    }
+
+    // As export_encrypted_private_key_to_pem, with explicit Argon2 parameters
+    pub fn export_encrypted_private_key_with_params(private_key: &PatientPrivateKey, passphrase: &str, params: Params) -> Result<String> {
+        let der = private_key.to_pkcs8_der()?;
+        let mut salt = [0u8; PASSPHRASE_SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let key = Self::derive_passphrase_key(passphrase.as_bytes(), &salt, params.clone())?;
+
+        let mut encoded = Vec::with_capacity(ENCRYPTED_KEY_HEADER_SIZE + der.len() + 16);
+        encoded.push(ENCRYPTED_KEY_VERSION);
+        encoded.extend_from_slice(&params.m_cost().to_be_bytes());
+        encoded.extend_from_slice(&params.t_cost().to_be_bytes());
+        encoded.extend_from_slice(&params.p_cost().to_be_bytes());
+        encoded.extend_from_slice(&salt);
+        let (ciphertext, nonce) = Self::encrypt_data_with_aad(&der, &key, &encoded)?;
+        encoded.extend_from_slice(&nonce);
+        encoded.extend_from_slice(&ciphertext);
+        pem::encode_string(ENCRYPTED_KEY_PEM_LABEL, LineEnding::LF, &encoded)
+            .map_err(|e| anyhow!("Failed to export encrypted private key to PEM: {}", e))
+    }
+
+    // Import a patient private key from a passphrase-encrypted PKCS8 PEM
+    pub fn import_encrypted_private_key_from_pem(encrypted_pem: &str, passphrase: &str) -> Result<PatientPrivateKey> {
+        let (label, encoded) = pem::decode_vec(encrypted_pem.as_bytes())
+            .map_err(|e| anyhow!("Failed to decode encrypted private key PEM: {}", e))?;
+        if label != ENCRYPTED_KEY_PEM_LABEL {
+            return Err(anyhow!("Unexpected PEM label {}", label));
+        }
+        let version = encoded.first().copied();
+        if encoded.len() <= ENCRYPTED_KEY_HEADER_SIZE || !matches!(version, Some(ENCRYPTED_KEY_VERSION | LEGACY_ENCRYPTED_KEY_VERSION)) {
+            return Err(anyhow!("Unsupported encrypted private key format"));
+        }
+        let read_u32 = |offset: usize| u32::from_be_bytes([encoded[offset], encoded[offset + 1], encoded[offset + 2], encoded[offset + 3]]);
+        let (m_cost, t_cost, p_cost) = (read_u32(1), read_u32(5), read_u32(9));
+        if m_cost > MAX_KDF_MEMORY_KIB || t_cost > MAX_KDF_ITERATIONS {
+            return Err(anyhow!("Argon2 parameters of the encrypted private key are too expensive"));
+        }
+        let params = Params::new(m_cost, t_cost, p_cost, None)
+            .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+        let (header, rest) = encoded.split_at(13 + PASSPHRASE_SALT_SIZE);
+        let salt = &header[13..];
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+        let aad: &[u8] = if version == Some(LEGACY_ENCRYPTED_KEY_VERSION) { b"" } else { header };
+
+        let key = Self::derive_passphrase_key(passphrase.as_bytes(), salt, params)?;
+        // A wrong passphrase or altered header surfaces as an authentication failure here
+        let der = Zeroizing::new(Self::decrypt_data_with_aad(ciphertext, &key, nonce, aad)
+            .map_err(|_| anyhow!("Incorrect passphrase or corrupted key"))?);
+        PatientPrivateKey::from_pkcs8_der(&der)
     }
 }
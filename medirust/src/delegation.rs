@@ -0,0 +1,442 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+use crate::auth::{AuthenticatedPatient, Principal};
+use crate::crypto::CryptoUtils;
+use crate::keys::{self, KeyHierarchy};
+use crate::metrics::METRICS;
+use crate::models::{ClinicianKey, Delegation, HealthRecord};
+use crate::pre;
+use crate::schema::{clinician_keys, delegations, health_records, patients};
+use crate::DbPool;
+
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug, Deserialize)]
+pub struct PreKeyRequest {
+    // Base64 Ristretto public key
+    pub public_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateDelegationRequest {
+    // Principal name of the clinician receiving access
+    pub clinician: String,
+    // Re-encryption key computed on the patient's device with
+    // pre::generate_re_key (base64). The delegation secret key never leaves it.
+    pub re_key: String,
+}
+
+// Reasons a delegation call is refused, mapped to HTTP responses
+#[derive(Debug)]
+enum Rejection {
+    NotFound(&'static str),
+    Forbidden(&'static str),
+    Conflict(&'static str),
+    Unprocessable(&'static str),
+}
+
+impl Rejection {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Rejection::NotFound(msg) => HttpResponse::NotFound().body(msg),
+            Rejection::Forbidden(msg) => HttpResponse::Forbidden().body(msg),
+            Rejection::Conflict(msg) => HttpResponse::Conflict().body(msg),
+            Rejection::Unprocessable(msg) => HttpResponse::UnprocessableEntity().body(msg),
+        }
+    }
+}
+
+// Encapsulates a record's AES key under the patient's delegation key, returning
+// the base64 capsule and base64(nonce || AES key encrypted under the capsule key)
+pub fn encapsulate_record_key(pre_public_key: &str, aes_key: &[u8]) -> Result<(String, String)> {
+    let public_key = pre::PublicKey::from_bytes(&CryptoUtils::decode_base64(pre_public_key)?)?;
+    let (capsule, capsule_key) = pre::encapsulate(&public_key)?;
+    let (ciphertext, nonce) = CryptoUtils::encrypt_data(aes_key, &capsule_key)?;
+    let mut wrapped = nonce;
+    wrapped.extend_from_slice(&ciphertext);
+    Ok((CryptoUtils::encode_base64(&capsule.to_bytes()), CryptoUtils::encode_base64(&wrapped)))
+}
+
+// Clinician side: recovers a record's AES key from the capsule fragment and
+// wrapped key returned by get_reencrypted_record_key
+pub fn open_reencrypted_record_key(delegatee: &pre::SecretKey, capsule_frag: &str, wrapped_key: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let capsule_frag = pre::CapsuleFrag::from_bytes(&CryptoUtils::decode_base64(capsule_frag)?)?;
+    let capsule_key = pre::decapsulate_frag(delegatee, &capsule_frag)?;
+    let wrapped = CryptoUtils::decode_base64(wrapped_key)?;
+    if wrapped.len() <= NONCE_SIZE {
+        return Err(anyhow!("Wrapped key is too short"));
+    }
+    let (nonce, ciphertext) = wrapped.split_at(NONCE_SIZE);
+    CryptoUtils::decrypt_data(ciphertext, &capsule_key, nonce).map(Zeroizing::new)
+}
+
+// Handler for a patient to register their delegation (proxy re-encryption)
+// public key. Records stored afterwards can be shared with clinicians.
+pub async fn set_patient_pre_key(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+    request: web::Json<PreKeyRequest>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let public_key = match parse_public_key(&request.public_key) {
+        Ok(key) => key,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid public key: {:?}", e)),
+    };
+
+    match web::block(move || -> Result<usize> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            let updated = diesel::update(patients::table.find(&patient_id_bytes))
+                .set((
+                    patients::pre_public_key.eq(Some(&public_key)),
+                    patients::updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)?;
+            // Re-encryption keys issued for the previous key no longer match
+            revoke_all(conn, &patient_id_bytes)?;
+            Ok(updated)
+        })
+    })
+    .await
+    {
+        Ok(Ok(0)) => HttpResponse::NotFound().body("Patient not found"),
+        Ok(Ok(_)) => HttpResponse::NoContent().finish(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error setting delegation key: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for the authenticated clinician to register their delegation public key
+pub async fn set_clinician_pre_key(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    request: web::Json<PreKeyRequest>,
+) -> impl Responder {
+    let public_key = match parse_public_key(&request.public_key) {
+        Ok(key) => key,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid public key: {:?}", e)),
+    };
+
+    match web::block(move || -> Result<()> {
+        let mut conn = pool.get()?;
+        let now = Utc::now().naive_utc();
+        let key = ClinicianKey {
+            principal: principal.name,
+            pre_public_key: public_key,
+            created_at: now,
+            updated_at: now,
+        };
+        diesel::insert_into(clinician_keys::table)
+            .values(&key)
+            .on_conflict(clinician_keys::principal)
+            .do_update()
+            .set((
+                clinician_keys::pre_public_key.eq(&key.pre_public_key),
+                clinician_keys::updated_at.eq(now),
+            ))
+            .execute(&mut conn)?;
+        Ok(())
+    })
+    .await
+    {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error setting clinician key: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler returning a clinician's delegation public key, which patients
+// need to compute a re-encryption key
+pub async fn get_clinician_pre_key(
+    pool: web::Data<DbPool>,
+    clinician: web::Path<String>,
+) -> impl Responder {
+    let clinician = clinician.into_inner();
+    match web::block(move || -> Result<Option<ClinicianKey>> {
+        let mut conn = pool.get()?;
+        Ok(clinician_keys::table
+            .find(&clinician)
+            .select(ClinicianKey::as_select())
+            .first(&mut conn)
+            .optional()?)
+    })
+    .await
+    {
+        Ok(Ok(Some(key))) => HttpResponse::Ok().json(json!({
+            "clinician": key.principal,
+            "public_key": key.pre_public_key,
+            "updated_at": key.updated_at,
+        })),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Clinician has no delegation key"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error getting clinician key: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for a patient to delegate record access to a clinician. Any earlier
+// delegation to the same clinician is replaced.
+pub async fn create_delegation(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+    request: web::Json<CreateDelegationRequest>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let request = request.into_inner();
+    let re_key = match parse_re_key(&request.re_key) {
+        Ok(re_key) => re_key,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid re_key: {:?}", e)),
+    };
+    let clinician = request.clinician;
+
+    match web::block(move || -> Result<Result<Delegation, Rejection>> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            let patient_pre_key: Option<Option<String>> = patients::table
+                .find(&patient_id_bytes)
+                .select(patients::pre_public_key)
+                .first(conn)
+                .optional()?;
+            match patient_pre_key {
+                Some(Some(_)) => {}
+                Some(None) => return Ok(Err(Rejection::Conflict("Patient has not registered a delegation key"))),
+                None => return Ok(Err(Rejection::NotFound("Patient not found"))),
+            }
+            let has_clinician_key = clinician_keys::table
+                .find(&clinician)
+                .select(clinician_keys::principal)
+                .first::<String>(conn)
+                .optional()?
+                .is_some();
+            if !has_clinician_key {
+                return Ok(Err(Rejection::Unprocessable("Clinician has not registered a delegation key")));
+            }
+
+            revoke_clinician(conn, &patient_id_bytes, &clinician)?;
+            let delegation = Delegation {
+                id: Uuid::new_v4().as_bytes().to_vec(),
+                patient_id: patient_id_bytes.clone(),
+                clinician: clinician.clone(),
+                re_key: Some(CryptoUtils::encode_base64(&re_key.to_bytes())),
+                created_at: Utc::now().naive_utc(),
+                revoked_at: None,
+            };
+            diesel::insert_into(delegations::table).values(&delegation).execute(conn)?;
+            Ok(Ok(delegation))
+        })
+    })
+    .await
+    {
+        Ok(Ok(Ok(delegation))) => {
+            tracing::info!(clinician = %delegation.clinician, "record access delegated");
+            HttpResponse::Created().json(delegation_json(&delegation))
+        }
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error creating delegation: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler listing a patient's delegations, including revoked ones
+pub async fn list_delegations(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+
+    match web::block(move || -> Result<Vec<Delegation>> {
+        let mut conn = pool.get()?;
+        Ok(delegations::table
+            .filter(delegations::patient_id.eq(patient_id_bytes))
+            .order(delegations::created_at.asc())
+            .select(Delegation::as_select())
+            .load(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(delegations)) => HttpResponse::Ok().json(delegations.iter().map(delegation_json).collect::<Vec<_>>()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error listing delegations: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler to revoke a delegation. The re-encryption key is deleted, so the
+// server can no longer transform record keys for the clinician. Keys the
+// clinician already received stay usable for those records.
+pub async fn revoke_delegation(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id, delegation_id) = path.into_inner();
+    let (patient_id_bytes, delegation_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&delegation_id)) {
+        (Ok(p), Ok(d)) => (p.as_bytes().to_vec(), d.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or delegation ID"),
+    };
+
+    match web::block(move || -> Result<usize> {
+        let mut conn = pool.get()?;
+        Ok(diesel::update(
+            delegations::table
+                .filter(delegations::id.eq(delegation_id_bytes))
+                .filter(delegations::patient_id.eq(patient_id_bytes))
+                .filter(delegations::revoked_at.is_null()),
+        )
+        .set((
+            delegations::re_key.eq(None::<String>),
+            delegations::revoked_at.eq(Some(Utc::now().naive_utc())),
+        ))
+        .execute(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(0)) => HttpResponse::NotFound().body("Active delegation not found"),
+        Ok(Ok(_)) => HttpResponse::NoContent().finish(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error revoking delegation: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for a delegated clinician to fetch a record's key material,
+// re-encrypted for them. The server only transforms the capsule; the AES key
+// is recovered on the clinician's side with their delegation secret key.
+pub async fn get_reencrypted_record_key(
+    pool: web::Data<DbPool>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id, record_id) = path.into_inner();
+    let (patient_id_bytes, record_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&record_id)) {
+        (Ok(p), Ok(r)) => (p.as_bytes().to_vec(), r.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or record ID"),
+    };
+
+    match web::block(move || -> Result<Result<serde_json::Value, Rejection>> {
+        let mut conn = pool.get()?;
+        let re_key = match active_re_key(&mut conn, &patient_id_bytes, &principal.name)? {
+            Some(re_key) => pre::ReKey::from_bytes(&CryptoUtils::decode_base64(&re_key)?)?,
+            None => return Ok(Err(Rejection::Forbidden("No active delegation for this clinician"))),
+        };
+        let mut record = match health_records::table
+            .filter(health_records::id.eq(&record_id_bytes))
+            .filter(health_records::patient_id.eq(&patient_id_bytes))
+            .select(HealthRecord::as_select())
+            .first(&mut conn)
+            .optional()?
+        {
+            Some(record) => record,
+            None => return Ok(Err(Rejection::NotFound("Health record not found"))),
+        };
+        keys::open_record_metadata(keys.get_ref().as_ref(), &mut conn, &mut record)?;
+        let (Some(capsule), Some(wrapped_key)) = (&record.pre_capsule, &record.pre_wrapped_key) else {
+            return Ok(Err(Rejection::Conflict("Record was stored before the patient enabled delegation")));
+        };
+        let capsule = pre::Capsule::from_bytes(&CryptoUtils::decode_base64(capsule)?)?;
+        let capsule_frag = METRICS.time_crypto("pre_reencrypt", || pre::reencrypt(&capsule, &re_key))?;
+        let (_, nonce) = keys::record_key_material(keys.get_ref().as_ref(), &mut conn, &record)?;
+        if CryptoUtils::decode_base64(wrapped_key)?.len() <= NONCE_SIZE {
+            return Err(anyhow!("Stored wrapped key is too short"));
+        }
+
+        tracing::info!(clinician = %principal.name, "record key re-encrypted for delegate");
+        Ok(Ok(json!({
+            "record_id": Uuid::from_slice(&record.id).unwrap_or_default().to_string(),
+            "ipfs_cid": record.ipfs_cid,
+            "record_type": record.record_type,
+            "title": record.title,
+            "capsule_frag": CryptoUtils::encode_base64(&capsule_frag.to_bytes()),
+            "wrapped_key": wrapped_key,
+            "nonce": nonce,
+            "aad_version": record.aad_version,
+        })))
+    })
+    .await
+    {
+        Ok(Ok(Ok(body))) => HttpResponse::Ok().json(body),
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error re-encrypting record key: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+fn parse_public_key(encoded: &str) -> Result<String> {
+    pre::PublicKey::from_bytes(&CryptoUtils::decode_base64(encoded)?)?;
+    Ok(encoded.to_string())
+}
+
+fn parse_re_key(encoded: &str) -> Result<pre::ReKey> {
+    pre::ReKey::from_bytes(&CryptoUtils::decode_base64(encoded)?)
+}
+
+fn active_re_key(conn: &mut PgConnection, patient_id: &[u8], clinician: &str) -> Result<Option<String>> {
+    Ok(delegations::table
+        .filter(delegations::patient_id.eq(patient_id))
+        .filter(delegations::clinician.eq(clinician))
+        .filter(delegations::revoked_at.is_null())
+        .order(delegations::created_at.desc())
+        .select(delegations::re_key)
+        .first::<Option<String>>(conn)
+        .optional()?
+        .flatten())
+}
+
+fn revoke_clinician(conn: &mut PgConnection, patient_id: &[u8], clinician: &str) -> Result<()> {
+    diesel::update(
+        delegations::table
+            .filter(delegations::patient_id.eq(patient_id))
+            .filter(delegations::clinician.eq(clinician))
+            .filter(delegations::revoked_at.is_null()),
+    )
+    .set((
+        delegations::re_key.eq(None::<String>),
+        delegations::revoked_at.eq(Some(Utc::now().naive_utc())),
+    ))
+    .execute(conn)?;
+    Ok(())
+}
+
+fn revoke_all(conn: &mut PgConnection, patient_id: &[u8]) -> Result<()> {
+    diesel::update(
+        delegations::table
+            .filter(delegations::patient_id.eq(patient_id))
+            .filter(delegations::revoked_at.is_null()),
+    )
+    .set((
+        delegations::re_key.eq(None::<String>),
+        delegations::revoked_at.eq(Some(Utc::now().naive_utc())),
+    ))
+    .execute(conn)?;
+    Ok(())
+}
+
+fn delegation_json(delegation: &Delegation) -> serde_json::Value {
+    json!({
+        "id": Uuid::from_slice(&delegation.id).unwrap_or_default().to_string(),
+        "patient_id": Uuid::from_slice(&delegation.patient_id).unwrap_or_default().to_string(),
+        "clinician": delegation.clinician,
+        "active": delegation.revoked_at.is_none(),
+        "created_at": delegation.created_at,
+        "revoked_at": delegation.revoked_at,
+    })
+}
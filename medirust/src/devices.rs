@@ -0,0 +1,500 @@
+use actix_web::{web, HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::upsert::excluded;
+use diesel::PgConnection;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+use crate::auth::AuthenticatedPatient;
+use crate::crypto::{CryptoUtils, KeyAlgorithm, PatientPublicKey};
+use crate::keys::{self, KeyHierarchy};
+use crate::metrics::METRICS;
+use crate::models::{Device, DeviceRecordKey, HealthRecord, Patient};
+use crate::schema::{device_record_keys, devices, health_records, patients};
+use crate::DbPool;
+
+// Multi-device sync. Each of a patient's devices holds its own key pair and
+// every record's AES key is wrapped to each active device, so a device never
+// needs the patient key. Devices poll a change feed ordered by
+// (updated_at, id) with an opaque cursor.
+
+const MAX_DEVICE_NAME_LEN: usize = 255;
+const DEFAULT_CHANGE_LIMIT: usize = 100;
+const MAX_CHANGE_LIMIT: usize = 500;
+const MAX_KEY_UPLOAD: usize = 1000;
+// The feed only returns rows older than this, so a transaction that commits
+// with an earlier timestamp than an already returned row is not skipped
+const CHANGE_FEED_SETTLE_MS: i64 = 2000;
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterDeviceRequest {
+    pub name: String,
+    // RSA (PKCS1) or X25519 (SPKI) public key PEM
+    pub public_key_pem: String,
+    // Detected from the PEM label when omitted
+    pub key_algorithm: Option<KeyAlgorithm>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WrappedRecordKey {
+    pub record_id: String,
+    // Record AES key wrapped to the device key by another of the patient's devices (base64)
+    pub wrapped_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadRecordKeysRequest {
+    pub keys: Vec<WrappedRecordKey>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    pub since: Option<String>,
+    pub limit: Option<usize>,
+}
+
+// Reasons a device call is refused, mapped to HTTP responses
+#[derive(Debug)]
+enum Rejection {
+    NotFound(&'static str),
+    Forbidden(&'static str),
+    Unprocessable(&'static str),
+}
+
+impl Rejection {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Rejection::NotFound(msg) => HttpResponse::NotFound().body(msg),
+            Rejection::Forbidden(msg) => HttpResponse::Forbidden().body(msg),
+            Rejection::Unprocessable(msg) => HttpResponse::UnprocessableEntity().body(msg),
+        }
+    }
+}
+
+// Position in a patient's change feed: the last returned row's update time and id
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+    updated_at: NaiveDateTime,
+    record_id: Vec<u8>,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let mut bytes = self.updated_at.and_utc().timestamp_micros().to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.record_id);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn decode(encoded: &str) -> Result<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(encoded).map_err(|_| anyhow!("Invalid cursor encoding"))?;
+        if bytes.len() != 24 {
+            return Err(anyhow!("Invalid cursor length"));
+        }
+        let micros = i64::from_be_bytes(bytes[..8].try_into()?);
+        let updated_at = DateTime::from_timestamp_micros(micros)
+            .ok_or_else(|| anyhow!("Invalid cursor timestamp"))?
+            .naive_utc();
+        Ok(Cursor { updated_at, record_id: bytes[8..].to_vec() })
+    }
+}
+
+// Wraps a new record's AES key to every active device of the patient
+pub fn wrap_for_devices(conn: &mut PgConnection, patient_id: &[u8], record_id: &[u8], aes_key: &[u8]) -> Result<usize> {
+    let active: Vec<Device> = devices::table
+        .filter(devices::patient_id.eq(patient_id))
+        .filter(devices::revoked_at.is_null())
+        .select(Device::as_select())
+        .load(conn)?;
+    let now = Utc::now().naive_utc();
+    let wrapped = active
+        .iter()
+        .map(|device| {
+            let public_key = device.public_key()?;
+            let wrapped_key = METRICS.time_crypto(public_key.algorithm().wrap_operation(), || public_key.wrap_key(aes_key))?;
+            Ok(DeviceRecordKey {
+                device_id: device.id.clone(),
+                record_id: record_id.to_vec(),
+                wrapped_key: CryptoUtils::encode_base64(&wrapped_key),
+                created_at: now,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(diesel::insert_into(device_record_keys::table).values(&wrapped).execute(conn)?)
+}
+
+// Wraps the keys of all existing records to a newly registered device, using
+// the patient's escrowed key. Returns None if the server does not hold it.
+fn backfill_device_keys(
+    keys: Option<&KeyHierarchy>,
+    conn: &mut PgConnection,
+    patient: &Patient,
+    device: &Device,
+    public_key: &PatientPublicKey,
+) -> Result<Option<usize>> {
+    let Some(private_key) = keys::escrowed_private_key(keys, conn, patient)? else {
+        return Ok(None);
+    };
+    let records: Vec<HealthRecord> = health_records::table
+        .filter(health_records::patient_id.eq(&patient.id))
+        .select(HealthRecord::as_select())
+        .load(conn)?;
+    let now = Utc::now().naive_utc();
+    let wrapped = records
+        .iter()
+        .map(|record| {
+            let (encrypted_aes_key, _) = keys::record_key_material(keys, conn, record)?;
+            let aes_key = Zeroizing::new(METRICS.time_crypto(private_key.algorithm().unwrap_operation(), || {
+                private_key.unwrap_key(&CryptoUtils::decode_base64(&encrypted_aes_key)?)
+            })?);
+            let wrapped_key = METRICS.time_crypto(public_key.algorithm().wrap_operation(), || public_key.wrap_key(&aes_key))?;
+            Ok(DeviceRecordKey {
+                device_id: device.id.clone(),
+                record_id: record.id.clone(),
+                wrapped_key: CryptoUtils::encode_base64(&wrapped_key),
+                created_at: now,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Some(diesel::insert_into(device_record_keys::table).values(&wrapped).execute(conn)?))
+}
+
+// Loads a device of the patient that has not been revoked
+fn active_device(conn: &mut PgConnection, patient_id: &[u8], device_id: &[u8]) -> Result<Result<Device, Rejection>> {
+    let device = devices::table
+        .find(device_id)
+        .filter(devices::patient_id.eq(patient_id))
+        .select(Device::as_select())
+        .first(conn)
+        .optional()?;
+    Ok(match device {
+        Some(device) if device.revoked_at.is_none() => Ok(device),
+        Some(_) => Err(Rejection::Forbidden("Device has been revoked")),
+        None => Err(Rejection::NotFound("Device not found")),
+    })
+}
+
+// Handler for the authenticated patient registering a device and wrapping
+// existing record keys to it when the server holds the patient's key
+pub async fn register_device(
+    pool: web::Data<DbPool>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+    request: web::Json<RegisterDeviceRequest>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let request = request.into_inner();
+    let name = request.name.trim().to_string();
+    if name.is_empty() || name.len() > MAX_DEVICE_NAME_LEN {
+        return HttpResponse::BadRequest().body("Device name must be between 1 and 255 bytes");
+    }
+    let algorithm = request.key_algorithm.unwrap_or_else(|| KeyAlgorithm::detect(&request.public_key_pem));
+    let public_key = match PatientPublicKey::from_pem(algorithm, &request.public_key_pem) {
+        Ok(key) => key,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid device public key: {:?}", e)),
+    };
+    let (public_key_pem, key_fingerprint) = match public_key.to_pem().and_then(|pem| Ok((pem, public_key.fingerprint()?))) {
+        Ok(exported) => exported,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error exporting device public key: {:?}", e)),
+    };
+
+    match web::block(move || -> Result<Option<(Device, Option<usize>)>> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            let Some(patient) = patients::table
+                .find(&patient_id_bytes)
+                .select(Patient::as_select())
+                .first(conn)
+                .optional()?
+            else {
+                return Ok(None);
+            };
+            let device = Device {
+                id: Uuid::new_v4().as_bytes().to_vec(),
+                patient_id: patient_id_bytes.clone(),
+                name,
+                key_algorithm: algorithm.id().to_string(),
+                public_key_pem,
+                key_fingerprint,
+                created_at: Utc::now().naive_utc(),
+                revoked_at: None,
+            };
+            diesel::insert_into(devices::table).values(&device).execute(conn)?;
+            let backfilled = backfill_device_keys(keys.get_ref().as_ref(), conn, &patient, &device, &public_key)?;
+            Ok(Some((device, backfilled)))
+        })
+    })
+    .await
+    {
+        Ok(Ok(Some((device, backfilled)))) => {
+            tracing::info!(backfilled = backfilled.unwrap_or_default(), "device registered");
+            let mut body = device_json(&device);
+            // None: another device must upload the existing record keys
+            body["backfilled_keys"] = json!(backfilled);
+            HttpResponse::Created().json(body)
+        }
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Patient not found"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error registering device: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler listing the authenticated patient's devices, including revoked ones
+pub async fn list_devices(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+
+    match web::block(move || -> Result<Vec<Device>> {
+        let mut conn = pool.get()?;
+        Ok(devices::table
+            .filter(devices::patient_id.eq(patient_id_bytes))
+            .order(devices::created_at.asc())
+            .select(Device::as_select())
+            .load(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(registered)) => HttpResponse::Ok().json(registered.iter().map(device_json).collect::<Vec<_>>()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error listing devices: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for the authenticated patient revoking a device and deleting the
+// record keys wrapped to it. Keys the device already synced stay readable to
+// it; rotate the patient key and re-encrypt if that matters.
+pub async fn revoke_device(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id, device_id) = path.into_inner();
+    let (patient_id_bytes, device_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&device_id)) {
+        (Ok(p), Ok(d)) => (p.as_bytes().to_vec(), d.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or device ID"),
+    };
+
+    match web::block(move || -> Result<usize> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            let revoked = diesel::update(
+                devices::table
+                    .find(&device_id_bytes)
+                    .filter(devices::patient_id.eq(&patient_id_bytes))
+                    .filter(devices::revoked_at.is_null()),
+            )
+            .set(devices::revoked_at.eq(Some(Utc::now().naive_utc())))
+            .execute(conn)?;
+            if revoked > 0 {
+                diesel::delete(device_record_keys::table.filter(device_record_keys::device_id.eq(&device_id_bytes))).execute(conn)?;
+            }
+            Ok(revoked)
+        })
+    })
+    .await
+    {
+        Ok(Ok(0)) => HttpResponse::NotFound().body("Active device not found"),
+        Ok(Ok(_)) => HttpResponse::NoContent().finish(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error revoking device: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler storing record keys wrapped to a device by another of the patient's
+// devices, for patients whose key the server does not hold. The uploading
+// device acts under the patient's session.
+pub async fn upload_record_keys(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+    request: web::Json<UploadRecordKeysRequest>,
+) -> impl Responder {
+    let (patient_id, device_id) = path.into_inner();
+    let (patient_id_bytes, device_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&device_id)) {
+        (Ok(p), Ok(d)) => (p.as_bytes().to_vec(), d.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or device ID"),
+    };
+    let request = request.into_inner();
+    if request.keys.is_empty() || request.keys.len() > MAX_KEY_UPLOAD {
+        return HttpResponse::BadRequest().body(format!("Upload between 1 and {} keys", MAX_KEY_UPLOAD));
+    }
+    let now = Utc::now().naive_utc();
+    let mut uploaded = Vec::with_capacity(request.keys.len());
+    for key in request.keys {
+        let record_id = match Uuid::parse_str(&key.record_id) {
+            Ok(uuid) => uuid.as_bytes().to_vec(),
+            Err(_) => return HttpResponse::BadRequest().body(format!("Invalid record ID {}", key.record_id)),
+        };
+        if CryptoUtils::decode_base64(&key.wrapped_key).is_err() {
+            return HttpResponse::BadRequest().body(format!("wrapped_key for record {} is not base64", key.record_id));
+        }
+        uploaded.push(DeviceRecordKey {
+            device_id: device_id_bytes.clone(),
+            record_id,
+            wrapped_key: key.wrapped_key,
+            created_at: now,
+        });
+    }
+
+    match web::block(move || -> Result<Result<usize, Rejection>> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            if let Err(rejection) = active_device(conn, &patient_id_bytes, &device_id_bytes)? {
+                return Ok(Err(rejection));
+            }
+            let record_ids: Vec<Vec<u8>> = uploaded.iter().map(|key| key.record_id.clone()).collect();
+            let owned: i64 = health_records::table
+                .filter(health_records::patient_id.eq(&patient_id_bytes))
+                .filter(health_records::id.eq_any(&record_ids))
+                .count()
+                .get_result(conn)?;
+            if owned != record_ids.len() as i64 {
+                return Ok(Err(Rejection::Unprocessable("Every record must exist, belong to the patient and appear once")));
+            }
+            diesel::insert_into(device_record_keys::table)
+                .values(&uploaded)
+                .on_conflict((device_record_keys::device_id, device_record_keys::record_id))
+                .do_update()
+                .set(device_record_keys::wrapped_key.eq(excluded(device_record_keys::wrapped_key)))
+                .execute(conn)?;
+            // Bump the records so the device's change feed delivers the new keys
+            diesel::update(health_records::table.filter(health_records::id.eq_any(&record_ids)))
+                .set(health_records::updated_at.eq(Utc::now().naive_utc()))
+                .execute(conn)?;
+            Ok(Ok(record_ids.len()))
+        })
+    })
+    .await
+    {
+        Ok(Ok(Ok(stored))) => HttpResponse::Ok().json(json!({ "stored": stored })),
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error storing device keys: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler returning records created or updated after the cursor, with the
+// record key wrapped to the device (null until one is available). The feed
+// carries opened metadata, so it needs the patient's session.
+pub async fn get_changes(
+    pool: web::Data<DbPool>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+    query: web::Query<ChangesQuery>,
+) -> impl Responder {
+    let (patient_id, device_id) = path.into_inner();
+    let (patient_id_bytes, device_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&device_id)) {
+        (Ok(p), Ok(d)) => (p.as_bytes().to_vec(), d.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or device ID"),
+    };
+    let since = match query.since.as_deref().map(Cursor::decode) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(e)) => return HttpResponse::BadRequest().body(format!("Invalid since cursor: {:?}", e)),
+        None => None,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_CHANGE_LIMIT);
+    if limit == 0 || limit > MAX_CHANGE_LIMIT {
+        return HttpResponse::BadRequest().body(format!("limit must be between 1 and {}", MAX_CHANGE_LIMIT));
+    }
+
+    let loaded = web::block(move || -> Result<Result<(Vec<Value>, bool), Rejection>> {
+        let mut conn = pool.get()?;
+        if let Err(rejection) = active_device(&mut conn, &patient_id_bytes, &device_id_bytes)? {
+            return Ok(Err(rejection));
+        }
+        let settled = Utc::now().naive_utc() - Duration::milliseconds(CHANGE_FEED_SETTLE_MS);
+        let mut changes_query = health_records::table
+            .left_join(
+                device_record_keys::table.on(device_record_keys::record_id
+                    .eq(health_records::id)
+                    .and(device_record_keys::device_id.eq(device_id_bytes))),
+            )
+            .filter(health_records::patient_id.eq(patient_id_bytes))
+            .filter(health_records::updated_at.le(settled))
+            .order((health_records::updated_at.asc(), health_records::id.asc()))
+            .limit(limit as i64 + 1)
+            .select((HealthRecord::as_select(), device_record_keys::wrapped_key.nullable()))
+            .into_boxed();
+        if let Some(cursor) = since {
+            changes_query = changes_query.filter(
+                health_records::updated_at.gt(cursor.updated_at).or(health_records::updated_at
+                    .eq(cursor.updated_at)
+                    .and(health_records::id.gt(cursor.record_id))),
+            );
+        }
+        let mut rows: Vec<(HealthRecord, Option<String>)> = changes_query.load(&mut conn)?;
+        let has_more = rows.len() > limit;
+        rows.truncate(limit);
+        let changes = rows
+            .into_iter()
+            .map(|(mut record, wrapped_key)| {
+                keys::open_record_metadata(keys.get_ref().as_ref(), &mut conn, &mut record)?;
+                let (_, nonce) = keys::record_key_material(keys.get_ref().as_ref(), &mut conn, &record)?;
+                Ok(change_json(&record, nonce, wrapped_key))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Ok((changes, has_more)))
+    })
+    .await;
+    let (changes, has_more) = match loaded {
+        Ok(Ok(Ok(loaded))) => loaded,
+        Ok(Ok(Err(rejection))) => return rejection.into_response(),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error loading changes: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    // An empty page keeps the caller's cursor
+    let cursor = changes.last().map(|change| change["cursor"].clone()).unwrap_or_else(|| json!(query.since));
+    HttpResponse::Ok().json(json!({
+        "changes": changes,
+        "cursor": cursor,
+        "has_more": has_more,
+    }))
+}
+
+fn change_json(record: &HealthRecord, nonce: String, wrapped_key: Option<String>) -> Value {
+    let cursor = Cursor { updated_at: record.updated_at, record_id: record.id.clone() };
+    json!({
+        "record_id": Uuid::from_slice(&record.id).unwrap_or_default().to_string(),
+        "ipfs_cid": record.ipfs_cid,
+        "record_type": record.record_type,
+        "title": record.title,
+        "nonce": nonce,
+        "aad_version": record.aad_version,
+        "signature": record.signature,
+        "signing_key_id": record.signing_key_id,
+        "wrapped_key": wrapped_key,
+        "created_at": record.created_at,
+        "updated_at": record.updated_at,
+        "cursor": cursor.encode(),
+    })
+}
+
+fn device_json(device: &Device) -> Value {
+    json!({
+        "id": Uuid::from_slice(&device.id).unwrap_or_default().to_string(),
+        "patient_id": Uuid::from_slice(&device.patient_id).unwrap_or_default().to_string(),
+        "name": device.name,
+        "key_algorithm": device.key_algorithm,
+        "public_key_pem": device.public_key_pem,
+        "key_fingerprint": device.key_fingerprint,
+        "active": device.revoked_at.is_none(),
+        "created_at": device.created_at,
+        "revoked_at": device.revoked_at,
+    })
+}
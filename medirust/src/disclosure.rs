@@ -0,0 +1,321 @@
+use actix_web::{web, HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use ed25519_dalek::{pkcs8::DecodePrivateKey, SigningKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::fs;
+use uuid::Uuid;
+use anyhow::{Result, anyhow, Context};
+
+use crate::auth::Principal;
+use crate::blobstore::BlobStore;
+use crate::config::{AppConfig, DisclosureConfig};
+use crate::handlers;
+use crate::keys::KeyHierarchy;
+use crate::sdjwt;
+use crate::signing::{SignatureStatus, VerifyingKey};
+use crate::DbPool;
+
+// Record content claim from which `age_over_N` claims are derived
+const BIRTH_DATE_CLAIM: &str = "birth_date";
+
+// Key and settings for issuing selective-disclosure credentials
+pub struct DisclosureIssuer {
+    key: SigningKey,
+    kid: String,
+    issuer: String,
+    public_url: String,
+    ttl_secs: u64,
+    age_thresholds: Vec<u32>,
+}
+
+impl DisclosureIssuer {
+    // Returns None when no issuer key is configured
+    pub fn from_config(config: &DisclosureConfig) -> Result<Option<Self>> {
+        let Some(path) = &config.issuer_key_path else {
+            return Ok(None);
+        };
+        let pem = zeroize::Zeroizing::new(
+            fs::read_to_string(path).with_context(|| format!("Failed to read issuer key {}", path.display()))?,
+        );
+        let key = SigningKey::from_pkcs8_pem(&pem)
+            .map_err(|e| anyhow!("Issuer key {} is not an Ed25519 PKCS8 key: {}", path.display(), e))?;
+        let kid = VerifyingKey::Ed25519(key.verifying_key()).fingerprint()?;
+        Ok(Some(DisclosureIssuer {
+            key,
+            kid,
+            issuer: config.issuer.clone(),
+            public_url: config.public_url.trim_end_matches('/').to_string(),
+            ttl_secs: config.ttl_secs,
+            age_thresholds: config.age_thresholds.clone(),
+        }))
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn public_url(&self) -> &str {
+        &self.public_url
+    }
+
+    pub fn ttl_secs(&self) -> u64 {
+        self.ttl_secs
+    }
+
+    pub fn sign_jwt(&self, typ: &str, claims: &Map<String, Value>) -> String {
+        sdjwt::sign_jwt(&self.key, typ, &self.kid, claims)
+    }
+
+    // Verifies a JWT of the given type issued with this server's key
+    pub fn verify_jwt(&self, jwt: &str, typ: &str) -> Result<Map<String, Value>> {
+        if sdjwt::key_id(jwt)? != self.kid {
+            return Err(anyhow!("Token was not issued with this server's issuer key"));
+        }
+        sdjwt::verify_jwt(jwt, &self.key.verifying_key(), typ, Utc::now().timestamp())
+    }
+
+    // Public key as a JWK, for verifiers checking presentations offline
+    pub fn jwk(&self) -> Value {
+        json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "alg": "EdDSA",
+            "kid": self.kid,
+            "x": URL_SAFE_NO_PAD.encode(self.key.verifying_key().as_bytes()),
+        })
+    }
+
+    // Every top-level field of the record becomes a disclosable claim, plus
+    // age_over_N booleans when it carries a birth date
+    pub fn disclosable_claims(&self, content: &Map<String, Value>, today: NaiveDate) -> Vec<(String, Value)> {
+        let mut claims: Vec<(String, Value)> = content.iter().map(|(name, value)| (name.clone(), value.clone())).collect();
+        let birth_date = content.get(BIRTH_DATE_CLAIM).and_then(Value::as_str).and_then(parse_date);
+        if let Some(birth_date) = birth_date {
+            let age = years_between(birth_date, today);
+            for threshold in &self.age_thresholds {
+                let name = format!("age_over_{}", threshold);
+                if !content.contains_key(&name) {
+                    claims.push((name, json!(age >= *threshold as i32)));
+                }
+            }
+        }
+        claims
+    }
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .or_else(|| DateTime::parse_from_rfc3339(value).ok().map(|dt| dt.date_naive()))
+}
+
+fn years_between(from: NaiveDate, to: NaiveDate) -> i32 {
+    let mut years = to.year() - from.year();
+    if (to.month(), to.day()) < (from.month(), from.day()) {
+        years -= 1;
+    }
+    years
+}
+
+// Presentation submitted to the verifier. Tagged by format so that other
+// proof systems (e.g. range proofs) can be accepted next to SD-JWT.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "format")]
+pub enum Presentation {
+    #[serde(rename = "sd-jwt")]
+    SdJwt { token: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+// Statement the verifier checks against disclosed claims
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Predicate {
+    // Compares a disclosed claim with a value. Numbers compare numerically,
+    // dates (YYYY-MM-DD or RFC 3339) chronologically.
+    Compare { claim: String, op: Comparison, value: Value },
+}
+
+#[derive(Debug, Serialize)]
+pub struct PredicateResult {
+    pub claim: String,
+    pub satisfied: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Predicate {
+    fn evaluate(&self, claims: &Map<String, Value>) -> PredicateResult {
+        match self {
+            Predicate::Compare { claim, op, value } => {
+                let outcome = match claims.get(claim) {
+                    Some(disclosed) => compare(disclosed, *op, value),
+                    None => Err(anyhow!("claim was not disclosed")),
+                };
+                PredicateResult {
+                    claim: claim.clone(),
+                    satisfied: *outcome.as_ref().unwrap_or(&false),
+                    reason: outcome.err().map(|e| e.to_string()),
+                }
+            }
+        }
+    }
+}
+
+fn compare(disclosed: &Value, op: Comparison, expected: &Value) -> Result<bool> {
+    match op {
+        Comparison::Eq => return Ok(disclosed == expected),
+        Comparison::Ne => return Ok(disclosed != expected),
+        _ => {}
+    }
+    let ordering = match (disclosed, expected) {
+        (Value::Number(a), Value::Number(b)) => {
+            let (a, b) = (a.as_f64().unwrap_or(f64::NAN), b.as_f64().unwrap_or(f64::NAN));
+            a.partial_cmp(&b).ok_or_else(|| anyhow!("values are not comparable"))?
+        }
+        (Value::String(a), Value::String(b)) => match (parse_date(a), parse_date(b)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => return Err(anyhow!("values are not comparable")),
+        },
+        _ => return Err(anyhow!("values are not comparable")),
+    };
+    Ok(match op {
+        Comparison::Gt => ordering == Ordering::Greater,
+        Comparison::Gte => ordering != Ordering::Less,
+        Comparison::Lt => ordering == Ordering::Less,
+        Comparison::Lte => ordering != Ordering::Greater,
+        Comparison::Eq | Comparison::Ne => unreachable!(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyPresentationRequest {
+    pub presentation: Presentation,
+    #[serde(default)]
+    pub predicates: Vec<Predicate>,
+}
+
+// Handler issuing an SD-JWT over a signed record's claims. The record content
+// must be a JSON object; the holder later reveals only the claims they choose.
+pub async fn issue_record_credential(
+    pool: web::Data<DbPool>,
+    blob_store: web::Data<BlobStore>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    issuer: web::Data<Option<DisclosureIssuer>>,
+    principal: Option<Principal>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let Some(issuer) = issuer.get_ref().as_ref() else {
+        return HttpResponse::NotFound().body("Selective disclosure is not configured");
+    };
+    let (patient_id, record_id) = path.into_inner();
+    let (patient_id_bytes, record_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&record_id)) {
+        (Ok(p), Ok(r)) => (p.as_bytes().to_vec(), r.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or record ID"),
+    };
+
+    let opened = match handlers::open_health_record(&pool, &blob_store, &config, &keys, principal.as_ref(), patient_id_bytes, record_id_bytes).await {
+        Ok(opened) => opened,
+        Err(response) => return response,
+    };
+    // Only facts a clinician vouched for are attested
+    if opened.verification.status != SignatureStatus::Valid {
+        return HttpResponse::UnprocessableEntity().body("Only records with a valid author signature can be attested");
+    }
+    let content = match serde_json::from_slice::<Value>(&opened.content) {
+        Ok(Value::Object(content)) => content,
+        _ => return HttpResponse::UnprocessableEntity().body("Record content is not a JSON object"),
+    };
+
+    let now = Utc::now();
+    let mut claims = Map::new();
+    claims.insert("iss".to_string(), json!(issuer.issuer));
+    claims.insert("iat".to_string(), json!(now.timestamp()));
+    claims.insert("exp".to_string(), json!(now.timestamp() + issuer.ttl_secs as i64));
+    claims.insert("record_type".to_string(), json!(opened.record.record_type));
+    claims.insert("signed_by".to_string(), json!(opened.verification.signed_by));
+    let disclosable: Vec<(String, Value)> = issuer
+        .disclosable_claims(&content, now.date_naive())
+        .into_iter()
+        .filter(|(name, _)| !claims.contains_key(name))
+        .collect();
+
+    match sdjwt::issue(&issuer.key, &issuer.kid, claims, disclosable) {
+        Ok((jwt, disclosures)) => HttpResponse::Created().json(json!({
+            "format": "sd-jwt",
+            "token": sdjwt::present(&jwt, &disclosures),
+            "issuer_jwt": jwt,
+            "disclosures": disclosures.iter().map(|d| json!({
+                "claim": d.name,
+                "value": d.value,
+                "disclosure": d.encoded,
+            })).collect::<Vec<_>>(),
+        })),
+        Err(e) => HttpResponse::UnprocessableEntity().body(format!("Error issuing credential: {:?}", e)),
+    }
+}
+
+// Handler verifying a presentation and evaluating predicates over the
+// disclosed claims. Verification failures are reported in the body.
+pub async fn verify_presentation(
+    issuer: web::Data<Option<DisclosureIssuer>>,
+    request: web::Json<VerifyPresentationRequest>,
+) -> impl Responder {
+    let Some(issuer) = issuer.get_ref().as_ref() else {
+        return HttpResponse::NotFound().body("Selective disclosure is not configured");
+    };
+    let request = request.into_inner();
+    let verified = match &request.presentation {
+        Presentation::SdJwt { token } => verify_sd_jwt(issuer, token),
+    };
+    let claims = match verified {
+        Ok(claims) => claims,
+        Err(e) => {
+            return HttpResponse::Ok().json(json!({
+                "valid": false,
+                "error": e.to_string(),
+            }));
+        }
+    };
+
+    let results: Vec<PredicateResult> = request.predicates.iter().map(|predicate| predicate.evaluate(&claims)).collect();
+    HttpResponse::Ok().json(json!({
+        "valid": true,
+        "satisfied": results.iter().all(|result| result.satisfied),
+        "predicates": results,
+        "claims": claims,
+    }))
+}
+
+fn verify_sd_jwt(issuer: &DisclosureIssuer, token: &str) -> Result<Map<String, Value>> {
+    if sdjwt::key_id(token)? != issuer.kid() {
+        return Err(anyhow!("Presentation was not issued with this server's issuer key"));
+    }
+    sdjwt::verify(token, &issuer.key.verifying_key(), Utc::now().timestamp())
+}
+
+// Handler publishing the issuer public key
+pub async fn get_issuer_key(issuer: web::Data<Option<DisclosureIssuer>>) -> impl Responder {
+    match issuer.get_ref().as_ref() {
+        Some(issuer) => HttpResponse::Ok().json(json!({ "keys": [issuer.jwk()] })),
+        None => HttpResponse::NotFound().body("Selective disclosure is not configured"),
+    }
+}
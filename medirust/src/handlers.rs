@@ -1,48 +1,125 @@
 use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
 use diesel::prelude::*;
 use uuid::Uuid;
 use anyhow::Result;
-use futures::{StreamExt, TryStreamExt}; // Import TryStreamExt
+use serde::Deserialize;
 use serde_json::json;
-use ipfs_api_backend_hyper::IpfsApi;
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+use zeroize::Zeroizing;
 
 use crate::models::{Patient, NewPatient, HealthRecord, NewHealthRecord};
 use crate::schema::{patients, health_records};
-use crate::{DbPool, IpfsClientType};
-use crate::crypto::CryptoUtils;
+use crate::DbPool;
+use crate::config::AppConfig;
+use crate::auth::{PatientSessions, Principal};
+use crate::audit;
+use crate::metrics::METRICS;
+use crate::keys::{self, KeyHierarchy};
+use crate::crypto::{CryptoUtils, KeyAlgorithm, PatientPrivateKey, RECORD_AAD_VERSION};
+use crate::blobstore::{BlobStore, FetchError};
+use crate::delegation;
+use crate::devices;
+use crate::pins;
+use crate::signing;
+
+// Shortest passphrase accepted for passphrase-protected keys
+const MIN_PASSPHRASE_CHARS: usize = 12;
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePatientRequest {
+    #[serde(flatten)]
+    pub patient: NewPatient,
+    // Passphrase protecting the private key when passphrase unlock is enabled
+    pub passphrase: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PassphraseLogin {
+    pub passphrase: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecordsQuery {
+    // Only records of this type, matched through the blind index for sealed records
+    pub record_type: Option<String>,
+}
 
 // Handler to create a new patient
 pub async fn create_patient(
     pool: web::Data<DbPool>,
-    new_patient_data: web::Json<NewPatient>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    new_patient_data: web::Json<CreatePatientRequest>,
 ) -> impl Responder {
     let mut conn = pool.get().expect("couldn't get db connection from pool");
 
-    let patient_data = new_patient_data.into_inner();
+    let CreatePatientRequest { patient: patient_data, passphrase } = new_patient_data.into_inner();
+    let passphrase = passphrase.map(Zeroizing::new);
+    if let Some(passphrase) = &passphrase {
+        if !config.key_management.passphrase_unlock {
+            return HttpResponse::BadRequest().body("Passphrase unlock is not enabled on this server");
+        }
+        if passphrase.chars().count() < MIN_PASSPHRASE_CHARS {
+            return HttpResponse::BadRequest().body(format!("passphrase must be at least {} characters", MIN_PASSPHRASE_CHARS));
+        }
+    }
 
-    // Generate RSA key pair for the patient
-    let (_private_key, public_key) = match CryptoUtils::generate_rsa_key_pair() {
-        Ok(keys) => keys,
-        Err(e) => return HttpResponse::InternalServerError().body(format!("Error generating RSA key pair: {:?}", e)),
+    // Generate the patient's key pair with the configured backend
+    let algorithm = config.crypto.patient_key_algorithm;
+    let generate_operation = match algorithm {
+        KeyAlgorithm::Rsa => "rsa_generate",
+        KeyAlgorithm::X25519Hpke => "x25519_generate",
+    };
+    let private_key = match METRICS.time_crypto(generate_operation, || PatientPrivateKey::generate(algorithm, config.crypto.rsa_key_bits)) {
+        Ok(key) => key,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error generating key pair: {:?}", e)),
     };
 
     // Export public key to PEM format
-    let public_key_pem = match CryptoUtils::export_public_key_to_pem(&public_key) {
+    let public_key_pem = match private_key.public_key().to_pem() {
         Ok(pem) => pem,
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error exporting public key: {:?}", e)),
     };
 
-    let new_patient = patient_data.to_patient(public_key_pem);
-    let patient_to_return = new_patient.clone(); // Clone for the response
+    // Export the private key only if the server is configured to escrow it
+    let private_key_pem = if config.key_management.escrow_patient_keys {
+        match private_key.to_pem() {
+            Ok(pem) => Some(Zeroizing::new(pem)),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error exporting private key: {:?}", e)),
+        }
+    } else {
+        None
+    };
 
-    match web::block(move || {
+    // Encrypt the private key under the patient's passphrase for unlock at login
+    let passphrase_protected_key = match &passphrase {
+        Some(passphrase) => match METRICS.time_crypto("argon2_wrap_key", || CryptoUtils::export_encrypted_private_key_to_pem(&private_key, passphrase)) {
+            Ok(pem) => Some(pem),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error encrypting private key: {:?}", e)),
+        },
+        None => None,
+    };
+
+    let mut new_patient = patient_data.to_patient(public_key_pem, algorithm);
+    new_patient.passphrase_protected_key = passphrase_protected_key;
+
+    match web::block(move || -> Result<Patient> {
+        // Seal the escrowed private key under the tenant data key
+        if let (Some(keys), Some(pem)) = (keys.get_ref().as_ref(), private_key_pem) {
+            let data_key = keys.active_data_key(&mut conn)?;
+            new_patient.escrowed_private_key = Some(data_key.seal(pem.as_bytes())?);
+            new_patient.escrow_key_id = Some(data_key.id);
+        }
         diesel::insert_into(patients::table)
             .values(&new_patient)
-            .execute(&mut conn)
+            .execute(&mut conn)?;
+        Ok(new_patient)
     })
     .await
     {
-        Ok(Ok(_)) => HttpResponse::Created().json(patient_to_return),
+        Ok(Ok(patient)) => HttpResponse::Created().json(patient),
         Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error creating patient: {:?}", e)),
         Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     }
@@ -73,17 +150,81 @@ pub async fn get_patient(
     }
 }
 
+// Handler to log in with a passphrase. The passphrase is checked by unlocking
+// the patient's key, but only the still-encrypted key is returned: the
+// patient's client unlocks it locally with the same passphrase.
+pub async fn login_with_passphrase(
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    sessions: web::Data<PatientSessions>,
+    patient_id: web::Path<String>,
+    login: web::Json<PassphraseLogin>,
+) -> impl Responder {
+    if !config.key_management.passphrase_unlock {
+        return HttpResponse::NotFound().body("Passphrase unlock is not enabled on this server");
+    }
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let passphrase = Zeroizing::new(login.into_inner().passphrase);
+
+    let patient = match web::block(move || {
+        let mut conn = pool.get().expect("couldn't get db connection from pool");
+        patients::table
+            .filter(patients::id.eq(patient_id_bytes))
+            .select(Patient::as_select())
+            .first(&mut conn)
+    })
+    .await
+    {
+        Ok(Ok(p)) => p,
+        Ok(Err(diesel::NotFound)) => return HttpResponse::NotFound().body("Patient not found"),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error getting patient: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+    let Some(protected_key) = patient.passphrase_protected_key.clone() else {
+        return HttpResponse::Conflict().body("This patient has no passphrase-protected key");
+    };
+
+    // Argon2 is deliberately expensive, so keep it off the async workers
+    let unlocked = web::block(move || -> Result<()> {
+        METRICS.time_crypto("argon2_unwrap_key", || CryptoUtils::import_encrypted_private_key_from_pem(&protected_key, &passphrase))?;
+        Ok(())
+    })
+    .await;
+    match unlocked {
+        Ok(Ok(())) => HttpResponse::Ok().json(json!({
+            "patient_id": Uuid::from_slice(&patient.id).unwrap_or_default().to_string(),
+            "encrypted_private_key_pem": patient.passphrase_protected_key,
+            "session_token": sessions.open(&patient.id),
+        })),
+        // Counted as an authentication failure by the rate limiter
+        Ok(Err(_)) => HttpResponse::Unauthorized().body("Invalid passphrase"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
 // Handler to create a new health record for a patient
 pub async fn create_health_record(
     pool: web::Data<DbPool>,
-    ipfs_client: web::Data<IpfsClientType>,
+    blob_store: web::Data<BlobStore>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    principal: Option<Principal>,
     new_health_record_data: web::Json<NewHealthRecord>,
 ) -> impl Responder {
+    // With mutual TLS configured, only registered clinician systems may author records
+    if config.tls.client_ca_path.is_some() && principal.is_none() {
+        return HttpResponse::Forbidden().body("A registered clinician certificate is required to create health records");
+    }
+
     let mut conn = pool.get().expect("couldn't get db connection from pool");
-    let record_data = new_health_record_data.into_inner();
+    let mut record_data = new_health_record_data.into_inner();
 
     // 1. Retrieve patient's public key
     let patient_id_bytes = record_data.patient_id.clone();
+    let pool_for_signing = pool.clone();
     let patient = match web::block(move || {
         let mut conn_for_query = pool.get().expect("couldn't get db connection from pool");
         patients::table
@@ -99,46 +240,143 @@ pub async fn create_health_record(
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     };
 
-    let public_key = match CryptoUtils::import_public_key_from_pem(&patient.public_key_pem) {
+    let public_key = match patient.public_key() {
         Ok(key) => key,
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error importing public key: {:?}", e)),
     };
+    let key_fingerprint = match public_key.fingerprint() {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error fingerprinting public key: {:?}", e)),
+    };
+
+    // 2. Check the author's signature, if the record is signed. The signature
+    // covers the record's id and creation time, so the author picks both.
+    match (&record_data.signature, &record_data.signing_key_id) {
+        (Some(_), Some(signing_key_id)) => {
+            let (Some(record_id), Some(created_at)) = (record_data.id, record_data.created_at) else {
+                return HttpResponse::BadRequest().body("Signed records must carry their id and created_at");
+            };
+            record_data.created_at = match signing::signed_creation_time(created_at) {
+                Ok(created_at) => Some(created_at),
+                Err(e) => return HttpResponse::UnprocessableEntity().body(format!("Invalid record signature: {:?}", e)),
+            };
+            let signing_key_id = signing_key_id.clone();
+            let signing_key = match web::block(move || -> Result<_> {
+                let mut conn_for_query = pool_for_signing.get()?;
+                let taken = diesel::select(diesel::dsl::exists(
+                    health_records::table.filter(health_records::id.eq(record_id.as_bytes().to_vec())),
+                ))
+                .get_result::<bool>(&mut conn_for_query)?;
+                Ok((taken, signing::load_signing_key(&mut conn_for_query, &signing_key_id)?))
+            })
+            .await
+            {
+                Ok(Ok((true, _))) => return HttpResponse::Conflict().body("A record with this id already exists"),
+                Ok(Ok((false, Some(key)))) => key,
+                Ok(Ok((false, None))) => return HttpResponse::UnprocessableEntity().body("Unknown signing key"),
+                Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error getting signing key: {:?}", e)),
+                Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+            };
+            if principal.as_ref().is_some_and(|principal| principal.name != signing_key.principal) {
+                return HttpResponse::Forbidden().body("Signing key belongs to another clinician");
+            }
+            if let Err(e) = signing::verify_new_record(&signing_key, &record_data) {
+                return HttpResponse::UnprocessableEntity().body(format!("Invalid record signature: {:?}", e));
+            }
+        }
+        (None, None) if record_data.id.is_some() || record_data.created_at.is_some() => {
+            return HttpResponse::BadRequest().body("id and created_at are only taken from signed records");
+        }
+        (None, None) => {}
+        _ => return HttpResponse::BadRequest().body("signature and signing_key_id must be given together"),
+    }
 
-    // 2. Encrypt health record content using AES-GCM
+    // 3. Encrypt health record content using AES-GCM, bound to the record's id and metadata
+    let record_id = record_data.id.unwrap_or_else(Uuid::new_v4).as_bytes().to_vec();
+    let aad = CryptoUtils::record_aad(&record_id, &record_data.patient_id, &record_data.record_type, RECORD_AAD_VERSION);
     let aes_key = CryptoUtils::generate_aes_key();
-    let (encrypted_content, nonce) = match CryptoUtils::encrypt_data(record_data.content.as_bytes(), &aes_key) {
+    let (encrypted_content, nonce) = match METRICS.time_crypto("aes_encrypt", || CryptoUtils::encrypt_data_with_aad(record_data.content.as_bytes(), &aes_key, &aad)) {
         Ok(data) => data,
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error encrypting data: {:?}", e)),
     };
 
-    // 3. Upload encrypted content to IPFS
-    let ipfs_cid = match ipfs_client.add(std::io::Cursor::new(encrypted_content)).await {
-        Ok(res) => res.hash,
-        Err(e) => return HttpResponse::InternalServerError().body(format!("Error uploading to IPFS: {:?}", e)),
+    // 4. Upload encrypted content to IPFS and pin it against garbage collection
+    let (ipfs_cid, content_sha256) = match store_blob(&blob_store, encrypted_content).await {
+        Ok(stored) => stored,
+        Err(response) => return response,
     };
 
-    // 4. Encrypt the AES key using the patient's RSA public key
-    let encrypted_aes_key = match CryptoUtils::encrypt_aes_key_with_rsa(&aes_key, &public_key) {
+    // 5. Wrap the AES key to the patient's public key (RSA or HPKE)
+    let encrypted_aes_key = match METRICS.time_crypto(public_key.algorithm().wrap_operation(), || public_key.wrap_key(&aes_key)) {
         Ok(key) => CryptoUtils::encode_base64(&key),
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error encrypting AES key: {:?}", e)),
     };
 
-    // 5. Store IPFS CID, encrypted AES key, and nonce in the database
-    let new_health_record = record_data.to_health_record(
+    // 6. Encapsulate the AES key for proxy re-encryption if the patient allows delegation
+    let pre_material = match patient.pre_public_key.as_deref() {
+        Some(pre_public_key) => match METRICS.time_crypto("pre_encapsulate", || delegation::encapsulate_record_key(pre_public_key, &aes_key)) {
+            Ok(material) => Some(material),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error encapsulating AES key: {:?}", e)),
+        },
+        None => None,
+    };
+
+    // 7. Store IPFS CID, encrypted AES key, and nonce in the database,
+    // sealed under the tenant data key when a key hierarchy is configured,
+    // and wrap the AES key to each of the patient's devices
+    let mut new_health_record = record_data.to_health_record(
+        record_id,
         ipfs_cid,
-        encrypted_aes_key,
-        CryptoUtils::encode_base64(&nonce),
+        String::new(),
+        String::new(),
+        None,
+        Some(key_fingerprint),
     );
-    let health_record_to_return = new_health_record.clone(); // Clone for the response
-
-    match web::block(move || {
-        diesel::insert_into(health_records::table)
-            .values(&new_health_record)
-            .execute(&mut conn)
+    new_health_record.content_sha256 = Some(content_sha256);
+    if let Some((capsule, wrapped_key)) = pre_material {
+        new_health_record.pre_capsule = Some(capsule);
+        new_health_record.pre_wrapped_key = Some(wrapped_key);
+    }
+    // Audit the stored CID together with the record, so it is sealed into the trail
+    let cid_event = config.audit.enabled.then(|| {
+        let mut event = audit::new_event(
+            audit::actor(principal.as_ref()),
+            audit::RECORD_CREATE_ACTION.to_string(),
+            201,
+        );
+        event.patient_id = Some(new_health_record.patient_id.clone());
+        event.record_id = Some(new_health_record.id.clone());
+        event.record_cid = Some(new_health_record.ipfs_cid.clone());
+        event
+    });
+
+    match web::block(move || -> Result<HealthRecord> {
+        keys::seal_record_key_material(
+            keys.get_ref().as_ref(),
+            &mut conn,
+            &mut new_health_record,
+            encrypted_aes_key,
+            CryptoUtils::encode_base64(&nonce),
+        )?;
+        // The title and type are stored sealed; the response carries them in plaintext
+        let mut stored_record = new_health_record.clone();
+        keys::seal_record_metadata(keys.get_ref().as_ref(), &mut conn, &mut stored_record)?;
+        conn.transaction(|conn| -> Result<()> {
+            diesel::insert_into(health_records::table)
+                .values(&stored_record)
+                .execute(conn)?;
+            pins::mark(conn, &new_health_record.ipfs_cid, pins::PINNED, None)?;
+            devices::wrap_for_devices(conn, &new_health_record.patient_id, &new_health_record.id, &aes_key)?;
+            if let Some(event) = cid_event {
+                audit::record_event(conn, event)?;
+            }
+            Ok(())
+        })?;
+        Ok(new_health_record)
     })
     .await
     {
-        Ok(Ok(_)) => HttpResponse::Created().json(health_record_to_return),
+        Ok(Ok(record)) => HttpResponse::Created().json(record),
         Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error creating health record: {:?}", e)),
         Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     }
@@ -147,8 +385,12 @@ pub async fn create_health_record(
 // Handler to get all health records for a specific patient
 pub async fn get_health_records_for_patient(
     pool: web::Data<DbPool>,
-    ipfs_client: web::Data<IpfsClientType>,
+    blob_store: web::Data<BlobStore>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    principal: Option<Principal>,
     patient_id: web::Path<String>,
+    query: web::Query<RecordsQuery>,
 ) -> impl Responder {
     let _conn = pool.get().expect("couldn't get db connection from pool");
     let patient_uuid = Uuid::parse_str(&patient_id).expect("Invalid UUID format");
@@ -157,8 +399,8 @@ pub async fn get_health_records_for_patient(
     let patient_id_bytes_clone_for_records_query = patient_id_bytes.clone();
     let pool_clone_for_patient_query = pool.clone(); // Clone pool for the first block
 
-    // Retrieve patient to get their public key
-    let _patient = match web::block(move || {
+    // Retrieve patient to get their escrowed private key
+    let patient = match web::block(move || {
         let mut conn_for_query = pool_clone_for_patient_query.get().expect("couldn't get db connection from pool");
         patients::table
             .filter(patients::id.eq(patient_id_bytes_clone_for_patient_query))
@@ -173,57 +415,77 @@ pub async fn get_health_records_for_patient(
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     };
 
-    // For demonstration, we'll assume the private key is available.
-    // In a real decentralized system, the patient's client would hold and use the private key.
-    let (private_key, _) = match CryptoUtils::generate_rsa_key_pair() { // This is a placeholder!
-        Ok(keys) => keys,
-        Err(e) => return HttpResponse::InternalServerError().body(format!("Error generating dummy RSA key pair: {:?}", e)),
-    };
-
-    let records = match web::block(move || {
-        let mut conn_for_query = pool.get().expect("couldn't get db connection from pool");
-        health_records::table
-            .filter(health_records::patient_id.eq(patient_id_bytes_clone_for_records_query))
+    // The server can only decrypt when it holds the patient's key in escrow.
+    // Otherwise the patient's client must hold and use the private key.
+    let pool_for_keys = pool.clone();
+    let (private_key, records) = match web::block(move || -> Result<_> {
+        let mut conn_for_query = pool_for_keys.get()?;
+        let private_key = keys::escrowed_private_key(keys.get_ref().as_ref(), &mut conn_for_query, &patient)?;
+        let mut records_query = health_records::table
+            .filter(health_records::patient_id.eq(&patient_id_bytes_clone_for_records_query))
             .select(HealthRecord::as_select())
-            .load(&mut conn_for_query)
+            .into_boxed();
+        if let Some(record_type) = &query.record_type {
+            // Sealed records match on the blind index, plaintext ones on the type itself
+            let indexes = keys::record_type_indexes(keys.get_ref().as_ref(), &mut conn_for_query, &patient_id_bytes_clone_for_records_query, record_type)?;
+            records_query = records_query.filter(
+                health_records::record_type_index.eq_any(indexes).or(health_records::metadata_key_id
+                    .is_null()
+                    .and(health_records::record_type.eq(record_type))),
+            );
+        }
+        let records = records_query
+            .load(&mut conn_for_query)?
+            .into_iter()
+            .map(|mut record| {
+                keys::open_record_metadata(keys.get_ref().as_ref(), &mut conn_for_query, &mut record)?;
+                let (encrypted_aes_key, nonce) = keys::record_key_material(keys.get_ref().as_ref(), &mut conn_for_query, &record)?;
+                let signing_key = signing::record_signing_key(&mut conn_for_query, &record)?;
+                Ok((record, encrypted_aes_key, nonce, signing_key))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((private_key, records))
     })
     .await
     {
-        Ok(Ok(recs)) => recs,
+        Ok(Ok((Some(private_key), recs))) => (private_key, recs),
+        Ok(Ok((None, _))) => return HttpResponse::Conflict().body("The server does not hold this patient's private key"),
         Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error getting health records: {:?}", e)),
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     };
 
     let mut decrypted_records = Vec::new();
-    for record in records {
+    for (record, encrypted_aes_key, nonce, signing_key) in records {
         // Retrieve encrypted content from IPFS
-        let encrypted_content_bytes = match ipfs_client.cat(&record.ipfs_cid).map_ok(|chunk| chunk.to_vec()).collect::<Vec<Result<Vec<u8>, _>>>().await {
-            chunks if chunks.iter().all(Result::is_ok) => chunks.into_iter().filter_map(Result::ok).flatten().collect::<Vec<u8>>(),
-            _ => return HttpResponse::InternalServerError().body(format!("Error retrieving encrypted content from IPFS for CID: {}", record.ipfs_cid)),
+        let encrypted_content_bytes = match fetch_record_content(&pool, &config, principal.as_ref(), &blob_store, &record).await {
+            Ok(content) => content,
+            Err(response) => return response,
         };
 
         // Decode encrypted AES key and nonce from base64
-        let decoded_encrypted_aes_key = match CryptoUtils::decode_base64(&record.encrypted_aes_key) {
+        let decoded_encrypted_aes_key = match CryptoUtils::decode_base64(&encrypted_aes_key) {
             Ok(key) => key,
             Err(e) => return HttpResponse::InternalServerError().body(format!("Error decoding encrypted AES key: {:?}", e)),
         };
-        let decoded_nonce = match CryptoUtils::decode_base64(&record.nonce) {
+        let decoded_nonce = match CryptoUtils::decode_base64(&nonce) {
             Ok(nonce) => nonce,
             Err(e) => return HttpResponse::InternalServerError().body(format!("Error decoding nonce: {:?}", e)),
         };
 
-        // Decrypt AES key with RSA private key
-        let decrypted_aes_key = match CryptoUtils::decrypt_aes_key_with_rsa(&decoded_encrypted_aes_key, &private_key) {
+        // Unwrap the AES key with the patient private key
+        let decrypted_aes_key = match METRICS.time_crypto(private_key.algorithm().unwrap_operation(), || private_key.unwrap_key(&decoded_encrypted_aes_key)) {
             Ok(key) => key,
             Err(e) => return HttpResponse::InternalServerError().body(format!("Error decrypting AES key: {:?}", e)),
         };
 
         // Decrypt health record content with AES key and nonce
-        let decrypted_content_bytes = match CryptoUtils::decrypt_data(&encrypted_content_bytes, &decrypted_aes_key, &decoded_nonce) {
+        let aad = CryptoUtils::record_aad(&record.id, &record.patient_id, &record.record_type, record.aad_version);
+        let decrypted_content_bytes = match METRICS.time_crypto("aes_decrypt", || CryptoUtils::decrypt_data_with_aad(&encrypted_content_bytes, &decrypted_aes_key, &decoded_nonce, &aad)) {
             Ok(content) => content,
             Err(e) => return HttpResponse::InternalServerError().body(format!("Error decrypting health record content: {:?}", e)),
         };
 
+        let verification = signing::verify_record(&record, signing_key.as_ref(), &decrypted_content_bytes);
         let decrypted_content = String::from_utf8(decrypted_content_bytes)
             .unwrap_or_else(|_| "Could not decode UTF-8".to_string());
 
@@ -236,6 +498,7 @@ pub async fn get_health_records_for_patient(
             "record_type": record.record_type,
             "title": record.title,
             "content": decrypted_content, // Decrypted content
+            "signature": verification,
             "created_at": record.created_at,
             "updated_at": record.updated_at,
         }));
@@ -247,7 +510,10 @@ pub async fn get_health_records_for_patient(
 // Handler to get a single health record by ID and decrypt its content
 pub async fn get_health_record_by_id(
     pool: web::Data<DbPool>,
-    ipfs_client: web::Data<IpfsClientType>,
+    blob_store: web::Data<BlobStore>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    principal: Option<Principal>,
     record_id: web::Path<String>,
 ) -> impl Responder {
     let _conn = pool.get().expect("couldn't get db connection from pool");
@@ -270,10 +536,10 @@ pub async fn get_health_record_by_id(
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     };
 
-    // Retrieve patient to get their public key (for private key assumption)
+    // Retrieve patient to get their escrowed private key
     let patient_id_bytes = record.patient_id.clone();
     let pool_clone_for_patient_query = pool.clone(); // Clone pool for this block
-    let _patient = match web::block(move || {
+    let patient = match web::block(move || {
         let mut conn_for_query = pool_clone_for_patient_query.get().expect("couldn't get db connection from pool");
         patients::table
             .filter(patients::id.eq(patient_id_bytes))
@@ -288,40 +554,54 @@ pub async fn get_health_record_by_id(
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     };
 
-    // For demonstration, we'll assume the private key is available.
-    let (private_key, _) = match CryptoUtils::generate_rsa_key_pair() { // This is a placeholder!
-        Ok(keys) => keys,
-        Err(e) => return HttpResponse::InternalServerError().body(format!("Error generating dummy RSA key pair: {:?}", e)),
+    let pool_for_keys = pool.clone();
+    let (record, private_key, encrypted_aes_key, nonce, signing_key) = match web::block(move || -> Result<_> {
+        let mut conn_for_query = pool_for_keys.get()?;
+        let mut record = record;
+        keys::open_record_metadata(keys.get_ref().as_ref(), &mut conn_for_query, &mut record)?;
+        let private_key = keys::escrowed_private_key(keys.get_ref().as_ref(), &mut conn_for_query, &patient)?;
+        let (encrypted_aes_key, nonce) = keys::record_key_material(keys.get_ref().as_ref(), &mut conn_for_query, &record)?;
+        let signing_key = signing::record_signing_key(&mut conn_for_query, &record)?;
+        Ok((record, private_key, encrypted_aes_key, nonce, signing_key))
+    })
+    .await
+    {
+        Ok(Ok((record, Some(private_key), encrypted_aes_key, nonce, signing_key))) => (record, private_key, encrypted_aes_key, nonce, signing_key),
+        Ok(Ok((_, None, _, _, _))) => return HttpResponse::Conflict().body("The server does not hold this patient's private key"),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error unsealing record keys: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
     };
 
     // Retrieve encrypted content from IPFS
-    let encrypted_content_bytes = match ipfs_client.cat(&record.ipfs_cid).map_ok(|chunk| chunk.to_vec()).collect::<Vec<Result<Vec<u8>, _>>>().await {
-        chunks if chunks.iter().all(Result::is_ok) => chunks.into_iter().filter_map(Result::ok).flatten().collect::<Vec<u8>>(),
-        _ => return HttpResponse::InternalServerError().body(format!("Error retrieving encrypted content from IPFS for CID: {}", record.ipfs_cid)),
+    let encrypted_content_bytes = match fetch_record_content(&pool, &config, principal.as_ref(), &blob_store, &record).await {
+        Ok(content) => content,
+        Err(response) => return response,
     };
 
     // Decode encrypted AES key and nonce from base64
-    let decoded_encrypted_aes_key = match CryptoUtils::decode_base64(&record.encrypted_aes_key) {
+    let decoded_encrypted_aes_key = match CryptoUtils::decode_base64(&encrypted_aes_key) {
         Ok(key) => key,
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error decoding encrypted AES key: {:?}", e)),
     };
-    let decoded_nonce = match CryptoUtils::decode_base64(&record.nonce) {
+    let decoded_nonce = match CryptoUtils::decode_base64(&nonce) {
         Ok(nonce) => nonce,
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error decoding nonce: {:?}", e)),
     };
 
-    // Decrypt AES key with RSA private key
-    let decrypted_aes_key = match CryptoUtils::decrypt_aes_key_with_rsa(&decoded_encrypted_aes_key, &private_key) {
+    // Unwrap the AES key with the patient private key
+    let decrypted_aes_key = match METRICS.time_crypto(private_key.algorithm().unwrap_operation(), || private_key.unwrap_key(&decoded_encrypted_aes_key)) {
         Ok(key) => key,
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error decrypting AES key: {:?}", e)),
     };
 
     // Decrypt health record content with AES key and nonce
-    let decrypted_content_bytes = match CryptoUtils::decrypt_data(&encrypted_content_bytes, &decrypted_aes_key, &decoded_nonce) {
+    let aad = CryptoUtils::record_aad(&record.id, &record.patient_id, &record.record_type, record.aad_version);
+    let decrypted_content_bytes = match METRICS.time_crypto("aes_decrypt", || CryptoUtils::decrypt_data_with_aad(&encrypted_content_bytes, &decrypted_aes_key, &decoded_nonce, &aad)) {
         Ok(content) => content,
         Err(e) => return HttpResponse::InternalServerError().body(format!("Error decrypting health record content: {:?}", e)),
     };
 
+    let verification = signing::verify_record(&record, signing_key.as_ref(), &decrypted_content_bytes);
     let decrypted_content = String::from_utf8(decrypted_content_bytes)
         .unwrap_or_else(|_| "Could not decode UTF-8".to_string());
 
@@ -333,7 +613,294 @@ pub async fn get_health_record_by_id(
         "record_type": record.record_type,
         "title": record.title,
         "content": decrypted_content, // Decrypted content
+        "signature": verification,
         "created_at": record.created_at,
         "updated_at": record.updated_at,
     }))
 }
+
+// Handler re-encrypting a patient's legacy records (stored before content was
+// bound to record metadata) with associated data. Requires the escrowed
+// private key. Each record is uploaded under a new CID with a fresh nonce;
+// its AES key is unchanged.
+pub async fn upgrade_record_encryption(
+    pool: web::Data<DbPool>,
+    blob_store: web::Data<BlobStore>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    principal: Option<Principal>,
+    patient_id: web::Path<String>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+
+    let pool_for_load = pool.clone();
+    let keys_for_load = keys.clone();
+    let (private_key, records) = match web::block(move || -> Result<_> {
+        let mut conn_for_query = pool_for_load.get()?;
+        let patient = match patients::table
+            .find(&patient_id_bytes)
+            .select(Patient::as_select())
+            .first(&mut conn_for_query)
+            .optional()?
+        {
+            Some(patient) => patient,
+            None => return Ok(None),
+        };
+        let private_key = keys::escrowed_private_key(keys_for_load.get_ref().as_ref(), &mut conn_for_query, &patient)?;
+        let records = health_records::table
+            .filter(health_records::patient_id.eq(&patient_id_bytes))
+            .filter(health_records::aad_version.eq(0))
+            .select(HealthRecord::as_select())
+            .load(&mut conn_for_query)?
+            .into_iter()
+            .map(|mut record| {
+                keys::open_record_metadata(keys_for_load.get_ref().as_ref(), &mut conn_for_query, &mut record)?;
+                let (encrypted_aes_key, nonce) = keys::record_key_material(keys_for_load.get_ref().as_ref(), &mut conn_for_query, &record)?;
+                Ok((record, encrypted_aes_key, nonce))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some((private_key, records)))
+    })
+    .await
+    {
+        Ok(Ok(Some((Some(private_key), records)))) => (private_key, records),
+        Ok(Ok(Some((None, _)))) => return HttpResponse::Conflict().body("The server does not hold this patient's private key"),
+        Ok(Ok(None)) => return HttpResponse::NotFound().body("Patient not found"),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error loading legacy records: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    let mut upgraded = 0;
+    let mut skipped = 0;
+    for (record, encrypted_aes_key, nonce) in records {
+        let encrypted_content_bytes = match fetch_record_content(&pool, &config, principal.as_ref(), &blob_store, &record).await {
+            Ok(content) => content,
+            Err(response) => return response,
+        };
+
+        let reencrypted = CryptoUtils::decode_base64(&encrypted_aes_key)
+            .and_then(|wrapped| METRICS.time_crypto(private_key.algorithm().unwrap_operation(), || private_key.unwrap_key(&wrapped)))
+            .map(Zeroizing::new)
+            .and_then(|aes_key| {
+                let legacy_nonce = CryptoUtils::decode_base64(&nonce)?;
+                let content = Zeroizing::new(METRICS.time_crypto("aes_decrypt", || CryptoUtils::decrypt_data(&encrypted_content_bytes, &aes_key, &legacy_nonce))?);
+                let aad = CryptoUtils::record_aad(&record.id, &record.patient_id, &record.record_type, RECORD_AAD_VERSION);
+                METRICS.time_crypto("aes_encrypt", || CryptoUtils::encrypt_data_with_aad(&content, &aes_key, &aad))
+            });
+        let (encrypted_content, new_nonce) = match reencrypted {
+            Ok(data) => data,
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error re-encrypting health record content: {:?}", e)),
+        };
+
+        let (ipfs_cid, content_sha256) = match store_blob(&blob_store, encrypted_content).await {
+            Ok(stored) => stored,
+            Err(response) => return response,
+        };
+
+        let record_cid = record.ipfs_cid.clone();
+        let new_cid = ipfs_cid.clone();
+        let pool_for_update = pool.clone();
+        let keys_for_update = keys.clone();
+        match web::block(move || -> Result<usize> {
+            let mut conn_for_query = pool_for_update.get()?;
+            let mut upgraded_record = record.clone();
+            keys::seal_record_key_material(
+                keys_for_update.get_ref().as_ref(),
+                &mut conn_for_query,
+                &mut upgraded_record,
+                encrypted_aes_key,
+                CryptoUtils::encode_base64(&new_nonce),
+            )?;
+            // Only touch rows unchanged since they were read, so a concurrent
+            // key rotation re-wrapping the same record is never overwritten
+            conn_for_query.transaction(|conn| -> Result<usize> {
+                pins::mark(conn, &ipfs_cid, pins::PINNED, None)?;
+                Ok(diesel::update(
+                    health_records::table
+                        .filter(health_records::id.eq(&record.id))
+                        .filter(health_records::aad_version.eq(0))
+                        .filter(health_records::encrypted_aes_key.eq(&record.encrypted_aes_key)),
+                )
+                .set((
+                    health_records::ipfs_cid.eq(&ipfs_cid),
+                    health_records::content_sha256.eq(Some(content_sha256)),
+                    health_records::encrypted_aes_key.eq(upgraded_record.encrypted_aes_key),
+                    health_records::nonce.eq(upgraded_record.nonce),
+                    health_records::data_key_id.eq(upgraded_record.data_key_id),
+                    health_records::aad_version.eq(RECORD_AAD_VERSION),
+                    health_records::updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)?)
+            })
+        })
+        .await
+        {
+            Ok(Ok(0)) => {
+                skipped += 1;
+                release_blob(&pool, &blob_store, &new_cid).await;
+            }
+            Ok(Ok(_)) => {
+                upgraded += 1;
+                release_blob(&pool, &blob_store, &record_cid).await;
+            }
+            Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error updating health record: {:?}", e)),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+        }
+    }
+
+    tracing::info!(upgraded, skipped, "legacy health records re-encrypted with associated data");
+    HttpResponse::Ok().json(json!({
+        "upgraded": upgraded,
+        "skipped": skipped,
+    }))
+}
+
+// Adds encrypted record content to the blob store and pins it, returning its
+// CID and hex SHA-256. Failures come back as the response to send.
+async fn store_blob(blob_store: &BlobStore, encrypted_content: Vec<u8>) -> Result<(String, String), HttpResponse> {
+    let content_sha256 = Sha256::digest(&encrypted_content).iter().map(|b| format!("{:02x}", b)).collect();
+    let ipfs_started = Instant::now();
+    let ipfs_result = blob_store.put(encrypted_content).await;
+    METRICS.observe_ipfs("add", ipfs_started.elapsed().as_secs_f64(), ipfs_result.is_ok());
+    let ipfs_cid = match ipfs_result {
+        Ok(cid) => cid,
+        Err(e) => {
+            tracing::error!(error = %e, "IPFS add failed");
+            return Err(HttpResponse::InternalServerError().body(format!("Error uploading to IPFS: {:?}", e)));
+        }
+    };
+    let pin_started = Instant::now();
+    let pin_result = blob_store.pin(&ipfs_cid).await;
+    METRICS.observe_ipfs("pin", pin_started.elapsed().as_secs_f64(), pin_result.is_ok());
+    if let Err(e) = pin_result {
+        tracing::error!(cid = %ipfs_cid, error = %e, "IPFS pin failed");
+        return Err(HttpResponse::InternalServerError().body(format!("Error pinning content on IPFS: {:?}", e)));
+    }
+    Ok((ipfs_cid, content_sha256))
+}
+
+// Fetches a record's encrypted content, checked against its CID and stored
+// hash. Content failing the check, or that cannot be checked, is refused with
+// 502 and recorded in the audit trail. Failures come back as the response to
+// send.
+async fn fetch_record_content(
+    pool: &DbPool,
+    config: &AppConfig,
+    principal: Option<&Principal>,
+    blob_store: &BlobStore,
+    record: &HealthRecord,
+) -> Result<Vec<u8>, HttpResponse> {
+    let ipfs_started = Instant::now();
+    let ipfs_result = blob_store.fetch_verified(&record.ipfs_cid, record.content_sha256.as_deref()).await;
+    METRICS.observe_ipfs("cat", ipfs_started.elapsed().as_secs_f64(), ipfs_result.is_ok());
+    let reason = match ipfs_result {
+        Ok(content) => return Ok(content),
+        Err(FetchError::Unavailable(e)) => {
+            tracing::error!(cid = %record.ipfs_cid, error = %e, "IPFS cat failed");
+            return Err(HttpResponse::InternalServerError().body(format!("Error retrieving encrypted content from IPFS for CID: {}", record.ipfs_cid)));
+        }
+        Err(FetchError::Integrity(reason) | FetchError::Unverifiable(reason)) => reason,
+    };
+
+    tracing::error!(cid = %record.ipfs_cid, reason = %reason, "record content failed its integrity check");
+    if config.audit.enabled {
+        let mut event = audit::new_event(
+            audit::actor(principal),
+            audit::RECORD_INTEGRITY_FAILURE_ACTION.to_string(),
+            502,
+        );
+        event.patient_id = Some(record.patient_id.clone());
+        event.record_id = Some(record.id.clone());
+        event.record_cid = Some(record.ipfs_cid.clone());
+        let pool = pool.clone();
+        let recorded = web::block(move || -> Result<_> {
+            let mut conn = pool.get()?;
+            audit::record_event(&mut conn, event)
+        })
+        .await;
+        if !matches!(recorded, Ok(Ok(_))) {
+            tracing::error!(cid = %record.ipfs_cid, "failed to record integrity failure in the audit trail");
+        }
+    }
+    Err(HttpResponse::BadGateway().body(format!("Integrity check failed for the encrypted content of CID {}", record.ipfs_cid)))
+}
+
+// Unpins a blob no record references anymore. A failure only delays the
+// release to the next pin reconciliation.
+async fn release_blob(pool: &DbPool, blob_store: &BlobStore, cid: &str) {
+    if let Err(e) = pins::release(pool, blob_store, cid).await {
+        tracing::warn!(cid = %cid, error = %e, "failed to release blob");
+    }
+}
+
+// A record decrypted with the patient's escrowed key
+pub struct OpenedRecord {
+    pub record: HealthRecord,
+    pub content: Zeroizing<Vec<u8>>,
+    pub verification: signing::RecordVerification,
+}
+
+// Loads, decrypts and signature-checks one of a patient's records for
+// server-side features built on record content. Failures come back as the
+// response to send.
+pub async fn open_health_record(
+    pool: &web::Data<DbPool>,
+    blob_store: &BlobStore,
+    config: &AppConfig,
+    keys: &web::Data<Option<KeyHierarchy>>,
+    principal: Option<&Principal>,
+    patient_id: Vec<u8>,
+    record_id: Vec<u8>,
+) -> Result<OpenedRecord, HttpResponse> {
+    let pool_for_load = pool.clone();
+    let keys = keys.clone();
+    let loaded = web::block(move || -> Result<_> {
+        let mut conn_for_query = pool_for_load.get()?;
+        let Some(mut record) = health_records::table
+            .filter(health_records::id.eq(&record_id))
+            .filter(health_records::patient_id.eq(&patient_id))
+            .select(HealthRecord::as_select())
+            .first(&mut conn_for_query)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        keys::open_record_metadata(keys.get_ref().as_ref(), &mut conn_for_query, &mut record)?;
+        let patient = patients::table
+            .find(&patient_id)
+            .select(Patient::as_select())
+            .first(&mut conn_for_query)?;
+        let private_key = keys::escrowed_private_key(keys.get_ref().as_ref(), &mut conn_for_query, &patient)?;
+        let (encrypted_aes_key, nonce) = keys::record_key_material(keys.get_ref().as_ref(), &mut conn_for_query, &record)?;
+        let signing_key = signing::record_signing_key(&mut conn_for_query, &record)?;
+        Ok(Some((record, private_key, encrypted_aes_key, nonce, signing_key)))
+    })
+    .await;
+    let (record, private_key, encrypted_aes_key, nonce, signing_key) = match loaded {
+        Ok(Ok(Some((record, Some(private_key), encrypted_aes_key, nonce, signing_key)))) => (record, private_key, encrypted_aes_key, nonce, signing_key),
+        Ok(Ok(Some((_, None, _, _, _)))) => return Err(HttpResponse::Conflict().body("The server does not hold this patient's private key")),
+        Ok(Ok(None)) => return Err(HttpResponse::NotFound().body("Health record not found")),
+        Ok(Err(e)) => return Err(HttpResponse::InternalServerError().body(format!("Error getting health record: {:?}", e))),
+        Err(e) => return Err(HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e))),
+    };
+
+    let encrypted_content_bytes = fetch_record_content(pool, config, principal, blob_store, &record).await?;
+
+    let decrypted = CryptoUtils::decode_base64(&encrypted_aes_key)
+        .and_then(|wrapped| METRICS.time_crypto(private_key.algorithm().unwrap_operation(), || private_key.unwrap_key(&wrapped)))
+        .map(Zeroizing::new)
+        .and_then(|aes_key| {
+            let nonce = CryptoUtils::decode_base64(&nonce)?;
+            let aad = CryptoUtils::record_aad(&record.id, &record.patient_id, &record.record_type, record.aad_version);
+            METRICS.time_crypto("aes_decrypt", || CryptoUtils::decrypt_data_with_aad(&encrypted_content_bytes, &aes_key, &nonce, &aad))
+        });
+    let content = match decrypted {
+        Ok(content) => Zeroizing::new(content),
+        Err(e) => return Err(HttpResponse::InternalServerError().body(format!("Error decrypting health record content: {:?}", e))),
+    };
+    let verification = signing::verify_record(&record, signing_key.as_ref(), &content);
+    Ok(OpenedRecord { record, content, verification })
+}
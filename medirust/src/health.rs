@@ -0,0 +1,131 @@
+use actix_web::{web, HttpResponse, Responder};
+use diesel::prelude::*;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::blobstore::BlobStore;
+use crate::DbPool;
+
+// Upper bound for each dependency probe so a hung backend cannot stall the probe itself
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Prefix of the blob each readiness probe writes. Probes unpin their blob
+// once read back, so it is never tracked in blob_pins and the store may drop
+// it; a per-probe suffix keeps one probe from unpinning another's blob.
+const BLOB_PROBE_PREFIX: &str = "medirust-readiness-probe-";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ComponentStatus {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Serialize)]
+struct ComponentReport {
+    status: ComponentStatus,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ComponentBreakdown {
+    database: ComponentReport,
+    blob_store: ComponentReport,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadinessReport {
+    status: &'static str,
+    components: ComponentBreakdown,
+}
+
+impl ComponentReport {
+    fn from_result(started: Instant, result: Result<(), String>) -> Self {
+        let latency_ms = started.elapsed().as_millis() as u64;
+        match result {
+            Ok(()) => ComponentReport { status: ComponentStatus::Up, latency_ms, error: None },
+            Err(e) => ComponentReport { status: ComponentStatus::Down, latency_ms, error: Some(e) },
+        }
+    }
+
+    fn is_up(&self) -> bool {
+        matches!(self.status, ComponentStatus::Up)
+    }
+}
+
+// Liveness: the process is running and able to serve requests
+pub async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+// Readiness: every dependency needed to serve patient data is reachable
+pub async fn readyz(
+    pool: web::Data<DbPool>,
+    blob_store: web::Data<BlobStore>,
+) -> impl Responder {
+    let (database, blob_store) = futures::join!(
+        probe_database(pool.into_inner()),
+        probe_blob_store(&blob_store),
+    );
+
+    let ready = database.is_up() && blob_store.is_up();
+    let report = ReadinessReport {
+        status: if ready { "ready" } else { "not_ready" },
+        components: ComponentBreakdown { database, blob_store },
+    };
+
+    if ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        tracing::warn!(
+            database_up = report.components.database.is_up(),
+            blob_store_up = report.components.blob_store.is_up(),
+            "readiness check failed"
+        );
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+// Checks out a pooled connection and runs a trivial query
+async fn probe_database(pool: std::sync::Arc<DbPool>) -> ComponentReport {
+    let started = Instant::now();
+    let result = web::block(move || -> Result<(), String> {
+        let mut conn = pool.get_timeout(PROBE_TIMEOUT)
+            .map_err(|e| format!("Could not get a connection from the pool: {}", e))?;
+        diesel::sql_query("SELECT 1")
+            .execute(&mut conn)
+            .map(|_| ())
+            .map_err(|e| format!("Probe query failed: {}", e))
+    })
+    .await
+    .unwrap_or_else(|e| Err(format!("Error blocking thread: {}", e)));
+    ComponentReport::from_result(started, result)
+}
+
+// Writes a probe blob, reads it back comparing the bytes, and unpins it
+async fn probe_blob_store(blob_store: &BlobStore) -> ComponentReport {
+    let started = Instant::now();
+    let payload = format!("{}{}", BLOB_PROBE_PREFIX, Uuid::new_v4()).into_bytes();
+    let round_trip = async {
+        let cid = blob_store.put(payload.clone())
+            .await
+            .map_err(|e| format!("Blob store write failed: {:#}", e))?;
+        let bytes = blob_store.get(&cid)
+            .await
+            .map_err(|e| format!("Blob store read failed: {:#}", e))?;
+        if bytes != payload {
+            return Err(format!("Blob store returned unexpected content for {}", cid));
+        }
+        blob_store.unpin(&cid)
+            .await
+            .map_err(|e| format!("Blob store unpin failed: {:#}", e))
+    };
+    let result = match tokio::time::timeout(PROBE_TIMEOUT, round_trip).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("Blob store round-trip timed out after {}s", PROBE_TIMEOUT.as_secs())),
+    };
+    ComponentReport::from_result(started, result)
+}
@@ -0,0 +1,583 @@
+use argon2::Argon2;
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::sync::Mutex;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow, Context};
+
+use crate::config::{KeyManagementConfig, KeySourceConfig};
+use crate::crypto::{CryptoUtils, PatientPrivateKey};
+use crate::models::{DataKeyRecord, HealthRecord, Patient};
+use crate::schema::{data_keys, health_records};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const RECORD_TYPE_INDEX_INFO: &[u8] = b"medirust record type index";
+const RECORD_METADATA_AAD_DOMAIN: &[u8] = b"medirust record metadata";
+const METADATA_BATCH_SIZE: i64 = 500;
+
+// Key-encryption key at the top of the hierarchy. It never touches the
+// database; only its ID is stored next to the data keys it wraps.
+pub struct RootKey {
+    id: String,
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl RootKey {
+    pub fn from_bytes(key: Vec<u8>) -> Result<Self> {
+        let key = Zeroizing::new(key);
+        if key.len() != KEY_SIZE {
+            return Err(anyhow!("Root key must be {} bytes, got {}", KEY_SIZE, key.len()));
+        }
+        // The ID identifies the key without revealing anything about it
+        let digest = Sha256::new()
+            .chain_update(b"medirust-root-key-id")
+            .chain_update(key.as_slice())
+            .finalize();
+        let id = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Ok(RootKey { id, key })
+    }
+
+    // Reads 32 raw bytes, or their base64 encoding, from a file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = Zeroizing::new(
+            fs::read(path).with_context(|| format!("Failed to read root key file {}", path.display()))?,
+        );
+        if contents.len() == KEY_SIZE {
+            return Self::from_bytes(contents.to_vec());
+        }
+        let text = std::str::from_utf8(&contents)
+            .map_err(|_| anyhow!("Root key file {} is neither 32 raw bytes nor base64", path.display()))?;
+        let decoded = CryptoUtils::decode_base64(text.trim())
+            .with_context(|| format!("Root key file {} is neither 32 raw bytes nor base64", path.display()))?;
+        Self::from_bytes(decoded)
+    }
+
+    // Derives the root key from a passphrase with Argon2id
+    pub fn from_passphrase(passphrase: &[u8], salt: &[u8]) -> Result<Self> {
+        let mut key = Zeroizing::new(vec![0u8; KEY_SIZE]);
+        Argon2::default()
+            .hash_password_into(passphrase, salt, &mut key)
+            .map_err(|e| anyhow!("Failed to derive root key from passphrase: {}", e))?;
+        Self::from_bytes(key.to_vec())
+    }
+
+    // Loads the key described by a [key_management.*] section, if any
+    pub fn load(source: &KeySourceConfig) -> Result<Option<Self>> {
+        if let Some(path) = &source.path {
+            return Self::from_file(path).map(Some);
+        }
+        if let Some(var) = &source.passphrase_env {
+            let passphrase = Zeroizing::new(
+                env::var(var).map_err(|_| anyhow!("Environment variable {} is not set", var))?,
+            );
+            let salt = source.salt.as_deref()
+                .ok_or_else(|| anyhow!("A salt is required for passphrase-derived root keys"))?;
+            let salt = CryptoUtils::decode_base64(salt)?;
+            return Self::from_passphrase(passphrase.as_bytes(), &salt).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // Wraps a key kept outside the data_keys table, e.g. a backup archive key
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String> {
+        seal_with(&self.key, plaintext)
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<Vec<u8>> {
+        open_with(&self.key, sealed)
+    }
+}
+
+// Unwrapped tenant data key
+#[derive(Clone)]
+pub struct DataKey {
+    pub id: String,
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl DataKey {
+    // Encrypts a server-held secret, returning base64(nonce || ciphertext)
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String> {
+        seal_with(&self.key, plaintext)
+    }
+
+    pub fn open(&self, sealed: &str) -> Result<Vec<u8>> {
+        open_with(&self.key, sealed)
+    }
+
+    // Like `seal`, authenticating `aad` alongside the ciphertext
+    pub fn seal_with_aad(&self, plaintext: &[u8], aad: &[u8]) -> Result<String> {
+        seal_with_aad(&self.key, plaintext, aad)
+    }
+
+    pub fn open_with_aad(&self, sealed: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        open_with_aad(&self.key, sealed, aad)
+    }
+
+    // Blind index of a record type: hex HMAC-SHA256 under a key derived from
+    // this data key, so equal types can be matched without being readable
+    pub fn record_type_index(&self, record_type: &str) -> Result<String> {
+        let mut index_key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(None, &self.key)
+            .expand(RECORD_TYPE_INDEX_INFO, index_key.as_mut())
+            .map_err(|e| anyhow!("Failed to derive record type index key: {}", e))?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(index_key.as_ref())
+            .map_err(|e| anyhow!("Failed to create record type index: {}", e))?;
+        mac.update(record_type.as_bytes());
+        Ok(mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+// Root key plus the tenant data keys it protects
+pub struct KeyHierarchy {
+    root: RootKey,
+    tenant: String,
+    cache: Mutex<HashMap<String, DataKey>>,
+}
+
+impl KeyHierarchy {
+    pub fn new(root: RootKey, tenant: String) -> Self {
+        KeyHierarchy {
+            root,
+            tenant,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Builds the hierarchy from configuration; None when no root key is configured
+    pub fn from_config(config: &KeyManagementConfig) -> Result<Option<Self>> {
+        Ok(RootKey::load(&config.root_key)?.map(|root| KeyHierarchy::new(root, config.tenant.clone())))
+    }
+
+    pub fn root_key_id(&self) -> &str {
+        self.root.id()
+    }
+
+    // Newest data key of the tenant, creating the first one on demand
+    pub fn active_data_key(&self, conn: &mut PgConnection) -> Result<DataKey> {
+        let latest = data_keys::table
+            .filter(data_keys::tenant.eq(&self.tenant))
+            .order(data_keys::created_at.desc())
+            .select(DataKeyRecord::as_select())
+            .first(conn)
+            .optional()
+            .context("Failed to load data key")?;
+        match latest {
+            Some(record) => self.unwrap_record(&record),
+            None => self.create_data_key(conn),
+        }
+    }
+
+    // Data key by ID, as referenced next to a wrapped secret
+    pub fn data_key(&self, conn: &mut PgConnection, id: &str) -> Result<DataKey> {
+        if let Some(key) = self.cache.lock().unwrap_or_else(|e| e.into_inner()).get(id) {
+            return Ok(key.clone());
+        }
+        let record = data_keys::table
+            .find(id)
+            .select(DataKeyRecord::as_select())
+            .first(conn)
+            .with_context(|| format!("Failed to load data key {}", id))?;
+        self.unwrap_record(&record)
+    }
+
+    // Generates a fresh data key for the tenant, wrapped under the root key
+    pub fn create_data_key(&self, conn: &mut PgConnection) -> Result<DataKey> {
+        let key = Zeroizing::new(CryptoUtils::generate_aes_key());
+        let now = Utc::now().naive_utc();
+        let record = DataKeyRecord {
+            id: Uuid::new_v4().to_string(),
+            tenant: self.tenant.clone(),
+            root_key_id: self.root.id.clone(),
+            wrapped_key: seal_with(&self.root.key, &key)?,
+            created_at: now,
+            updated_at: now,
+        };
+        diesel::insert_into(data_keys::table)
+            .values(&record)
+            .execute(conn)
+            .context("Failed to store data key")?;
+        let data_key = DataKey { id: record.id, key };
+        self.cache_key(&data_key);
+        Ok(data_key)
+    }
+
+    fn unwrap_record(&self, record: &DataKeyRecord) -> Result<DataKey> {
+        if record.root_key_id != self.root.id {
+            return Err(anyhow!(
+                "Data key {} is wrapped under root key {}, but root key {} is loaded",
+                record.id, record.root_key_id, self.root.id
+            ));
+        }
+        let key = Zeroizing::new(open_with(&self.root.key, &record.wrapped_key)
+            .with_context(|| format!("Failed to unwrap data key {}", record.id))?);
+        let data_key = DataKey { id: record.id.clone(), key };
+        self.cache_key(&data_key);
+        Ok(data_key)
+    }
+
+    fn cache_key(&self, key: &DataKey) {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner()).insert(key.id.clone(), key.clone());
+    }
+
+    // Re-wraps every data key held under `previous` with this hierarchy's root
+    // key. Only the data_keys rows change; record ciphertext in IPFS and the
+    // secrets sealed under the data keys stay as they are.
+    pub fn rotate_root_key(&self, conn: &mut PgConnection, previous: &RootKey) -> Result<usize> {
+        if previous.id == self.root.id {
+            return Ok(0);
+        }
+        conn.transaction(|conn| {
+            let records = data_keys::table
+                .filter(data_keys::root_key_id.eq(&previous.id))
+                .select(DataKeyRecord::as_select())
+                .load(conn)
+                .context("Failed to load data keys for rotation")?;
+            let now = Utc::now().naive_utc();
+            for record in &records {
+                let key = Zeroizing::new(open_with(&previous.key, &record.wrapped_key)
+                    .with_context(|| format!("Failed to unwrap data key {} with the previous root key", record.id))?);
+                diesel::update(data_keys::table.find(&record.id))
+                    .set((
+                        data_keys::wrapped_key.eq(seal_with(&self.root.key, &key)?),
+                        data_keys::root_key_id.eq(&self.root.id),
+                        data_keys::updated_at.eq(now),
+                    ))
+                    .execute(conn)
+                    .with_context(|| format!("Failed to re-wrap data key {}", record.id))?;
+            }
+            Ok(records.len())
+        })
+    }
+}
+
+// Unseals the patient's escrowed private key; None if the server does not hold it
+pub fn escrowed_private_key(
+    keys: Option<&KeyHierarchy>,
+    conn: &mut PgConnection,
+    patient: &Patient,
+) -> Result<Option<PatientPrivateKey>> {
+    let (Some(keys), Some(sealed), Some(key_id)) = (keys, &patient.escrowed_private_key, &patient.escrow_key_id) else {
+        return Ok(None);
+    };
+    let data_key = keys.data_key(conn, key_id)?;
+    let pem = Zeroizing::new(String::from_utf8(data_key.open(sealed)?)?);
+    PatientPrivateKey::from_pem(patient.algorithm()?, &pem).map(Some)
+}
+
+// Returns the record's base64 RSA-wrapped AES key and nonce, removing the
+// data key envelope from records written with a key hierarchy
+pub fn record_key_material(
+    keys: Option<&KeyHierarchy>,
+    conn: &mut PgConnection,
+    record: &HealthRecord,
+) -> Result<(String, String)> {
+    let Some(key_id) = &record.data_key_id else {
+        return Ok((record.encrypted_aes_key.clone(), record.nonce.clone()));
+    };
+    let keys = keys.ok_or_else(|| anyhow!("Record is sealed under data key {} but no root key is configured", key_id))?;
+    let data_key = keys.data_key(conn, key_id)?;
+    let encrypted_aes_key = String::from_utf8(data_key.open(&record.encrypted_aes_key)?)?;
+    let nonce = String::from_utf8(data_key.open(&record.nonce)?)?;
+    Ok((encrypted_aes_key, nonce))
+}
+
+// Stores the base64 RSA-wrapped AES key and nonce on the record, sealed
+// under the active data key when a key hierarchy is configured
+pub fn seal_record_key_material(
+    keys: Option<&KeyHierarchy>,
+    conn: &mut PgConnection,
+    record: &mut HealthRecord,
+    encrypted_aes_key: String,
+    nonce: String,
+) -> Result<()> {
+    match keys {
+        Some(keys) => {
+            let data_key = keys.active_data_key(conn)?;
+            record.encrypted_aes_key = data_key.seal(encrypted_aes_key.as_bytes())?;
+            record.nonce = data_key.seal(nonce.as_bytes())?;
+            record.data_key_id = Some(data_key.id);
+        }
+        None => {
+            record.encrypted_aes_key = encrypted_aes_key;
+            record.nonce = nonce;
+            record.data_key_id = None;
+        }
+    }
+    Ok(())
+}
+
+// Associated data binding a sealed metadata column to its record and
+// column, so sealed values cannot be moved between rows or between the
+// title and type of one row
+pub fn record_metadata_aad(record_id: &[u8], column: &str) -> Vec<u8> {
+    let mut aad = RECORD_METADATA_AAD_DOMAIN.to_vec();
+    for field in [record_id, column.as_bytes()] {
+        aad.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        aad.extend_from_slice(field);
+    }
+    aad
+}
+
+// Fills in the record's type and title from their sealed columns. Records
+// stored in plaintext, from before their metadata was sealed, are left as
+// they are.
+pub fn open_record_metadata(
+    keys: Option<&KeyHierarchy>,
+    conn: &mut PgConnection,
+    record: &mut HealthRecord,
+) -> Result<()> {
+    let Some(key_id) = &record.metadata_key_id else {
+        return Ok(());
+    };
+    let (Some(sealed_record_type), Some(sealed_title)) = (&record.sealed_record_type, &record.sealed_title) else {
+        return Err(anyhow!("Record metadata is sealed under data key {} but the sealed columns are empty", key_id));
+    };
+    let keys = keys.ok_or_else(|| anyhow!("Record metadata is sealed under data key {} but no root key is configured", key_id))?;
+    let data_key = keys.data_key(conn, key_id)?;
+    let record_type = data_key.open_with_aad(sealed_record_type, &record_metadata_aad(&record.id, "record_type"))?;
+    let title = data_key.open_with_aad(sealed_title, &record_metadata_aad(&record.id, "title"))?;
+    record.record_type = String::from_utf8(record_type)?;
+    record.title = String::from_utf8(title)?;
+    Ok(())
+}
+
+// Moves the record's type and title into their sealed columns under the
+// active data key and sets the type's blind index, leaving the plaintext
+// columns empty. Metadata is never stored in plaintext, so this fails
+// without a key hierarchy.
+pub fn seal_record_metadata(
+    keys: Option<&KeyHierarchy>,
+    conn: &mut PgConnection,
+    record: &mut HealthRecord,
+) -> Result<()> {
+    let keys = keys.ok_or_else(|| anyhow!("Record titles and types are sealed under the root key, but none is configured"))?;
+    let data_key = keys.active_data_key(conn)?;
+    record.record_type_index = Some(data_key.record_type_index(&record.record_type)?);
+    let record_type = mem::take(&mut record.record_type);
+    let title = mem::take(&mut record.title);
+    record.sealed_record_type = Some(data_key.seal_with_aad(record_type.as_bytes(), &record_metadata_aad(&record.id, "record_type"))?);
+    record.sealed_title = Some(data_key.seal_with_aad(title.as_bytes(), &record_metadata_aad(&record.id, "title"))?);
+    record.metadata_key_id = Some(data_key.id);
+    Ok(())
+}
+
+// Blind indexes `record_type` has under each data key sealing some of the
+// patient's records, for matching against `record_type_index`
+pub fn record_type_indexes(
+    keys: Option<&KeyHierarchy>,
+    conn: &mut PgConnection,
+    patient_id: &[u8],
+    record_type: &str,
+) -> Result<Vec<String>> {
+    let key_ids: Vec<Option<String>> = health_records::table
+        .filter(health_records::patient_id.eq(patient_id))
+        .filter(health_records::metadata_key_id.is_not_null())
+        .select(health_records::metadata_key_id)
+        .distinct()
+        .load(conn)
+        .context("Failed to load record metadata keys")?;
+    let Some(keys) = keys else {
+        return match key_ids.is_empty() {
+            true => Ok(Vec::new()),
+            false => Err(anyhow!("Record metadata is sealed but no root key is configured")),
+        };
+    };
+    key_ids
+        .into_iter()
+        .flatten()
+        .map(|key_id| keys.data_key(conn, &key_id)?.record_type_index(record_type))
+        .collect()
+}
+
+// Seals the type and title of records stored in plaintext, from before
+// record metadata was sealed. Runs in batches, each in its own transaction,
+// and returns the number sealed.
+pub fn seal_plaintext_record_metadata(keys: &KeyHierarchy, conn: &mut PgConnection) -> Result<usize> {
+    let mut sealed = 0;
+    loop {
+        let batch = conn.transaction(|conn| -> Result<usize> {
+            let records = health_records::table
+                .filter(health_records::metadata_key_id.is_null())
+                .limit(METADATA_BATCH_SIZE)
+                .select(HealthRecord::as_select())
+                .load(conn)
+                .context("Failed to load plaintext record metadata")?;
+            for mut record in records.iter().cloned() {
+                seal_record_metadata(Some(keys), conn, &mut record)?;
+                // updated_at is left alone: the metadata clients see has not changed
+                diesel::update(health_records::table.find(&record.id))
+                    .set((
+                        health_records::record_type.eq(&record.record_type),
+                        health_records::title.eq(&record.title),
+                        health_records::metadata_key_id.eq(&record.metadata_key_id),
+                        health_records::sealed_record_type.eq(&record.sealed_record_type),
+                        health_records::sealed_title.eq(&record.sealed_title),
+                        health_records::record_type_index.eq(&record.record_type_index),
+                    ))
+                    .execute(conn)
+                    .context("Failed to seal record metadata")?;
+            }
+            Ok(records.len())
+        })?;
+        sealed += batch;
+        if batch < METADATA_BATCH_SIZE as usize {
+            return Ok(sealed);
+        }
+    }
+}
+
+// Writes sealed record types and titles back to their plaintext columns and
+// clears the sealed ones, so the migration adding them can be reverted
+// without losing metadata. Batched like `seal_plaintext_record_metadata`;
+// returns the number opened.
+pub fn unseal_record_metadata(keys: &KeyHierarchy, conn: &mut PgConnection) -> Result<usize> {
+    let mut opened = 0;
+    loop {
+        let batch = conn.transaction(|conn| -> Result<usize> {
+            let records = health_records::table
+                .filter(health_records::metadata_key_id.is_not_null())
+                .limit(METADATA_BATCH_SIZE)
+                .select(HealthRecord::as_select())
+                .load(conn)
+                .context("Failed to load sealed record metadata")?;
+            for mut record in records.iter().cloned() {
+                open_record_metadata(Some(keys), conn, &mut record)?;
+                diesel::update(health_records::table.find(&record.id))
+                    .set((
+                        health_records::record_type.eq(&record.record_type),
+                        health_records::title.eq(&record.title),
+                        health_records::metadata_key_id.eq(None::<String>),
+                        health_records::sealed_record_type.eq(None::<String>),
+                        health_records::sealed_title.eq(None::<String>),
+                        health_records::record_type_index.eq(None::<String>),
+                    ))
+                    .execute(conn)
+                    .context("Failed to unseal record metadata")?;
+            }
+            Ok(records.len())
+        })?;
+        opened += batch;
+        if batch < METADATA_BATCH_SIZE as usize {
+            return Ok(opened);
+        }
+    }
+}
+
+fn seal_with(key: &[u8], plaintext: &[u8]) -> Result<String> {
+    seal_with_aad(key, plaintext, b"")
+}
+
+fn open_with(key: &[u8], sealed: &str) -> Result<Vec<u8>> {
+    open_with_aad(key, sealed, b"")
+}
+
+fn seal_with_aad(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<String> {
+    let (ciphertext, nonce) = CryptoUtils::encrypt_data_with_aad(plaintext, key, aad)?;
+    let mut sealed = nonce;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(CryptoUtils::encode_base64(&sealed))
+}
+
+fn open_with_aad(key: &[u8], sealed: &str, aad: &[u8]) -> Result<Vec<u8>> {
+    let bytes = CryptoUtils::decode_base64(sealed)?;
+    if bytes.len() < NONCE_SIZE {
+        return Err(anyhow!("Sealed value is too short"));
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+    CryptoUtils::decrypt_data_with_aad(ciphertext, key, nonce, aad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_key(byte: u8) -> DataKey {
+        DataKey { id: format!("data-key-{}", byte), key: Zeroizing::new(vec![byte; KEY_SIZE]) }
+    }
+
+    #[test]
+    fn root_keys_are_32_bytes_from_raw_or_base64_files() {
+        assert!(RootKey::from_bytes(vec![7u8; 31]).is_err());
+        let root = RootKey::from_bytes(vec![7u8; 32]).unwrap();
+        assert_eq!(root.id().len(), 16);
+        assert_eq!(root.id(), RootKey::from_bytes(vec![7u8; 32]).unwrap().id());
+        assert_ne!(root.id(), RootKey::from_bytes(vec![8u8; 32]).unwrap().id());
+
+        let dir = env::temp_dir().join(format!("medirust-root-key-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("raw.key"), [7u8; 32]).unwrap();
+        fs::write(dir.join("base64.key"), format!("{}\n", CryptoUtils::encode_base64(&[7u8; 32]))).unwrap();
+        fs::write(dir.join("short.key"), CryptoUtils::encode_base64(&[7u8; 16])).unwrap();
+        assert_eq!(RootKey::from_file(&dir.join("raw.key")).unwrap().id(), root.id());
+        assert_eq!(RootKey::from_file(&dir.join("base64.key")).unwrap().id(), root.id());
+        assert!(RootKey::from_file(&dir.join("short.key")).is_err());
+        assert!(RootKey::from_file(&dir.join("missing.key")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sealed_values_open_only_under_the_same_root_key() {
+        let root = RootKey::from_bytes(vec![7u8; 32]).unwrap();
+        let sealed = root.seal(b"archive key").unwrap();
+        assert_ne!(root.seal(b"archive key").unwrap(), sealed);
+        assert_eq!(root.open(&sealed).unwrap(), b"archive key");
+        assert!(RootKey::from_bytes(vec![8u8; 32]).unwrap().open(&sealed).is_err());
+        assert!(root.open(&CryptoUtils::encode_base64(&[0u8; 8])).is_err());
+    }
+
+    #[test]
+    fn data_keys_bind_associated_data() {
+        let key = data_key(1);
+        let record_id = Uuid::new_v4().as_bytes().to_vec();
+        let title_aad = record_metadata_aad(&record_id, "title");
+        let sealed = key.seal_with_aad(b"HbA1c", &title_aad).unwrap();
+        assert_eq!(key.open_with_aad(&sealed, &title_aad).unwrap(), b"HbA1c");
+
+        // Not under another column, another record, no AAD, or another key
+        assert!(key.open_with_aad(&sealed, &record_metadata_aad(&record_id, "record_type")).is_err());
+        assert!(key.open_with_aad(&sealed, &record_metadata_aad(Uuid::new_v4().as_bytes(), "title")).is_err());
+        assert!(key.open(&sealed).is_err());
+        assert!(data_key(2).open_with_aad(&sealed, &title_aad).is_err());
+
+        let mut tampered = CryptoUtils::decode_base64(&sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(key.open_with_aad(&CryptoUtils::encode_base64(&tampered), &title_aad).is_err());
+    }
+
+    #[test]
+    fn metadata_aad_fields_cannot_run_together() {
+        assert_ne!(record_metadata_aad(b"ab", "c"), record_metadata_aad(b"a", "bc"));
+        assert!(record_metadata_aad(b"id", "title").starts_with(RECORD_METADATA_AAD_DOMAIN));
+    }
+
+    #[test]
+    fn blind_indexes_match_equal_types_under_one_key() {
+        let key = data_key(1);
+        let lab = key.record_type_index("lab").unwrap();
+        assert_eq!(lab.len(), 64);
+        assert!(lab.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(key.record_type_index("lab").unwrap(), lab);
+        assert_ne!(key.record_type_index("Lab").unwrap(), lab);
+        assert_ne!(key.record_type_index("imaging").unwrap(), lab);
+        assert_ne!(data_key(2).record_type_index("lab").unwrap(), lab);
+        // Keyed, so not a plain hash of the type
+        let plain: String = Sha256::digest(b"lab").iter().map(|b| format!("{:02x}", b)).collect();
+        assert_ne!(lab, plain);
+    }
+}
@@ -0,0 +1,211 @@
+use actix_web::web;
+use chrono::Utc;
+use hyper::{body, Body, Client, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use anyhow::{Result, anyhow, Context};
+
+use crate::config::{AuditConfig, LedgerKind};
+use crate::merkle::{self, Hash};
+
+// Tag prepended to anchored roots in transaction data, so anchors can be
+// told apart from other transactions of the same account
+const EVM_ANCHOR_TAG: &[u8] = b"MDRA";
+
+// Backend anchoring audit Merkle roots. Each anchor returns a reference
+// that can later be resolved to the root it recorded.
+pub enum Ledger {
+    Local(LocalLedger),
+    Evm(EvmLedger),
+}
+
+impl Ledger {
+    pub fn from_config(config: &AuditConfig) -> Result<Self> {
+        match config.ledger {
+            LedgerKind::Local => Ok(Ledger::Local(LocalLedger::new(config.local_ledger_path.clone()))),
+            LedgerKind::Evm => {
+                let from = config.evm_from.clone().ok_or_else(|| anyhow!("audit.evm_from is not set"))?;
+                Ok(Ledger::Evm(EvmLedger::new(config.evm_rpc_url.clone(), from)))
+            }
+        }
+    }
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Ledger::Local(_) => "local",
+            Ledger::Evm(_) => "evm",
+        }
+    }
+
+    pub async fn anchor(&self, root: &Hash) -> Result<String> {
+        match self {
+            Ledger::Local(ledger) => {
+                let ledger = ledger.clone();
+                let root = *root;
+                web::block(move || ledger.append(&root)).await?
+            }
+            Ledger::Evm(ledger) => ledger.anchor(root).await,
+        }
+    }
+
+    // Root recorded under an anchor reference; None if it is unknown or not yet final
+    pub async fn lookup(&self, anchor_ref: &str) -> Result<Option<Hash>> {
+        match self {
+            Ledger::Local(ledger) => {
+                let ledger = ledger.clone();
+                let anchor_ref = anchor_ref.to_string();
+                web::block(move || ledger.lookup(&anchor_ref)).await?
+            }
+            Ledger::Evm(ledger) => ledger.lookup(anchor_ref).await,
+        }
+    }
+}
+
+// One line of the local ledger; each entry commits to its predecessor
+#[derive(Debug, Serialize, Deserialize)]
+struct LocalEntry {
+    seq: u64,
+    root: String,
+    prev: String,
+    hash: String,
+    anchored_at: String,
+}
+
+fn local_entry_hash(seq: u64, root: &str, prev: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(seq.to_be_bytes())
+        .chain_update(root.as_bytes())
+        .chain_update(prev.as_bytes())
+        .finalize();
+    merkle::to_hex(&digest)
+}
+
+// Append-only, hash-chained JSON lines file. Rewriting an earlier entry
+// breaks the chain, which `lookup` detects.
+#[derive(Clone)]
+pub struct LocalLedger {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl LocalLedger {
+    pub fn new(path: PathBuf) -> Self {
+        LocalLedger { path, lock: Arc::new(Mutex::new(())) }
+    }
+
+    // Reads and chain-checks all entries
+    fn entries(&self) -> Result<Vec<LocalEntry>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to open ledger {}", self.path.display())),
+        };
+        let mut entries: Vec<LocalEntry> = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let entry: LocalEntry = serde_json::from_str(&line?).context("Corrupt ledger entry")?;
+            let prev = entries.last().map(|e| e.hash.clone()).unwrap_or_default();
+            if entry.seq != entries.len() as u64 || entry.prev != prev || entry.hash != local_entry_hash(entry.seq, &entry.root, &entry.prev) {
+                return Err(anyhow!("Ledger {} is broken at entry {}", self.path.display(), entries.len()));
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    fn append(&self, root: &Hash) -> Result<String> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let entries = self.entries()?;
+        let seq = entries.len() as u64;
+        let prev = entries.last().map(|e| e.hash.clone()).unwrap_or_default();
+        let root = merkle::to_hex(root);
+        let entry = LocalEntry {
+            seq,
+            hash: local_entry_hash(seq, &root, &prev),
+            root,
+            prev,
+            anchored_at: Utc::now().to_rfc3339(),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open ledger {}", self.path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        file.sync_all()?;
+        Ok(format!("local:{}", seq))
+    }
+
+    fn lookup(&self, anchor_ref: &str) -> Result<Option<Hash>> {
+        let seq: usize = anchor_ref
+            .strip_prefix("local:")
+            .and_then(|seq| seq.parse().ok())
+            .ok_or_else(|| anyhow!("Not a local ledger reference: {}", anchor_ref))?;
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(self.entries()?.get(seq).and_then(|entry| merkle::from_hex(&entry.root)))
+    }
+}
+
+// Anchors roots as data of zero-value self-transfers, sent with
+// eth_sendTransaction from an account the node holds unlocked (as on Anvil
+// or Hardhat). A root counts once its transaction has a successful receipt.
+pub struct EvmLedger {
+    rpc_url: String,
+    from: String,
+    client: Client<hyper::client::HttpConnector>,
+    next_id: AtomicU64,
+}
+
+impl EvmLedger {
+    pub fn new(rpc_url: String, from: String) -> Self {
+        EvmLedger { rpc_url, from, client: Client::new(), next_id: AtomicU64::new(1) }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let payload = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let request = Request::post(&self.rpc_url)
+            .header("content-type", "application/json")
+            .body(Body::from(payload.to_string()))?;
+        let response = self.client.request(request).await
+            .with_context(|| format!("JSON-RPC request {} failed", method))?;
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            return Err(anyhow!("JSON-RPC {} returned HTTP {}", method, status));
+        }
+        let mut reply: Value = serde_json::from_slice(&bytes).context("Invalid JSON-RPC response")?;
+        if let Some(error) = reply.get("error") {
+            return Err(anyhow!("JSON-RPC {} failed: {}", method, error));
+        }
+        Ok(reply["result"].take())
+    }
+
+    async fn anchor(&self, root: &Hash) -> Result<String> {
+        let data = format!("0x{}{}", merkle::to_hex(EVM_ANCHOR_TAG), merkle::to_hex(root));
+        let tx = json!({ "from": self.from, "to": self.from, "value": "0x0", "data": data });
+        match self.call("eth_sendTransaction", json!([tx])).await? {
+            Value::String(tx_hash) => Ok(tx_hash),
+            other => Err(anyhow!("Unexpected eth_sendTransaction result: {}", other)),
+        }
+    }
+
+    async fn lookup(&self, tx_hash: &str) -> Result<Option<Hash>> {
+        let receipt = self.call("eth_getTransactionReceipt", json!([tx_hash])).await?;
+        if receipt.get("status").and_then(Value::as_str) != Some("0x1") {
+            return Ok(None);
+        }
+        let tx = self.call("eth_getTransactionByHash", json!([tx_hash])).await?;
+        let input = tx.get("input").and_then(Value::as_str).unwrap_or_default();
+        let tag = merkle::to_hex(EVM_ANCHOR_TAG);
+        Ok(input
+            .strip_prefix("0x")
+            .and_then(|input| input.strip_prefix(tag.as_str()))
+            .and_then(merkle::from_hex))
+    }
+}
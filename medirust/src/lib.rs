@@ -0,0 +1,45 @@
+// Library half of MediRust: the server binary and medirust-admin share the
+// configuration, database layer and crypto defined here.
+
+use diesel::r2d2::{self, ConnectionManager};
+use diesel::{PgConnection, SqliteConnection};
+
+pub mod schema;
+pub mod models;
+pub mod handlers;
+pub mod crypto;
+pub mod pre;
+pub mod config;
+pub mod tls;
+pub mod auth;
+pub mod metrics;
+pub mod telemetry;
+pub mod health;
+pub mod ratelimit;
+pub mod keys;
+pub mod rotation;
+pub mod recovery;
+pub mod delegation;
+pub mod devices;
+pub mod signing;
+pub mod sdjwt;
+pub mod disclosure;
+pub mod credentials;
+pub mod merkle;
+pub mod ledger;
+pub mod audit;
+pub mod blobstore;
+pub mod replication;
+pub mod pins;
+pub mod backup;
+
+// Database connection pool type
+pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>; // Use PgConnection
+
+// Connection to any supported database. The server pools PgConnection
+// directly; backup and restore take this so they also run against SQLite.
+#[derive(diesel::MultiConnection)]
+pub enum AnyConnection {
+    Postgresql(PgConnection),
+    Sqlite(SqliteConnection),
+}
@@ -1,53 +1,216 @@
 extern crate dotenvy;
 
-use actix_web::{web, App, HttpServer, Responder, HttpResponse};
+use actix_web::{middleware, web, App, HttpServer, Responder, HttpResponse};
 use diesel::r2d2::{self, ConnectionManager};
 use diesel::PgConnection; // Use PgConnection
 use dotenvy::dotenv;
-use std::env;
-use ipfs_api_backend_hyper::{IpfsClient}; // Corrected import for IpfsClient
 
-pub mod schema;
-pub mod models;
-pub mod handlers;
-pub mod crypto;
-
-// Database connection pool type
-pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>; // Use PgConnection
-
-// IPFS client type
-pub type IpfsClientType = IpfsClient;
+use medirust::blobstore::BlobStore;
+use medirust::config::AppConfig;
+use medirust::{
+    audit, auth, credentials, delegation, devices, disclosure, handlers, health, keys, ledger, metrics, pins, ratelimit,
+    recovery, rotation, signing, telemetry, tls,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    // Load and validate configuration before touching any external resource
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = telemetry::init_tracing(&config.logging) {
+        eprintln!("{:#}", e);
+        std::process::exit(2);
+    }
 
     // create db connection pool
-    let manager = ConnectionManager::<PgConnection>::new(database_url); // Use PgConnection
+    let manager = ConnectionManager::<PgConnection>::new(config.database.url.clone()); // Use PgConnection
     let pool = r2d2::Pool::builder()
+        .max_size(config.database.pool_size)
         .build(manager)
-        .expect("Failed to create pool.");
+        .map_err(|e| std::io::Error::other(format!("Failed to create pool: {}", e)))?;
+
+    // Initialize the blob store: the IPFS node, replicated if configured
+    let blob_store = BlobStore::from_config(&config.blob_store)
+        .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+    let blob_store = web::Data::new(blob_store);
 
-    // Initialize IPFS client
-    let ipfs_client = IpfsClient::default();
+    let bind_address = config.bind_address();
+    let workers = config.server.workers;
+    let tls_config = if config.tls.enabled {
+        let tls_config = tls::load_server_config(&config.tls)
+            .map_err(|e| std::io::Error::other(format!("Failed to configure TLS: {:#}", e)))?;
+        Some(tls_config)
+    } else {
+        None
+    };
+    tracing::info!(
+        host = %bind_address.0,
+        port = bind_address.1,
+        tls = config.tls.enabled,
+        mutual_tls = config.tls.client_ca_path.is_some(),
+        "starting MediRust"
+    );
+    // Load the root key and finish any pending root key rotation. Record
+    // titles and types are only stored sealed, so the server needs one.
+    let key_hierarchy = keys::KeyHierarchy::from_config(&config.key_management)
+        .map_err(|e| std::io::Error::other(format!("Failed to load root key: {:#}", e)))?;
+    let Some(hierarchy) = &key_hierarchy else {
+        return Err(std::io::Error::other(
+            "No root key configured; set [key_management.root_key] to seal record titles and types",
+        ));
+    };
+    let mut conn = pool.get()
+        .map_err(|e| std::io::Error::other(format!("Failed to get db connection: {}", e)))?;
+    if let Some(previous) = keys::RootKey::load(&config.key_management.previous_root_key)
+        .map_err(|e| std::io::Error::other(format!("Failed to load previous root key: {:#}", e)))?
+    {
+        let rewrapped = hierarchy.rotate_root_key(&mut conn, &previous)
+            .map_err(|e| std::io::Error::other(format!("Root key rotation failed: {:#}", e)))?;
+        tracing::info!(rewrapped, root_key_id = hierarchy.root_key_id(), "re-wrapped data keys under the current root key");
+    }
+    // Seal the titles and types of records stored in plaintext
+    let sealed = keys::seal_plaintext_record_metadata(hierarchy, &mut conn)
+        .map_err(|e| std::io::Error::other(format!("Sealing record metadata failed: {:#}", e)))?;
+    if sealed > 0 {
+        tracing::info!(sealed, "sealed plaintext record metadata");
+    }
+    drop(conn);
+    let key_hierarchy = web::Data::new(key_hierarchy);
+    let disclosure_issuer = disclosure::DisclosureIssuer::from_config(&config.disclosure)
+        .map_err(|e| std::io::Error::other(format!("Failed to load disclosure issuer key: {:#}", e)))?;
+    let disclosure_issuer = web::Data::new(disclosure_issuer);
+    let audit_ledger = if config.audit.enabled {
+        Some(ledger::Ledger::from_config(&config.audit)
+            .map_err(|e| std::io::Error::other(format!("Failed to configure audit ledger: {:#}", e)))?)
+    } else {
+        None
+    };
+    let audit_ledger = web::Data::new(audit_ledger);
+    let rotation_runner = web::Data::new(rotation::RotationRunner::default());
+    let rate_limiter = web::Data::new(ratelimit::RateLimiter::new(config.rate_limit.clone()));
+    let patient_sessions = web::Data::new(auth::PatientSessions::default());
+    let app_config = web::Data::new(config);
 
-    HttpServer::new(move || {
+    let server_pool = pool.clone();
+    let server_keys = key_hierarchy.clone();
+    let server_runner = rotation_runner.clone();
+    let server_ledger = audit_ledger.clone();
+    let server_blob_store = blob_store.clone();
+    let server_config = app_config.clone();
+
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(middleware::from_fn(audit::record_access))
+            .wrap(middleware::from_fn(ratelimit::rate_limit))
+            .wrap(middleware::from_fn(telemetry::request_telemetry))
             .app_data(web::Data::new(pool.clone()))
-            .app_data(web::Data::new(ipfs_client.clone())) // Add IPFS client to app data
+            .app_data(blob_store.clone())
+            .app_data(app_config.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(patient_sessions.clone())
+            .app_data(key_hierarchy.clone())
+            .app_data(rotation_runner.clone())
+            .app_data(disclosure_issuer.clone())
+            .app_data(audit_ledger.clone())
             .service(
                 web::scope("/patients")
                     .route("", web::post().to(handlers::create_patient))
                     .route("/{patient_id}", web::get().to(handlers::get_patient))
+                    .route("/{patient_id}/login", web::post().to(handlers::login_with_passphrase))
+                    .route("/{patient_id}/sessions/challenge", web::post().to(auth::create_session_challenge))
+                    .route("/{patient_id}/sessions", web::post().to(auth::create_session))
                     .route("/{patient_id}/records", web::post().to(handlers::create_health_record))
                     .route("/{patient_id}/records", web::get().to(handlers::get_health_records_for_patient))
+                    .route("/{patient_id}/records/upgrade-encryption", web::post().to(handlers::upgrade_record_encryption))
+                    .route("/{patient_id}/records/{record_id}/sd-jwt", web::post().to(disclosure::issue_record_credential))
+                    .route("/{patient_id}/records/{record_id}/credentials", web::post().to(credentials::issue_credential))
+                    .route("/{patient_id}/credentials", web::get().to(credentials::list_credentials))
+                    .route("/{patient_id}/credentials/{credential_id}", web::delete().to(credentials::revoke_credential))
+                    .route("/{patient_id}/key-rotations", web::post().to(rotation::start_key_rotation))
+                    .route("/{patient_id}/key-rotations/{job_id}", web::get().to(rotation::get_key_rotation))
+                    .route("/{patient_id}/key-rotations/{job_id}/resume", web::post().to(rotation::resume_key_rotation))
+                    .route("/{patient_id}/key-history", web::get().to(rotation::get_key_history))
+                    .route("/{patient_id}/recovery", web::post().to(recovery::setup_recovery))
+                    .route("/{patient_id}/recovery/guardians/{guardian_id}/share", web::get().to(recovery::get_guardian_share))
+                    .route("/{patient_id}/recovery/guardians/{guardian_id}/challenge", web::post().to(recovery::create_guardian_challenge))
+                    .route("/{patient_id}/recovery/requests", web::post().to(recovery::open_recovery_request))
+                    .route("/{patient_id}/recovery/requests/{request_id}", web::get().to(recovery::get_recovery_request))
+                    .route("/{patient_id}/recovery/requests/{request_id}/verify", web::post().to(recovery::verify_recovery_request))
+                    .route("/{patient_id}/recovery/requests/{request_id}/shares", web::post().to(recovery::submit_share))
+                    .route("/{patient_id}/recovery/requests/{request_id}/complete", web::post().to(recovery::complete_recovery))
+                    .route("/{patient_id}/pre-key", web::put().to(delegation::set_patient_pre_key))
+                    .route("/{patient_id}/delegations", web::post().to(delegation::create_delegation))
+                    .route("/{patient_id}/delegations", web::get().to(delegation::list_delegations))
+                    .route("/{patient_id}/delegations/{delegation_id}", web::delete().to(delegation::revoke_delegation))
+                    .route("/{patient_id}/records/{record_id}/reencrypted", web::get().to(delegation::get_reencrypted_record_key))
+                    .route("/{patient_id}/devices", web::post().to(devices::register_device))
+                    .route("/{patient_id}/devices", web::get().to(devices::list_devices))
+                    .route("/{patient_id}/devices/{device_id}", web::delete().to(devices::revoke_device))
+                    .route("/{patient_id}/devices/{device_id}/record-keys", web::put().to(devices::upload_record_keys))
+                    .route("/{patient_id}/devices/{device_id}/changes", web::get().to(devices::get_changes))
+                    .route("/{patient_id}/audit", web::get().to(audit::list_patient_events))
+            )
+            .service(
+                web::scope("/clinicians")
+                    .route("/me/pre-key", web::put().to(delegation::set_clinician_pre_key))
+                    .route("/{clinician}/pre-key", web::get().to(delegation::get_clinician_pre_key))
+                    .route("/me/signing-keys", web::post().to(signing::register_signing_key))
+                    .route("/{clinician}/signing-keys", web::get().to(signing::list_signing_keys))
+            )
+            .service(
+                web::scope("/disclosures")
+                    .route("/issuer-key", web::get().to(disclosure::get_issuer_key))
+                    .route("/verify", web::post().to(disclosure::verify_presentation))
             )
+            .service(
+                web::scope("/credentials")
+                    .route("/status", web::get().to(credentials::get_status_list))
+                    .route("/verify", web::post().to(credentials::verify_credential))
+            )
+            .service(
+                web::scope("/audit")
+                    .route("/events/{event_id}/proof", web::get().to(audit::get_event_proof))
+                    .route("/batches/{batch_id}", web::get().to(audit::get_batch))
+                    .route("/verify", web::post().to(audit::verify_proof))
+            )
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/metrics", web::get().to(metrics::metrics_endpoint))
             .route("/", web::get().to(hello)) // Keep the hello route for basic testing
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .on_connect(tls::extract_client_cert);
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+
+    let server = match tls_config {
+        Some(tls_config) => server.bind_rustls_0_23(bind_address, tls_config)?,
+        None => server.bind(bind_address)?,
+    };
+    let server = server.run();
+
+    // Pick up key rotations interrupted by a restart once the runtime is up
+    match rotation::resume_interrupted_jobs(&server_pool, &server_keys, &server_runner) {
+        Ok(0) => {}
+        Ok(resumed) => tracing::info!(resumed, "resumed interrupted key rotations"),
+        Err(e) => tracing::error!(error = %e, "failed to resume key rotations"),
+    }
+
+    // Seal and anchor the audit trail in the background
+    audit::spawn_anchoring(server_pool.clone(), server_ledger, &server_config);
+
+    // Keep the IPFS node's pins in line with the records referencing them
+    pins::spawn_reconciler(server_pool, server_blob_store, &server_config);
+
+    server.await
 }
 
 async fn hello() -> impl Responder {
@@ -0,0 +1,202 @@
+use actix_web::{web, HttpResponse, Responder};
+use diesel::dsl::count_star;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+use crate::schema::{admin_users, audit_batches, audit_events, blob_pins, data_keys, devices, health_records, patients};
+use crate::DbPool;
+
+// Process-wide metric registry, exposed on GET /metrics
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_request_duration: HistogramVec,
+    pub ipfs_operation_duration: HistogramVec,
+    pub ipfs_operation_failures: IntCounterVec,
+    pub blob_replica_up: IntGaugeVec,
+    pub blob_replica_failures: IntCounterVec,
+    pub crypto_operation_duration: HistogramVec,
+    pub db_pool_connections: IntGaugeVec,
+    pub db_pool_max_size: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("medirust".to_string()), None)
+            .expect("valid metrics registry");
+
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+            &["method", "route", "status"],
+        ).expect("valid metric");
+        let ipfs_operation_duration = HistogramVec::new(
+            HistogramOpts::new("ipfs_operation_duration_seconds", "Latency of IPFS add/cat calls"),
+            &["operation"],
+        ).expect("valid metric");
+        let ipfs_operation_failures = IntCounterVec::new(
+            Opts::new("ipfs_operation_failures_total", "Failed IPFS add/cat calls"),
+            &["operation"],
+        ).expect("valid metric");
+        let blob_replica_up = IntGaugeVec::new(
+            Opts::new("blob_replica_up", "Whether the last call to each blob replica succeeded"),
+            &["replica"],
+        ).expect("valid metric");
+        let blob_replica_failures = IntCounterVec::new(
+            Opts::new("blob_replica_failures_total", "Failed blob replica calls, including reads failing verification"),
+            &["replica", "operation"],
+        ).expect("valid metric");
+        let crypto_operation_duration = HistogramVec::new(
+            HistogramOpts::new("crypto_operation_duration_seconds", "Time spent in encryption and key operations")
+                .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+            &["operation"],
+        ).expect("valid metric");
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Database pool connections by state"),
+            &["state"],
+        ).expect("valid metric");
+        let db_pool_max_size = IntGauge::new("db_pool_max_size", "Configured database pool size")
+            .expect("valid metric");
+
+        registry.register(Box::new(http_request_duration.clone())).expect("metric registered once");
+        registry.register(Box::new(ipfs_operation_duration.clone())).expect("metric registered once");
+        registry.register(Box::new(ipfs_operation_failures.clone())).expect("metric registered once");
+        registry.register(Box::new(blob_replica_up.clone())).expect("metric registered once");
+        registry.register(Box::new(blob_replica_failures.clone())).expect("metric registered once");
+        registry.register(Box::new(crypto_operation_duration.clone())).expect("metric registered once");
+        registry.register(Box::new(db_pool_connections.clone())).expect("metric registered once");
+        registry.register(Box::new(db_pool_max_size.clone())).expect("metric registered once");
+
+        Metrics {
+            registry,
+            http_request_duration,
+            ipfs_operation_duration,
+            ipfs_operation_failures,
+            blob_replica_up,
+            blob_replica_failures,
+            crypto_operation_duration,
+            db_pool_connections,
+            db_pool_max_size,
+        }
+    }
+
+    // Runs `f`, recording its duration under the given crypto operation label
+    pub fn time_crypto<T>(&self, operation: &str, f: impl FnOnce() -> T) -> T {
+        let _timer = self.crypto_operation_duration.with_label_values(&[operation]).start_timer();
+        f()
+    }
+
+    // Records the outcome of an IPFS call that took `seconds`
+    pub fn observe_ipfs(&self, operation: &str, seconds: f64, succeeded: bool) {
+        self.ipfs_operation_duration.with_label_values(&[operation]).observe(seconds);
+        if !succeeded {
+            self.ipfs_operation_failures.with_label_values(&[operation]).inc();
+        }
+    }
+
+    // Records the outcome of a call to one replica of a replicated blob store
+    pub fn observe_replica(&self, replica: &str, operation: &str, succeeded: bool) {
+        self.blob_replica_up.with_label_values(&[replica]).set(i64::from(succeeded));
+        if !succeeded {
+            self.blob_replica_failures.with_label_values(&[replica, operation]).inc();
+        }
+    }
+
+    // Samples the pool state; called on every scrape so the gauges are current
+    pub fn update_pool_gauges(&self, pool: &DbPool) {
+        let state = pool.state();
+        let idle = i64::from(state.idle_connections);
+        let total = i64::from(state.connections);
+        self.db_pool_connections.with_label_values(&["idle"]).set(idle);
+        self.db_pool_connections.with_label_values(&["in_use"]).set(total - idle);
+        self.db_pool_max_size.set(i64::from(pool.max_size()));
+    }
+
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+// Handler for the Prometheus scrape endpoint
+pub async fn metrics_endpoint(pool: web::Data<DbPool>) -> impl Responder {
+    METRICS.update_pool_gauges(&pool);
+    match METRICS.render() {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error encoding metrics: {:?}", e)),
+    }
+}
+
+fn inventory_gauge(registry: &Registry, name: &str, help: &str, label: &str, counts: &[(String, i64)]) -> anyhow::Result<()> {
+    let gauge = IntGaugeVec::new(Opts::new(name, help), &[label])?;
+    for (value, count) in counts {
+        gauge.with_label_values(&[value.as_str()]).set(*count);
+    }
+    registry.register(Box::new(gauge))?;
+    Ok(())
+}
+
+fn split_count(present: i64, total: i64, (with, without): (&str, &str)) -> Vec<(String, i64)> {
+    vec![(with.to_string(), present), (without.to_string(), total - present)]
+}
+
+// Renders what the vault holds, counted from the database. Unlike the process
+// metrics above this needs no running server; `medirust-admin export-metrics`
+// writes it for a textfile collector.
+pub fn render_inventory(conn: &mut PgConnection) -> anyhow::Result<String> {
+    let registry = Registry::new_custom(Some("medirust".to_string()), None)?;
+
+    let patients = patients::table
+        .group_by(patients::key_algorithm)
+        .select((patients::key_algorithm, count_star()))
+        .load::<(String, i64)>(conn)?;
+    inventory_gauge(&registry, "vault_patients", "Patients by key algorithm", "key_algorithm", &patients)?;
+
+    let records = health_records::table
+        .group_by(health_records::aad_version)
+        .select((health_records::aad_version, count_star()))
+        .load::<(i32, i64)>(conn)?
+        .into_iter()
+        .map(|(version, count)| (version.to_string(), count))
+        .collect::<Vec<_>>();
+    inventory_gauge(&registry, "vault_health_records", "Health records by associated data version", "aad_version", &records)?;
+
+    let pins = blob_pins::table
+        .group_by(blob_pins::status)
+        .select((blob_pins::status, count_star()))
+        .load::<(String, i64)>(conn)?;
+    inventory_gauge(&registry, "vault_blob_pins", "Stored blobs by pin state", "status", &pins)?;
+
+    let data_keys = data_keys::table
+        .group_by(data_keys::root_key_id)
+        .select((data_keys::root_key_id, count_star()))
+        .load::<(String, i64)>(conn)?;
+    inventory_gauge(&registry, "vault_data_keys", "Tenant data keys by wrapping root key", "root_key_id", &data_keys)?;
+
+    let events = audit_events::table.count().get_result::<i64>(conn)?;
+    let sealed = audit_events::table.filter(audit_events::batch_id.is_not_null()).count().get_result::<i64>(conn)?;
+    inventory_gauge(&registry, "vault_audit_events", "Audit events by sealing state", "state", &split_count(sealed, events, ("sealed", "unsealed")))?;
+
+    let batches = audit_batches::table.count().get_result::<i64>(conn)?;
+    let anchored = audit_batches::table.filter(audit_batches::anchor_ref.is_not_null()).count().get_result::<i64>(conn)?;
+    inventory_gauge(&registry, "vault_audit_batches", "Audit batches by anchoring state", "state", &split_count(anchored, batches, ("anchored", "pending")))?;
+
+    let all_devices = devices::table.count().get_result::<i64>(conn)?;
+    let revoked = devices::table.filter(devices::revoked_at.is_not_null()).count().get_result::<i64>(conn)?;
+    inventory_gauge(&registry, "vault_devices", "Patient devices by state", "state", &split_count(revoked, all_devices, ("revoked", "active")))?;
+
+    let admins = admin_users::table.count().get_result::<i64>(conn)?;
+    let disabled = admin_users::table.filter(admin_users::disabled_at.is_not_null()).count().get_result::<i64>(conn)?;
+    inventory_gauge(&registry, "vault_admin_users", "Admin users by state", "state", &split_count(disabled, admins, ("disabled", "enabled")))?;
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8_lossy(&buffer).into_owned())
+}
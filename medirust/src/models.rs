@@ -3,7 +3,11 @@ use chrono::{NaiveDateTime, Utc};
 use uuid::Uuid;
 use diesel::{Queryable, Insertable, AsChangeset, Identifiable, Selectable, Associations};
 
-use crate::schema::{patients, health_records};
+use crate::crypto::{KeyAlgorithm, PatientPublicKey, RECORD_AAD_VERSION};
+use crate::schema::{
+    patients, admin_users, audit_batches, audit_events, blob_pins, health_records, data_keys, clinician_keys, credentials, delegations, device_record_keys, devices, key_rotation_jobs, patient_key_history,
+    recovery_configs, recovery_guardians, recovery_requests, recovery_submissions, signing_keys,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable, AsChangeset, Selectable, Identifiable)]
 #[diesel(table_name = patients)]
@@ -14,6 +18,28 @@ pub struct Patient {
     pub public_key_pem: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    // Private key sealed under a tenant data key; never returned to clients
+    #[serde(skip_serializing, default)]
+    pub escrowed_private_key: Option<String>,
+    #[serde(skip_serializing, default)]
+    pub escrow_key_id: Option<String>,
+    // Private key encrypted under the patient's passphrase, for passphrase unlock
+    #[serde(skip_serializing, default)]
+    pub passphrase_protected_key: Option<String>,
+    // Key-encapsulation backend of the key pair, see KeyAlgorithm
+    pub key_algorithm: String,
+    // Proxy re-encryption public key (base64), set when the patient enables delegation
+    pub pre_public_key: Option<String>,
+}
+
+impl Patient {
+    pub fn algorithm(&self) -> anyhow::Result<KeyAlgorithm> {
+        KeyAlgorithm::from_id(&self.key_algorithm)
+    }
+
+    pub fn public_key(&self) -> anyhow::Result<PatientPublicKey> {
+        PatientPublicKey::from_pem(self.algorithm()?, &self.public_key_pem)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, AsChangeset, Selectable)]
@@ -37,6 +63,32 @@ pub struct HealthRecord {
     pub nonce: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    // Data key wrapping `encrypted_aes_key` and `nonce`; None for records stored before envelope encryption
+    pub data_key_id: Option<String>,
+    // Fingerprint of the patient public key wrapping the AES key; None for legacy records
+    pub key_fingerprint: Option<String>,
+    // Proxy re-encryption capsule and the AES key encrypted under its key;
+    // None unless the patient had a delegation key when the record was stored
+    pub pre_capsule: Option<String>,
+    pub pre_wrapped_key: Option<String>,
+    // Author's detached signature over the content hash and metadata; None for unsigned records
+    pub signature: Option<String>,
+    pub signing_key_id: Option<String>,
+    // Associated data version bound into the content ciphertext, see CryptoUtils::record_aad
+    pub aad_version: i32,
+    // Hex SHA-256 of the encrypted content, checked on every read; None for older records
+    pub content_sha256: Option<String>,
+    // Data key sealing the record type and title into the columns below; None
+    // while they are stored in plaintext, otherwise `record_type` and `title` are empty
+    #[serde(skip_serializing, default)]
+    pub metadata_key_id: Option<String>,
+    #[serde(skip_serializing, default)]
+    pub sealed_record_type: Option<String>,
+    #[serde(skip_serializing, default)]
+    pub sealed_title: Option<String>,
+    // Blind index of `record_type` for filtering sealed records, see keys::record_type_index
+    #[serde(skip_serializing, default)]
+    pub record_type_index: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, AsChangeset, Selectable)]
@@ -50,6 +102,240 @@ pub struct UpdateHealthRecord {
     pub updated_at: NaiveDateTime,
 }
 
+// Tenant data key wrapped under the root key
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset, Selectable, Identifiable)]
+#[diesel(table_name = data_keys)]
+pub struct DataKeyRecord {
+    pub id: String,
+    pub tenant: String,
+    pub root_key_id: String,
+    pub wrapped_key: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+// Batch job re-wrapping a patient's record keys under a new key pair
+#[derive(Debug, Clone, Serialize, Queryable, Insertable, AsChangeset, Selectable, Identifiable, Associations)]
+#[diesel(table_name = key_rotation_jobs)]
+#[diesel(belongs_to(Patient))]
+pub struct KeyRotationJob {
+    pub id: Vec<u8>,
+    pub patient_id: Vec<u8>,
+    pub old_key_fingerprint: String,
+    pub new_key_fingerprint: String,
+    pub status: String,
+    pub total_records: i64,
+    pub rewrapped_records: i64,
+    pub error: Option<String>,
+    #[serde(skip_serializing)]
+    pub old_escrowed_private_key: Option<String>,
+    #[serde(skip_serializing)]
+    pub old_escrow_key_id: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+    // Algorithm of the retired key, needed to unwrap its escrow on resume
+    pub old_key_algorithm: String,
+}
+
+// Retired patient public key, kept for audit
+#[derive(Debug, Clone, Serialize, Queryable, Insertable, Selectable, Identifiable, Associations)]
+#[diesel(table_name = patient_key_history)]
+#[diesel(belongs_to(Patient))]
+pub struct PatientKeyHistory {
+    pub id: Vec<u8>,
+    pub patient_id: Vec<u8>,
+    pub key_fingerprint: String,
+    pub public_key_pem: String,
+    pub rotation_job_id: Option<Vec<u8>>,
+    pub retired_at: NaiveDateTime,
+    pub key_algorithm: String,
+}
+
+// Proxy re-encryption public key registered by a clinician
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset, Selectable)]
+#[diesel(table_name = clinician_keys)]
+pub struct ClinicianKey {
+    pub principal: String,
+    pub pre_public_key: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+// Patient-issued re-encryption key letting the server re-encrypt record keys for a clinician
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset, Selectable)]
+#[diesel(table_name = delegations)]
+pub struct Delegation {
+    pub id: Vec<u8>,
+    pub patient_id: Vec<u8>,
+    pub clinician: String,
+    pub re_key: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+// Clinician public key used to verify record signatures
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = devices)]
+pub struct Device {
+    pub id: Vec<u8>,
+    pub patient_id: Vec<u8>,
+    pub name: String,
+    // Key-encapsulation backend of the device key, see KeyAlgorithm
+    pub key_algorithm: String,
+    pub public_key_pem: String,
+    pub key_fingerprint: String,
+    pub created_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl Device {
+    pub fn public_key(&self) -> anyhow::Result<PatientPublicKey> {
+        PatientPublicKey::from_pem(KeyAlgorithm::from_id(&self.key_algorithm)?, &self.public_key_pem)
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Insertable, Selectable)]
+#[diesel(table_name = device_record_keys)]
+pub struct DeviceRecordKey {
+    pub device_id: Vec<u8>,
+    pub record_id: Vec<u8>,
+    // Record AES key wrapped to the device public key (base64)
+    pub wrapped_key: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = signing_keys)]
+pub struct SigningKey {
+    pub id: String,
+    pub principal: String,
+    pub algorithm: String,
+    pub public_key_pem: String,
+    pub created_at: NaiveDateTime,
+}
+
+// Verifiable credential issued for a record, tracked for revocation
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = credentials)]
+pub struct Credential {
+    pub id: Vec<u8>,
+    pub patient_id: Vec<u8>,
+    pub record_id: Vec<u8>,
+    pub credential_type: String,
+    pub status_index: i32,
+    pub issued_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+// Vault operator, resolved from a client certificate fingerprint
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = admin_users)]
+pub struct AdminUser {
+    pub id: Vec<u8>,
+    pub name: String,
+    pub certificate_fingerprint: String,
+    pub created_at: NaiveDateTime,
+    pub disabled_at: Option<NaiveDateTime>,
+}
+
+// Access to patient data, hashed into the audit Merkle tree once sealed
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = audit_events)]
+pub struct AuditEvent {
+    pub id: Vec<u8>,
+    pub occurred_at: NaiveDateTime,
+    pub actor: String,
+    pub action: String,
+    pub patient_id: Option<Vec<u8>>,
+    pub record_id: Option<Vec<u8>>,
+    pub record_cid: Option<String>,
+    pub status: i32,
+    pub leaf_hash: String,
+    pub batch_id: Option<Vec<u8>>,
+    pub leaf_index: Option<i32>,
+}
+
+// Sealed batch of audit events whose Merkle root is anchored to a ledger
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = audit_batches)]
+pub struct AuditBatch {
+    pub id: Vec<u8>,
+    pub merkle_root: String,
+    pub leaf_count: i32,
+    pub ledger: String,
+    pub anchor_ref: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub anchored_at: Option<NaiveDateTime>,
+}
+
+// Pin state of a stored blob, see the pins module
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = blob_pins)]
+#[diesel(primary_key(cid))]
+pub struct BlobPin {
+    pub cid: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub verified_at: Option<NaiveDateTime>,
+}
+
+// k-of-n social recovery setup for a patient's private key
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset, Selectable)]
+#[diesel(table_name = recovery_configs)]
+pub struct RecoveryConfig {
+    pub patient_id: Vec<u8>,
+    pub threshold: i32,
+    pub share_count: i32,
+    pub key_fingerprint: String,
+    pub encrypted_private_key: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+// Trusted party holding one recovery share
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = recovery_guardians)]
+pub struct RecoveryGuardian {
+    pub id: Vec<u8>,
+    pub patient_id: Vec<u8>,
+    pub name: String,
+    pub public_key_pem: String,
+    pub encrypted_share: String,
+    pub share_hash: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Queryable, Insertable, AsChangeset, Selectable, Identifiable)]
+#[diesel(table_name = recovery_requests)]
+pub struct RecoveryRequest {
+    pub id: Vec<u8>,
+    pub patient_id: Vec<u8>,
+    pub status: String,
+    pub verified_by: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+    // Requester's public key; shares and the rebuilt private key are encrypted to it
+    pub recovery_public_key_pem: Option<String>,
+    // SHA-256 (hex) of the token the requester follows the request with
+    pub access_token_hash: Option<String>,
+}
+
+// Share handed in by a guardian for a recovery request
+#[derive(Debug, Clone, Queryable, Insertable, Selectable, Identifiable)]
+#[diesel(table_name = recovery_submissions)]
+pub struct RecoverySubmission {
+    pub id: Vec<u8>,
+    pub request_id: Vec<u8>,
+    pub guardian_id: Vec<u8>,
+    pub share: String,
+    pub submitted_at: NaiveDateTime,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Insertable, AsChangeset, Selectable)]
 #[diesel(table_name = patients)]
 pub struct NewPatient {
@@ -64,10 +350,20 @@ pub struct NewHealthRecord {
     pub record_type: String,
     pub title: String,
     pub content: String, // The actual health record content (will be encrypted)
+    // Base64 signature over signing::record_signing_payload, with the id of the key that made it
+    #[serde(default)]
+    pub signature: Option<String>,
+    #[serde(default)]
+    pub signing_key_id: Option<String>,
+    // Chosen by the author of a signed record, since the signature covers both
+    #[serde(default)]
+    pub id: Option<Uuid>,
+    #[serde(default)]
+    pub created_at: Option<NaiveDateTime>,
 }
 
 impl NewPatient {
-    pub fn to_patient(self, public_key_pem: String) -> Patient {
+    pub fn to_patient(self, public_key_pem: String, key_algorithm: KeyAlgorithm) -> Patient {
         let now = Utc::now().naive_utc();
         Patient {
             id: Uuid::new_v4().as_bytes().to_vec(),
@@ -76,28 +372,49 @@ impl NewPatient {
             public_key_pem,
             created_at: now,
             updated_at: now,
+            escrowed_private_key: None,
+            escrow_key_id: None,
+            passphrase_protected_key: None,
+            key_algorithm: key_algorithm.id().to_string(),
+            pre_public_key: None,
         }
     }
 }
 
 impl NewHealthRecord {
+    // The id is chosen by the caller because it is bound into the content encryption
     pub fn to_health_record(
         self,
+        id: Vec<u8>,
         ipfs_cid: String,
         encrypted_aes_key: String,
         nonce: String,
+        data_key_id: Option<String>,
+        key_fingerprint: Option<String>,
     ) -> HealthRecord {
         let now = Utc::now().naive_utc();
         HealthRecord {
-            id: Uuid::new_v4().as_bytes().to_vec(),
+            id,
             patient_id: self.patient_id,
             ipfs_cid,
             record_type: self.record_type,
             title: self.title,
             encrypted_aes_key,
             nonce,
-            created_at: now,
+            created_at: self.created_at.unwrap_or(now),
             updated_at: now,
+            data_key_id,
+            key_fingerprint,
+            pre_capsule: None,
+            pre_wrapped_key: None,
+            signature: self.signature,
+            signing_key_id: self.signing_key_id,
+            aad_version: RECORD_AAD_VERSION,
+            content_sha256: None,
+            metadata_key_id: None,
+            sealed_record_type: None,
+            sealed_title: None,
+            record_type_index: None,
         }
     }
 }
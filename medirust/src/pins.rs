@@ -0,0 +1,235 @@
+// Pin tracking for record blobs. Every CID a health record references must
+// stay pinned on the IPFS node, or its garbage collection may drop the
+// content. The blob_pins table records what the node was last seen to hold;
+// a periodic reconciliation re-pins anything that went missing and unpins
+// blobs no record references anymore.
+
+use actix_web::{rt, web};
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use diesel::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use crate::blobstore::BlobStore;
+use crate::config::AppConfig;
+use crate::models::BlobPin;
+use crate::schema::{blob_pins, health_records};
+use crate::DbPool;
+
+pub const PENDING: &str = "pending";
+pub const PINNED: &str = "pinned";
+pub const MISSING: &str = "missing";
+pub const RELEASED: &str = "released";
+
+// Pinning a CID the node has to fetch from the network can take a while
+const PIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Records the pin state of a blob, creating its row on first sight
+pub fn mark(conn: &mut PgConnection, cid: &str, status: &str, error: Option<String>) -> QueryResult<()> {
+    let now = Utc::now().naive_utc();
+    let row = blob_pins::table.find(cid);
+    let updated = if status == PINNED {
+        diesel::update(row)
+            .set((blob_pins::status.eq(status), blob_pins::error.eq(&error), blob_pins::updated_at.eq(now), blob_pins::verified_at.eq(now)))
+            .execute(conn)?
+    } else {
+        diesel::update(row)
+            .set((blob_pins::status.eq(status), blob_pins::error.eq(&error), blob_pins::updated_at.eq(now)))
+            .execute(conn)?
+    };
+    if updated == 0 {
+        diesel::insert_into(blob_pins::table)
+            .values(&BlobPin {
+                cid: cid.to_string(),
+                status: status.to_string(),
+                error,
+                created_at: now,
+                updated_at: now,
+                verified_at: (status == PINNED).then_some(now),
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+async fn mark_blocking(pool: &DbPool, cid: &str, status: &'static str, error: Option<String>) -> Result<()> {
+    let pool = pool.clone();
+    let cid = cid.to_string();
+    web::block(move || -> Result<()> {
+        let mut conn = pool.get()?;
+        Ok(mark(&mut conn, &cid, status, error)?)
+    })
+    .await?
+}
+
+async fn referenced_cids(pool: &DbPool) -> Result<HashSet<String>> {
+    let pool = pool.clone();
+    web::block(move || -> Result<HashSet<String>> {
+        let mut conn = pool.get()?;
+        Ok(health_records::table
+            .select(health_records::ipfs_cid)
+            .distinct()
+            .load::<String>(&mut conn)?
+            .into_iter()
+            .collect())
+    })
+    .await?
+}
+
+// Unpins a blob once no health record references it, e.g. after a record
+// was re-encrypted under a new CID. Returns whether it was released.
+pub async fn release(pool: &DbPool, store: &BlobStore, cid: &str) -> Result<bool> {
+    release_unreferenced(pool, store, cid, true).await
+}
+
+// Checks references right before unpinning, so a record written since the
+// caller last looked keeps its blob. Blobs the node no longer holds are only
+// marked released.
+async fn release_unreferenced(pool: &DbPool, store: &BlobStore, cid: &str, pinned: bool) -> Result<bool> {
+    let lookup_pool = pool.clone();
+    let lookup_cid = cid.to_string();
+    let referenced = web::block(move || -> Result<bool> {
+        let mut conn = lookup_pool.get()?;
+        Ok(diesel::select(diesel::dsl::exists(
+            health_records::table.filter(health_records::ipfs_cid.eq(&lookup_cid)),
+        ))
+        .get_result(&mut conn)?)
+    })
+    .await??;
+    if referenced {
+        return Ok(false);
+    }
+    if pinned {
+        store.unpin(cid).await?;
+    }
+    mark_blocking(pool, cid, RELEASED, None).await?;
+    Ok(true)
+}
+
+async fn pin_with_timeout(store: &BlobStore, cid: &str) -> Result<()> {
+    rt::time::timeout(PIN_TIMEOUT, store.pin(cid))
+        .await
+        .map_err(|_| anyhow!("Timed out pinning blob {}", cid))?
+}
+
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    pub checked: usize,
+    pub repinned: usize,
+    pub missing: usize,
+    pub released: usize,
+}
+
+// Compares the node's pins with the blobs health records reference: pins
+// what the node lost, marks what it cannot find as missing, and unpins
+// tracked blobs nothing references. Rows are only written when a blob's
+// state changes, so verified_at is when its pin was last established.
+pub async fn reconcile(pool: &DbPool, store: &BlobStore) -> Result<ReconcileReport> {
+    // Tracked pins are read before references: a record written in between
+    // then shows up as referenced rather than as an orphan to release
+    let tracked_pool = pool.clone();
+    let tracked = web::block(move || -> Result<HashMap<String, String>> {
+        let mut conn = tracked_pool.get()?;
+        Ok(blob_pins::table
+            .select((blob_pins::cid, blob_pins::status))
+            .load::<(String, String)>(&mut conn)?
+            .into_iter()
+            .collect())
+    })
+    .await??;
+    let pinned = store.pinned().await?;
+    let referenced = referenced_cids(pool).await?;
+    let mut report = ReconcileReport::default();
+
+    for cid in &referenced {
+        report.checked += 1;
+        let tracked_as = tracked.get(cid).map(String::as_str);
+        let (status, error) = match pinned.contains(cid) {
+            true => (PINNED, None),
+            false => match pin_with_timeout(store, cid).await {
+                Ok(()) => {
+                    tracing::warn!(cid = %cid, "re-pinned blob the node had lost its pin for");
+                    report.repinned += 1;
+                    (PINNED, None)
+                }
+                Err(e) => {
+                    tracing::error!(cid = %cid, error = %e, "referenced blob is missing from the node");
+                    report.missing += 1;
+                    (MISSING, Some(e.to_string()))
+                }
+            },
+        };
+        if tracked_as != Some(status) {
+            mark_blocking(pool, cid, status, error).await?;
+        }
+    }
+
+    let orphans = tracked.into_iter().filter(|(cid, status)| status != RELEASED && !referenced.contains(cid));
+    for (cid, _) in orphans {
+        if release_unreferenced(pool, store, &cid, pinned.contains(&cid)).await? {
+            report.released += 1;
+        }
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub repaired: usize,
+    pub failed: usize,
+}
+
+// Re-uploads referenced blobs the primary store has lost from a secondary
+// one. Content is put back unchanged, so it lands under the same CID.
+pub async fn repair(pool: &DbPool, store: &BlobStore, secondary: &BlobStore) -> Result<RepairReport> {
+    let pinned = store.pinned().await?;
+    let mut report = RepairReport::default();
+    for cid in referenced_cids(pool).await? {
+        if pinned.contains(&cid) {
+            continue;
+        }
+        match copy_blob(store, secondary, &cid).await {
+            Ok(()) => {
+                tracing::info!(cid = %cid, from = secondary.id(), "repaired missing blob");
+                mark_blocking(pool, &cid, PINNED, None).await?;
+                report.repaired += 1;
+            }
+            Err(e) => {
+                tracing::error!(cid = %cid, error = %e, "failed to repair missing blob");
+                mark_blocking(pool, &cid, MISSING, Some(e.to_string())).await?;
+                report.failed += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn copy_blob(store: &BlobStore, secondary: &BlobStore, cid: &str) -> Result<()> {
+    let content = secondary.get(cid).await?;
+    store.put_as(cid, content).await?;
+    pin_with_timeout(store, cid).await
+}
+
+// Runs `reconcile` every `blob_store.reconcile_interval_secs` on the server runtime
+pub fn spawn_reconciler(pool: DbPool, store: web::Data<BlobStore>, config: &AppConfig) {
+    let interval_secs = config.blob_store.reconcile_interval_secs;
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            match reconcile(&pool, &store).await {
+                Ok(report) if report.repinned + report.missing + report.released == 0 => {}
+                Ok(report) => tracing::info!(
+                    checked = report.checked,
+                    repinned = report.repinned,
+                    missing = report.missing,
+                    released = report.released,
+                    "reconciled blob pins"
+                ),
+                Err(e) => tracing::error!(error = %e, "failed to reconcile blob pins"),
+            }
+        }
+    });
+}
@@ -0,0 +1,245 @@
+use aes_gcm::aead::OsRng;
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+// Single-hop proxy re-encryption KEM in the style of Umbral (threshold 1),
+// on the Ristretto group.
+//
+// A capsule (E, V, s) encapsulates K = KDF(pk_A^(r+u)) with E = g^r, V = g^u.
+// The delegator A issues rk = a / d for delegatee B, where d is derived from
+// an ephemeral X_A = g^x and the shared point pk_B^x. The proxy computes
+// (E^rk, V^rk), which is useless without d; B recovers d from X_A^b and
+// K = KDF((E^rk * V^rk)^d). The proxy never holds a, b, x or K.
+//
+// Key generation, re-key generation and decapsulation run on the patient's
+// and clinician's devices; the server only encapsulates and re-encrypts.
+
+const POINT_SIZE: usize = 32;
+const SCALAR_SIZE: usize = 32;
+pub const CAPSULE_SIZE: usize = 2 * POINT_SIZE + SCALAR_SIZE;
+pub const RE_KEY_SIZE: usize = SCALAR_SIZE + POINT_SIZE;
+pub const KEY_SIZE: usize = 32;
+
+fn hash_to_scalar(domain: &[u8], parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    hasher.update(domain);
+    for part in parts {
+        hasher.update(part);
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+fn random_scalar() -> Scalar {
+    let mut bytes = Zeroizing::new([0u8; 64]);
+    OsRng.fill_bytes(bytes.as_mut_slice());
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+fn decode_point(bytes: &[u8]) -> Result<RistrettoPoint> {
+    CompressedRistretto::from_slice(bytes)
+        .map_err(|_| anyhow!("Invalid point encoding"))?
+        .decompress()
+        .ok_or_else(|| anyhow!("Invalid point encoding"))
+}
+
+fn decode_scalar(bytes: &[u8]) -> Result<Scalar> {
+    let bytes: [u8; SCALAR_SIZE] = bytes.try_into().map_err(|_| anyhow!("Invalid scalar length"))?;
+    Option::from(Scalar::from_canonical_bytes(bytes)).ok_or_else(|| anyhow!("Invalid scalar encoding"))
+}
+
+fn derive_key(shared: &RistrettoPoint) -> Result<Zeroizing<Vec<u8>>> {
+    let mut key = Zeroizing::new(vec![0u8; KEY_SIZE]);
+    Hkdf::<Sha256>::new(None, shared.compress().as_bytes())
+        .expand(b"medirust-pre-key", &mut key)
+        .map_err(|e| anyhow!("HKDF expand failed: {}", e))?;
+    Ok(key)
+}
+
+// Shared-secret scalar d between the re-key precursor and the delegatee
+fn delegatee_scalar(precursor: &RistrettoPoint, delegatee: &PublicKey, shared: &RistrettoPoint) -> Scalar {
+    hash_to_scalar(b"medirust-pre-delegation", &[
+        precursor.compress().as_bytes(),
+        delegatee.0.compress().as_bytes(),
+        shared.compress().as_bytes(),
+    ])
+}
+
+pub struct SecretKey(Scalar);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        SecretKey(random_scalar())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        decode_scalar(bytes).map(SecretKey)
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<[u8; SCALAR_SIZE]> {
+        Zeroizing::new(self.0.to_bytes())
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(RISTRETTO_BASEPOINT_POINT * self.0)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey(RistrettoPoint);
+
+impl PublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        decode_point(bytes).map(PublicKey)
+    }
+
+    pub fn to_bytes(self) -> [u8; POINT_SIZE] {
+        self.0.compress().to_bytes()
+    }
+}
+
+// Encapsulated symmetric key, re-encryptable by a proxy holding a re-key
+pub struct Capsule {
+    e: RistrettoPoint,
+    v: RistrettoPoint,
+    s: Scalar,
+}
+
+impl Capsule {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CAPSULE_SIZE);
+        bytes.extend_from_slice(self.e.compress().as_bytes());
+        bytes.extend_from_slice(self.v.compress().as_bytes());
+        bytes.extend_from_slice(self.s.as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != CAPSULE_SIZE {
+            return Err(anyhow!("Capsule must be {} bytes", CAPSULE_SIZE));
+        }
+        let capsule = Capsule {
+            e: decode_point(&bytes[..POINT_SIZE])?,
+            v: decode_point(&bytes[POINT_SIZE..2 * POINT_SIZE])?,
+            s: decode_scalar(&bytes[2 * POINT_SIZE..])?,
+        };
+        capsule.verify()?;
+        Ok(capsule)
+    }
+
+    fn challenge(e: &RistrettoPoint, v: &RistrettoPoint) -> Scalar {
+        hash_to_scalar(b"medirust-pre-capsule", &[e.compress().as_bytes(), v.compress().as_bytes()])
+    }
+
+    // Checks g^s = V * E^H(E, V), so a proxy never transforms a forged capsule
+    fn verify(&self) -> Result<()> {
+        let h = Self::challenge(&self.e, &self.v);
+        if RISTRETTO_BASEPOINT_POINT * self.s != self.v + self.e * h {
+            return Err(anyhow!("Capsule failed verification"));
+        }
+        Ok(())
+    }
+}
+
+// Re-encryption key from a delegator to one delegatee
+pub struct ReKey {
+    rk: Scalar,
+    precursor: RistrettoPoint,
+}
+
+impl ReKey {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(RE_KEY_SIZE);
+        bytes.extend_from_slice(self.rk.as_bytes());
+        bytes.extend_from_slice(self.precursor.compress().as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != RE_KEY_SIZE {
+            return Err(anyhow!("Re-encryption key must be {} bytes", RE_KEY_SIZE));
+        }
+        Ok(ReKey {
+            rk: decode_scalar(&bytes[..SCALAR_SIZE])?,
+            precursor: decode_point(&bytes[SCALAR_SIZE..])?,
+        })
+    }
+}
+
+// Capsule transformed for the delegatee: (E', V', X_A)
+pub struct CapsuleFrag {
+    e: RistrettoPoint,
+    v: RistrettoPoint,
+    precursor: RistrettoPoint,
+}
+
+impl CapsuleFrag {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(3 * POINT_SIZE);
+        bytes.extend_from_slice(self.e.compress().as_bytes());
+        bytes.extend_from_slice(self.v.compress().as_bytes());
+        bytes.extend_from_slice(self.precursor.compress().as_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 3 * POINT_SIZE {
+            return Err(anyhow!("Capsule fragment must be {} bytes", 3 * POINT_SIZE));
+        }
+        Ok(CapsuleFrag {
+            e: decode_point(&bytes[..POINT_SIZE])?,
+            v: decode_point(&bytes[POINT_SIZE..2 * POINT_SIZE])?,
+            precursor: decode_point(&bytes[2 * POINT_SIZE..])?,
+        })
+    }
+}
+
+// Creates a capsule for the delegator's public key and the symmetric key it carries
+pub fn encapsulate(delegator: &PublicKey) -> Result<(Capsule, Zeroizing<Vec<u8>>)> {
+    let r = Zeroizing::new(random_scalar());
+    let u = Zeroizing::new(random_scalar());
+    let e = RISTRETTO_BASEPOINT_POINT * *r;
+    let v = RISTRETTO_BASEPOINT_POINT * *u;
+    let s = *u + *r * Capsule::challenge(&e, &v);
+    let key = derive_key(&(delegator.0 * (*r + *u)))?;
+    Ok((Capsule { e, v, s }, key))
+}
+
+// Issues the re-encryption key; run by the delegator, who holds the secret key
+pub fn generate_re_key(delegator: &SecretKey, delegatee: &PublicKey) -> Result<ReKey> {
+    let x = Zeroizing::new(random_scalar());
+    let precursor = RISTRETTO_BASEPOINT_POINT * *x;
+    let d = delegatee_scalar(&precursor, delegatee, &(delegatee.0 * *x));
+    if d == Scalar::ZERO {
+        return Err(anyhow!("Degenerate re-encryption key"));
+    }
+    Ok(ReKey { rk: delegator.0 * d.invert(), precursor })
+}
+
+// Proxy step: transforms a capsule for the delegatee without learning the key
+pub fn reencrypt(capsule: &Capsule, re_key: &ReKey) -> Result<CapsuleFrag> {
+    capsule.verify()?;
+    Ok(CapsuleFrag {
+        e: capsule.e * re_key.rk,
+        v: capsule.v * re_key.rk,
+        precursor: re_key.precursor,
+    })
+}
+
+// Delegator side: opens one of their own capsules, K = KDF((E * V)^a)
+pub fn decapsulate(delegator: &SecretKey, capsule: &Capsule) -> Result<Zeroizing<Vec<u8>>> {
+    capsule.verify()?;
+    derive_key(&((capsule.e + capsule.v) * delegator.0))
+}
+
+// Delegatee side: recovers d from X_A^b and K = KDF((E' * V')^d)
+pub fn decapsulate_frag(delegatee: &SecretKey, capsule_frag: &CapsuleFrag) -> Result<Zeroizing<Vec<u8>>> {
+    let shared = capsule_frag.precursor * delegatee.0;
+    let d = delegatee_scalar(&capsule_frag.precursor, &delegatee.public_key(), &shared);
+    derive_key(&((capsule_frag.e + capsule_frag.v) * d))
+}
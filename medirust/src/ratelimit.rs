@@ -0,0 +1,281 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ResourceDef, ServiceRequest, ServiceResponse},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    web, HttpResponse,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::config::{AppConfig, BucketConfig, RateLimitConfig};
+use crate::tls::ClientCertificate;
+
+// Number of tracked keys above which idle entries are pruned
+const PRUNE_THRESHOLD: usize = 10_000;
+
+// Separately budgeted classes of expensive or sensitive requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    KeyGeneration,
+    RecordRead,
+    RecordWrite,
+    Login,
+    Recovery,
+}
+
+const CHALLENGE_ROUTE: &str = "/patients/{patient_id}/sessions/challenge";
+
+impl Budget {
+    // Maps a route pattern to its budget. Requests outside these classes are not limited.
+    pub fn for_route(method: &Method, pattern: &str) -> Option<Self> {
+        match (method.as_str(), pattern) {
+            ("POST", "/patients") => Some(Budget::KeyGeneration),
+            ("GET", "/patients/{patient_id}/records") => Some(Budget::RecordRead),
+            ("GET", "/patients/{patient_id}/records/{record_id}/reencrypted") => Some(Budget::RecordRead),
+            ("GET", "/patients/{patient_id}/devices/{device_id}/changes") => Some(Budget::RecordRead),
+            ("POST", "/patients/{patient_id}/records") => Some(Budget::RecordWrite),
+            ("POST", "/patients/{patient_id}/records/upgrade-encryption") => Some(Budget::RecordWrite),
+            ("POST", "/patients/{patient_id}/login") => Some(Budget::Login),
+            ("POST", CHALLENGE_ROUTE) => Some(Budget::Login),
+            ("POST", "/patients/{patient_id}/sessions") => Some(Budget::Login),
+            ("POST", "/patients/{patient_id}/recovery/requests") => Some(Budget::Recovery),
+            ("POST", "/patients/{patient_id}/recovery/guardians/{guardian_id}/challenge") => Some(Budget::Recovery),
+            ("POST", "/patients/{patient_id}/recovery/requests/{request_id}/shares") => Some(Budget::Recovery),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Budget::KeyGeneration => "key_generation",
+            Budget::RecordRead => "record_read",
+            Budget::RecordWrite => "record_write",
+            Budget::Login => "login",
+            Budget::Recovery => "recovery",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(config: BucketConfig, now: Instant) -> Self {
+        TokenBucket { tokens: f64::from(config.capacity), updated: now }
+    }
+
+    fn refill(&mut self, config: BucketConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let rate = f64::from(config.refill_per_minute) / 60.0;
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(config.capacity));
+        self.updated = now;
+    }
+
+    // Time until one token is available
+    fn wait_time(&self, config: BucketConfig) -> Duration {
+        let rate = f64::from(config.refill_per_minute) / 60.0;
+        Duration::from_secs_f64(((1.0 - self.tokens) / rate).max(0.0))
+    }
+}
+
+#[derive(Debug, Default)]
+struct FailureRecord {
+    failures: Vec<Instant>,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    buckets: HashMap<(Budget, String), TokenBucket>,
+    failures: HashMap<String, FailureRecord>,
+}
+
+// In-memory token-bucket limiter with temporary account lockout
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            state: Mutex::new(LimiterState::default()),
+        }
+    }
+
+    fn bucket_config(&self, budget: Budget) -> BucketConfig {
+        match budget {
+            Budget::KeyGeneration => self.config.key_generation,
+            Budget::RecordRead => self.config.record_read,
+            Budget::RecordWrite => self.config.record_write,
+            Budget::Login => self.config.login,
+            Budget::Recovery => self.config.recovery,
+        }
+    }
+
+    // Takes one token from every key's bucket, or none if any is exhausted.
+    // On rejection returns how long the caller should wait.
+    pub fn check(&self, budget: Budget, keys: &[String]) -> Result<(), Duration> {
+        let config = self.bucket_config(budget);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut wait = Duration::ZERO;
+        for key in keys {
+            let bucket = state.buckets
+                .entry((budget, key.clone()))
+                .or_insert_with(|| TokenBucket::full(config, now));
+            bucket.refill(config, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(bucket.wait_time(config));
+            }
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for key in keys {
+            if let Some(bucket) = state.buckets.get_mut(&(budget, key.clone())) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        if state.buckets.len() > PRUNE_THRESHOLD {
+            // A bucket that has refilled completely is equivalent to a fresh one
+            state.buckets.retain(|(budget, _), bucket| {
+                let mut refreshed = *bucket;
+                refreshed.refill(self.bucket_config(*budget), now);
+                refreshed.tokens < f64::from(self.bucket_config(*budget).capacity)
+            });
+        }
+        Ok(())
+    }
+
+    // Remaining lockout time for the account, if it is locked
+    pub fn locked_for(&self, account: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.failures
+            .get(account)
+            .and_then(|record| record.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    // Records an authentication failure, locking the account once the
+    // threshold is reached within the window
+    pub fn record_auth_failure(&self, account: &str) {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.lockout_window_secs);
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let record = state.failures.entry(account.to_string()).or_default();
+        record.failures.retain(|at| now.saturating_duration_since(*at) < window);
+        record.failures.push(now);
+        if record.failures.len() >= self.config.lockout_threshold as usize {
+            record.failures.clear();
+            record.locked_until = Some(now + Duration::from_secs(self.config.lockout_duration_secs));
+            // The account key can name a patient, so only its kind is logged
+            let kind = account.split(':').next().unwrap_or_default();
+            tracing::warn!(account_kind = kind, "account locked after repeated authentication failures");
+        }
+
+        if state.failures.len() > PRUNE_THRESHOLD {
+            state.failures.retain(|_, r| {
+                r.locked_until.is_some_and(|until| until > now)
+                    || r.failures.iter().any(|at| now.saturating_duration_since(*at) < window)
+            });
+        }
+    }
+
+    pub fn clear_auth_failures(&self, account: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(record) = state.failures.get_mut(account) {
+            record.failures.clear();
+        }
+    }
+}
+
+// Middleware enforcing the per-route budgets and account lockout
+pub async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+    let pattern = req.match_pattern();
+    let budget = pattern.as_deref().and_then(|pattern| Budget::for_route(req.method(), pattern));
+    // Handing out a session challenge proves nothing, so it leaves failures standing
+    let proves_identity = budget == Some(Budget::Login) && pattern.as_deref() != Some(CHALLENGE_ROUTE);
+
+    let (limiter, budget) = match (limiter, budget) {
+        (Some(limiter), Some(budget)) if limiter.config.enabled => (limiter, budget),
+        _ => return next.call(req).await.map(ServiceResponse::map_into_left_body),
+    };
+
+    let ip_key = req.peer_addr()
+        .map(|addr| format!("ip:{}", addr.ip()))
+        .unwrap_or_else(|| "ip:unknown".to_string());
+    let principal = req.app_data::<web::Data<AppConfig>>()
+        .zip(req.conn_data::<ClientCertificate>())
+        .and_then(|(config, cert)| Principal::from_client_certificate(config, cert));
+    let account = account_key(&req, pattern.as_deref(), budget, principal.as_ref(), &ip_key);
+
+    if let Some(remaining) = limiter.locked_for(&account) {
+        return Ok(too_many_requests(req, remaining, "Account temporarily locked after repeated authentication failures"));
+    }
+
+    let mut keys = vec![ip_key];
+    if let Some(principal) = &principal {
+        keys.push(format!("principal:{}", principal.name));
+    }
+    if let Err(wait) = limiter.check(budget, &keys) {
+        tracing::warn!(budget = budget.name(), "rate limit exceeded");
+        return Ok(too_many_requests(req, wait, "Rate limit exceeded"));
+    }
+
+    let res = next.call(req).await?;
+    match res.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => limiter.record_auth_failure(&account),
+        status if status.is_success() && proves_identity => limiter.clear_auth_failures(&account),
+        _ => {}
+    }
+    Ok(res.map_into_left_body())
+}
+
+// The account a failed authentication is charged to. Logins are charged to
+// the patient together with the client, its certificate else its address, so
+// nobody can lock a patient out from elsewhere; guessing from many addresses
+// is held back by the per-address login budget. Other requests are charged to
+// the resolved principal, else the certificate, else the address.
+fn account_key(req: &ServiceRequest, pattern: Option<&str>, budget: Budget, principal: Option<&Principal>, ip_key: &str) -> String {
+    let cert_key = req.conn_data::<ClientCertificate>()
+        .map(|cert| format!("cert:{}", cert.fingerprint()));
+    if let (Budget::Login, Some(pattern)) = (budget, pattern) {
+        // Path parameters are only filled in once the request is routed
+        let mut path = req.match_info().clone();
+        if ResourceDef::new(pattern).capture_match_info(&mut path)
+            && let Some(patient_id) = path.get("patient_id").and_then(|id| Uuid::parse_str(id).ok())
+        {
+            return format!("patient:{}|{}", patient_id, cert_key.as_deref().unwrap_or(ip_key));
+        }
+    }
+    if let Some(principal) = principal {
+        return format!("principal:{}", principal.name);
+    }
+    cert_key.unwrap_or_else(|| ip_key.to_string())
+}
+
+fn too_many_requests<B>(req: ServiceRequest, wait: Duration, message: &str) -> ServiceResponse<EitherBody<B>> {
+    // Round up so clients never retry before the budget has refilled
+    let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let response = HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_after.max(1).to_string()))
+        .body(message.to_string());
+    req.into_response(response).map_into_right_body()
+}
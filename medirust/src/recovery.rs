@@ -0,0 +1,727 @@
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sharks::{Share, Sharks};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+use crate::auth::{self, AuthenticatedPatient, PatientSessions, Principal, PrincipalKind};
+use crate::config::AppConfig;
+use crate::crypto::{CryptoUtils, KeyAlgorithm, PatientPrivateKey, PatientPublicKey};
+use crate::keys::{self, KeyHierarchy};
+use crate::models::{Patient, RecoveryConfig, RecoveryGuardian, RecoveryRequest, RecoverySubmission};
+use crate::schema::{patients, recovery_configs, recovery_guardians, recovery_requests, recovery_submissions};
+use crate::DbPool;
+
+// How long guardians have to hand in their shares once a request is opened
+const REQUEST_LIFETIME_HOURS: i64 = 72;
+const MAX_GUARDIANS: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+pub const STATUS_PENDING_VERIFICATION: &str = "pending_verification";
+pub const STATUS_VERIFIED: &str = "verified";
+pub const STATUS_COMPLETED: &str = "completed";
+
+#[derive(Debug, Deserialize)]
+pub struct GuardianInput {
+    pub name: String,
+    pub public_key_pem: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetupRecoveryRequest {
+    // Number of guardians needed to recover the key
+    pub threshold: u8,
+    pub guardians: Vec<GuardianInput>,
+    // Patient private key to protect; defaults to the server-held escrow
+    pub private_key_pem: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OpenRecoveryRequest {
+    // Fresh public key generated by the requester for this request only
+    pub recovery_public_key_pem: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubmitShareRequest {
+    pub guardian_id: String,
+    // Guardian challenge, unwrapped with the guardian's private key (base64)
+    pub challenge_id: String,
+    pub challenge: String,
+    // Guardian's share, wrapped on their side to the request's recovery public key (base64)
+    pub wrapped_share: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnwrappedShare {
+    pub guardian_id: String,
+    // Submitted share, unwrapped with the request's recovery private key (base64)
+    pub share: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteRecoveryRequest {
+    pub shares: Vec<UnwrappedShare>,
+}
+
+// Reasons a recovery call is refused, mapped to HTTP responses
+#[derive(Debug)]
+enum Rejection {
+    NotFound(&'static str),
+    Conflict(&'static str),
+    Unprocessable(&'static str),
+}
+
+impl Rejection {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Rejection::NotFound(msg) => HttpResponse::NotFound().body(msg),
+            Rejection::Conflict(msg) => HttpResponse::Conflict().body(msg),
+            Rejection::Unprocessable(msg) => HttpResponse::UnprocessableEntity().body(msg),
+        }
+    }
+}
+
+// Handler to split a patient's private key among guardians.
+//
+// A fresh recovery key encrypts the private key; the recovery key is split
+// k-of-n and each share is encrypted to one guardian. The server keeps only
+// the encrypted private key and the encrypted shares.
+pub async fn setup_recovery(
+    pool: web::Data<DbPool>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+    request: web::Json<SetupRecoveryRequest>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let request = request.into_inner();
+    let share_count = request.guardians.len();
+    if request.threshold < 2 || usize::from(request.threshold) > share_count || share_count > MAX_GUARDIANS {
+        return HttpResponse::BadRequest().body(format!(
+            "threshold must be at least 2 and at most the number of guardians (at most {})", MAX_GUARDIANS
+        ));
+    }
+    let mut guardian_keys = Vec::with_capacity(share_count);
+    for guardian in &request.guardians {
+        match PatientPublicKey::from_pem(KeyAlgorithm::detect(&guardian.public_key_pem), &guardian.public_key_pem) {
+            Ok(key) => guardian_keys.push(key),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid public key for guardian {}: {:?}", guardian.name, e)),
+        }
+    }
+    let supplied_private_key_pem = request.private_key_pem.map(Zeroizing::new);
+
+    match web::block(move || -> Result<Result<Vec<RecoveryGuardian>, Rejection>> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            let patient = match find_patient(conn, &patient_id_bytes)? {
+                Some(patient) => patient,
+                None => return Ok(Err(Rejection::NotFound("Patient not found"))),
+            };
+            let public_key = patient.public_key()?;
+            let private_key = match supplied_private_key_pem {
+                Some(pem) => PatientPrivateKey::from_pem(KeyAlgorithm::detect(&pem), &pem)?,
+                None => match keys::escrowed_private_key(keys.get_ref().as_ref(), conn, &patient)? {
+                    Some(key) => key,
+                    None => return Ok(Err(Rejection::Unprocessable(
+                        "private_key_pem is required because the server does not hold this patient's key",
+                    ))),
+                },
+            };
+            if private_key.public_key() != public_key {
+                return Ok(Err(Rejection::Unprocessable("private key does not match the patient's public key")));
+            }
+
+            let recovery_key = Zeroizing::new(CryptoUtils::generate_aes_key());
+            let private_key_pem = Zeroizing::new(private_key.to_pem()?);
+            let (ciphertext, nonce) = CryptoUtils::encrypt_data(private_key_pem.as_bytes(), &recovery_key)?;
+            let mut encrypted_private_key = nonce;
+            encrypted_private_key.extend_from_slice(&ciphertext);
+
+            let now = Utc::now().naive_utc();
+            let config = RecoveryConfig {
+                patient_id: patient_id_bytes.clone(),
+                threshold: i32::from(request.threshold),
+                share_count: share_count as i32,
+                key_fingerprint: public_key.fingerprint()?,
+                encrypted_private_key: CryptoUtils::encode_base64(&encrypted_private_key),
+                created_at: now,
+                updated_at: now,
+            };
+
+            // A new setup replaces the previous guardians and invalidates open requests
+            diesel::delete(recovery_requests::table.filter(recovery_requests::patient_id.eq(&patient_id_bytes))).execute(conn)?;
+            diesel::delete(recovery_guardians::table.filter(recovery_guardians::patient_id.eq(&patient_id_bytes))).execute(conn)?;
+            diesel::delete(recovery_configs::table.find(&patient_id_bytes)).execute(conn)?;
+            diesel::insert_into(recovery_configs::table).values(&config).execute(conn)?;
+
+            let shares = Sharks(request.threshold).dealer(&recovery_key).take(share_count);
+            let mut guardians = Vec::with_capacity(share_count);
+            for ((input, public_key), share) in request.guardians.iter().zip(&guardian_keys).zip(shares) {
+                let share_bytes = Zeroizing::new(Vec::from(&share));
+                let guardian = RecoveryGuardian {
+                    id: Uuid::new_v4().as_bytes().to_vec(),
+                    patient_id: patient_id_bytes.clone(),
+                    name: input.name.clone(),
+                    public_key_pem: input.public_key_pem.clone(),
+                    encrypted_share: CryptoUtils::encode_base64(&public_key.wrap_key(&share_bytes)?),
+                    share_hash: hex_sha256(&share_bytes),
+                    created_at: now,
+                };
+                diesel::insert_into(recovery_guardians::table).values(&guardian).execute(conn)?;
+                guardians.push(guardian);
+            }
+            Ok(Ok(guardians))
+        })
+    })
+    .await
+    {
+        Ok(Ok(Ok(guardians))) => HttpResponse::Created().json(json!({
+            "threshold": request.threshold,
+            "guardians": guardians.iter().map(guardian_json).collect::<Vec<_>>(),
+        })),
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error setting up recovery: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for the patient to fetch a guardian's encrypted share again, to
+// hand it over
+pub async fn get_guardian_share(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id_bytes, guardian_id_bytes) = match parse_guardian_path(path.into_inner()) {
+        Some(ids) => ids,
+        None => return HttpResponse::BadRequest().body("Invalid patient or guardian ID"),
+    };
+
+    match web::block(move || -> Result<Option<RecoveryGuardian>> {
+        let mut conn = pool.get()?;
+        find_guardian(&mut conn, &patient_id_bytes, &guardian_id_bytes)
+    })
+    .await
+    {
+        Ok(Ok(Some(guardian))) => HttpResponse::Ok().json(json!({
+            "guardian_id": Uuid::from_slice(&guardian.id).unwrap_or_default().to_string(),
+            "encrypted_share": guardian.encrypted_share,
+        })),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Guardian not found"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error getting guardian share: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler issuing a guardian a challenge wrapped to their registered public
+// key. Answering it with their share proves the hand-in comes from them.
+pub async fn create_guardian_challenge(
+    pool: web::Data<DbPool>,
+    sessions: web::Data<PatientSessions>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id_bytes, guardian_id_bytes) = match parse_guardian_path(path.into_inner()) {
+        Some(ids) => ids,
+        None => return HttpResponse::BadRequest().body("Invalid patient or guardian ID"),
+    };
+    let guardian = match web::block(move || -> Result<Option<RecoveryGuardian>> {
+        let mut conn = pool.get()?;
+        find_guardian(&mut conn, &patient_id_bytes, &guardian_id_bytes)
+    })
+    .await
+    {
+        Ok(Ok(Some(guardian))) => guardian,
+        Ok(Ok(None)) => return HttpResponse::NotFound().body("Guardian not found"),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error getting guardian: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    let (challenge_id, secret) = sessions.issue_challenge(&guardian.id);
+    match guardian_public_key(&guardian).and_then(|public_key| public_key.wrap_key(&secret)) {
+        Ok(wrapped) => HttpResponse::Ok().json(json!({
+            "challenge_id": challenge_id,
+            "wrapped_challenge": CryptoUtils::encode_base64(&wrapped),
+        })),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error wrapping challenge: {:?}", e)),
+    }
+}
+
+// Handler to open a recovery request. Guardians can only hand in shares once
+// the patient's identity has been verified. The request is bound to a key
+// pair the requester generates for it: shares are wrapped to its public key
+// and only its holder can complete the request. The response carries the
+// token the requester follows and completes the request with.
+pub async fn open_recovery_request(
+    pool: web::Data<DbPool>,
+    patient_id: web::Path<String>,
+    request: web::Json<OpenRecoveryRequest>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let recovery_public_key_pem = request.into_inner().recovery_public_key_pem;
+    if let Err(e) = PatientPublicKey::from_pem(KeyAlgorithm::detect(&recovery_public_key_pem), &recovery_public_key_pem) {
+        return HttpResponse::BadRequest().body(format!("Invalid recovery public key: {:?}", e));
+    }
+
+    let access_token = CryptoUtils::encode_base64(&CryptoUtils::generate_aes_key());
+    let access_token_hash = hex_sha256(access_token.as_bytes());
+
+    match web::block(move || -> Result<Result<RecoveryRequest, Rejection>> {
+        let mut conn = pool.get()?;
+        let configured = recovery_configs::table
+            .find(&patient_id_bytes)
+            .select(recovery_configs::patient_id)
+            .first::<Vec<u8>>(&mut conn)
+            .optional()?
+            .is_some();
+        if !configured {
+            return Ok(Err(Rejection::NotFound("Social recovery is not set up for this patient")));
+        }
+        let now = Utc::now().naive_utc();
+        let request = RecoveryRequest {
+            id: Uuid::new_v4().as_bytes().to_vec(),
+            patient_id: patient_id_bytes,
+            status: STATUS_PENDING_VERIFICATION.to_string(),
+            verified_by: None,
+            created_at: now,
+            expires_at: now + Duration::hours(REQUEST_LIFETIME_HOURS),
+            completed_at: None,
+            recovery_public_key_pem: Some(recovery_public_key_pem),
+            access_token_hash: Some(access_token_hash),
+        };
+        diesel::insert_into(recovery_requests::table).values(&request).execute(&mut conn)?;
+        Ok(Ok(request))
+    })
+    .await
+    {
+        Ok(Ok(Ok(request))) => {
+            let mut body = request_json(&request, 0, None);
+            body["access_token"] = json!(access_token);
+            HttpResponse::Created().json(body)
+        }
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error opening recovery request: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for a vault administrator to confirm the patient's identity out of band
+pub async fn verify_recovery_request(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    if principal.kind != PrincipalKind::Admin {
+        return HttpResponse::Forbidden().body("Only vault administrators can verify recovery requests");
+    }
+    let (patient_id_bytes, request_id_bytes) = match parse_request_path(path.into_inner()) {
+        Some(ids) => ids,
+        None => return HttpResponse::BadRequest().body("Invalid patient or request ID"),
+    };
+
+    match web::block(move || -> Result<Result<RecoveryRequest, Rejection>> {
+        let mut conn = pool.get()?;
+        let mut request = match load_open_request(&mut conn, &patient_id_bytes, &request_id_bytes)? {
+            Ok(request) => request,
+            Err(rejection) => return Ok(Err(rejection)),
+        };
+        if request.status != STATUS_PENDING_VERIFICATION {
+            return Ok(Err(Rejection::Conflict("Recovery request is already verified")));
+        }
+        request.status = STATUS_VERIFIED.to_string();
+        request.verified_by = Some(principal.name.clone());
+        diesel::update(recovery_requests::table.find(&request.id))
+            .set((
+                recovery_requests::status.eq(&request.status),
+                recovery_requests::verified_by.eq(&request.verified_by),
+            ))
+            .execute(&mut conn)?;
+        tracing::info!(verified_by = %principal.name, "recovery request verified");
+        Ok(Ok(request))
+    })
+    .await
+    {
+        Ok(Ok(Ok(request))) => HttpResponse::Ok().json(request_json(&request, 0, None)),
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error verifying recovery request: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for a guardian to hand in their share. The guardian answers a
+// challenge wrapped to their registered key and wraps the share to the
+// request's recovery key themselves, so the server never sees it in plaintext.
+pub async fn submit_share(
+    pool: web::Data<DbPool>,
+    sessions: web::Data<PatientSessions>,
+    path: web::Path<(String, String)>,
+    submission: web::Json<SubmitShareRequest>,
+) -> impl Responder {
+    let (patient_id_bytes, request_id_bytes) = match parse_request_path(path.into_inner()) {
+        Some(ids) => ids,
+        None => return HttpResponse::BadRequest().body("Invalid patient or request ID"),
+    };
+    let submission = submission.into_inner();
+    let guardian_id_bytes = match Uuid::parse_str(&submission.guardian_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid guardian ID"),
+    };
+    let (secret, wrapped_share) = match (CryptoUtils::decode_base64(&submission.challenge), CryptoUtils::decode_base64(&submission.wrapped_share)) {
+        (Ok(secret), Ok(wrapped_share)) if !wrapped_share.is_empty() => (secret, wrapped_share),
+        _ => return HttpResponse::BadRequest().body("challenge and wrapped_share must be base64"),
+    };
+    if !sessions.verify_challenge(&guardian_id_bytes, &submission.challenge_id, &secret) {
+        tracing::warn!("recovery share rejected: guardian challenge answer is wrong or expired");
+        return HttpResponse::Unauthorized().body("Guardian challenge answer is wrong or expired");
+    }
+
+    match web::block(move || -> Result<Result<(RecoveryRequest, i64, i32), Rejection>> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            let request = match load_open_request(conn, &patient_id_bytes, &request_id_bytes)? {
+                Ok(request) => request,
+                Err(rejection) => return Ok(Err(rejection)),
+            };
+            if request.status != STATUS_VERIFIED {
+                return Ok(Err(Rejection::Conflict("Recovery request has not been verified yet")));
+            }
+            let guardian = match find_guardian(conn, &patient_id_bytes, &guardian_id_bytes)? {
+                Some(guardian) => guardian,
+                None => return Ok(Err(Rejection::NotFound("Guardian not found"))),
+            };
+
+            // Checked against the issued share's hash once unwrapped at completion
+            let submission = RecoverySubmission {
+                id: Uuid::new_v4().as_bytes().to_vec(),
+                request_id: request.id.clone(),
+                guardian_id: guardian.id.clone(),
+                share: CryptoUtils::encode_base64(&wrapped_share),
+                submitted_at: Utc::now().naive_utc(),
+            };
+            diesel::insert_into(recovery_submissions::table)
+                .values(&submission)
+                // A guardian handing in again replaces their earlier share
+                .on_conflict((recovery_submissions::request_id, recovery_submissions::guardian_id))
+                .do_update()
+                .set((
+                    recovery_submissions::share.eq(&submission.share),
+                    recovery_submissions::submitted_at.eq(submission.submitted_at),
+                ))
+                .execute(conn)?;
+
+            let submitted = count_submissions(conn, &request.id)?;
+            let threshold = load_config(conn, &patient_id_bytes)?.threshold;
+            Ok(Ok((request, submitted, threshold)))
+        })
+    })
+    .await
+    {
+        Ok(Ok(Ok((request, submitted, threshold)))) => HttpResponse::Ok().json(request_json(&request, submitted, Some(threshold))),
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error submitting share: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler for the requester to query a recovery request's progress
+pub async fn get_recovery_request(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id_bytes, request_id_bytes) = match parse_request_path(path.into_inner()) {
+        Some(ids) => ids,
+        None => return HttpResponse::BadRequest().body("Invalid patient or request ID"),
+    };
+    let Some(access_token_hash) = auth::bearer_token(&req).map(|token| hex_sha256(token.as_bytes())) else {
+        return HttpResponse::Unauthorized().body("The recovery request's access token is required");
+    };
+
+    match web::block(move || -> Result<Option<(RecoveryRequest, Vec<RecoverySubmission>, i32)>> {
+        let mut conn = pool.get()?;
+        let request = recovery_requests::table
+            .filter(recovery_requests::id.eq(&request_id_bytes))
+            .filter(recovery_requests::patient_id.eq(&patient_id_bytes))
+            .select(RecoveryRequest::as_select())
+            .first(&mut conn)
+            .optional()?;
+        match request {
+            Some(request) if request.access_token_hash.as_deref() == Some(access_token_hash.as_str()) => {
+                let submissions = load_submissions(&mut conn, &request.id)?;
+                let threshold = load_config(&mut conn, &patient_id_bytes)?.threshold;
+                Ok(Some((request, submissions, threshold)))
+            }
+            _ => Ok(None),
+        }
+    })
+    .await
+    {
+        Ok(Ok(Some((request, submissions, threshold)))) => {
+            let mut body = request_json(&request, submissions.len() as i64, Some(threshold));
+            // Wrapped to the recovery key, so only the requester can use them
+            body["shares"] = submissions.iter()
+                .map(|submission| json!({
+                    "guardian_id": Uuid::from_slice(&submission.guardian_id).unwrap_or_default().to_string(),
+                    "wrapped_share": submission.share,
+                }))
+                .collect();
+            HttpResponse::Ok().json(body)
+        }
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Recovery request not found"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error getting recovery request: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler to rebuild the patient's private key once enough guardians have
+// handed in their shares. The requester proves they hold the request's
+// recovery key by sending the shares back unwrapped. The rebuilt key is
+// returned encrypted to the recovery key and, when escrow is enabled,
+// escrowed again under the tenant data key.
+pub async fn complete_recovery(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    path: web::Path<(String, String)>,
+    completion: web::Json<CompleteRecoveryRequest>,
+) -> impl Responder {
+    let (patient_id_bytes, request_id_bytes) = match parse_request_path(path.into_inner()) {
+        Some(ids) => ids,
+        None => return HttpResponse::BadRequest().body("Invalid patient or request ID"),
+    };
+    let Some(access_token_hash) = auth::bearer_token(&req).map(|token| hex_sha256(token.as_bytes())) else {
+        return HttpResponse::Unauthorized().body("The recovery request's access token is required");
+    };
+    let mut unwrapped = Vec::new();
+    for share in completion.into_inner().shares {
+        match (Uuid::parse_str(&share.guardian_id), CryptoUtils::decode_base64(&share.share)) {
+            (Ok(guardian_id), Ok(bytes)) => unwrapped.push((guardian_id.as_bytes().to_vec(), Zeroizing::new(bytes))),
+            _ => return HttpResponse::BadRequest().body("Each share needs a guardian ID and a base64 share"),
+        }
+    }
+    let escrow = config.key_management.escrow_patient_keys;
+
+    match web::block(move || -> Result<Result<serde_json::Value, Rejection>> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            let request = match load_open_request(conn, &patient_id_bytes, &request_id_bytes)? {
+                Ok(request) if request.access_token_hash.as_deref() == Some(access_token_hash.as_str()) => request,
+                Ok(_) => return Ok(Err(Rejection::NotFound("Recovery request not found"))),
+                Err(rejection) => return Ok(Err(rejection)),
+            };
+            if request.status != STATUS_VERIFIED {
+                return Ok(Err(Rejection::Conflict("Recovery request has not been verified yet")));
+            }
+            let recovery_public_key = recovery_public_key(&request)?;
+            let recovery_config = load_config(conn, &patient_id_bytes)?;
+            let submissions = load_submissions(conn, &request.id)?;
+            if (submissions.len() as i32) < recovery_config.threshold {
+                return Ok(Err(Rejection::Conflict("Not enough guardian shares have been submitted")));
+            }
+
+            // Only shares handed in for this request count, each checked
+            // against the hash of the share issued to its guardian
+            let guardians = recovery_guardians::table
+                .filter(recovery_guardians::patient_id.eq(&patient_id_bytes))
+                .select(RecoveryGuardian::as_select())
+                .load(conn)?;
+            let mut shares = Vec::with_capacity(unwrapped.len());
+            for (guardian_id, bytes) in &unwrapped {
+                let submitted = submissions.iter().any(|submission| &submission.guardian_id == guardian_id);
+                let issued = guardians.iter().find(|guardian| &guardian.id == guardian_id);
+                match issued {
+                    Some(guardian) if submitted && hex_sha256(bytes) == guardian.share_hash => {
+                        shares.push(Share::try_from(bytes.as_slice()).map_err(|e| anyhow!("Malformed share: {}", e))?);
+                    }
+                    _ => return Ok(Err(Rejection::Unprocessable(
+                        "shares must be the submitted shares unwrapped with the recovery private key",
+                    ))),
+                }
+            }
+            if (shares.len() as i32) < recovery_config.threshold {
+                return Ok(Err(Rejection::Unprocessable("Not enough unwrapped shares were sent")));
+            }
+            let recovery_key = Zeroizing::new(
+                Sharks(recovery_config.threshold as u8)
+                    .recover(&shares)
+                    .map_err(|e| anyhow!("Failed to combine shares: {}", e))?,
+            );
+
+            let encrypted = CryptoUtils::decode_base64(&recovery_config.encrypted_private_key)?;
+            if encrypted.len() < NONCE_SIZE {
+                return Err(anyhow!("Stored recovery ciphertext is too short"));
+            }
+            let (nonce, ciphertext) = encrypted.split_at(NONCE_SIZE);
+            let private_key_pem = Zeroizing::new(String::from_utf8(
+                CryptoUtils::decrypt_data(ciphertext, &recovery_key, nonce)?,
+            )?);
+
+            let patient = find_patient(conn, &patient_id_bytes)?
+                .ok_or_else(|| anyhow!("Patient disappeared during recovery"))?;
+            let private_key = PatientPrivateKey::from_pem(KeyAlgorithm::detect(&private_key_pem), &private_key_pem)?;
+            if patient.public_key()?.fingerprint()? != recovery_config.key_fingerprint
+                || private_key.public_key().fingerprint()? != recovery_config.key_fingerprint
+            {
+                return Ok(Err(Rejection::Conflict(
+                    "The patient's key was rotated after recovery was set up; the recovered key is outdated",
+                )));
+            }
+
+            if let (true, Some(keys)) = (escrow, keys.get_ref().as_ref()) {
+                let data_key = keys.active_data_key(conn)?;
+                diesel::update(patients::table.find(&patient_id_bytes))
+                    .set((
+                        patients::escrowed_private_key.eq(Some(data_key.seal(private_key_pem.as_bytes())?)),
+                        patients::escrow_key_id.eq(Some(&data_key.id)),
+                        patients::updated_at.eq(Utc::now().naive_utc()),
+                    ))
+                    .execute(conn)?;
+            }
+
+            // Shares are only needed until the key is rebuilt
+            diesel::delete(recovery_submissions::table.filter(recovery_submissions::request_id.eq(&request.id))).execute(conn)?;
+            diesel::update(recovery_requests::table.find(&request.id))
+                .set((
+                    recovery_requests::status.eq(STATUS_COMPLETED),
+                    recovery_requests::completed_at.eq(Some(Utc::now().naive_utc())),
+                ))
+                .execute(conn)?;
+
+            // Envelope: the PEM is too long to wrap directly under RSA
+            let envelope_key = Zeroizing::new(CryptoUtils::generate_aes_key());
+            let (ciphertext, nonce) = CryptoUtils::encrypt_data(private_key_pem.as_bytes(), &envelope_key)?;
+            Ok(Ok(json!({
+                "wrapped_key": CryptoUtils::encode_base64(&recovery_public_key.wrap_key(&envelope_key)?),
+                "encrypted_private_key": CryptoUtils::encode_base64(&ciphertext),
+                "nonce": CryptoUtils::encode_base64(&nonce),
+            })))
+        })
+    })
+    .await
+    {
+        Ok(Ok(Ok(sealed_private_key))) => {
+            tracing::info!("social recovery completed");
+            HttpResponse::Ok().json(sealed_private_key)
+        }
+        Ok(Ok(Err(rejection))) => rejection.into_response(),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error completing recovery: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+fn parse_request_path((patient_id, request_id): (String, String)) -> Option<(Vec<u8>, Vec<u8>)> {
+    let patient_id = Uuid::parse_str(&patient_id).ok()?;
+    let request_id = Uuid::parse_str(&request_id).ok()?;
+    Some((patient_id.as_bytes().to_vec(), request_id.as_bytes().to_vec()))
+}
+
+fn parse_guardian_path((patient_id, guardian_id): (String, String)) -> Option<(Vec<u8>, Vec<u8>)> {
+    let patient_id = Uuid::parse_str(&patient_id).ok()?;
+    let guardian_id = Uuid::parse_str(&guardian_id).ok()?;
+    Some((patient_id.as_bytes().to_vec(), guardian_id.as_bytes().to_vec()))
+}
+
+fn find_guardian(conn: &mut PgConnection, patient_id: &[u8], guardian_id: &[u8]) -> Result<Option<RecoveryGuardian>> {
+    Ok(recovery_guardians::table
+        .filter(recovery_guardians::id.eq(guardian_id))
+        .filter(recovery_guardians::patient_id.eq(patient_id))
+        .select(RecoveryGuardian::as_select())
+        .first(conn)
+        .optional()?)
+}
+
+// A guardian's registered key, RSA or X25519
+fn guardian_public_key(guardian: &RecoveryGuardian) -> Result<PatientPublicKey> {
+    PatientPublicKey::from_pem(KeyAlgorithm::detect(&guardian.public_key_pem), &guardian.public_key_pem)
+}
+
+fn find_patient(conn: &mut PgConnection, patient_id: &[u8]) -> Result<Option<Patient>> {
+    Ok(patients::table
+        .filter(patients::id.eq(patient_id))
+        .select(Patient::as_select())
+        .first(conn)
+        .optional()?)
+}
+
+fn load_config(conn: &mut PgConnection, patient_id: &[u8]) -> Result<RecoveryConfig> {
+    Ok(recovery_configs::table
+        .find(patient_id)
+        .select(RecoveryConfig::as_select())
+        .first(conn)?)
+}
+
+// Loads a request that can still make progress
+fn load_open_request(conn: &mut PgConnection, patient_id: &[u8], request_id: &[u8]) -> Result<Result<RecoveryRequest, Rejection>> {
+    let request = recovery_requests::table
+        .filter(recovery_requests::id.eq(request_id))
+        .filter(recovery_requests::patient_id.eq(patient_id))
+        .select(RecoveryRequest::as_select())
+        .first(conn)
+        .optional()?;
+    Ok(match request {
+        None => Err(Rejection::NotFound("Recovery request not found")),
+        Some(request) if request.status == STATUS_COMPLETED => Err(Rejection::Conflict("Recovery request has already completed")),
+        Some(request) if request.expires_at < Utc::now().naive_utc() => Err(Rejection::Conflict("Recovery request has expired")),
+        Some(request) => Ok(request),
+    })
+}
+
+fn load_submissions(conn: &mut PgConnection, request_id: &[u8]) -> Result<Vec<RecoverySubmission>> {
+    Ok(recovery_submissions::table
+        .filter(recovery_submissions::request_id.eq(request_id))
+        .select(RecoverySubmission::as_select())
+        .load(conn)?)
+}
+
+// The key a request's shares and rebuilt private key are encrypted to
+fn recovery_public_key(request: &RecoveryRequest) -> Result<PatientPublicKey> {
+    let pem = request.recovery_public_key_pem.as_deref()
+        .ok_or_else(|| anyhow!("Recovery request has no recovery public key"))?;
+    PatientPublicKey::from_pem(KeyAlgorithm::detect(pem), pem)
+}
+
+fn count_submissions(conn: &mut PgConnection, request_id: &[u8]) -> Result<i64> {
+    Ok(recovery_submissions::table
+        .filter(recovery_submissions::request_id.eq(request_id))
+        .count()
+        .get_result(conn)?)
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn guardian_json(guardian: &RecoveryGuardian) -> serde_json::Value {
+    json!({
+        "id": Uuid::from_slice(&guardian.id).unwrap_or_default().to_string(),
+        "name": guardian.name,
+        "encrypted_share": guardian.encrypted_share,
+    })
+}
+
+fn request_json(request: &RecoveryRequest, submitted_shares: i64, threshold: Option<i32>) -> serde_json::Value {
+    json!({
+        "id": Uuid::from_slice(&request.id).unwrap_or_default().to_string(),
+        "patient_id": Uuid::from_slice(&request.patient_id).unwrap_or_default().to_string(),
+        "status": request.status,
+        "verified_by": request.verified_by,
+        "submitted_shares": submitted_shares,
+        "threshold": threshold,
+        "created_at": request.created_at,
+        "expires_at": request.expires_at,
+        "completed_at": request.completed_at,
+    })
+}
@@ -0,0 +1,227 @@
+// Replicated blob storage: every blob is written to all configured backends
+// and a write succeeds once `write_quorum` of them hold it. Reads try the
+// backends in turn, preferring healthy ones, and only accept content that
+// hashes to the requested CID and, when known, its recorded SHA-256.
+
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::blobstore::{self, BlobStore, FetchError};
+use crate::metrics::METRICS;
+
+struct Replica {
+    name: String,
+    store: BlobStore,
+    // Outcome of the last call, used to order reads
+    healthy: AtomicBool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaHealth {
+    pub name: String,
+    pub healthy: bool,
+}
+
+pub struct ReplicatedBlobStore {
+    replicas: Vec<Replica>,
+    write_quorum: usize,
+}
+
+impl ReplicatedBlobStore {
+    pub fn new(replicas: Vec<(String, BlobStore)>, write_quorum: usize) -> Result<Self> {
+        if !(1..=replicas.len()).contains(&write_quorum) {
+            return Err(anyhow!("Write quorum must be between 1 and {}, got {}", replicas.len(), write_quorum));
+        }
+        let replicas = replicas.into_iter()
+            .map(|(name, store)| Replica { name, store, healthy: AtomicBool::new(true) })
+            .collect();
+        Ok(ReplicatedBlobStore { replicas, write_quorum })
+    }
+
+    pub fn write_quorum(&self) -> usize {
+        self.write_quorum
+    }
+
+    pub fn health(&self) -> Vec<ReplicaHealth> {
+        self.replicas.iter()
+            .map(|replica| ReplicaHealth { name: replica.name.clone(), healthy: replica.healthy.load(Ordering::SeqCst) })
+            .collect()
+    }
+
+    fn observe<T>(&self, replica: &Replica, operation: &str, result: &Result<T>) {
+        replica.healthy.store(result.is_ok(), Ordering::SeqCst);
+        METRICS.observe_replica(&replica.name, operation, result.is_ok());
+        if let Err(e) = result {
+            tracing::warn!(replica = %replica.name, operation, error = %e, "blob replica call failed");
+        }
+    }
+
+    // Writes to every replica at once. Replicas must agree on the CID; the
+    // one most of them returned wins and the others count as failed.
+    pub async fn put(&self, content: Vec<u8>) -> Result<String> {
+        let results = join_all(self.replicas.iter().map(|replica| Box::pin(replica.store.put(content.clone())))).await;
+        let mut votes: HashMap<&str, usize> = HashMap::new();
+        for cid in results.iter().flatten() {
+            *votes.entry(cid.as_str()).or_default() += 1;
+        }
+        let agreed = votes.into_iter().max_by_key(|(_, count)| *count).map(|(cid, _)| cid.to_string());
+
+        let mut stored = 0;
+        let mut errors = Vec::new();
+        for (replica, result) in self.replicas.iter().zip(results) {
+            let result = result.and_then(|cid| match Some(&cid) == agreed.as_ref() {
+                true => Ok(()),
+                false => Err(anyhow!("stored the blob as {}", cid)),
+            });
+            self.observe(replica, "put", &result);
+            match result {
+                Ok(()) => stored += 1,
+                Err(e) => errors.push(format!("{}: {}", replica.name, e)),
+            }
+        }
+        match agreed {
+            Some(cid) if stored >= self.write_quorum => Ok(cid),
+            _ => Err(anyhow!(
+                "Only {} of {} replicas stored the blob, {} required ({})",
+                stored, self.replicas.len(), self.write_quorum, errors.join("; ")
+            )),
+        }
+    }
+
+    // Returns the first copy matching the CID and, when known, the SHA-256
+    // recorded at write time, trying healthy replicas first. Fails with an
+    // integrity error only if some replica answered but none with intact
+    // content, and as unverifiable if the copies could not be checked at all.
+    pub async fn get(&self, cid: &str, sha256: Option<&str>) -> Result<Vec<u8>, FetchError> {
+        let mut replicas = self.replicas.iter().collect::<Vec<_>>();
+        replicas.sort_by_key(|replica| !replica.healthy.load(Ordering::SeqCst));
+
+        let mut errors = Vec::new();
+        let (mut corrupt, mut unverifiable) = (false, false);
+        for replica in replicas {
+            let result = Box::pin(replica.store.get(cid)).await;
+            self.observe(replica, "get", &result);
+            let content = match result {
+                Ok(content) => content,
+                Err(e) => {
+                    errors.push(format!("{}: {}", replica.name, e));
+                    continue;
+                }
+            };
+            match blobstore::verify_content(cid, sha256, &content) {
+                Ok(()) => {
+                    self.observe(replica, "verify", &Ok(()));
+                    return Ok(content);
+                }
+                // Not the replica's fault: no copy of this blob can be checked
+                Err(FetchError::Unverifiable(reason)) => {
+                    errors.push(format!("{}: {}", replica.name, reason));
+                    unverifiable = true;
+                }
+                Err(failure) => {
+                    let e = anyhow!(match failure {
+                        FetchError::Integrity(reason) => reason,
+                        failure => failure.to_string(),
+                    });
+                    errors.push(format!("{}: {}", replica.name, e));
+                    self.observe::<()>(replica, "verify", &Err(e));
+                    corrupt = true;
+                }
+            }
+        }
+        let reason = format!("No replica returned blob {} ({})", cid, errors.join("; "));
+        Err(match (corrupt, unverifiable) {
+            (true, _) => FetchError::Integrity(reason),
+            (false, true) => FetchError::Unverifiable(reason),
+            (false, false) => FetchError::Unavailable(anyhow!(reason)),
+        })
+    }
+
+    // Pins on every replica; like writes, this needs the write quorum. A
+    // replica that lost the blob gets a copy from one that pinned it.
+    pub async fn pin(&self, cid: &str) -> Result<()> {
+        let mut results = join_all(self.replicas.iter().map(|replica| Box::pin(replica.store.pin(cid)))).await;
+        let holders: Vec<&Replica> = self.replicas.iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_ok())
+            .map(|(replica, _)| replica)
+            .collect();
+        if !holders.is_empty() {
+            for (replica, result) in self.replicas.iter().zip(results.iter_mut()) {
+                if result.is_err() {
+                    *result = self.restore(replica, &holders, cid).await;
+                }
+            }
+        }
+        self.require_quorum("pin", cid, results)
+    }
+
+    // Copies a blob to `replica` from the first holder that serves it. The
+    // copy must come back under the same CID, which checks the content.
+    async fn restore(&self, replica: &Replica, holders: &[&Replica], cid: &str) -> Result<()> {
+        let mut errors = Vec::new();
+        for holder in holders {
+            let copied = match Box::pin(holder.store.get(cid)).await {
+                Ok(content) => Box::pin(replica.store.put_as(cid, content)).await,
+                Err(e) => Err(e),
+            };
+            match copied {
+                Ok(()) => {
+                    Box::pin(replica.store.pin(cid)).await?;
+                    tracing::warn!(replica = %replica.name, from = %holder.name, cid = %cid, "restored blob a replica had lost");
+                    return Ok(());
+                }
+                Err(e) => errors.push(format!("{}: {}", holder.name, e)),
+            }
+        }
+        Err(anyhow!("No replica could supply blob {} ({})", cid, errors.join("; ")))
+    }
+
+    pub async fn unpin(&self, cid: &str) -> Result<()> {
+        let results = join_all(self.replicas.iter().map(|replica| Box::pin(replica.store.unpin(cid)))).await;
+        self.require_quorum("unpin", cid, results)
+    }
+
+    // CIDs pinned on every replica that answered, so a blob one replica lost
+    // is re-pinned, and copied back to that replica, by the reconciliation
+    pub async fn pinned(&self) -> Result<HashSet<String>> {
+        let results = join_all(self.replicas.iter().map(|replica| Box::pin(replica.store.pinned()))).await;
+        let mut pinned: Option<HashSet<String>> = None;
+        let mut answered = 0;
+        for (replica, result) in self.replicas.iter().zip(results) {
+            self.observe(replica, "pinned", &result);
+            if let Ok(cids) = result {
+                answered += 1;
+                pinned = Some(match pinned {
+                    Some(pinned) => pinned.intersection(&cids).cloned().collect(),
+                    None => cids,
+                });
+            }
+        }
+        match pinned {
+            Some(pinned) if answered >= self.write_quorum => Ok(pinned),
+            _ => Err(anyhow!("Only {} of {} replicas listed their pins, {} required", answered, self.replicas.len(), self.write_quorum)),
+        }
+    }
+
+    fn require_quorum(&self, operation: &str, cid: &str, results: Vec<Result<()>>) -> Result<()> {
+        let mut succeeded = 0;
+        let mut errors = Vec::new();
+        for (replica, result) in self.replicas.iter().zip(results) {
+            self.observe(replica, operation, &result);
+            match result {
+                Ok(()) => succeeded += 1,
+                Err(e) => errors.push(format!("{}: {}", replica.name, e)),
+            }
+        }
+        if succeeded < self.write_quorum {
+            return Err(anyhow!(
+                "Only {} of {} replicas could {} blob {}, {} required ({})",
+                succeeded, self.replicas.len(), operation, cid, self.write_quorum, errors.join("; ")
+            ));
+        }
+        Ok(())
+    }
+}
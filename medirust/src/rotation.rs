@@ -0,0 +1,588 @@
+use actix_web::{rt, web, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::PgConnection;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+use crate::auth::AuthenticatedPatient;
+use crate::config::AppConfig;
+use crate::crypto::{CryptoUtils, KeyAlgorithm, PatientPrivateKey, PatientPublicKey};
+use crate::keys::{self, KeyHierarchy};
+use crate::models::{HealthRecord, KeyRotationJob, Patient, PatientKeyHistory};
+use crate::schema::{health_records, key_rotation_jobs, patient_key_history, patients};
+use crate::DbPool;
+
+// Records re-wrapped per transaction; progress is committed after each batch
+const BATCH_SIZE: i64 = 50;
+
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_COMPLETED: &str = "completed";
+pub const STATUS_FAILED: &str = "failed";
+
+#[derive(Debug, Deserialize)]
+pub struct StartRotationRequest {
+    // New public key supplied by the patient's device. The server generates a
+    // key pair when omitted.
+    pub new_public_key_pem: Option<String>,
+    // Current private key, required unless the server holds it in escrow
+    pub current_private_key_pem: Option<String>,
+    // Backend of a server-generated key; defaults to crypto.patient_key_algorithm
+    pub new_key_algorithm: Option<KeyAlgorithm>,
+}
+
+// Reasons a rotation request is refused, mapped to HTTP responses
+#[derive(Debug)]
+enum Rejection {
+    NotFound(&'static str),
+    Conflict(&'static str),
+    Unprocessable(&'static str),
+}
+
+impl Rejection {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Rejection::NotFound(msg) => HttpResponse::NotFound().body(msg),
+            Rejection::Conflict(msg) => HttpResponse::Conflict().body(msg),
+            Rejection::Unprocessable(msg) => HttpResponse::UnprocessableEntity().body(msg),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResumeRotationRequest {
+    pub current_private_key_pem: Option<String>,
+}
+
+// Tracks jobs with a worker in this process so a job is never run twice
+#[derive(Default)]
+pub struct RotationRunner {
+    active: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl RotationRunner {
+    fn try_claim(&self, job_id: &[u8]) -> bool {
+        self.active.lock().unwrap_or_else(|e| e.into_inner()).insert(job_id.to_vec())
+    }
+
+    fn release(&self, job_id: &[u8]) {
+        self.active.lock().unwrap_or_else(|e| e.into_inner()).remove(job_id);
+    }
+}
+
+// Handler to start rotating a patient's key pair
+pub async fn start_key_rotation(
+    pool: web::Data<DbPool>,
+    config: web::Data<AppConfig>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    runner: web::Data<RotationRunner>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+    request: web::Json<StartRotationRequest>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+    let request = request.into_inner();
+
+    let new_public_key_supplied = request.new_public_key_pem.is_some();
+    let (new_private_key, new_public_key) = match request.new_public_key_pem {
+        Some(pem) => match PatientPublicKey::from_pem(KeyAlgorithm::detect(&pem), &pem) {
+            Ok(key) => (None, key),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid new public key: {:?}", e)),
+        },
+        None => {
+            let algorithm = request.new_key_algorithm.unwrap_or(config.crypto.patient_key_algorithm);
+            match PatientPrivateKey::generate(algorithm, config.crypto.rsa_key_bits) {
+                Ok(private_key) => {
+                    let public_key = private_key.public_key();
+                    (Some(private_key), public_key)
+                }
+                Err(e) => return HttpResponse::InternalServerError().body(format!("Error generating key pair: {:?}", e)),
+            }
+        }
+    };
+    let supplied_private_key = match request.current_private_key_pem.map(Zeroizing::new) {
+        Some(pem) => match PatientPrivateKey::from_pem(KeyAlgorithm::detect(&pem), &pem) {
+            Ok(key) => Some(key),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid current private key: {:?}", e)),
+        },
+        None => None,
+    };
+
+    let escrow_new_key = config.key_management.escrow_patient_keys && !new_public_key_supplied;
+    let new_private_key_for_block = new_private_key.clone();
+    let new_public_key_for_block = new_public_key.clone();
+    let keys_for_block = keys.clone();
+    let pool_for_block = pool.clone();
+    let started = web::block(move || -> Result<Result<(KeyRotationJob, PatientPrivateKey), Rejection>> {
+        let mut conn = pool_for_block.get()?;
+        conn.transaction(|conn| {
+            let patient = match patients::table
+                .filter(patients::id.eq(&patient_id_bytes))
+                .select(Patient::as_select())
+                .first(conn)
+                .optional()?
+            {
+                Some(patient) => patient,
+                None => return Ok(Err(Rejection::NotFound("Patient not found"))),
+            };
+
+            let running: i64 = key_rotation_jobs::table
+                .filter(key_rotation_jobs::patient_id.eq(&patient_id_bytes))
+                .filter(key_rotation_jobs::status.ne(STATUS_COMPLETED))
+                .count()
+                .get_result(conn)?;
+            if running > 0 {
+                return Ok(Err(Rejection::Conflict("A key rotation is already in progress for this patient")));
+            }
+
+            let old_public_key = patient.public_key()?;
+            let old_fingerprint = old_public_key.fingerprint()?;
+            let old_private_key = match supplied_private_key {
+                Some(key) => key,
+                None => match keys::escrowed_private_key(keys_for_block.get_ref().as_ref(), conn, &patient)? {
+                    Some(key) => key,
+                    None => return Ok(Err(Rejection::Unprocessable(
+                        "current_private_key_pem is required because the server does not hold this patient's key",
+                    ))),
+                },
+            };
+            if old_private_key.public_key() != old_public_key {
+                return Ok(Err(Rejection::Unprocessable("current private key does not match the patient's public key")));
+            }
+            let new_fingerprint = new_public_key_for_block.fingerprint()?;
+            if new_fingerprint == old_fingerprint {
+                return Ok(Err(Rejection::Unprocessable("new public key is the same as the current key")));
+            }
+
+            let total_records: i64 = health_records::table
+                .filter(health_records::patient_id.eq(&patient_id_bytes))
+                .count()
+                .get_result(conn)?;
+            let now = Utc::now().naive_utc();
+            let job = KeyRotationJob {
+                id: Uuid::new_v4().as_bytes().to_vec(),
+                patient_id: patient_id_bytes.clone(),
+                old_key_fingerprint: old_fingerprint.clone(),
+                new_key_fingerprint: new_fingerprint,
+                status: STATUS_RUNNING.to_string(),
+                total_records,
+                rewrapped_records: 0,
+                error: None,
+                old_escrowed_private_key: patient.escrowed_private_key.clone(),
+                old_escrow_key_id: patient.escrow_key_id.clone(),
+                created_at: now,
+                updated_at: now,
+                completed_at: None,
+                old_key_algorithm: patient.key_algorithm.clone(),
+            };
+            diesel::insert_into(key_rotation_jobs::table).values(&job).execute(conn)?;
+
+            // Keep the retired key for audit
+            diesel::insert_into(patient_key_history::table)
+                .values(&PatientKeyHistory {
+                    id: Uuid::new_v4().as_bytes().to_vec(),
+                    patient_id: patient_id_bytes.clone(),
+                    key_fingerprint: old_fingerprint,
+                    public_key_pem: patient.public_key_pem.clone(),
+                    rotation_job_id: Some(job.id.clone()),
+                    retired_at: now,
+                    key_algorithm: patient.key_algorithm.clone(),
+                })
+                .execute(conn)?;
+
+            // New records are wrapped under the new key from here on. The
+            // escrow moves to the new key only if the server generated it.
+            let (escrowed_private_key, escrow_key_id) = match (keys_for_block.get_ref().as_ref(), &new_private_key_for_block) {
+                (Some(keys), Some(private_key)) if escrow_new_key => {
+                    let pem = Zeroizing::new(private_key.to_pem()?);
+                    let data_key = keys.active_data_key(conn)?;
+                    (Some(data_key.seal(pem.as_bytes())?), Some(data_key.id))
+                }
+                _ => (None, None),
+            };
+            diesel::update(patients::table.find(&patient_id_bytes))
+                .set((
+                    patients::public_key_pem.eq(new_public_key_for_block.to_pem()?),
+                    patients::key_algorithm.eq(new_public_key_for_block.algorithm().id()),
+                    patients::escrowed_private_key.eq(escrowed_private_key),
+                    patients::escrow_key_id.eq(escrow_key_id),
+                    patients::updated_at.eq(now),
+                ))
+                .execute(conn)?;
+
+            Ok(Ok((job, old_private_key)))
+        })
+    })
+    .await;
+
+    let (job, old_private_key) = match started {
+        Ok(Ok(Ok(started))) => started,
+        Ok(Ok(Err(rejection))) => return rejection.into_response(),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error starting key rotation: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    spawn_job(pool.get_ref().clone(), keys.clone(), runner.clone(), job.id.clone(), old_private_key, new_public_key);
+
+    // A server-generated key that is not escrowed is handed to the patient once
+    let new_private_key_pem = match new_private_key {
+        Some(private_key) if !escrow_new_key => match private_key.to_pem() {
+            Ok(pem) => Some(pem),
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Error exporting new private key: {:?}", e)),
+        },
+        _ => None,
+    };
+    HttpResponse::Accepted().json(json!({
+        "job": job_json(&job),
+        "new_private_key_pem": new_private_key_pem,
+    }))
+}
+
+// Handler to resume a failed or interrupted rotation
+pub async fn resume_key_rotation(
+    pool: web::Data<DbPool>,
+    keys: web::Data<Option<KeyHierarchy>>,
+    runner: web::Data<RotationRunner>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+    request: web::Json<ResumeRotationRequest>,
+) -> impl Responder {
+    let (patient_id, job_id) = path.into_inner();
+    let (patient_id_bytes, job_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&job_id)) {
+        (Ok(p), Ok(j)) => (p.as_bytes().to_vec(), j.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or job ID"),
+    };
+    let supplied_private_key = match request.into_inner().current_private_key_pem.map(Zeroizing::new) {
+        Some(pem) => match PatientPrivateKey::from_pem(KeyAlgorithm::detect(&pem), &pem) {
+            Ok(key) => Some(key),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid current private key: {:?}", e)),
+        },
+        None => None,
+    };
+
+    let pool_for_block = pool.clone();
+    let keys_for_block = keys.clone();
+    let loaded = web::block(move || -> Result<Result<(KeyRotationJob, PatientPrivateKey, PatientPublicKey), Rejection>> {
+        let mut conn = pool_for_block.get()?;
+        let job = match load_job(&mut conn, &patient_id_bytes, &job_id_bytes)? {
+            Some(job) => job,
+            None => return Ok(Err(Rejection::NotFound("Key rotation job not found"))),
+        };
+        if job.status == STATUS_COMPLETED {
+            return Ok(Err(Rejection::Conflict("Key rotation job has already completed")));
+        }
+        let old_private_key = match supplied_private_key {
+            Some(key) => key,
+            None => match old_escrowed_key(keys_for_block.get_ref().as_ref(), &mut conn, &job)? {
+                Some(key) => key,
+                None => return Ok(Err(Rejection::Unprocessable(
+                    "current_private_key_pem is required because the server does not hold the retired key",
+                ))),
+            },
+        };
+        if old_private_key.public_key().fingerprint()? != job.old_key_fingerprint {
+            return Ok(Err(Rejection::Unprocessable("private key does not match the key being retired")));
+        }
+        let new_public_key = current_public_key(&mut conn, &job)?;
+        diesel::update(key_rotation_jobs::table.find(&job.id))
+            .set((
+                key_rotation_jobs::status.eq(STATUS_RUNNING),
+                key_rotation_jobs::error.eq(None::<String>),
+                key_rotation_jobs::updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&mut conn)?;
+        Ok(Ok((job, old_private_key, new_public_key)))
+    })
+    .await;
+
+    let (job, old_private_key, new_public_key) = match loaded {
+        Ok(Ok(Ok(loaded))) => loaded,
+        Ok(Ok(Err(rejection))) => return rejection.into_response(),
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(format!("Error resuming key rotation: {:?}", e)),
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    };
+
+    if !spawn_job(pool.get_ref().clone(), keys.clone(), runner.clone(), job.id.clone(), old_private_key, new_public_key) {
+        return HttpResponse::Conflict().body("Key rotation job is already running");
+    }
+    HttpResponse::Accepted().json(job_json(&job))
+}
+
+// Handler to query the progress of a rotation
+pub async fn get_key_rotation(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (patient_id, job_id) = path.into_inner();
+    let (patient_id_bytes, job_id_bytes) = match (Uuid::parse_str(&patient_id), Uuid::parse_str(&job_id)) {
+        (Ok(p), Ok(j)) => (p.as_bytes().to_vec(), j.as_bytes().to_vec()),
+        _ => return HttpResponse::BadRequest().body("Invalid patient or job ID"),
+    };
+
+    match web::block(move || -> Result<Option<KeyRotationJob>> {
+        let mut conn = pool.get()?;
+        load_job(&mut conn, &patient_id_bytes, &job_id_bytes)
+    })
+    .await
+    {
+        Ok(Ok(Some(job))) => HttpResponse::Ok().json(job_json(&job)),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Key rotation job not found"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error getting key rotation: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler listing the patient's retired key fingerprints
+pub async fn get_key_history(
+    pool: web::Data<DbPool>,
+    _patient: AuthenticatedPatient,
+    patient_id: web::Path<String>,
+) -> impl Responder {
+    let patient_id_bytes = match Uuid::parse_str(&patient_id) {
+        Ok(uuid) => uuid.as_bytes().to_vec(),
+        Err(_) => return HttpResponse::BadRequest().body("Invalid patient ID"),
+    };
+
+    match web::block(move || -> Result<Vec<PatientKeyHistory>> {
+        let mut conn = pool.get()?;
+        Ok(patient_key_history::table
+            .filter(patient_key_history::patient_id.eq(patient_id_bytes))
+            .order(patient_key_history::retired_at.asc())
+            .select(PatientKeyHistory::as_select())
+            .load(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(history)) => HttpResponse::Ok().json(
+            history.iter()
+                .map(|entry| json!({
+                    "key_fingerprint": entry.key_fingerprint,
+                    "key_algorithm": entry.key_algorithm,
+                    "public_key_pem": entry.public_key_pem,
+                    "rotation_job_id": entry.rotation_job_id.as_deref().and_then(|id| Uuid::from_slice(id).ok()),
+                    "retired_at": entry.retired_at,
+                }))
+                .collect::<Vec<_>>(),
+        ),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error getting key history: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Restarts interrupted jobs whose retired key is held in escrow. Jobs that
+// need the patient's key stay paused until resumed through the API.
+pub fn resume_interrupted_jobs(pool: &DbPool, keys: &web::Data<Option<KeyHierarchy>>, runner: &web::Data<RotationRunner>) -> Result<usize> {
+    let mut conn = pool.get()?;
+    let jobs = key_rotation_jobs::table
+        .filter(key_rotation_jobs::status.eq(STATUS_RUNNING))
+        .select(KeyRotationJob::as_select())
+        .load(&mut conn)?;
+
+    let mut resumed = 0;
+    for job in jobs {
+        let old_private_key = match old_escrowed_key(keys.get_ref().as_ref(), &mut conn, &job)? {
+            Some(key) => key,
+            None => {
+                mark_failed(&mut conn, &job.id, "Interrupted; resume with the retired private key")?;
+                continue;
+            }
+        };
+        let new_public_key = current_public_key(&mut conn, &job)?;
+        if spawn_job(pool.clone(), keys.clone(), runner.clone(), job.id.clone(), old_private_key, new_public_key) {
+            resumed += 1;
+        }
+    }
+    Ok(resumed)
+}
+
+// Runs the job in the background; false if it is already running here
+fn spawn_job(
+    pool: DbPool,
+    keys: web::Data<Option<KeyHierarchy>>,
+    runner: web::Data<RotationRunner>,
+    job_id: Vec<u8>,
+    old_private_key: PatientPrivateKey,
+    new_public_key: PatientPublicKey,
+) -> bool {
+    if !runner.try_claim(&job_id) {
+        return false;
+    }
+    rt::spawn(async move {
+        loop {
+            let pool = pool.clone();
+            let keys = keys.clone();
+            let job_id = job_id.clone();
+            let old_private_key = old_private_key.clone();
+            let new_public_key = new_public_key.clone();
+            let outcome = web::block(move || -> Result<i64> {
+                let mut conn = pool.get()?;
+                match rewrap_batch(&mut conn, keys.get_ref().as_ref(), &job_id, &old_private_key, &new_public_key) {
+                    Ok(0) => {
+                        complete_job(&mut conn, &job_id)?;
+                        Ok(0)
+                    }
+                    Ok(n) => Ok(n),
+                    Err(e) => {
+                        mark_failed(&mut conn, &job_id, &format!("{:#}", e))?;
+                        Err(e)
+                    }
+                }
+            })
+            .await;
+            match outcome {
+                Ok(Ok(0)) => {
+                    tracing::info!("key rotation completed");
+                    break;
+                }
+                Ok(Ok(_)) => continue,
+                Ok(Err(e)) => {
+                    tracing::error!(error = %e, "key rotation failed");
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "key rotation worker failed");
+                    break;
+                }
+            }
+        }
+        runner.release(&job_id);
+    });
+    true
+}
+
+// Re-wraps the next batch of records not yet under the new key. Each batch
+// commits with the job's progress, so an interrupted job resumes where it stopped.
+fn rewrap_batch(
+    conn: &mut PgConnection,
+    keys: Option<&KeyHierarchy>,
+    job_id: &[u8],
+    old_private_key: &PatientPrivateKey,
+    new_public_key: &PatientPublicKey,
+) -> Result<i64> {
+    conn.transaction(|conn| {
+        let job = key_rotation_jobs::table
+            .find(job_id)
+            .select(KeyRotationJob::as_select())
+            .first(conn)?;
+        let records = health_records::table
+            .filter(health_records::patient_id.eq(&job.patient_id))
+            .filter(
+                health_records::key_fingerprint.is_null()
+                    .or(health_records::key_fingerprint.ne(&job.new_key_fingerprint)),
+            )
+            .limit(BATCH_SIZE)
+            .select(HealthRecord::as_select())
+            .load(conn)?;
+
+        let now = Utc::now().naive_utc();
+        for mut record in records.iter().cloned() {
+            let (encrypted_aes_key, nonce) = keys::record_key_material(keys, conn, &record)?;
+            let aes_key = Zeroizing::new(old_private_key.unwrap_key(
+                &CryptoUtils::decode_base64(&encrypted_aes_key)?,
+            ).map_err(|e| anyhow!("Record {} is not wrapped under the retired key: {}", Uuid::from_slice(&record.id).unwrap_or_default(), e))?);
+            let rewrapped = new_public_key.wrap_key(&aes_key)?;
+            keys::seal_record_key_material(keys, conn, &mut record, CryptoUtils::encode_base64(&rewrapped), nonce)?;
+
+            diesel::update(health_records::table.find(&record.id))
+                .set((
+                    health_records::encrypted_aes_key.eq(&record.encrypted_aes_key),
+                    health_records::nonce.eq(&record.nonce),
+                    health_records::data_key_id.eq(&record.data_key_id),
+                    health_records::key_fingerprint.eq(&job.new_key_fingerprint),
+                    health_records::updated_at.eq(now),
+                ))
+                .execute(conn)?;
+        }
+
+        let count = records.len() as i64;
+        diesel::update(key_rotation_jobs::table.find(job_id))
+            .set((
+                key_rotation_jobs::rewrapped_records.eq(key_rotation_jobs::rewrapped_records + count),
+                key_rotation_jobs::updated_at.eq(now),
+            ))
+            .execute(conn)?;
+        Ok(count)
+    })
+}
+
+fn complete_job(conn: &mut PgConnection, job_id: &[u8]) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    // The retired key is no longer needed once every record is re-wrapped
+    diesel::update(key_rotation_jobs::table.find(job_id))
+        .set((
+            key_rotation_jobs::status.eq(STATUS_COMPLETED),
+            key_rotation_jobs::old_escrowed_private_key.eq(None::<String>),
+            key_rotation_jobs::old_escrow_key_id.eq(None::<String>),
+            key_rotation_jobs::updated_at.eq(now),
+            key_rotation_jobs::completed_at.eq(Some(now)),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn mark_failed(conn: &mut PgConnection, job_id: &[u8], error: &str) -> Result<()> {
+    diesel::update(key_rotation_jobs::table.find(job_id))
+        .set((
+            key_rotation_jobs::status.eq(STATUS_FAILED),
+            key_rotation_jobs::error.eq(Some(error)),
+            key_rotation_jobs::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn load_job(conn: &mut PgConnection, patient_id: &[u8], job_id: &[u8]) -> Result<Option<KeyRotationJob>> {
+    Ok(key_rotation_jobs::table
+        .filter(key_rotation_jobs::id.eq(job_id))
+        .filter(key_rotation_jobs::patient_id.eq(patient_id))
+        .select(KeyRotationJob::as_select())
+        .first(conn)
+        .optional()?)
+}
+
+// The retired private key, if it was escrowed when the job started
+fn old_escrowed_key(keys: Option<&KeyHierarchy>, conn: &mut PgConnection, job: &KeyRotationJob) -> Result<Option<PatientPrivateKey>> {
+    let (Some(keys), Some(sealed), Some(key_id)) = (keys, &job.old_escrowed_private_key, &job.old_escrow_key_id) else {
+        return Ok(None);
+    };
+    let pem = Zeroizing::new(String::from_utf8(keys.data_key(conn, key_id)?.open(sealed)?)?);
+    PatientPrivateKey::from_pem(KeyAlgorithm::from_id(&job.old_key_algorithm)?, &pem).map(Some)
+}
+
+// The patient's current public key, which must be the job's target key
+fn current_public_key(conn: &mut PgConnection, job: &KeyRotationJob) -> Result<PatientPublicKey> {
+    let patient = patients::table
+        .find(&job.patient_id)
+        .select(Patient::as_select())
+        .first(conn)?;
+    let public_key = patient.public_key()?;
+    if public_key.fingerprint()? != job.new_key_fingerprint {
+        return Err(anyhow!("Patient key changed since the rotation started"));
+    }
+    Ok(public_key)
+}
+
+fn job_json(job: &KeyRotationJob) -> serde_json::Value {
+    json!({
+        "id": Uuid::from_slice(&job.id).unwrap_or_default().to_string(),
+        "patient_id": Uuid::from_slice(&job.patient_id).unwrap_or_default().to_string(),
+        "status": job.status,
+        "old_key_fingerprint": job.old_key_fingerprint,
+        "new_key_fingerprint": job.new_key_fingerprint,
+        "total_records": job.total_records,
+        "rewrapped_records": job.rewrapped_records,
+        "error": job.error,
+        "created_at": job.created_at,
+        "updated_at": job.updated_at,
+        "completed_at": job.completed_at,
+    })
+}
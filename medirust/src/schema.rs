@@ -1,5 +1,120 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    admin_users (id) {
+        id -> Binary,
+        name -> Text,
+        certificate_fingerprint -> Text,
+        created_at -> Timestamp,
+        disabled_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    audit_batches (id) {
+        id -> Binary,
+        merkle_root -> Text,
+        leaf_count -> Integer,
+        ledger -> Text,
+        anchor_ref -> Nullable<Text>,
+        created_at -> Timestamp,
+        anchored_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    audit_events (id) {
+        id -> Binary,
+        occurred_at -> Timestamp,
+        actor -> Text,
+        action -> Text,
+        patient_id -> Nullable<Binary>,
+        record_id -> Nullable<Binary>,
+        record_cid -> Nullable<Text>,
+        status -> Integer,
+        leaf_hash -> Text,
+        batch_id -> Nullable<Binary>,
+        leaf_index -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    blob_pins (cid) {
+        cid -> Text,
+        status -> Text,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        verified_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    clinician_keys (principal) {
+        principal -> Text,
+        pre_public_key -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    credentials (id) {
+        id -> Binary,
+        patient_id -> Binary,
+        record_id -> Binary,
+        credential_type -> Text,
+        status_index -> Integer,
+        issued_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    data_keys (id) {
+        id -> Text,
+        tenant -> Text,
+        root_key_id -> Text,
+        wrapped_key -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    delegations (id) {
+        id -> Binary,
+        patient_id -> Binary,
+        clinician -> Text,
+        re_key -> Nullable<Text>,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    device_record_keys (device_id, record_id) {
+        device_id -> Binary,
+        record_id -> Binary,
+        wrapped_key -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    devices (id) {
+        id -> Binary,
+        patient_id -> Binary,
+        name -> Text,
+        key_algorithm -> Text,
+        public_key_pem -> Text,
+        key_fingerprint -> Text,
+        created_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
 diesel::table! {
     health_records (id) {
         id -> Binary,
@@ -12,6 +127,49 @@ diesel::table! {
         updated_at -> Timestamp,
         encrypted_aes_key -> Text,
         nonce -> Text,
+        data_key_id -> Nullable<Text>,
+        key_fingerprint -> Nullable<Text>,
+        pre_capsule -> Nullable<Text>,
+        pre_wrapped_key -> Nullable<Text>,
+        signature -> Nullable<Text>,
+        signing_key_id -> Nullable<Text>,
+        aad_version -> Integer,
+        content_sha256 -> Nullable<Text>,
+        metadata_key_id -> Nullable<Text>,
+        sealed_record_type -> Nullable<Text>,
+        sealed_title -> Nullable<Text>,
+        record_type_index -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    key_rotation_jobs (id) {
+        id -> Binary,
+        patient_id -> Binary,
+        old_key_fingerprint -> Text,
+        new_key_fingerprint -> Text,
+        status -> Text,
+        total_records -> BigInt,
+        rewrapped_records -> BigInt,
+        error -> Nullable<Text>,
+        old_escrowed_private_key -> Nullable<Text>,
+        old_escrow_key_id -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
+        old_key_algorithm -> Text,
+    }
+}
+
+diesel::table! {
+    patient_key_history (id) {
+        id -> Binary,
+        patient_id -> Binary,
+        key_fingerprint -> Text,
+        public_key_pem -> Text,
+        rotation_job_id -> Nullable<Binary>,
+        retired_at -> Timestamp,
+        key_algorithm -> Text,
     }
 }
 
@@ -23,12 +181,103 @@ diesel::table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         public_key_pem -> Text,
+        escrowed_private_key -> Nullable<Text>,
+        escrow_key_id -> Nullable<Text>,
+        passphrase_protected_key -> Nullable<Text>,
+        key_algorithm -> Text,
+        pre_public_key -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    recovery_configs (patient_id) {
+        patient_id -> Binary,
+        threshold -> Integer,
+        share_count -> Integer,
+        key_fingerprint -> Text,
+        encrypted_private_key -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_guardians (id) {
+        id -> Binary,
+        patient_id -> Binary,
+        name -> Text,
+        public_key_pem -> Text,
+        encrypted_share -> Text,
+        share_hash -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    recovery_requests (id) {
+        id -> Binary,
+        patient_id -> Binary,
+        status -> Text,
+        verified_by -> Nullable<Text>,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
+        recovery_public_key_pem -> Nullable<Text>,
+        access_token_hash -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    recovery_submissions (id) {
+        id -> Binary,
+        request_id -> Binary,
+        guardian_id -> Binary,
+        share -> Text,
+        submitted_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    signing_keys (id) {
+        id -> Text,
+        principal -> Text,
+        algorithm -> Text,
+        public_key_pem -> Text,
+        created_at -> Timestamp,
     }
 }
 
+diesel::joinable!(delegations -> patients (patient_id));
+diesel::joinable!(device_record_keys -> devices (device_id));
+diesel::joinable!(device_record_keys -> health_records (record_id));
+diesel::joinable!(devices -> patients (patient_id));
 diesel::joinable!(health_records -> patients (patient_id));
+diesel::joinable!(key_rotation_jobs -> patients (patient_id));
+diesel::joinable!(patient_key_history -> patients (patient_id));
+diesel::joinable!(recovery_configs -> patients (patient_id));
+diesel::joinable!(recovery_guardians -> patients (patient_id));
+diesel::joinable!(recovery_requests -> patients (patient_id));
+diesel::joinable!(recovery_submissions -> recovery_guardians (guardian_id));
+diesel::joinable!(recovery_submissions -> recovery_requests (request_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    admin_users,
+    audit_batches,
+    audit_events,
+    blob_pins,
+    clinician_keys,
+    credentials,
+    data_keys,
+    delegations,
+    device_record_keys,
+    devices,
     health_records,
+    key_rotation_jobs,
+    patient_key_history,
     patients,
+    recovery_configs,
+    recovery_guardians,
+    recovery_requests,
+    recovery_submissions,
+    signing_keys,
 );
@@ -0,0 +1,202 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use anyhow::{Result, anyhow};
+
+// Selective Disclosure JWTs (IETF SD-JWT) with flat, object-level claims.
+// The issuer signs a JWT holding SHA-256 digests of salted disclosures
+// [salt, name, value]; the holder reveals a subset as
+// <issuer-jwt>~<disclosure>~...~ and the verifier checks each disclosure's
+// digest against the signed `_sd` array. Key binding is not used, so a
+// presentation is a bearer token.
+
+const JWT_ALG: &str = "EdDSA";
+const JWT_TYP: &str = "sd+jwt";
+const SD_ALG: &str = "sha-256";
+const SALT_SIZE: usize = 16;
+// `_sd` is padded with decoy digests to a multiple of this, hiding the claim count
+const DIGEST_BLOCK: usize = 8;
+// Claims the issuer sets and disclosures must not shadow
+const RESERVED_CLAIMS: [&str; 6] = ["iss", "iat", "exp", "_sd", "_sd_alg", "..."];
+
+fn encode_json(value: &Value) -> String {
+    URL_SAFE_NO_PAD.encode(value.to_string())
+}
+
+fn decode_json(segment: &str) -> Result<Value> {
+    let bytes = URL_SAFE_NO_PAD.decode(segment).map_err(|_| anyhow!("Invalid base64url segment"))?;
+    serde_json::from_slice(&bytes).map_err(|_| anyhow!("Invalid JSON segment"))
+}
+
+fn digest(encoded: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(encoded.as_bytes()))
+}
+
+fn random_b64(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// One salted claim that can be revealed independently
+#[derive(Debug, Clone)]
+pub struct Disclosure {
+    pub name: String,
+    pub value: Value,
+    pub encoded: String,
+}
+
+impl Disclosure {
+    pub fn new(name: &str, value: Value) -> Self {
+        let encoded = encode_json(&json!([random_b64(SALT_SIZE), name, value]));
+        Disclosure { name: name.to_string(), value, encoded }
+    }
+
+    pub fn parse(encoded: &str) -> Result<Self> {
+        match decode_json(encoded)? {
+            Value::Array(parts) if parts.len() == 3 => match (&parts[0], &parts[1]) {
+                (Value::String(_), Value::String(name)) => Ok(Disclosure {
+                    name: name.clone(),
+                    value: parts[2].clone(),
+                    encoded: encoded.to_string(),
+                }),
+                _ => Err(anyhow!("Disclosure salt and claim name must be strings")),
+            },
+            _ => Err(anyhow!("Disclosure must be a [salt, name, value] array")),
+        }
+    }
+
+    pub fn digest(&self) -> String {
+        digest(&self.encoded)
+    }
+}
+
+// Signs an SD-JWT: `claims` are always visible, `disclosable` only when the
+// holder reveals them. Returns the issuer-signed JWT and the disclosures.
+pub fn issue(
+    key: &SigningKey,
+    kid: &str,
+    mut claims: Map<String, Value>,
+    disclosable: Vec<(String, Value)>,
+) -> Result<(String, Vec<Disclosure>)> {
+    let disclosures: Vec<Disclosure> = disclosable
+        .into_iter()
+        .map(|(name, value)| {
+            if RESERVED_CLAIMS.contains(&name.as_str()) || claims.contains_key(&name) {
+                return Err(anyhow!("Claim {} cannot be selectively disclosed", name));
+            }
+            Ok(Disclosure::new(&name, value))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut digests: Vec<String> = disclosures.iter().map(Disclosure::digest).collect();
+    while digests.is_empty() || !digests.len().is_multiple_of(DIGEST_BLOCK) {
+        digests.push(digest(&random_b64(SALT_SIZE)));
+    }
+    // Sorting hides which position belonged to which claim
+    digests.sort();
+    claims.insert("_sd".to_string(), json!(digests));
+    claims.insert("_sd_alg".to_string(), json!(SD_ALG));
+
+    Ok((sign_jwt(key, JWT_TYP, kid, &claims), disclosures))
+}
+
+// Signs a compact EdDSA JWS over the claims
+pub fn sign_jwt(key: &SigningKey, typ: &str, kid: &str, claims: &Map<String, Value>) -> String {
+    let header = json!({ "alg": JWT_ALG, "typ": typ, "kid": kid });
+    let signing_input = format!("{}.{}", encode_json(&header), encode_json(&Value::Object(claims.clone())));
+    let signature = key.sign(signing_input.as_bytes());
+    format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+}
+
+// Checks a compact EdDSA JWS of the given type and its expiry, returning its claims
+pub fn verify_jwt(jwt: &str, key: &VerifyingKey, typ: &str, now: i64) -> Result<Map<String, Value>> {
+    let segments: Vec<&str> = jwt.split('.').collect();
+    let [header, payload, signature] = segments[..] else {
+        return Err(anyhow!("Malformed JWT"));
+    };
+
+    let decoded_header = decode_json(header)?;
+    if decoded_header.get("alg") != Some(&json!(JWT_ALG)) {
+        return Err(anyhow!("Unsupported JWT algorithm"));
+    }
+    if decoded_header.get("typ") != Some(&json!(typ)) {
+        return Err(anyhow!("Expected a JWT of type {}", typ));
+    }
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| anyhow!("Invalid JWT signature encoding"))?;
+    let signature = Signature::from_slice(&signature).map_err(|_| anyhow!("Invalid JWT signature"))?;
+    key.verify_strict(format!("{}.{}", header, payload).as_bytes(), &signature)
+        .map_err(|_| anyhow!("Issuer signature does not verify"))?;
+
+    let Value::Object(claims) = decode_json(payload)? else {
+        return Err(anyhow!("JWT payload must be an object"));
+    };
+    match claims.get("exp").and_then(Value::as_i64) {
+        Some(exp) if exp > now => Ok(claims),
+        Some(_) => Err(anyhow!("JWT has expired")),
+        None => Err(anyhow!("JWT has no expiry")),
+    }
+}
+
+// Serializes an SD-JWT revealing the given disclosures
+pub fn present(jwt: &str, disclosures: &[Disclosure]) -> String {
+    let mut presentation = jwt.to_string();
+    presentation.push('~');
+    for disclosure in disclosures {
+        presentation.push_str(&disclosure.encoded);
+        presentation.push('~');
+    }
+    presentation
+}
+
+// Key id of the issuer key from the JWT header; accepts a JWT or an SD-JWT
+pub fn key_id(presentation: &str) -> Result<String> {
+    let jwt = presentation.split('~').next().unwrap_or_default();
+    let header = jwt.split('.').next().ok_or_else(|| anyhow!("Malformed JWT"))?;
+    match decode_json(header)?.get("kid") {
+        Some(Value::String(kid)) => Ok(kid.clone()),
+        _ => Err(anyhow!("JWT header has no kid")),
+    }
+}
+
+// Checks the issuer signature, expiry and every disclosure, returning the
+// visible claims merged with the disclosed ones
+pub fn verify(presentation: &str, key: &VerifyingKey, now: i64) -> Result<Map<String, Value>> {
+    let mut parts = presentation.split('~');
+    let mut claims = verify_jwt(parts.next().unwrap_or_default(), key, JWT_TYP, now)?;
+    if claims.get("_sd_alg") != Some(&json!(SD_ALG)) {
+        return Err(anyhow!("Unsupported _sd_alg"));
+    }
+    let digests: HashSet<String> = match claims.remove("_sd") {
+        Some(Value::Array(digests)) => digests.into_iter().filter_map(|d| d.as_str().map(str::to_string)).collect(),
+        _ => return Err(anyhow!("SD-JWT has no _sd array")),
+    };
+    claims.remove("_sd_alg");
+
+    let mut seen = HashSet::new();
+    let mut disclosed = HashSet::new();
+    for encoded in parts.filter(|part| !part.is_empty()) {
+        let disclosure = Disclosure::parse(encoded)?;
+        let disclosure_digest = disclosure.digest();
+        if !digests.contains(&disclosure_digest) {
+            return Err(anyhow!("Disclosure for {} is not covered by the issuer signature", disclosure.name));
+        }
+        if !seen.insert(disclosure_digest) {
+            return Err(anyhow!("Disclosure for {} is presented twice", disclosure.name));
+        }
+        if RESERVED_CLAIMS.contains(&disclosure.name.as_str()) {
+            return Err(anyhow!("Disclosure shadows the reserved claim {}", disclosure.name));
+        }
+        if !disclosed.insert(disclosure.name.clone()) {
+            return Err(anyhow!("Claim {} is disclosed by two different disclosures", disclosure.name));
+        }
+        if claims.contains_key(&disclosure.name) {
+            return Err(anyhow!("Disclosure shadows the issuer-signed visible claim {}", disclosure.name));
+        }
+        claims.insert(disclosure.name, disclosure.value);
+    }
+    Ok(claims)
+}
@@ -0,0 +1,346 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use ed25519_dalek::{Signature as Ed25519Signature, VerifyingKey as Ed25519VerifyingKey};
+use rsa::{
+    pkcs8::{DecodePublicKey, EncodePublicKey, LineEnding},
+    pss::{Signature as PssSignature, VerifyingKey as PssVerifyingKey},
+    signature::Verifier,
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use anyhow::{Result, anyhow};
+
+use crate::auth::Principal;
+use crate::crypto::CryptoUtils;
+use crate::metrics::METRICS;
+use crate::models::{HealthRecord, NewHealthRecord, SigningKey};
+use crate::schema::signing_keys;
+use crate::DbPool;
+
+// Domain separator opening every record signing payload
+const RECORD_SIGNATURE_DOMAIN: &[u8] = b"medirust-record-signature-v1";
+// Smallest RSA modulus accepted for RSA-PSS signing keys
+const MIN_RSA_PSS_BITS: usize = 2048;
+// How far a signed record's creation time may be from the server's clock
+const MAX_SIGNING_CLOCK_SKEW_SECS: i64 = 300;
+
+// Signature scheme of a clinician signing key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum SignatureAlgorithm {
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    // RSASSA-PSS with SHA-256 and a 32-byte salt
+    #[serde(rename = "rsa-pss-sha256")]
+    RsaPss,
+}
+
+impl SignatureAlgorithm {
+    pub fn id(self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Ed25519 => "ed25519",
+            SignatureAlgorithm::RsaPss => "rsa-pss-sha256",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Result<Self> {
+        match id {
+            "ed25519" => Ok(SignatureAlgorithm::Ed25519),
+            "rsa-pss-sha256" => Ok(SignatureAlgorithm::RsaPss),
+            _ => Err(anyhow!("Unknown signature algorithm {}", id)),
+        }
+    }
+
+    fn verify_operation(self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Ed25519 => "ed25519_verify",
+            SignatureAlgorithm::RsaPss => "rsa_pss_verify",
+        }
+    }
+}
+
+// Public half of a clinician signing key, stored as SPKI PEM
+pub enum VerifyingKey {
+    Ed25519(Ed25519VerifyingKey),
+    RsaPss(Box<RsaPublicKey>),
+}
+
+impl VerifyingKey {
+    pub fn from_pem(algorithm: SignatureAlgorithm, pem: &str) -> Result<Self> {
+        match algorithm {
+            SignatureAlgorithm::Ed25519 => Ed25519VerifyingKey::from_public_key_pem(pem)
+                .map(VerifyingKey::Ed25519)
+                .map_err(|e| anyhow!("Failed to import Ed25519 public key: {}", e)),
+            SignatureAlgorithm::RsaPss => {
+                let key = RsaPublicKey::from_public_key_pem(pem)
+                    .map_err(|e| anyhow!("Failed to import RSA public key: {}", e))?;
+                if key.size() * 8 < MIN_RSA_PSS_BITS {
+                    return Err(anyhow!("RSA signing keys must be at least {} bits", MIN_RSA_PSS_BITS));
+                }
+                Ok(VerifyingKey::RsaPss(Box::new(key)))
+            }
+        }
+    }
+
+    pub fn algorithm(&self) -> SignatureAlgorithm {
+        match self {
+            VerifyingKey::Ed25519(_) => SignatureAlgorithm::Ed25519,
+            VerifyingKey::RsaPss(_) => SignatureAlgorithm::RsaPss,
+        }
+    }
+
+    fn to_der(&self) -> Result<Vec<u8>> {
+        let der = match self {
+            VerifyingKey::Ed25519(key) => key.to_public_key_der(),
+            VerifyingKey::RsaPss(key) => key.to_public_key_der(),
+        };
+        der.map(|der| der.into_vec()).map_err(|e| anyhow!("Failed to encode public key: {}", e))
+    }
+
+    pub fn to_pem(&self) -> Result<String> {
+        let pem = match self {
+            VerifyingKey::Ed25519(key) => key.to_public_key_pem(LineEnding::LF),
+            VerifyingKey::RsaPss(key) => key.to_public_key_pem(LineEnding::LF),
+        };
+        pem.map_err(|e| anyhow!("Failed to export public key to PEM: {}", e))
+    }
+
+    // SHA-256 fingerprint (hex) of the SPKI DER encoding; used as the key id
+    pub fn fingerprint(&self) -> Result<String> {
+        Ok(Sha256::digest(self.to_der()?).iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        match self {
+            VerifyingKey::Ed25519(key) => {
+                let signature = Ed25519Signature::from_slice(signature)
+                    .map_err(|_| anyhow!("Malformed Ed25519 signature"))?;
+                key.verify_strict(message, &signature)
+                    .map_err(|_| anyhow!("Signature does not verify"))
+            }
+            VerifyingKey::RsaPss(key) => {
+                let signature = PssSignature::try_from(signature)
+                    .map_err(|_| anyhow!("Malformed RSA-PSS signature"))?;
+                PssVerifyingKey::<Sha256>::new(key.as_ref().clone())
+                    .verify(message, &signature)
+                    .map_err(|_| anyhow!("Signature does not verify"))
+            }
+        }
+    }
+}
+
+// Bytes a clinician signs for a record: the domain separator followed by the
+// record id, patient id, creation time (microseconds since the Unix epoch, as
+// a big-endian i64), record type, title and SHA-256 of the plaintext content,
+// each prefixed with its length as a big-endian u32. The id and creation time
+// keep a signature from being replayed onto another record.
+pub fn record_signing_payload(
+    record_id: &[u8],
+    patient_id: &[u8],
+    created_at: NaiveDateTime,
+    record_type: &str,
+    title: &str,
+    content: &[u8],
+) -> Vec<u8> {
+    let content_hash = Sha256::digest(content);
+    let created_at = created_at.and_utc().timestamp_micros().to_be_bytes();
+    let mut payload = RECORD_SIGNATURE_DOMAIN.to_vec();
+    for field in [record_id, patient_id, &created_at, record_type.as_bytes(), title.as_bytes(), content_hash.as_slice()] {
+        payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        payload.extend_from_slice(field);
+    }
+    payload
+}
+
+// Creation time of a signed record as it will be stored, so the signature
+// still verifies once the database has dropped sub-microsecond precision
+pub fn signed_creation_time(created_at: NaiveDateTime) -> Result<NaiveDateTime> {
+    let now = Utc::now().naive_utc();
+    if (now - created_at).abs() > chrono::Duration::seconds(MAX_SIGNING_CLOCK_SKEW_SECS) {
+        return Err(anyhow!("created_at must be within {} seconds of the server's clock", MAX_SIGNING_CLOCK_SKEW_SECS));
+    }
+    DateTime::from_timestamp_micros(created_at.and_utc().timestamp_micros())
+        .map(|created_at| created_at.naive_utc())
+        .ok_or_else(|| anyhow!("created_at is out of range"))
+}
+
+// Outcome of checking a record's author signature, reported on read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureStatus {
+    Valid,
+    // The record carries no signature
+    Unsigned,
+    // The signature does not match the content or metadata
+    Invalid,
+    // The signing key is no longer registered
+    UnknownKey,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordVerification {
+    pub status: SignatureStatus,
+    pub signing_key_id: Option<String>,
+    pub signed_by: Option<String>,
+    pub algorithm: Option<String>,
+}
+
+// Verifies a record's detached signature against its decrypted content
+pub fn verify_record(record: &HealthRecord, signing_key: Option<&SigningKey>, content: &[u8]) -> RecordVerification {
+    let (Some(signature), Some(key_id)) = (&record.signature, &record.signing_key_id) else {
+        return RecordVerification { status: SignatureStatus::Unsigned, signing_key_id: None, signed_by: None, algorithm: None };
+    };
+    let Some(signing_key) = signing_key else {
+        return RecordVerification { status: SignatureStatus::UnknownKey, signing_key_id: Some(key_id.clone()), signed_by: None, algorithm: None };
+    };
+
+    let payload = record_signing_payload(
+        &record.id,
+        &record.patient_id,
+        record.created_at,
+        &record.record_type,
+        &record.title,
+        content,
+    );
+    let verified = SignatureAlgorithm::from_id(&signing_key.algorithm).and_then(|algorithm| {
+        let key = VerifyingKey::from_pem(algorithm, &signing_key.public_key_pem)?;
+        let signature = CryptoUtils::decode_base64(signature)?;
+        METRICS.time_crypto(algorithm.verify_operation(), || key.verify(&payload, &signature))
+    });
+    let status = match verified {
+        Ok(()) => SignatureStatus::Valid,
+        Err(e) => {
+            tracing::warn!(signing_key_id = %key_id, error = %e, "health record signature verification failed");
+            SignatureStatus::Invalid
+        }
+    };
+    RecordVerification {
+        status,
+        signing_key_id: Some(key_id.clone()),
+        signed_by: Some(signing_key.principal.clone()),
+        algorithm: Some(signing_key.algorithm.clone()),
+    }
+}
+
+// Checks a signature submitted with a new record before it is stored
+pub fn verify_new_record(signing_key: &SigningKey, record: &NewHealthRecord) -> Result<()> {
+    let (Some(signature), Some(record_id), Some(created_at)) = (&record.signature, record.id, record.created_at) else {
+        return Err(anyhow!("Signed records must carry their signature, id and created_at"));
+    };
+    let algorithm = SignatureAlgorithm::from_id(&signing_key.algorithm)?;
+    let key = VerifyingKey::from_pem(algorithm, &signing_key.public_key_pem)?;
+    let signature = CryptoUtils::decode_base64(signature)?;
+    let payload = record_signing_payload(
+        record_id.as_bytes(),
+        &record.patient_id,
+        created_at,
+        &record.record_type,
+        &record.title,
+        record.content.as_bytes(),
+    );
+    METRICS.time_crypto(algorithm.verify_operation(), || key.verify(&payload, &signature))
+}
+
+pub fn load_signing_key(conn: &mut PgConnection, key_id: &str) -> Result<Option<SigningKey>> {
+    Ok(signing_keys::table
+        .find(key_id)
+        .select(SigningKey::as_select())
+        .first(conn)
+        .optional()?)
+}
+
+// Signing key of a record, if it is signed and the key is still registered
+pub fn record_signing_key(conn: &mut PgConnection, record: &HealthRecord) -> Result<Option<SigningKey>> {
+    match &record.signing_key_id {
+        Some(key_id) => load_signing_key(conn, key_id),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterSigningKeyRequest {
+    pub algorithm: SignatureAlgorithm,
+    // SPKI ("PUBLIC KEY") PEM
+    pub public_key_pem: String,
+}
+
+// Handler for the authenticated clinician to register a record signing key
+pub async fn register_signing_key(
+    pool: web::Data<DbPool>,
+    principal: Principal,
+    request: web::Json<RegisterSigningKeyRequest>,
+) -> impl Responder {
+    let request = request.into_inner();
+    let key = match VerifyingKey::from_pem(request.algorithm, &request.public_key_pem) {
+        Ok(key) => key,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid signing key: {:?}", e)),
+    };
+    let signing_key = match (key.fingerprint(), key.to_pem()) {
+        (Ok(id), Ok(public_key_pem)) => SigningKey {
+            id,
+            principal: principal.name,
+            algorithm: key.algorithm().id().to_string(),
+            public_key_pem,
+            created_at: Utc::now().naive_utc(),
+        },
+        (Err(e), _) | (_, Err(e)) => return HttpResponse::InternalServerError().body(format!("Error encoding signing key: {:?}", e)),
+    };
+
+    match web::block(move || -> Result<Option<SigningKey>> {
+        let mut conn = pool.get()?;
+        conn.transaction(|conn| {
+            match load_signing_key(conn, &signing_key.id)? {
+                // Re-registering the same key is a no-op; another clinician's key is refused
+                Some(existing) if existing.principal == signing_key.principal => Ok(Some(existing)),
+                Some(_) => Ok(None),
+                None => {
+                    diesel::insert_into(signing_keys::table).values(&signing_key).execute(conn)?;
+                    Ok(Some(signing_key))
+                }
+            }
+        })
+    })
+    .await
+    {
+        Ok(Ok(Some(key))) => HttpResponse::Created().json(signing_key_json(&key)),
+        Ok(Ok(None)) => HttpResponse::Conflict().body("Signing key is registered to another clinician"),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error registering signing key: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+// Handler listing a clinician's signing keys, so readers can verify records offline
+pub async fn list_signing_keys(
+    pool: web::Data<DbPool>,
+    clinician: web::Path<String>,
+) -> impl Responder {
+    let clinician = clinician.into_inner();
+    match web::block(move || -> Result<Vec<SigningKey>> {
+        let mut conn = pool.get()?;
+        Ok(signing_keys::table
+            .filter(signing_keys::principal.eq(clinician))
+            .order(signing_keys::created_at.asc())
+            .select(SigningKey::as_select())
+            .load(&mut conn)?)
+    })
+    .await
+    {
+        Ok(Ok(keys)) => HttpResponse::Ok().json(keys.iter().map(signing_key_json).collect::<Vec<_>>()),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Error listing signing keys: {:?}", e)),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error blocking thread: {:?}", e)),
+    }
+}
+
+fn signing_key_json(key: &SigningKey) -> serde_json::Value {
+    json!({
+        "id": key.id,
+        "clinician": key.principal,
+        "algorithm": key.algorithm,
+        "public_key_pem": key.public_key_pem,
+        "created_at": key.created_at,
+    })
+}
@@ -0,0 +1,107 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    HttpMessage,
+};
+use std::time::Instant;
+use tracing::{field, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+use anyhow::{Result, anyhow};
+
+use crate::config::{LogFormat, LoggingConfig};
+use crate::metrics::METRICS;
+
+// Header used to propagate correlation IDs between callers and the service
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Correlation ID of the current request, available from request extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// Installs the global tracing subscriber. RUST_LOG, when set, takes precedence
+// over logging.level so operators can raise verbosity for a single module.
+pub fn init_tracing(config: &LoggingConfig) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .map_err(|e| anyhow!("Invalid log filter: {}", e))?;
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Json => builder.json().try_init(),
+        LogFormat::Text => builder.try_init(),
+    }
+    .map_err(|e| anyhow!("Failed to install tracing subscriber: {}", e))
+}
+
+// Middleware wrapping every request in a span carrying its correlation ID,
+// recording latency metrics and echoing the ID back in the response.
+//
+// Only request metadata is logged here. Bodies may contain plaintext health
+// data and must never be attached to spans or log events.
+pub async fn request_telemetry(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid_request_id(v))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let method = req.method().to_string();
+    // Use the route pattern rather than the raw path so patient IDs do not
+    // end up in logs or metric labels
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+    let span = info_span!(
+        "http_request",
+        request_id = %request_id,
+        method = %method,
+        route = %route,
+        status = field::Empty,
+        latency_ms = field::Empty,
+    );
+
+    let start = Instant::now();
+    let result = next.call(req).instrument(span.clone()).await;
+    let elapsed = start.elapsed();
+
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    METRICS.http_request_duration
+        .with_label_values(&[method.as_str(), route.as_str(), status.as_str()])
+        .observe(elapsed.as_secs_f64());
+
+    span.record("status", status.as_u16());
+    span.record("latency_ms", elapsed.as_millis() as u64);
+    span.in_scope(|| {
+        if status.is_server_error() {
+            tracing::error!("request failed");
+        } else if status.is_client_error() {
+            tracing::warn!("request rejected");
+        } else {
+            tracing::info!("request completed");
+        }
+    });
+
+    let mut res = result?;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+// Accept caller-supplied IDs only if they are short and free of characters
+// that could be used for log injection
+fn is_valid_request_id(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= 128
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
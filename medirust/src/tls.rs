@@ -0,0 +1,108 @@
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use anyhow::{Result, anyhow, Context};
+
+use crate::config::TlsConfig;
+
+// DER-encoded leaf certificate presented by the client during the handshake.
+// Stored in the connection extensions by `extract_client_cert`.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate(pub Vec<u8>);
+
+impl ClientCertificate {
+    // Lower-case hex SHA-256 of the DER bytes, as used in tls.client_principals
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(&self.0)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    // Leaf certificate of a PEM file, e.g. to register an admin user
+    pub fn from_pem_file(path: &Path) -> Result<Self> {
+        let certs = load_certs(path)?;
+        Ok(ClientCertificate(certs[0].to_vec()))
+    }
+}
+
+// Builds the rustls server configuration from the [tls] section
+pub fn load_server_config(config: &TlsConfig) -> Result<ServerConfig> {
+    let cert_path = config.cert_path.as_ref().ok_or_else(|| anyhow!("tls.cert_path is not set"))?;
+    let key_path = config.key_path.as_ref().ok_or_else(|| anyhow!("tls.key_path is not set"))?;
+
+    let cert_chain = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let provider = Arc::new(default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| anyhow!("Failed to select TLS protocol versions: {}", e))?;
+
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots.add(cert)
+                    .map_err(|e| anyhow!("Invalid client CA certificate in {}: {}", ca_path.display(), e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.require_client_cert {
+                verifier.build()
+            } else {
+                verifier.allow_unauthenticated().build()
+            }
+            .map_err(|e| anyhow!("Failed to build client certificate verifier: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| anyhow!("Invalid TLS certificate/key pair: {}", e))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+// Connection hook for `HttpServer::on_connect`: records the verified client
+// certificate so request handlers can resolve the calling principal.
+pub fn extract_client_cert(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(tls_stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = tls_stream.get_ref();
+        if let Some(leaf) = session.peer_certificates().and_then(|certs| certs.first()) {
+            data.insert(ClientCertificate(leaf.to_vec()));
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificates in {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open private key file {}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse private key in {}", path.display()))?
+        .ok_or_else(|| anyhow!("No private key found in {}", path.display()))
+}
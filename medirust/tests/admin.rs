@@ -0,0 +1,197 @@
+// medirust-admin commands, run as operators run them: the built binary with a
+// configuration file pointing at the test database
+
+mod common;
+
+use diesel::prelude::*;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use uuid::Uuid;
+
+use medirust::blobstore::{FsBlobStore, MemoryBlobStore};
+use medirust::crypto::KeyAlgorithm;
+use medirust::keys::{KeyHierarchy, RootKey};
+use medirust::models::HealthRecord;
+use medirust::schema::{data_keys, health_records};
+
+use common::{escrow_private_key, insert_patient, insert_record, key_hierarchy, patient_uuid, TestDatabase};
+
+// Working directory of one test: configuration, root key, blobs and keys
+struct Deployment {
+    dir: PathBuf,
+}
+
+impl Deployment {
+    // The blob store is an unreachable IPFS node replicated to a directory,
+    // so reads are served from the files the test writes
+    fn new(db: &TestDatabase) -> Self {
+        let dir = std::env::temp_dir().join(format!("medirust-admin-{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("keys")).unwrap();
+        fs::write(dir.join("root.key"), [7u8; 32]).unwrap();
+        let config = format!(
+            r#"[database]
+url = "{}"
+
+[blob_store]
+ipfs_api_url = "http://127.0.0.1:1"
+write_quorum = 1
+
+[[blob_store.replicas]]
+kind = "fs"
+path = "{}"
+
+[key_management]
+tenant = "test"
+
+[key_management.root_key]
+path = "{}"
+"#,
+            db.url(),
+            dir.join("blobs").display(),
+            dir.join("root.key").display(),
+        );
+        fs::write(dir.join("medirust.toml"), config).unwrap();
+        Deployment { dir }
+    }
+
+    fn blobs(&self) -> PathBuf {
+        self.dir.join("blobs")
+    }
+
+    // Copies a record's blob from the store the test helpers write to
+    async fn store(&self, memory: &MemoryBlobStore, record: &HealthRecord) {
+        let cid = FsBlobStore::new(&self.blobs()).unwrap().put(memory.get(&record.ipfs_cid).unwrap()).await.unwrap();
+        assert_eq!(cid, record.ipfs_cid);
+    }
+
+    // Runs medirust-admin with only this deployment's configuration
+    fn run(&self, args: &[&str]) -> Output {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_medirust-admin"));
+        command.args(args).current_dir(&self.dir).env("MEDIRUST_CONFIG", self.dir.join("medirust.toml")).env_remove("DATABASE_URL");
+        for (name, _) in std::env::vars().filter(|(name, _)| name.starts_with("MEDIRUST_") && name != "MEDIRUST_CONFIG") {
+            command.env_remove(name);
+        }
+        command.output().unwrap()
+    }
+}
+
+impl Drop for Deployment {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn uuid(id: &[u8]) -> String {
+    Uuid::from_slice(id).unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn check_records_reports_each_kind_of_failure() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let deployment = Deployment::new(&db);
+    let memory = MemoryBlobStore::new();
+    let keys = key_hierarchy();
+    // One patient's key is escrowed, the other's is handed over as a file
+    let (mut escrowed, escrowed_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    escrow_private_key(&mut db.conn(), &keys, &mut escrowed, &escrowed_key);
+    let (filed, filed_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    fs::write(deployment.dir.join("keys").join(format!("{}.pem", patient_uuid(&filed))), filed_key.to_pem().unwrap().as_bytes()).unwrap();
+    let records = [
+        insert_record(&mut db.conn(), &memory, &escrowed, "HbA1c", b"6.1%"),
+        insert_record(&mut db.conn(), &memory, &filed, "LDL", b"2.4 mmol/L"),
+        insert_record(&mut db.conn(), &memory, &filed, "TSH", b"1.9 mU/L"),
+    ];
+    for record in &records {
+        deployment.store(&memory, record).await;
+    }
+
+    let output = deployment.run(&["check-records", "--keys-dir", "keys"]);
+    assert!(output.status.success(), "{}{}", stdout(&output), String::from_utf8_lossy(&output.stderr));
+    assert!(stdout(&output).contains("3 records checked: 3 retrievable, 3 decrypted, 0 failed"), "{}", stdout(&output));
+
+    // Without the key file only the escrowed patient's record decrypts
+    let output = deployment.run(&["check-records"]);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("3 records checked: 3 retrievable, 1 decrypted, 0 failed"), "{}", stdout(&output));
+
+    fs::write(deployment.blobs().join(&records[1].ipfs_cid), b"tampered").unwrap();
+    fs::remove_file(deployment.blobs().join(&records[2].ipfs_cid)).unwrap();
+    let output = deployment.run(&["check-records", "--keys-dir", "keys"]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = stdout(&output);
+    assert!(report.contains(&format!("CORRUPT record {}", uuid(&records[1].id))), "{}", report);
+    assert!(report.contains(&format!("MISSING record {}", uuid(&records[2].id))), "{}", report);
+    assert!(report.contains("3 records checked: 1 retrievable, 1 decrypted, 2 failed"), "{}", report);
+
+    // A record pointing at another record's intact blob fails to decrypt
+    diesel::update(health_records::table.find(&records[1].id))
+        .set((
+            health_records::ipfs_cid.eq(&records[0].ipfs_cid),
+            health_records::content_sha256.eq(&records[0].content_sha256),
+        ))
+        .execute(&mut db.conn())
+        .unwrap();
+    let output = deployment.run(&["check-records", "--patient", &patient_uuid(&filed), "--keys-dir", "keys"]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = stdout(&output);
+    assert!(report.contains(&format!("UNREADABLE record {}", uuid(&records[1].id))), "{}", report);
+    assert!(report.contains("2 records checked: 1 retrievable, 0 decrypted, 2 failed"), "{}", report);
+}
+
+#[test]
+fn rotate_root_key_rewraps_data_keys_under_a_new_key_file() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let deployment = Deployment::new(&db);
+    let keys = key_hierarchy();
+    let data_key = keys.active_data_key(&mut db.conn()).unwrap();
+
+    let output = deployment.run(&["rotate-root-key", "--new-key-file", "new-root.key"]);
+    assert!(output.status.success(), "{}{}", stdout(&output), String::from_utf8_lossy(&output.stderr));
+    assert!(stdout(&output).contains("Re-wrapped 1 data keys"), "{}", stdout(&output));
+
+    let new_key_file = deployment.dir.join("new-root.key");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&new_key_file).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+    let new_root = RootKey::from_file(&new_key_file).unwrap();
+    let root_key_ids: Vec<String> = data_keys::table.select(data_keys::root_key_id).load(&mut db.conn()).unwrap();
+    assert_eq!(root_key_ids, vec![new_root.id().to_string()]);
+    let rotated = KeyHierarchy::new(new_root, "test".to_string()).data_key(&mut db.conn(), &data_key.id).unwrap();
+    assert_eq!(rotated.seal(b"x").and_then(|sealed| data_key.open(&sealed)).unwrap(), b"x");
+    // A server still on the old root key can no longer unwrap it
+    assert!(key_hierarchy().data_key(&mut db.conn(), &data_key.id).is_err());
+
+    // The new key file is never overwritten
+    let before = fs::read(&new_key_file).unwrap();
+    let output = deployment.run(&["rotate-root-key", "--new-key-file", "new-root.key"]);
+    assert!(!output.status.success());
+    assert_eq!(fs::read(&new_key_file).unwrap(), before);
+}
+
+#[test]
+fn commands_refuse_to_run_on_an_invalid_configuration() {
+    let deployment_dir = std::env::temp_dir().join(format!("medirust-admin-{}", Uuid::new_v4()));
+    fs::create_dir_all(&deployment_dir).unwrap();
+    fs::write(deployment_dir.join("medirust.toml"), "[database]\nurl = \"postgres://localhost/medirust\"\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_medirust-admin"))
+        .args(["check-records"])
+        .current_dir(&deployment_dir)
+        .env("MEDIRUST_CONFIG", deployment_dir.join("medirust.toml"))
+        .env_remove("MEDIRUST_KEY_MANAGEMENT_ROOT_KEY_PATH")
+        .output()
+        .unwrap();
+    fs::remove_dir_all(&deployment_dir).unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("key_management.root_key"));
+}
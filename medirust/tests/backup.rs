@@ -0,0 +1,230 @@
+// Backup and restore round trips against SQLite and the in-memory blob store
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::io::Cursor;
+use uuid::Uuid;
+
+use medirust::backup::{self, ArchiveInfo};
+use medirust::blobstore::{BlobStore, MemoryBlobStore};
+use medirust::keys::RootKey;
+use medirust::schema::{audit_events, data_keys, health_records, patients};
+use medirust::AnyConnection;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+type RecordRow = (Vec<u8>, Vec<u8>, String, String, String, NaiveDateTime, Option<String>, i32, Option<String>, Option<String>);
+type PatientRow = (Vec<u8>, String, String, Option<String>, String);
+
+fn database() -> AnyConnection {
+    let mut conn = SqliteConnection::establish(":memory:").unwrap();
+    conn.run_pending_migrations(MIGRATIONS).unwrap();
+    AnyConnection::Sqlite(conn)
+}
+
+fn backup_key() -> RootKey {
+    RootKey::from_bytes(vec![7; 32]).unwrap()
+}
+
+fn add_patient(conn: &mut AnyConnection, health_id: &str) -> Vec<u8> {
+    let id = Uuid::new_v4().as_bytes().to_vec();
+    diesel::insert_into(patients::table)
+        .values((
+            patients::id.eq(&id),
+            patients::health_id.eq(health_id),
+            patients::name.eq("Test Patient"),
+            patients::public_key_pem.eq("-----BEGIN PUBLIC KEY-----"),
+            patients::escrowed_private_key.eq(Some("c2VhbGVk")),
+            patients::escrow_key_id.eq(Some("dk-1")),
+            patients::created_at.eq(Utc::now().naive_utc()),
+            patients::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .unwrap();
+    id
+}
+
+fn add_record(conn: &mut AnyConnection, store: &MemoryBlobStore, patient_id: &[u8], content: &[u8]) -> String {
+    let cid = store.put(content.to_vec());
+    diesel::insert_into(health_records::table)
+        .values((
+            health_records::id.eq(Uuid::new_v4().as_bytes().to_vec()),
+            health_records::patient_id.eq(patient_id),
+            health_records::ipfs_cid.eq(&cid),
+            health_records::record_type.eq(""),
+            health_records::title.eq(""),
+            health_records::metadata_key_id.eq(Some("dk-1")),
+            health_records::sealed_record_type.eq(Some("c2VhbGVkLXR5cGU=")),
+            health_records::sealed_title.eq(Some("c2VhbGVkLXRpdGxl")),
+            health_records::record_type_index.eq(Some("cd".repeat(32))),
+            health_records::encryption_key_cid.eq(""),
+            health_records::encrypted_aes_key.eq("d3JhcHBlZA=="),
+            health_records::nonce.eq("bm9uY2U="),
+            health_records::data_key_id.eq(Some("dk-1")),
+            health_records::aad_version.eq(1),
+            health_records::created_at.eq(Utc::now().naive_utc()),
+            health_records::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .unwrap();
+    cid
+}
+
+// A deployment with a data key, two patients, three records and an audit event
+fn populated(store: &MemoryBlobStore) -> AnyConnection {
+    let mut conn = database();
+    diesel::insert_into(data_keys::table)
+        .values((
+            data_keys::id.eq("dk-1"),
+            data_keys::tenant.eq("default"),
+            data_keys::root_key_id.eq("0011223344556677"),
+            data_keys::wrapped_key.eq("d3JhcHBlZC1kYXRhLWtleQ=="),
+            data_keys::created_at.eq(Utc::now().naive_utc()),
+            data_keys::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+    let alice = add_patient(&mut conn, "alice");
+    let bob = add_patient(&mut conn, "bob");
+    add_record(&mut conn, store, &alice, b"alice record one");
+    add_record(&mut conn, store, &alice, &[0xa5; 200_000]);
+    let cid = add_record(&mut conn, store, &bob, b"bob record");
+    diesel::insert_into(audit_events::table)
+        .values((
+            audit_events::id.eq(Uuid::new_v4().as_bytes().to_vec()),
+            audit_events::occurred_at.eq(Utc::now().naive_utc()),
+            audit_events::actor.eq("clinic-north"),
+            audit_events::action.eq("read_record"),
+            audit_events::patient_id.eq(Some(bob)),
+            audit_events::record_cid.eq(Some(cid)),
+            audit_events::status.eq(200),
+            audit_events::leaf_hash.eq("ab".repeat(32)),
+        ))
+        .execute(&mut conn)
+        .unwrap();
+    conn
+}
+
+fn records(conn: &mut AnyConnection) -> Vec<RecordRow> {
+    health_records::table
+        .order(health_records::ipfs_cid)
+        .select((
+            health_records::id,
+            health_records::patient_id,
+            health_records::ipfs_cid,
+            health_records::encrypted_aes_key,
+            health_records::encryption_key_cid,
+            health_records::created_at,
+            health_records::data_key_id,
+            health_records::aad_version,
+            health_records::sealed_title,
+            health_records::record_type_index,
+        ))
+        .load(conn)
+        .unwrap()
+}
+
+fn patient_rows(conn: &mut AnyConnection) -> Vec<PatientRow> {
+    patients::table
+        .order(patients::health_id)
+        .select((patients::id, patients::health_id, patients::public_key_pem, patients::escrowed_private_key, patients::key_algorithm))
+        .load(conn)
+        .unwrap()
+}
+
+async fn take_backup(conn: &mut AnyConnection, store: &MemoryBlobStore, parent: Option<&ArchiveInfo>) -> (Vec<u8>, backup::BackupSummary) {
+    let store = BlobStore::Memory(store.clone());
+    let mut archive = Vec::new();
+    let summary = backup::backup(conn, &store, &backup_key(), parent, &mut archive).await.unwrap();
+    (archive, summary)
+}
+
+async fn restore(archives: &[&Vec<u8>]) -> anyhow::Result<(AnyConnection, MemoryBlobStore)> {
+    let mut conn = database();
+    let store = MemoryBlobStore::new();
+    let archives = archives.iter().map(|archive| Cursor::new(archive.to_vec())).collect();
+    backup::restore(&mut conn, &BlobStore::Memory(store.clone()), &backup_key(), archives).await?;
+    Ok((conn, store))
+}
+
+fn assert_same_deployment(source: (&mut AnyConnection, &MemoryBlobStore), restored: (&mut AnyConnection, &MemoryBlobStore)) {
+    let source_records = records(source.0);
+    assert_eq!(records(restored.0), source_records);
+    assert_eq!(patient_rows(restored.0), patient_rows(source.0));
+    let events: i64 = audit_events::table.count().get_result(restored.0).unwrap();
+    assert_eq!(events, 1);
+    for (_, _, cid, ..) in &source_records {
+        assert_eq!(restored.1.get(cid), source.1.get(cid));
+        assert!(restored.1.is_pinned(cid), "{} is not pinned", cid);
+    }
+}
+
+#[tokio::test]
+async fn full_backup_round_trip() {
+    let store = MemoryBlobStore::new();
+    let mut conn = populated(&store);
+    let (archive, summary) = take_backup(&mut conn, &store, None).await;
+    assert_eq!((summary.blobs_written, summary.blobs_inherited), (3, 0));
+
+    // Record content stays encrypted at rest in the archive
+    assert!(!archive.windows(16).any(|window| window == b"alice record one"));
+
+    let (mut restored, restored_store) = restore(&[&archive]).await.unwrap();
+    assert_same_deployment((&mut conn, &store), (&mut restored, &restored_store));
+    assert_eq!(restored_store.len(), 3);
+}
+
+#[tokio::test]
+async fn incremental_backup_copies_only_new_blobs() {
+    let store = MemoryBlobStore::new();
+    let mut conn = populated(&store);
+    let (full, full_summary) = take_backup(&mut conn, &store, None).await;
+    let parent = backup::read_info(Cursor::new(&full), &backup_key()).unwrap();
+    assert_eq!(parent.header.archive_id, full_summary.archive_id);
+
+    let bob = patient_rows(&mut conn).remove(1).0;
+    add_record(&mut conn, &store, &bob, b"bob follow-up");
+    let (incremental, summary) = take_backup(&mut conn, &store, Some(&parent)).await;
+    assert_eq!((summary.blobs_written, summary.blobs_inherited), (1, 3));
+    assert!(incremental.len() < full.len());
+
+    let (mut restored, restored_store) = restore(&[&incremental, &full]).await.unwrap();
+    assert_same_deployment((&mut conn, &store), (&mut restored, &restored_store));
+    assert_eq!(records(&mut restored).len(), 4);
+
+    let err = restore(&[&incremental]).await.err().unwrap();
+    assert!(err.to_string().contains("is missing"), "{:#}", err);
+}
+
+#[tokio::test]
+async fn damaged_or_foreign_archives_are_rejected() {
+    let store = MemoryBlobStore::new();
+    let mut conn = populated(&store);
+    let (archive, _) = take_backup(&mut conn, &store, None).await;
+
+    let mut damaged = archive.clone();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 1;
+    let err = restore(&[&damaged]).await.err().unwrap();
+    assert!(err.to_string().contains("Checksum mismatch"), "{:#}", err);
+
+    let truncated = archive[..archive.len() - 100].to_vec();
+    assert!(restore(&[&truncated]).await.is_err());
+
+    let mut conn = database();
+    let other_key = RootKey::from_bytes(vec![8; 32]).unwrap();
+    let archives = vec![Cursor::new(archive.clone())];
+    let err = backup::restore(&mut conn, &BlobStore::Memory(MemoryBlobStore::new()), &other_key, archives).await.err().unwrap();
+    assert!(err.to_string().contains("backup key"), "{:#}", err);
+}
+
+#[tokio::test]
+async fn restore_requires_an_empty_database() {
+    let store = MemoryBlobStore::new();
+    let mut conn = populated(&store);
+    let (archive, _) = take_backup(&mut conn, &store, None).await;
+    let err = backup::restore(&mut conn, &BlobStore::Memory(store), &backup_key(), vec![Cursor::new(archive)]).await.err().unwrap();
+    assert!(err.to_string().contains("empty database"), "{:#}", err);
+}
@@ -0,0 +1,180 @@
+// Shared setup for tests that need PostgreSQL, the database the server pools.
+// They run against MEDIRUST_TEST_DATABASE_URL, e.g.
+// postgres://postgres@localhost/postgres, and are skipped when it is unset.
+// Each test gets a fresh database with all migrations applied, dropped again
+// at the end.
+
+#![allow(dead_code)]
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+use diesel::PgConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use medirust::blobstore::MemoryBlobStore;
+use medirust::crypto::{CryptoUtils, KeyAlgorithm, PatientPrivateKey, RECORD_AAD_VERSION};
+use medirust::keys::{KeyHierarchy, RootKey};
+use medirust::models::{HealthRecord, Patient};
+use medirust::schema::{health_records, patients};
+use medirust::DbPool;
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+pub struct TestDatabase {
+    pub pool: DbPool,
+    server_url: String,
+    name: String,
+}
+
+impl TestDatabase {
+    // None, with a note on stderr, when no test server is configured
+    pub fn create() -> Option<Self> {
+        let Ok(server_url) = std::env::var("MEDIRUST_TEST_DATABASE_URL") else {
+            eprintln!("MEDIRUST_TEST_DATABASE_URL is not set; skipping PostgreSQL test");
+            return None;
+        };
+        let name = format!("medirust_test_{}", Uuid::new_v4().simple());
+        let mut admin = PgConnection::establish(&server_url).expect("connect to the test server");
+        diesel::sql_query(format!("CREATE DATABASE {}", name)).execute(&mut admin).expect("create test database");
+
+        let url = database_url(&server_url, &name);
+        let mut conn = PgConnection::establish(&url).expect("connect to the test database");
+        // The migrations spell column types the SQLite way
+        diesel::sql_query("CREATE DOMAIN blob AS bytea").execute(&mut conn).unwrap();
+        diesel::sql_query("CREATE DOMAIN datetime AS timestamp").execute(&mut conn).unwrap();
+        conn.run_pending_migrations(MIGRATIONS).expect("run migrations");
+
+        let pool = r2d2::Pool::builder()
+            .max_size(4)
+            .build(ConnectionManager::<PgConnection>::new(url))
+            .expect("build pool");
+        Some(TestDatabase { pool, server_url, name })
+    }
+
+    pub fn conn(&self) -> r2d2::PooledConnection<ConnectionManager<PgConnection>> {
+        self.pool.get().expect("get connection")
+    }
+
+    // URL of this test database, for tools that connect themselves
+    pub fn url(&self) -> String {
+        database_url(&self.server_url, &self.name)
+    }
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        if let Ok(mut admin) = PgConnection::establish(&self.server_url) {
+            let _ = diesel::sql_query(format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", self.name)).execute(&mut admin);
+        }
+    }
+}
+
+// Replaces the database in a postgres://authority/database?query URL
+fn database_url(server_url: &str, name: &str) -> String {
+    let (scheme, rest) = server_url.split_once("://").expect("MEDIRUST_TEST_DATABASE_URL must be a postgres:// URL");
+    let authority = rest.split(['/', '?']).next().unwrap_or_default();
+    let query = rest.split_once('?').map(|(_, query)| format!("?{}", query)).unwrap_or_default();
+    format!("{}://{}/{}{}", scheme, authority, name, query)
+}
+
+// A patient with a fresh key pair of the given backend, and its private key
+pub fn insert_patient(conn: &mut PgConnection, algorithm: KeyAlgorithm) -> (Patient, PatientPrivateKey) {
+    let private_key = PatientPrivateKey::generate(algorithm, 2048).unwrap();
+    let now = Utc::now().naive_utc();
+    let patient = Patient {
+        id: Uuid::new_v4().as_bytes().to_vec(),
+        health_id: format!("HID-{}", Uuid::new_v4().simple()),
+        name: "Test Patient".to_string(),
+        public_key_pem: private_key.public_key().to_pem().unwrap(),
+        created_at: now,
+        updated_at: now,
+        escrowed_private_key: None,
+        escrow_key_id: None,
+        passphrase_protected_key: None,
+        key_algorithm: algorithm.id().to_string(),
+        pre_public_key: None,
+    };
+    diesel::insert_into(patients::table).values(&patient).execute(conn).unwrap();
+    (patient, private_key)
+}
+
+pub fn patient_uuid(patient: &Patient) -> String {
+    Uuid::from_slice(&patient.id).unwrap().to_string()
+}
+
+// Key hierarchy under a fixed root key
+pub fn key_hierarchy() -> KeyHierarchy {
+    KeyHierarchy::new(RootKey::from_bytes(vec![7u8; 32]).unwrap(), "test".to_string())
+}
+
+// Escrows the patient's private key under the tenant data key, as
+// registration does when the server is configured to
+pub fn escrow_private_key(conn: &mut PgConnection, keys: &KeyHierarchy, patient: &mut Patient, private_key: &PatientPrivateKey) {
+    let data_key = keys.active_data_key(conn).unwrap();
+    patient.escrowed_private_key = Some(data_key.seal(private_key.to_pem().unwrap().as_bytes()).unwrap());
+    patient.escrow_key_id = Some(data_key.id.clone());
+    diesel::update(patients::table.find(&patient.id))
+        .set((
+            patients::escrowed_private_key.eq(&patient.escrowed_private_key),
+            patients::escrow_key_id.eq(&patient.escrow_key_id),
+        ))
+        .execute(conn)
+        .unwrap();
+}
+
+// A record stored the way create_health_record stores one: content encrypted
+// under a fresh AES key bound to the record, kept in the blob store, and the
+// AES key wrapped to the patient
+pub fn insert_record(conn: &mut PgConnection, blob_store: &MemoryBlobStore, patient: &Patient, title: &str, content: &[u8]) -> HealthRecord {
+    insert_record_of_type(conn, blob_store, patient, "lab", title, content)
+}
+
+pub fn insert_record_of_type(
+    conn: &mut PgConnection,
+    blob_store: &MemoryBlobStore,
+    patient: &Patient,
+    record_type: &str,
+    title: &str,
+    content: &[u8],
+) -> HealthRecord {
+    let id = Uuid::new_v4().as_bytes().to_vec();
+    let record_type = record_type.to_string();
+    let aes_key = CryptoUtils::generate_aes_key();
+    let aad = CryptoUtils::record_aad(&id, &patient.id, &record_type, RECORD_AAD_VERSION);
+    let (ciphertext, nonce) = CryptoUtils::encrypt_data_with_aad(content, &aes_key, &aad).unwrap();
+    let content_sha256 = Sha256::digest(&ciphertext).iter().map(|b| format!("{:02x}", b)).collect();
+    let public_key = patient.public_key().unwrap();
+    let now = Utc::now().naive_utc();
+    let record = HealthRecord {
+        id,
+        patient_id: patient.id.clone(),
+        ipfs_cid: blob_store.put(ciphertext),
+        record_type,
+        title: title.to_string(),
+        encrypted_aes_key: CryptoUtils::encode_base64(&public_key.wrap_key(&aes_key).unwrap()),
+        nonce: CryptoUtils::encode_base64(&nonce),
+        created_at: now,
+        updated_at: now,
+        data_key_id: None,
+        key_fingerprint: Some(public_key.fingerprint().unwrap()),
+        pre_capsule: None,
+        pre_wrapped_key: None,
+        signature: None,
+        signing_key_id: None,
+        aad_version: RECORD_AAD_VERSION,
+        content_sha256: Some(content_sha256),
+        metadata_key_id: None,
+        sealed_record_type: None,
+        sealed_title: None,
+        record_type_index: None,
+    };
+    // The column predates the model, which leaves it out
+    diesel::insert_into(health_records::table)
+        .values((&record, health_records::encryption_key_cid.eq("")))
+        .execute(conn)
+        .unwrap();
+    record
+}
@@ -0,0 +1,123 @@
+// Passphrase-protected private keys and passphrase login
+
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::{web, App};
+use argon2::Params;
+use diesel::prelude::*;
+use rsa::pkcs8::der::pem;
+use rsa::pkcs8::LineEnding;
+use serde_json::{json, Value};
+
+use medirust::auth::PatientSessions;
+use medirust::config::AppConfig;
+use medirust::crypto::{CryptoUtils, KeyAlgorithm, PatientPrivateKey};
+use medirust::handlers;
+use medirust::schema::patients;
+
+use common::{insert_patient, patient_uuid, TestDatabase};
+
+const LABEL: &str = "MEDIRUST ENCRYPTED PRIVATE KEY";
+
+// Cheap Argon2 parameters keep the tests fast
+fn export(private_key: &PatientPrivateKey, passphrase: &str) -> String {
+    CryptoUtils::export_encrypted_private_key_with_params(private_key, passphrase, Params::new(1024, 1, 1, None).unwrap()).unwrap()
+}
+
+// Rewrites the binary payload of an encrypted key PEM
+fn edit(encrypted_pem: &str, change: impl FnOnce(&mut Vec<u8>)) -> String {
+    let (label, mut encoded) = pem::decode_vec(encrypted_pem.as_bytes()).unwrap();
+    assert_eq!(label, LABEL);
+    change(&mut encoded);
+    pem::encode_string(LABEL, LineEnding::LF, &encoded).unwrap()
+}
+
+#[test]
+fn encrypted_keys_round_trip_for_both_algorithms() {
+    for algorithm in [KeyAlgorithm::Rsa, KeyAlgorithm::X25519Hpke] {
+        let private_key = PatientPrivateKey::generate(algorithm, 1024).unwrap();
+        let encrypted = export(&private_key, "correct horse battery");
+        assert!(encrypted.starts_with(&format!("-----BEGIN {}-----", LABEL)));
+        let imported = CryptoUtils::import_encrypted_private_key_from_pem(&encrypted, "correct horse battery").unwrap();
+        assert_eq!(imported.algorithm(), algorithm);
+        assert!(imported.public_key() == private_key.public_key());
+    }
+
+    // The default parameters are within the import budget
+    let private_key = PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap();
+    let encrypted = CryptoUtils::export_encrypted_private_key_to_pem(&private_key, "correct horse battery").unwrap();
+    assert!(CryptoUtils::import_encrypted_private_key_from_pem(&encrypted, "correct horse battery").is_ok());
+}
+
+#[test]
+fn wrong_passphrases_and_altered_headers_are_refused() {
+    let private_key = PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap();
+    let encrypted = export(&private_key, "correct horse battery");
+    let err = CryptoUtils::import_encrypted_private_key_from_pem(&encrypted, "correct horse staple").err().unwrap();
+    assert!(err.to_string().contains("Incorrect passphrase"), "{}", err);
+
+    // The header is authenticated, so it cannot be passed off as a legacy export
+    let downgraded = edit(&encrypted, |encoded| encoded[0] = 1);
+    assert!(CryptoUtils::import_encrypted_private_key_from_pem(&downgraded, "correct horse battery").is_err());
+    let unknown = edit(&encrypted, |encoded| encoded[0] = 9);
+    let err = CryptoUtils::import_encrypted_private_key_from_pem(&unknown, "correct horse battery").err().unwrap();
+    assert!(err.to_string().contains("Unsupported"), "{}", err);
+    let truncated = edit(&encrypted, |encoded| encoded.truncate(20));
+    assert!(CryptoUtils::import_encrypted_private_key_from_pem(&truncated, "correct horse battery").is_err());
+    let relabelled = encrypted.replace(LABEL, "PRIVATE KEY");
+    assert!(CryptoUtils::import_encrypted_private_key_from_pem(&relabelled, "correct horse battery").is_err());
+}
+
+#[test]
+fn over_budget_kdf_parameters_are_refused_before_deriving() {
+    let private_key = PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap();
+    let encrypted = export(&private_key, "correct horse battery");
+    // m_cost sits at bytes 1..5 and t_cost at 5..9
+    for (offset, value) in [(1, (1u32 << 20) + 1), (5, 17)] {
+        let expensive = edit(&encrypted, |encoded| encoded[offset..offset + 4].copy_from_slice(&value.to_be_bytes()));
+        let err = CryptoUtils::import_encrypted_private_key_from_pem(&expensive, "correct horse battery").err().unwrap();
+        assert!(err.to_string().contains("too expensive"), "{}", err);
+    }
+}
+
+#[actix_web::test]
+async fn passphrase_login_returns_the_key_still_encrypted() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let (patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let encrypted = export(&private_key, "correct horse battery");
+    diesel::update(patients::table.find(&patient.id))
+        .set(patients::passphrase_protected_key.eq(Some(&encrypted)))
+        .execute(&mut db.conn())
+        .unwrap();
+    let mut config = AppConfig::default();
+    config.key_management.passphrase_unlock = true;
+    let sessions = web::Data::new(PatientSessions::default());
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .app_data(web::Data::new(config))
+            .app_data(sessions.clone())
+            .route("/patients/{patient_id}/login", web::post().to(handlers::login_with_passphrase)),
+    )
+    .await;
+    let login = |passphrase: &str| {
+        TestRequest::post()
+            .uri(&format!("/patients/{}/login", patient_uuid(&patient)))
+            .set_json(json!({ "passphrase": passphrase }))
+            .to_request()
+    };
+
+    let res = call_service(&app, login("correct horse staple")).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let body: Value = call_and_read_body_json(&app, login("correct horse battery")).await;
+    assert!(body.get("private_key_pem").is_none());
+    assert_eq!(body["encrypted_private_key_pem"], json!(encrypted));
+    assert_eq!(sessions.patient(body["session_token"].as_str().unwrap()), Some(patient.id.clone()));
+    let unlocked = CryptoUtils::import_encrypted_private_key_from_pem(body["encrypted_private_key_pem"].as_str().unwrap(), "correct horse battery").unwrap();
+    assert!(unlocked.public_key() == private_key.public_key());
+}
@@ -0,0 +1,183 @@
+// Proxy re-encryption delegation: delegates recover record keys through the
+// re-encrypted capsule, while what the server holds recovers nothing; and the
+// patient-side endpoints need the patient's session
+
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::{web, App};
+use chrono::Utc;
+use diesel::prelude::*;
+use serde_json::json;
+
+use medirust::auth::PatientSessions;
+use medirust::crypto::{CryptoUtils, KeyAlgorithm};
+use medirust::delegation;
+use medirust::models::{ClinicianKey, Delegation};
+use medirust::pre;
+use medirust::schema::{clinician_keys, delegations};
+
+use common::{insert_patient, patient_uuid, TestDatabase};
+
+fn encode(public_key: &pre::PublicKey) -> String {
+    CryptoUtils::encode_base64(&public_key.to_bytes())
+}
+
+// A record key stored for a patient with delegation enabled, and everything
+// the server keeps about it once the patient delegated to a clinician
+struct Stored {
+    aes_key: Vec<u8>,
+    capsule: String,
+    wrapped_key: String,
+    re_key: pre::ReKey,
+}
+
+fn stored(patient: &pre::SecretKey, clinician: &pre::PublicKey) -> Stored {
+    let aes_key = CryptoUtils::generate_aes_key();
+    let (capsule, wrapped_key) = delegation::encapsulate_record_key(&encode(&patient.public_key()), &aes_key).unwrap();
+    // On the patient's device
+    let re_key = pre::generate_re_key(patient, clinician).unwrap();
+    Stored { aes_key, capsule, wrapped_key, re_key }
+}
+
+fn capsule(stored: &Stored) -> pre::Capsule {
+    pre::Capsule::from_bytes(&CryptoUtils::decode_base64(&stored.capsule).unwrap()).unwrap()
+}
+
+fn reencrypt(stored: &Stored) -> String {
+    CryptoUtils::encode_base64(&pre::reencrypt(&capsule(stored), &stored.re_key).unwrap().to_bytes())
+}
+
+#[test]
+fn delegates_decrypt_through_the_reencrypted_capsule() {
+    let patient = pre::SecretKey::generate();
+    let clinician = pre::SecretKey::generate();
+    let stored = stored(&patient, &clinician.public_key());
+
+    let capsule_frag = reencrypt(&stored);
+    let aes_key = delegation::open_reencrypted_record_key(&clinician, &capsule_frag, &stored.wrapped_key).unwrap();
+    assert_eq!(aes_key.as_slice(), stored.aes_key.as_slice());
+
+    // The patient opens their own capsule directly
+    let wrapped = CryptoUtils::decode_base64(&stored.wrapped_key).unwrap();
+    let (nonce, ciphertext) = wrapped.split_at(12);
+    let capsule_key = pre::decapsulate(&patient, &capsule(&stored)).unwrap();
+    assert_eq!(CryptoUtils::decrypt_data(ciphertext, &capsule_key, nonce).unwrap(), stored.aes_key);
+}
+
+#[test]
+fn server_material_alone_recovers_nothing() {
+    let patient = pre::SecretKey::generate();
+    let clinician = pre::SecretKey::generate();
+    let stored = stored(&patient, &clinician.public_key());
+    let capsule_frag = reencrypt(&stored);
+
+    // The re-key is not a decryption key, for the capsule or for the fragment
+    let re_key_bytes = stored.re_key.to_bytes();
+    let re_key_scalar = pre::SecretKey::from_bytes(&re_key_bytes[..32]).unwrap();
+    let open_with = |capsule_key: &[u8]| {
+        let wrapped = CryptoUtils::decode_base64(&stored.wrapped_key).unwrap();
+        let (nonce, ciphertext) = wrapped.split_at(12);
+        CryptoUtils::decrypt_data(ciphertext, capsule_key, nonce)
+    };
+    assert!(open_with(&pre::decapsulate(&re_key_scalar, &capsule(&stored)).unwrap()).is_err());
+    assert!(delegation::open_reencrypted_record_key(&re_key_scalar, &capsule_frag, &stored.wrapped_key).is_err());
+
+    // Neither is any key the server could make up, nor another clinician's
+    let server_key = pre::SecretKey::generate();
+    assert!(delegation::open_reencrypted_record_key(&server_key, &capsule_frag, &stored.wrapped_key).is_err());
+    let other = pre::SecretKey::generate();
+    assert!(delegation::open_reencrypted_record_key(&other, &capsule_frag, &stored.wrapped_key).is_err());
+
+    // A fragment re-encrypted for another clinician does not open for this one
+    let for_other = Stored { re_key: pre::generate_re_key(&patient, &other.public_key()).unwrap(), ..stored };
+    assert!(delegation::open_reencrypted_record_key(&clinician, &reencrypt(&for_other), &for_other.wrapped_key).is_err());
+    assert!(delegation::open_reencrypted_record_key(&other, &reencrypt(&for_other), &for_other.wrapped_key).is_ok());
+}
+
+#[actix_web::test]
+async fn delegations_take_a_client_computed_re_key_from_the_patient() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let sessions = web::Data::new(PatientSessions::default());
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .app_data(sessions.clone())
+            .route("/patients/{patient_id}/pre-key", web::put().to(delegation::set_patient_pre_key))
+            .route("/patients/{patient_id}/delegations", web::post().to(delegation::create_delegation))
+            .route("/patients/{patient_id}/delegations", web::get().to(delegation::list_delegations))
+            .route("/patients/{patient_id}/delegations/{delegation_id}", web::delete().to(delegation::revoke_delegation)),
+    )
+    .await;
+    let (patient, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let (other, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let base = format!("/patients/{}", patient_uuid(&patient));
+    let token = sessions.open(&patient.id);
+    let bearer = ("Authorization", format!("Bearer {}", token));
+
+    let patient_key = pre::SecretKey::generate();
+    let clinician_key = pre::SecretKey::generate();
+    let now = Utc::now().naive_utc();
+    diesel::insert_into(clinician_keys::table)
+        .values(&ClinicianKey {
+            principal: "clinic-north".to_string(),
+            pre_public_key: encode(&clinician_key.public_key()),
+            created_at: now,
+            updated_at: now,
+        })
+        .execute(&mut db.conn())
+        .unwrap();
+
+    let pre_key = json!({ "public_key": encode(&patient_key.public_key()) });
+    let req = TestRequest::put().uri(&format!("{}/pre-key", base)).set_json(&pre_key).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = TestRequest::put()
+        .uri(&format!("{}/pre-key", base))
+        .insert_header(("Authorization", format!("Bearer {}", sessions.open(&other.id))))
+        .set_json(&pre_key)
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    let req = TestRequest::put().uri(&format!("{}/pre-key", base)).insert_header(bearer.clone()).set_json(&pre_key).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+    // The server no longer takes the delegation secret key
+    let secret = CryptoUtils::encode_base64(patient_key.to_bytes().as_slice());
+    let req = TestRequest::post()
+        .uri(&format!("{}/delegations", base))
+        .insert_header(bearer.clone())
+        .set_json(json!({ "clinician": "clinic-north", "delegator_secret_key": secret }))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+    let re_key = CryptoUtils::encode_base64(&pre::generate_re_key(&patient_key, &clinician_key.public_key()).unwrap().to_bytes());
+    let delegate = json!({ "clinician": "clinic-north", "re_key": re_key });
+    let req = TestRequest::post().uri(&format!("{}/delegations", base)).set_json(&delegate).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = TestRequest::post().uri(&format!("{}/delegations", base)).insert_header(bearer.clone()).set_json(&delegate).to_request();
+    let created: serde_json::Value = call_and_read_body_json(&app, req).await;
+    let stored: Delegation = delegations::table.select(Delegation::as_select()).first(&mut db.conn()).unwrap();
+    assert_eq!(stored.re_key.as_deref(), Some(re_key.as_str()));
+
+    let revoke_uri = format!("{}/delegations/{}", base, created["id"].as_str().unwrap());
+    let req = TestRequest::delete().uri(&revoke_uri).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = TestRequest::delete().uri(&revoke_uri).insert_header(bearer.clone()).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+    // Listing shows who the patient shares with, so it needs their session too
+    let list_uri = format!("{}/delegations", base);
+    let req = TestRequest::get().uri(&list_uri).to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    let req = TestRequest::get()
+        .uri(&list_uri)
+        .insert_header(("Authorization", format!("Bearer {}", sessions.open(&other.id))))
+        .to_request();
+    assert_eq!(call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    let req = TestRequest::get().uri(&list_uri).insert_header(bearer).to_request();
+    let listed: serde_json::Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["id"], created["id"]);
+}
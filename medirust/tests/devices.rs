@@ -0,0 +1,117 @@
+// Device registration, listing, key upload, revocation and the change feed
+// act for the patient, so they need the patient's session
+
+mod common;
+
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use medirust::auth::PatientSessions;
+use medirust::blobstore::MemoryBlobStore;
+use medirust::crypto::{CryptoUtils, KeyAlgorithm, PatientPrivateKey};
+use medirust::devices;
+
+use common::{escrow_private_key, insert_patient, insert_record, key_hierarchy, patient_uuid, TestDatabase};
+
+async fn app(
+    db: &TestDatabase,
+    sessions: &web::Data<PatientSessions>,
+) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+    test::init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .app_data(web::Data::new(Some(key_hierarchy())))
+            .app_data(sessions.clone())
+            .route("/patients/{patient_id}/devices", web::post().to(devices::register_device))
+            .route("/patients/{patient_id}/devices", web::get().to(devices::list_devices))
+            .route("/patients/{patient_id}/devices/{device_id}", web::delete().to(devices::revoke_device))
+            .route("/patients/{patient_id}/devices/{device_id}/record-keys", web::put().to(devices::upload_record_keys))
+            .route("/patients/{patient_id}/devices/{device_id}/changes", web::get().to(devices::get_changes)),
+    )
+    .await
+}
+
+fn with_session(req: test::TestRequest, token: Option<&str>) -> actix_http::Request {
+    match token {
+        Some(token) => req.insert_header(("Authorization", format!("Bearer {}", token))).to_request(),
+        None => req.to_request(),
+    }
+}
+
+#[actix_web::test]
+async fn device_changes_need_the_patients_session() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let sessions = web::Data::new(PatientSessions::default());
+    let app = app(&db, &sessions).await;
+    let memory = MemoryBlobStore::new();
+    let (mut patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    escrow_private_key(&mut db.conn(), &key_hierarchy(), &mut patient, &private_key);
+    let record = insert_record(&mut db.conn(), &memory, &patient, "HbA1c", b"6.1%");
+    let (other, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let base = format!("/patients/{}/devices", patient_uuid(&patient));
+    let token = sessions.open(&patient.id);
+    let other_token = sessions.open(&other.id);
+
+    let device_key = PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap();
+    let register = json!({ "name": "phone", "public_key_pem": device_key.public_key().to_pem().unwrap() });
+    let res = test::call_service(&app, with_session(test::TestRequest::post().uri(&base).set_json(&register), None)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, with_session(test::TestRequest::post().uri(&base).set_json(&register), Some("forged"))).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, with_session(test::TestRequest::post().uri(&base).set_json(&register), Some(&other_token))).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // The patient's own session registers the device and backfills its keys
+    let req = with_session(test::TestRequest::post().uri(&base).set_json(&register), Some(&token));
+    let device: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(device["backfilled_keys"], json!(1));
+    let device_uri = format!("{}/{}", base, device["id"].as_str().unwrap());
+    let changes_uri = format!("{}/changes", device_uri);
+
+    // Listing devices and reading a device's feed, which carries opened titles
+    // and wrapped keys, need the same session
+    let res = test::call_service(&app, with_session(test::TestRequest::get().uri(&base), None)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, with_session(test::TestRequest::get().uri(&base), Some(&other_token))).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let listed: Value = test::call_and_read_body_json(&app, with_session(test::TestRequest::get().uri(&base), Some(&token))).await;
+    assert_eq!(listed[0]["id"], device["id"]);
+    let res = test::call_service(&app, with_session(test::TestRequest::get().uri(&changes_uri), None)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, with_session(test::TestRequest::get().uri(&changes_uri), Some(&other_token))).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    // Keys wrapped by another device replace the backfilled one
+    let record_id = Uuid::from_slice(&record.id).unwrap().to_string();
+    let aes_key = CryptoUtils::generate_aes_key();
+    let upload = json!({ "keys": [{
+        "record_id": record_id,
+        "wrapped_key": CryptoUtils::encode_base64(&device_key.public_key().wrap_key(&aes_key).unwrap()),
+    }] });
+    let keys_uri = format!("{}/record-keys", device_uri);
+    let res = test::call_service(&app, with_session(test::TestRequest::put().uri(&keys_uri).set_json(&upload), None)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, with_session(test::TestRequest::put().uri(&keys_uri).set_json(&upload), Some(&other_token))).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let req = with_session(test::TestRequest::put().uri(&keys_uri).set_json(&upload), Some(&token));
+    let stored: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(stored["stored"], json!(1));
+
+    let res = test::call_service(&app, with_session(test::TestRequest::delete().uri(&device_uri), None)).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let res = test::call_service(&app, with_session(test::TestRequest::delete().uri(&device_uri), Some(&other_token))).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    // Still active, so it still reads its change feed
+    let res = test::call_service(&app, with_session(test::TestRequest::get().uri(&changes_uri), Some(&token))).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = test::call_service(&app, with_session(test::TestRequest::delete().uri(&device_uri), Some(&token))).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let res = test::call_service(&app, with_session(test::TestRequest::get().uri(&changes_uri), Some(&token))).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,220 @@
+// Selective disclosure: decoy digests, disclosures that collide with each
+// other or with visible claims, derived age claims and predicate checks
+
+use actix_web::http::StatusCode;
+use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+use actix_web::{web, App};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{NaiveDate, Utc};
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::pkcs8::EncodePrivateKey;
+use ed25519_dalek::SigningKey;
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+use medirust::config::DisclosureConfig;
+use medirust::disclosure::{self, DisclosureIssuer};
+use medirust::sdjwt::{self, Disclosure};
+
+const KID: &str = "test-issuer";
+
+fn key() -> SigningKey {
+    SigningKey::from_bytes(&[9u8; 32])
+}
+
+fn visible_claims() -> Map<String, Value> {
+    let mut claims = Map::new();
+    claims.insert("iss".to_string(), json!("medirust"));
+    claims.insert("exp".to_string(), json!(Utc::now().timestamp() + 3600));
+    claims.insert("record_type".to_string(), json!("lab"));
+    claims
+}
+
+fn payload(jwt: &str) -> Map<String, Value> {
+    let payload = jwt.split('.').nth(1).unwrap();
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).unwrap()).unwrap()
+}
+
+fn sd_digests(jwt: &str) -> Vec<String> {
+    payload(jwt)["_sd"].as_array().unwrap().iter().map(|d| d.as_str().unwrap().to_string()).collect()
+}
+
+fn verify(jwt: &str, disclosures: &[Disclosure]) -> anyhow::Result<Map<String, Value>> {
+    sdjwt::verify(&sdjwt::present(jwt, disclosures), &key().verifying_key(), Utc::now().timestamp())
+}
+
+#[test]
+fn digests_are_padded_with_decoys() {
+    let disclosable = vec![
+        ("hba1c".to_string(), json!(6.1)),
+        ("unit".to_string(), json!("%")),
+        ("fasting".to_string(), json!(true)),
+    ];
+    let (jwt, disclosures) = sdjwt::issue(&key(), KID, visible_claims(), disclosable).unwrap();
+    let digests = sd_digests(&jwt);
+    assert_eq!(digests.len(), 8);
+    let real: Vec<String> = disclosures.iter().map(Disclosure::digest).collect();
+    assert_eq!(digests.iter().filter(|digest| real.contains(digest)).count(), 3);
+
+    // Revealing nothing shows only the visible claims; the digests stay hidden
+    let claims = verify(&jwt, &[]).unwrap();
+    assert_eq!(claims.keys().collect::<Vec<_>>(), ["exp", "iss", "record_type"]);
+    let claims = verify(&jwt, &disclosures[..1]).unwrap();
+    assert_eq!(claims["hba1c"], json!(6.1));
+    assert!(!claims.contains_key("unit"));
+
+    // A disclosure the issuer never signed matches no digest, decoys included
+    let forged = Disclosure::new("diagnosis", json!("none"));
+    let err = verify(&jwt, &[forged]).unwrap_err();
+    assert!(err.to_string().contains("not covered"), "{}", err);
+
+    // Credentials with nothing to disclose still carry a full block of decoys
+    let (jwt, disclosures) = sdjwt::issue(&key(), KID, visible_claims(), Vec::new()).unwrap();
+    assert!(disclosures.is_empty());
+    assert_eq!(sd_digests(&jwt).len(), 8);
+}
+
+#[test]
+fn disclosures_cannot_repeat_or_shadow_claims() {
+    // Issuance refuses it outright
+    let shadowing = vec![("record_type".to_string(), json!("prescription"))];
+    assert!(sdjwt::issue(&key(), KID, visible_claims(), shadowing).is_err());
+    assert!(sdjwt::issue(&key(), KID, visible_claims(), vec![("iss".to_string(), json!("elsewhere"))]).is_err());
+
+    // A token signed by an issuer that did not check
+    let result = Disclosure::new("result", json!("negative"));
+    let other_result = Disclosure::new("result", json!("positive"));
+    let record_type = Disclosure::new("record_type", json!("prescription"));
+    let iss = Disclosure::new("iss", json!("elsewhere"));
+    let mut claims = visible_claims();
+    let digests: Vec<String> = [&result, &other_result, &record_type, &iss].iter().map(|d| d.digest()).collect();
+    claims.insert("_sd".to_string(), json!(digests));
+    claims.insert("_sd_alg".to_string(), json!("sha-256"));
+    let jwt = sdjwt::sign_jwt(&key(), "sd+jwt", KID, &claims);
+
+    assert_eq!(verify(&jwt, std::slice::from_ref(&result)).unwrap()["result"], json!("negative"));
+    let err = verify(&jwt, &[result.clone(), result.clone()]).unwrap_err();
+    assert_eq!(err.to_string(), "Disclosure for result is presented twice");
+    let err = verify(&jwt, &[result, other_result]).unwrap_err();
+    assert_eq!(err.to_string(), "Claim result is disclosed by two different disclosures");
+    let err = verify(&jwt, &[record_type]).unwrap_err();
+    assert_eq!(err.to_string(), "Disclosure shadows the issuer-signed visible claim record_type");
+    let err = verify(&jwt, &[iss]).unwrap_err();
+    assert_eq!(err.to_string(), "Disclosure shadows the reserved claim iss");
+}
+
+// Issuer configured with a key written to a scratch file
+fn issuer() -> DisclosureIssuer {
+    let dir = std::env::temp_dir().join(format!("medirust-disclosure-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("issuer.pem");
+    std::fs::write(&path, key().to_pkcs8_pem(LineEnding::LF).unwrap().as_bytes()).unwrap();
+    let config = DisclosureConfig { issuer_key_path: Some(path), ..DisclosureConfig::default() };
+    let issuer = DisclosureIssuer::from_config(&config).unwrap().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    issuer
+}
+
+fn claim<'a>(claims: &'a [(String, Value)], name: &str) -> Option<&'a Value> {
+    claims.iter().find(|(claim, _)| claim == name).map(|(_, value)| value)
+}
+
+#[test]
+fn age_claims_follow_the_birth_date() {
+    let issuer = issuer();
+    let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+    let content = |birth_date: &str| json!({ "birth_date": birth_date }).as_object().unwrap().clone();
+
+    // The eighteenth birthday counts from the day itself
+    let claims = issuer.disclosable_claims(&content("2008-10-18"), date("2026-10-17"));
+    assert_eq!(claim(&claims, "age_over_18"), Some(&json!(false)));
+    let claims = issuer.disclosable_claims(&content("2008-10-18"), date("2026-10-18"));
+    assert_eq!(claim(&claims, "age_over_18"), Some(&json!(true)));
+    assert_eq!(claim(&claims, "age_over_21"), Some(&json!(false)));
+    assert_eq!(claim(&claims, "age_over_65"), Some(&json!(false)));
+    assert_eq!(claim(&claims, "birth_date"), Some(&json!("2008-10-18")));
+
+    // Leap-day births and RFC 3339 timestamps
+    let claims = issuer.disclosable_claims(&content("1961-02-28T23:30:00Z"), date("2026-02-28"));
+    assert_eq!(claim(&claims, "age_over_65"), Some(&json!(true)));
+    let claims = issuer.disclosable_claims(&content("2008-02-29"), date("2026-02-28"));
+    assert_eq!(claim(&claims, "age_over_18"), Some(&json!(false)));
+    let claims = issuer.disclosable_claims(&content("2008-02-29"), date("2026-03-01"));
+    assert_eq!(claim(&claims, "age_over_18"), Some(&json!(true)));
+
+    // The record's own claims win; no usable birth date, no age claims
+    let mut stated = content("2008-10-18");
+    stated.insert("age_over_18".to_string(), json!("unknown"));
+    let claims = issuer.disclosable_claims(&stated, date("2026-10-18"));
+    assert_eq!(claims.iter().filter(|(name, _)| name == "age_over_18").count(), 1);
+    assert_eq!(claim(&claims, "age_over_18"), Some(&json!("unknown")));
+    let claims = issuer.disclosable_claims(&content("18 October 2008"), date("2026-10-18"));
+    assert!(claim(&claims, "age_over_18").is_none());
+}
+
+#[actix_web::test]
+async fn predicates_compare_disclosed_claims() {
+    let issuer = issuer();
+    let kid = issuer.kid().to_string();
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(Some(issuer)))
+            .route("/presentations/verify", web::post().to(disclosure::verify_presentation)),
+    )
+    .await;
+
+    let disclosable = vec![
+        ("hba1c".to_string(), json!(6.1)),
+        ("birth_date".to_string(), json!("1980-05-01")),
+        ("age_over_18".to_string(), json!(true)),
+        ("diagnosis".to_string(), json!("E11")),
+    ];
+    let (jwt, disclosures) = sdjwt::issue(&key(), &kid, visible_claims(), disclosable).unwrap();
+    // The holder withholds the diagnosis
+    let token = sdjwt::present(&jwt, &disclosures[..3]);
+    let verify = |predicates: Value| {
+        TestRequest::post()
+            .uri("/presentations/verify")
+            .set_json(json!({ "presentation": { "format": "sd-jwt", "token": token }, "predicates": predicates }))
+            .to_request()
+    };
+
+    let satisfied = json!([
+        { "type": "compare", "claim": "hba1c", "op": "lt", "value": 6.5 },
+        { "type": "compare", "claim": "hba1c", "op": "gte", "value": 6.1 },
+        { "type": "compare", "claim": "birth_date", "op": "lte", "value": "2000-01-01T00:00:00Z" },
+        { "type": "compare", "claim": "birth_date", "op": "gt", "value": "1980-04-30" },
+        { "type": "compare", "claim": "age_over_18", "op": "eq", "value": true },
+        { "type": "compare", "claim": "record_type", "op": "ne", "value": "prescription" },
+    ]);
+    let body: Value = call_and_read_body_json(&app, verify(satisfied)).await;
+    assert_eq!(body["valid"], json!(true));
+    assert_eq!(body["satisfied"], json!(true), "{}", body);
+    assert!(body["claims"].get("diagnosis").is_none());
+
+    let unsatisfied = json!([
+        { "type": "compare", "claim": "hba1c", "op": "gt", "value": 6.5 },
+        { "type": "compare", "claim": "hba1c", "op": "lt", "value": "high" },
+        { "type": "compare", "claim": "diagnosis", "op": "eq", "value": "E11" },
+    ]);
+    let body: Value = call_and_read_body_json(&app, verify(unsatisfied)).await;
+    assert_eq!(body["satisfied"], json!(false));
+    let results = body["predicates"].as_array().unwrap();
+    assert_eq!(results[0]["satisfied"], json!(false));
+    assert!(results[0].get("reason").is_none());
+    assert_eq!(results[1]["reason"], json!("values are not comparable"));
+    assert_eq!(results[2]["reason"], json!("claim was not disclosed"));
+
+    let unknown_op = json!([{ "type": "compare", "claim": "hba1c", "op": "approx", "value": 6 }]);
+    let res = call_service(&app, verify(unknown_op)).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    // Tampering with a disclosed value breaks its digest
+    let tampered = format!("{}~{}~", jwt, URL_SAFE_NO_PAD.encode(json!(["salt", "hba1c", 5.0]).to_string()));
+    let req = TestRequest::post()
+        .uri("/presentations/verify")
+        .set_json(json!({ "presentation": { "format": "sd-jwt", "token": tampered } }))
+        .to_request();
+    let body: Value = call_and_read_body_json(&app, req).await;
+    assert_eq!(body["valid"], json!(false));
+}
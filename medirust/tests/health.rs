@@ -0,0 +1,79 @@
+// Liveness and readiness probes
+
+mod common;
+
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+use actix_web::{web, App};
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager};
+use serde_json::Value;
+
+use medirust::blobstore::{BlobStore, MemoryBlobStore};
+use medirust::health;
+use medirust::schema::blob_pins;
+use medirust::DbPool;
+
+use common::TestDatabase;
+
+#[actix_web::test]
+async fn readiness_probes_the_database_and_blob_store() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let memory = MemoryBlobStore::new();
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .app_data(web::Data::new(BlobStore::Memory(memory.clone())))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let report: Value = read_body_json(res).await;
+    assert_eq!(report["status"], "ready");
+    assert_eq!(report["components"]["database"]["status"], "up");
+    assert_eq!(report["components"]["blob_store"]["status"], "up");
+    // The probe blob is unpinned once read back, so nothing is left to track
+    assert!(memory.is_empty() && memory.pinned().is_empty());
+    assert_eq!(blob_pins::table.count().get_result::<i64>(&mut db.conn()).unwrap(), 0);
+
+    memory.set_offline(true);
+    let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report: Value = read_body_json(res).await;
+    assert_eq!(report["status"], "not_ready");
+    assert_eq!(report["components"]["database"]["status"], "up");
+    assert_eq!(report["components"]["blob_store"]["status"], "down");
+    assert!(report["components"]["blob_store"]["error"].as_str().unwrap().contains("offline"), "{}", report);
+
+    // Liveness does not depend on either
+    let res = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn an_unreachable_database_is_not_ready() {
+    let pool: DbPool = r2d2::Pool::builder()
+        .max_size(1)
+        .build_unchecked(ConnectionManager::<PgConnection>::new("postgres://medirust@127.0.0.1:1/medirust"));
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(BlobStore::Memory(MemoryBlobStore::new())))
+            .route("/readyz", web::get().to(health::readyz)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let report: Value = read_body_json(res).await;
+    assert_eq!(report["components"]["database"]["status"], "down");
+    assert_eq!(report["components"]["blob_store"]["status"], "up");
+}
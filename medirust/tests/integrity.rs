@@ -0,0 +1,100 @@
+// Verification of fetched record content against CIDs and stored hashes
+
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+
+use medirust::blobstore::{self, BlobStore, FetchError, MemoryBlobStore};
+
+fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn cids_from_a_default_ipfs_add_are_checked_locally() {
+    // `echo "hello world" | ipfs add` and `ipfs add` of an empty file
+    let hello = "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o";
+    assert_eq!(blobstore::matches_cid(hello, b"hello world\n"), Some(true));
+    assert_eq!(blobstore::matches_cid(hello, b"hello world!"), Some(false));
+    assert_eq!(blobstore::matches_cid("QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH", b""), Some(true));
+
+    // Multi-chunk files depend on the chunker and are left to the stored hash
+    assert_eq!(blobstore::matches_cid(hello, &[0; 300_000]), None);
+}
+
+#[test]
+fn raw_cids_are_checked_locally() {
+    let cid = blobstore::raw_cid(b"ciphertext");
+    assert_eq!(blobstore::matches_cid(&cid, b"ciphertext"), Some(true));
+    assert_eq!(blobstore::matches_cid(&cid, b"ciphertexT"), Some(false));
+    assert!(blobstore::verify_content(&cid, Some(&sha256_hex(b"ciphertext")), b"ciphertext").is_ok());
+    assert!(blobstore::verify_content(&cid, Some(&sha256_hex(b"other")), b"ciphertext").is_err());
+}
+
+#[tokio::test]
+async fn fetches_distinguish_tampering_from_outages() {
+    let memory = MemoryBlobStore::new();
+    let store = BlobStore::Memory(memory.clone());
+    let cid = memory.put(b"ciphertext".to_vec());
+    let sha256 = sha256_hex(b"ciphertext");
+
+    assert_eq!(store.fetch_verified(&cid, Some(&sha256)).await.unwrap(), b"ciphertext");
+
+    // Content matching its CID but not the hash recorded with the record
+    let err = store.fetch_verified(&cid, Some(&sha256_hex(b"other"))).await.err().unwrap();
+    assert!(matches!(err, FetchError::Integrity(_)), "{}", err);
+
+    memory.corrupt(&cid);
+    let err = store.fetch_verified(&cid, None).await.err().unwrap();
+    assert!(matches!(err, FetchError::Integrity(_)), "{}", err);
+
+    memory.set_offline(true);
+    let err = store.fetch_verified(&cid, Some(&sha256)).await.err().unwrap();
+    assert!(matches!(err, FetchError::Unavailable(_)), "{}", err);
+}
+
+#[tokio::test]
+async fn large_blobs_without_a_recorded_hash_are_unverifiable() {
+    let memory = MemoryBlobStore::new();
+    let store = BlobStore::Memory(memory.clone());
+    // Several chunks, so the CID names a dag-pb root and only the hash can
+    // vouch for the content, as for records stored before hashes were kept
+    let content = vec![0x5a; 1_500_000];
+    let cid = memory.put(content.clone());
+    assert_eq!(blobstore::matches_cid(&cid, &content), None);
+
+    let err = store.fetch_verified(&cid, None).await.err().unwrap();
+    assert!(matches!(err, FetchError::Unverifiable(_)), "{}", err);
+    assert_eq!(store.fetch_verified(&cid, Some(&sha256_hex(&content))).await.unwrap(), content);
+
+    memory.corrupt(&cid);
+    let err = store.fetch_verified(&cid, Some(&sha256_hex(&content))).await.err().unwrap();
+    assert!(matches!(err, FetchError::Integrity(_)), "{}", err);
+}
+
+#[tokio::test]
+async fn fetches_retry_with_backoff_until_the_store_recovers() {
+    let memory = MemoryBlobStore::new();
+    let store = BlobStore::Memory(memory.clone());
+    let cid = memory.put(b"ciphertext".to_vec());
+    let sha256 = sha256_hex(b"ciphertext");
+
+    // Back before the second attempt, which waits 200ms
+    memory.set_offline(true);
+    let recovering = memory.clone();
+    let recovery = tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        recovering.set_offline(false);
+    });
+    let started = Instant::now();
+    assert_eq!(store.fetch_verified(&cid, Some(&sha256)).await.unwrap(), b"ciphertext");
+    assert!(started.elapsed() >= Duration::from_millis(200), "{:?}", started.elapsed());
+    recovery.await.unwrap();
+
+    // Three attempts with 200ms and 400ms between them, then it gives up
+    memory.set_offline(true);
+    let started = Instant::now();
+    let err = store.fetch_verified(&cid, Some(&sha256)).await.err().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(600), "{:?}", started.elapsed());
+    assert!(matches!(err, FetchError::Unavailable(_)), "{}", err);
+    assert!(err.to_string().contains("offline"), "{}", err);
+}
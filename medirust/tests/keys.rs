@@ -0,0 +1,114 @@
+// Tenant data keys under the root key: creation, record key material sealed
+// under them, and re-wrapping them when the root key rotates
+
+mod common;
+
+use diesel::prelude::*;
+
+use medirust::blobstore::MemoryBlobStore;
+use medirust::crypto::KeyAlgorithm;
+use medirust::keys::{self, KeyHierarchy, RootKey};
+use medirust::models::DataKeyRecord;
+use medirust::schema::data_keys;
+
+use common::{insert_patient, insert_record, key_hierarchy, TestDatabase};
+
+fn hierarchy(root: u8) -> KeyHierarchy {
+    KeyHierarchy::new(RootKey::from_bytes(vec![root; 32]).unwrap(), "test".to_string())
+}
+
+fn stored_data_keys(conn: &mut PgConnection) -> Vec<DataKeyRecord> {
+    data_keys::table.select(DataKeyRecord::as_select()).load(conn).unwrap()
+}
+
+#[test]
+fn data_keys_are_created_once_per_tenant_and_stored_wrapped() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let mut conn = db.conn();
+    let keys = key_hierarchy();
+    let active = keys.active_data_key(&mut conn).unwrap();
+    assert_eq!(keys.active_data_key(&mut conn).unwrap().id, active.id);
+
+    let stored = stored_data_keys(&mut conn);
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].tenant, "test");
+    assert_eq!(stored[0].root_key_id, keys.root_key_id());
+    let root = RootKey::from_bytes(vec![7u8; 32]).unwrap();
+    assert_eq!(root.open(&stored[0].wrapped_key).unwrap().len(), 32);
+
+    // A fresh process unwraps the same key from the database
+    let sealed = active.seal(b"secret").unwrap();
+    assert_eq!(key_hierarchy().data_key(&mut conn, &active.id).unwrap().open(&sealed).unwrap(), b"secret");
+
+    // Other tenants get their own key
+    let other = KeyHierarchy::new(RootKey::from_bytes(vec![7u8; 32]).unwrap(), "other".to_string());
+    assert_ne!(other.active_data_key(&mut conn).unwrap().id, active.id);
+    // Explicitly created keys become the active one
+    let newer = keys.create_data_key(&mut conn).unwrap();
+    assert_eq!(key_hierarchy().active_data_key(&mut conn).unwrap().id, newer.id);
+    assert_eq!(key_hierarchy().data_key(&mut conn, &active.id).unwrap().open(&sealed).unwrap(), b"secret");
+}
+
+#[test]
+fn record_key_material_is_sealed_under_the_data_key() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let mut conn = db.conn();
+    let (patient, _) = insert_patient(&mut conn, KeyAlgorithm::X25519Hpke);
+    let plain = insert_record(&mut conn, &MemoryBlobStore::new(), &patient, "HbA1c", b"6.1");
+    let keys = key_hierarchy();
+
+    let mut record = plain.clone();
+    keys::seal_record_key_material(Some(&keys), &mut conn, &mut record, plain.encrypted_aes_key.clone(), plain.nonce.clone()).unwrap();
+    assert_eq!(record.data_key_id, Some(keys.active_data_key(&mut conn).unwrap().id));
+    assert_ne!(record.encrypted_aes_key, plain.encrypted_aes_key);
+    assert_ne!(record.nonce, plain.nonce);
+    assert_eq!(
+        keys::record_key_material(Some(&key_hierarchy()), &mut conn, &record).unwrap(),
+        (plain.encrypted_aes_key.clone(), plain.nonce.clone()),
+    );
+
+    // Sealed material needs the hierarchy, and only its own root key opens it
+    let err = keys::record_key_material(None, &mut conn, &record).unwrap_err();
+    assert!(err.to_string().contains("no root key is configured"), "{}", err);
+    assert!(keys::record_key_material(Some(&hierarchy(8)), &mut conn, &record).is_err());
+
+    // Without a hierarchy the material is stored as given
+    let mut unsealed = plain.clone();
+    keys::seal_record_key_material(None, &mut conn, &mut unsealed, plain.encrypted_aes_key.clone(), plain.nonce.clone()).unwrap();
+    assert_eq!(unsealed.data_key_id, None);
+    assert_eq!(keys::record_key_material(None, &mut conn, &unsealed).unwrap(), (plain.encrypted_aes_key, plain.nonce));
+}
+
+#[test]
+fn rotating_the_root_key_rewraps_data_keys_only() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let mut conn = db.conn();
+    let old = hierarchy(7);
+    let data_key = old.active_data_key(&mut conn).unwrap();
+    let sealed = data_key.seal(b"escrowed key").unwrap();
+
+    // A new root key cannot read data keys still wrapped under the old one
+    let new = hierarchy(8);
+    let err = new.data_key(&mut conn, &data_key.id).err().unwrap();
+    assert!(err.to_string().contains("is wrapped under root key"), "{}", err);
+
+    // Rotating from the wrong previous key touches nothing
+    assert_eq!(new.rotate_root_key(&mut conn, &RootKey::from_bytes(vec![9u8; 32]).unwrap()).unwrap(), 0);
+    assert_eq!(stored_data_keys(&mut conn)[0].root_key_id, old.root_key_id());
+
+    let previous = RootKey::from_bytes(vec![7u8; 32]).unwrap();
+    assert_eq!(new.rotate_root_key(&mut conn, &previous).unwrap(), 1);
+    assert_eq!(stored_data_keys(&mut conn)[0].root_key_id, new.root_key_id());
+    // Secrets sealed before the rotation still open, now through the new root key
+    assert_eq!(hierarchy(8).data_key(&mut conn, &data_key.id).unwrap().open(&sealed).unwrap(), b"escrowed key");
+    assert!(hierarchy(7).data_key(&mut conn, &data_key.id).is_err());
+    // Rotating again finds nothing left to do
+    assert_eq!(new.rotate_root_key(&mut conn, &previous).unwrap(), 0);
+    assert_eq!(new.rotate_root_key(&mut conn, &RootKey::from_bytes(vec![8u8; 32]).unwrap()).unwrap(), 0);
+}
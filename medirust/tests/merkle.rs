@@ -0,0 +1,150 @@
+// Audit Merkle trees against the Certificate Transparency reference vectors,
+// and the hash chain of the local anchoring ledger
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use medirust::config::{AuditConfig, LedgerKind};
+use medirust::ledger::Ledger;
+use medirust::merkle::{self, Hash};
+
+// The eight leaves of the RFC 6962 / RFC 9162 reference tree
+fn leaves() -> Vec<Hash> {
+    let inputs: [&[u8]; 8] = [
+        b"",
+        &[0x00],
+        &[0x10],
+        &[0x20, 0x21],
+        &[0x30, 0x31],
+        &[0x40, 0x41, 0x42, 0x43],
+        &[0x50, 0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57],
+        &[0x60, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69, 0x6a, 0x6b, 0x6c, 0x6d, 0x6e, 0x6f],
+    ];
+    inputs.iter().map(|input| merkle::leaf_hash(input)).collect()
+}
+
+// Roots of the trees over the first 1..=8 leaves
+const ROOTS: [&str; 8] = [
+    "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+    "fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125",
+    "aeb6bcfe274b70a14fb067a5e5578264db0fa9b51af5e0ba159158f329e06e77",
+    "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+    "4e3bbb1f7b478dcfe71fb631631519a3bca12c9aefca1612bfce4c13a86264d4",
+    "76e67dadbcdf1e10e1b74ddc608abd2f98dfb16fbce75277b5232a127f2087ef",
+    "ddb89be403809e325750d3d263cd78929c2942b7942a34b77e122c9594a74c8c",
+    "5dc9da79a70659a9ad559cb701ded9a2ab9d823aad2f4960cfe370eff4604328",
+];
+
+// (leaf index, tree size, audit path)
+const PROOFS: [(u64, u64, &[&str]); 4] = [
+    (0, 8, &[
+        "96a296d224f285c67bee93c30f8a309157f0daa35dc5b87e410b78630a09cfc7",
+        "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+        "6b47aaf29ee3c2af9af889bc1fb9254dabd31177f16232dd6aab035ca39bf6e4",
+    ]),
+    (5, 8, &[
+        "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+        "ca854ea128ed050b41b35ffc1b87b8eb2bde461e9e3b5596ece6b9d5975a0ae0",
+        "d37ee418976dd95753c1c73862b9398fa2a2cf9b4ff0fdfe8b30cd95209614b7",
+    ]),
+    (2, 3, &["fac54203e7cc696cf0dfcb42c92a1d9dbaf70ad9e621f4bd8d98662f00e3c125"]),
+    (1, 5, &[
+        "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d",
+        "5f083f0a1a33ca076a95279832580db3e0ef4584bdff1f54c8a360f50de3031e",
+        "bc1a0643b12e4d2d7c77918f44e0f4f79a838b6cf9ec5b5c283e1f4d88599e6b",
+    ]),
+];
+
+fn hash(hex: &str) -> Hash {
+    merkle::from_hex(hex).unwrap()
+}
+
+#[test]
+fn roots_match_the_reference_tree() {
+    let leaves = leaves();
+    assert_eq!(merkle::to_hex(&merkle::root(&[])), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    for (size, root) in ROOTS.iter().enumerate() {
+        assert_eq!(merkle::to_hex(&merkle::root(&leaves[..size + 1])), *root, "tree of {} leaves", size + 1);
+    }
+}
+
+#[test]
+fn reference_inclusion_proofs_verify() {
+    let leaves = leaves();
+    for (index, size, path) in PROOFS {
+        let path: Vec<Hash> = path.iter().map(|hex| hash(hex)).collect();
+        let root = hash(ROOTS[size as usize - 1]);
+        let leaf = &leaves[index as usize];
+        assert_eq!(merkle::inclusion_proof(index as usize, &leaves[..size as usize]).unwrap(), path);
+        assert!(merkle::verify_inclusion(leaf, index, size, &path, &root), "leaf {} of {}", index, size);
+
+        // Any other position, root, leaf or path fails
+        assert!(!merkle::verify_inclusion(leaf, index ^ 1, size, &path, &root));
+        assert!(!merkle::verify_inclusion(leaf, index, size, &path, &hash(ROOTS[size as usize % 8])));
+        assert!(!merkle::verify_inclusion(leaf, size, size, &path, &root));
+        assert!(!merkle::verify_inclusion(&leaves[(index as usize + 1) % 8], index, size, &path, &root));
+        assert!(!merkle::verify_inclusion(leaf, index, size, &path[..path.len() - 1], &root));
+        let mut extended = path.clone();
+        extended.push(root);
+        assert!(!merkle::verify_inclusion(leaf, index, size, &extended, &root));
+    }
+
+    // Every leaf of every reference tree round-trips
+    for size in 1..=leaves.len() {
+        let root = merkle::root(&leaves[..size]);
+        for index in 0..size {
+            let path = merkle::inclusion_proof(index, &leaves[..size]).unwrap();
+            assert!(merkle::verify_inclusion(&leaves[index], index as u64, size as u64, &path, &root));
+        }
+    }
+    // A single leaf is its own root
+    assert!(merkle::verify_inclusion(&leaves[0], 0, 1, &[], &hash(ROOTS[0])));
+}
+
+#[actix_web::test]
+async fn rewriting_the_local_ledger_breaks_its_chain() {
+    let path = std::env::temp_dir().join(format!("medirust-ledger-{}.jsonl", Uuid::new_v4()));
+    let config = AuditConfig { ledger: LedgerKind::Local, local_ledger_path: path.clone(), ..AuditConfig::default() };
+    let ledger = Ledger::from_config(&config).unwrap();
+    let roots: Vec<Hash> = ROOTS[..3].iter().map(|hex| hash(hex)).collect();
+    let mut refs = Vec::new();
+    for root in &roots {
+        refs.push(ledger.anchor(root).await.unwrap());
+    }
+    for (anchor_ref, root) in refs.iter().zip(&roots) {
+        assert_eq!(ledger.lookup(anchor_ref).await.unwrap(), Some(*root));
+    }
+    let original = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = original.lines().collect();
+
+    // Swapping in another root without fixing the entry hash
+    let forged_root = merkle::to_hex(&hash(ROOTS[7]));
+    let forged = lines[1].replace(ROOTS[1], &forged_root);
+    assert_ne!(forged, lines[1]);
+    std::fs::write(&path, [lines[0], &forged, lines[2]].join("\n") + "\n").unwrap();
+    let err = ledger.lookup(&refs[0]).await.unwrap_err();
+    assert!(err.to_string().contains("broken at entry 1"), "{}", err);
+
+    // Recomputing that entry's hash breaks the link from the next one
+    let mut entry: serde_json::Value = serde_json::from_str(&forged).unwrap();
+    let prev = entry["prev"].as_str().unwrap().to_string();
+    let rehashed = Sha256::new()
+        .chain_update(1u64.to_be_bytes())
+        .chain_update(forged_root.as_bytes())
+        .chain_update(prev.as_bytes())
+        .finalize();
+    entry["hash"] = serde_json::json!(merkle::to_hex(&rehashed));
+    std::fs::write(&path, [lines[0].to_string(), entry.to_string(), lines[2].to_string()].join("\n") + "\n").unwrap();
+    let err = ledger.lookup(&refs[2]).await.unwrap_err();
+    assert!(err.to_string().contains("broken at entry 2"), "{}", err);
+
+    // Dropping an entry, and anchoring onto a broken chain
+    std::fs::write(&path, [lines[0], lines[2]].join("\n") + "\n").unwrap();
+    let err = ledger.lookup(&refs[0]).await.unwrap_err();
+    assert!(err.to_string().contains("broken at entry 1"), "{}", err);
+    assert!(ledger.anchor(&roots[0]).await.is_err());
+
+    std::fs::write(&path, &original).unwrap();
+    assert_eq!(ledger.lookup(&refs[1]).await.unwrap(), Some(roots[1]));
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,153 @@
+// Record titles and types sealed under the tenant data key: bound to their
+// record and column, never left in plaintext, filtered through a blind index,
+// and recoverable before the migration adding them is reverted
+
+mod common;
+
+use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+use actix_web::{web, App};
+use diesel::prelude::*;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde_json::Value;
+
+use medirust::blobstore::{BlobStore, MemoryBlobStore};
+use medirust::config::AppConfig;
+use medirust::crypto::KeyAlgorithm;
+use medirust::handlers;
+use medirust::keys;
+use medirust::models::HealthRecord;
+use medirust::schema::health_records;
+
+use common::{escrow_private_key, insert_patient, insert_record, insert_record_of_type, key_hierarchy, patient_uuid, TestDatabase};
+
+const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+fn stored(db: &TestDatabase, record: &HealthRecord) -> HealthRecord {
+    health_records::table.find(&record.id).select(HealthRecord::as_select()).first(&mut db.conn()).unwrap()
+}
+
+#[test]
+fn sealed_metadata_is_bound_to_its_record_and_column() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let keys = key_hierarchy();
+    let memory = MemoryBlobStore::new();
+    let (patient, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let record = insert_record(&mut db.conn(), &memory, &patient, "HbA1c", b"6.1%");
+    let other = insert_record(&mut db.conn(), &memory, &patient, "LDL", b"2.4 mmol/L");
+
+    // Without a root key there is nowhere to seal to, and nothing is stored
+    let mut unsealed = record.clone();
+    let err = keys::seal_record_metadata(None, &mut db.conn(), &mut unsealed).unwrap_err();
+    assert!(err.to_string().contains("root key"), "{}", err);
+    assert_eq!((unsealed.title.as_str(), unsealed.sealed_title.as_deref()), ("HbA1c", None));
+
+    let mut sealed = record.clone();
+    keys::seal_record_metadata(Some(&keys), &mut db.conn(), &mut sealed).unwrap();
+    assert_eq!((sealed.title.as_str(), sealed.record_type.as_str()), ("", ""));
+    assert!(sealed.metadata_key_id.is_some());
+    assert_eq!(sealed.record_type_index, Some(keys.active_data_key(&mut db.conn()).unwrap().record_type_index("lab").unwrap()));
+    let mut opened = sealed.clone();
+    keys::open_record_metadata(Some(&keys), &mut db.conn(), &mut opened).unwrap();
+    assert_eq!((opened.title.as_str(), opened.record_type.as_str()), ("HbA1c", "lab"));
+    assert!(keys::open_record_metadata(None, &mut db.conn(), &mut sealed.clone()).is_err());
+
+    // The title sealed as the type, or either moved to another record, no longer opens
+    let mut swapped = sealed.clone();
+    std::mem::swap(&mut swapped.sealed_title, &mut swapped.sealed_record_type);
+    assert!(keys::open_record_metadata(Some(&keys), &mut db.conn(), &mut swapped).is_err());
+    let mut moved = HealthRecord {
+        metadata_key_id: sealed.metadata_key_id.clone(),
+        sealed_title: sealed.sealed_title.clone(),
+        sealed_record_type: sealed.sealed_record_type.clone(),
+        ..other.clone()
+    };
+    assert!(keys::open_record_metadata(Some(&keys), &mut db.conn(), &mut moved).is_err());
+
+    // Plaintext rows from before sealing open as they are
+    let mut legacy = other.clone();
+    keys::open_record_metadata(None, &mut db.conn(), &mut legacy).unwrap();
+    assert_eq!(legacy.title, "LDL");
+}
+
+#[actix_web::test]
+async fn record_types_filter_through_the_blind_index() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let keys = key_hierarchy();
+    let memory = MemoryBlobStore::new();
+    let (mut patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    escrow_private_key(&mut db.conn(), &keys, &mut patient, &private_key);
+    insert_record(&mut db.conn(), &memory, &patient, "HbA1c", b"6.1%");
+    let scan = insert_record_of_type(&mut db.conn(), &memory, &patient, "imaging", "Chest X-ray", b"clear");
+    assert_eq!(keys::seal_plaintext_record_metadata(&keys, &mut db.conn()).unwrap(), 2);
+    assert_eq!(stored(&db, &scan).record_type, "");
+    // One row still in plaintext, as if written before sealing
+    insert_record(&mut db.conn(), &memory, &patient, "LDL", b"2.4 mmol/L");
+
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .app_data(web::Data::new(BlobStore::Memory(memory.clone())))
+            .app_data(web::Data::new(AppConfig::default()))
+            .app_data(web::Data::new(Some(key_hierarchy())))
+            .route("/patients/{patient_id}/records", web::get().to(handlers::get_health_records_for_patient)),
+    )
+    .await;
+    let titles = |records: Vec<Value>| {
+        let mut titles: Vec<String> = records.iter().map(|record| record["title"].as_str().unwrap().to_string()).collect();
+        titles.sort();
+        titles
+    };
+    let uri = format!("/patients/{}/records", patient_uuid(&patient));
+    let all: Vec<Value> = call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(titles(all), ["Chest X-ray", "HbA1c", "LDL"]);
+    let labs: Vec<Value> = call_and_read_body_json(&app, TestRequest::get().uri(&format!("{}?record_type=lab", uri)).to_request()).await;
+    assert!(labs.iter().all(|record| record["record_type"] == "lab"));
+    assert_eq!(titles(labs), ["HbA1c", "LDL"]);
+    let imaging: Vec<Value> = call_and_read_body_json(&app, TestRequest::get().uri(&format!("{}?record_type=imaging", uri)).to_request()).await;
+    assert_eq!(titles(imaging), ["Chest X-ray"]);
+    let none: Vec<Value> = call_and_read_body_json(&app, TestRequest::get().uri(&format!("{}?record_type=Lab", uri)).to_request()).await;
+    assert!(none.is_empty());
+}
+
+#[test]
+fn sealed_metadata_is_written_back_before_reverting() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let keys = key_hierarchy();
+    let memory = MemoryBlobStore::new();
+    let (patient, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let record = insert_record(&mut db.conn(), &memory, &patient, "HbA1c", b"6.1%");
+    keys::seal_plaintext_record_metadata(&keys, &mut db.conn()).unwrap();
+
+    // Reverting down to the migration that added the sealed columns
+    let revert = |conn: &mut PgConnection| -> Result<(), String> {
+        loop {
+            let version = conn.revert_last_migration(MIGRATIONS).map_err(|e| e.to_string())?;
+            if version.to_string().starts_with("20251018230000") {
+                return Ok(());
+            }
+        }
+    };
+    let mut conn = db.conn();
+    let err = revert(&mut conn).unwrap_err();
+    assert!(err.contains("unseal-metadata"), "{}", err);
+    assert!(stored(&db, &record).sealed_title.is_some());
+
+    assert_eq!(keys::unseal_record_metadata(&keys, &mut conn).unwrap(), 1);
+    let unsealed = stored(&db, &record);
+    assert_eq!((unsealed.title.as_str(), unsealed.record_type.as_str()), ("HbA1c", "lab"));
+    assert!(unsealed.metadata_key_id.is_none() && unsealed.sealed_title.is_none() && unsealed.record_type_index.is_none());
+    revert(&mut conn).unwrap();
+
+    let (title, record_type): (String, String) = health_records::table
+        .find(&record.id)
+        .select((health_records::title, health_records::record_type))
+        .first(&mut conn)
+        .unwrap();
+    assert_eq!((title.as_str(), record_type.as_str()), ("HbA1c", "lab"));
+}
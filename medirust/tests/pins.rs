@@ -0,0 +1,179 @@
+// Pin tracking: the blob_pins table follows what the node holds, and
+// reconciliation and repair bring the node back in line with the records
+
+mod common;
+
+use diesel::prelude::*;
+
+use medirust::blobstore::{BlobStore, MemoryBlobStore};
+use medirust::crypto::KeyAlgorithm;
+use medirust::models::{BlobPin, HealthRecord};
+use medirust::pins;
+use medirust::replication::ReplicatedBlobStore;
+use medirust::schema::{blob_pins, health_records};
+
+use common::{insert_patient, insert_record, TestDatabase};
+
+fn pin(db: &TestDatabase, cid: &str) -> BlobPin {
+    blob_pins::table.find(cid).first(&mut db.conn()).unwrap()
+}
+
+// A record whose blob is pinned and tracked, as create_health_record leaves it
+fn pinned_record(db: &TestDatabase, memory: &MemoryBlobStore, title: &str) -> HealthRecord {
+    let (patient, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let record = insert_record(&mut db.conn(), memory, &patient, title, title.as_bytes());
+    memory.pin(&record.ipfs_cid).unwrap();
+    pins::mark(&mut db.conn(), &record.ipfs_cid, pins::PINNED, None).unwrap();
+    record
+}
+
+fn delete(db: &TestDatabase, record: &HealthRecord) {
+    diesel::delete(health_records::table.find(&record.id)).execute(&mut db.conn()).unwrap();
+}
+
+#[test]
+fn pin_state_is_tracked_per_blob() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    pins::mark(&mut db.conn(), "bafy-pending", pins::PENDING, None).unwrap();
+    let pending = pin(&db, "bafy-pending");
+    assert_eq!(pending.status, pins::PENDING);
+    assert!(pending.verified_at.is_none());
+
+    // Later marks update the same row; only a confirmed pin counts as verified
+    pins::mark(&mut db.conn(), "bafy-pending", pins::PINNED, None).unwrap();
+    let pinned = pin(&db, "bafy-pending");
+    assert_eq!(pinned.status, pins::PINNED);
+    assert_eq!(pinned.created_at, pending.created_at);
+    assert!(pinned.verified_at.is_some());
+
+    pins::mark(&mut db.conn(), "bafy-pending", pins::MISSING, Some("not found".to_string())).unwrap();
+    let missing = pin(&db, "bafy-pending");
+    assert_eq!((missing.status.as_str(), missing.error.as_deref()), (pins::MISSING, Some("not found")));
+    assert_eq!(missing.verified_at, pinned.verified_at);
+    assert_eq!(blob_pins::table.count().get_result::<i64>(&mut db.conn()).unwrap(), 1);
+}
+
+#[actix_web::test]
+async fn blobs_are_released_only_once_unreferenced() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let memory = MemoryBlobStore::new();
+    let store = BlobStore::Memory(memory.clone());
+    let record = pinned_record(&db, &memory, "HbA1c");
+
+    assert!(!pins::release(&db.pool, &store, &record.ipfs_cid).await.unwrap());
+    assert!(memory.is_pinned(&record.ipfs_cid));
+    assert_eq!(pin(&db, &record.ipfs_cid).status, pins::PINNED);
+
+    delete(&db, &record);
+    assert!(pins::release(&db.pool, &store, &record.ipfs_cid).await.unwrap());
+    assert!(!memory.is_pinned(&record.ipfs_cid));
+    assert_eq!(pin(&db, &record.ipfs_cid).status, pins::RELEASED);
+}
+
+#[actix_web::test]
+async fn reconcile_repins_lost_blobs_and_releases_orphans() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let memory = MemoryBlobStore::new();
+    let store = BlobStore::Memory(memory.clone());
+    let kept = pinned_record(&db, &memory, "kept");
+    // The node dropped the pin but still holds the content
+    let unpinned = pinned_record(&db, &memory, "unpinned");
+    let unpinned_copy = memory.get(&unpinned.ipfs_cid).unwrap();
+    memory.unpin(&unpinned.ipfs_cid);
+    memory.put(unpinned_copy);
+    // The node lost the content altogether
+    let lost = pinned_record(&db, &memory, "lost");
+    memory.unpin(&lost.ipfs_cid);
+    // Records deleted while their blobs stayed pinned, or were already collected
+    let orphan = pinned_record(&db, &memory, "orphan");
+    delete(&db, &orphan);
+    let collected = pinned_record(&db, &memory, "collected");
+    delete(&db, &collected);
+    memory.unpin(&collected.ipfs_cid);
+    // A pin still pending when its record landed is confirmed, not released
+    let (patient, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let late = insert_record(&mut db.conn(), &memory, &patient, "late", b"late");
+    memory.pin(&late.ipfs_cid).unwrap();
+    pins::mark(&mut db.conn(), &late.ipfs_cid, pins::PENDING, None).unwrap();
+
+    let report = pins::reconcile(&db.pool, &store).await.unwrap();
+    assert_eq!((report.checked, report.repinned, report.missing, report.released), (4, 1, 1, 2));
+    for record in [&kept, &unpinned, &late] {
+        assert!(memory.is_pinned(&record.ipfs_cid), "{}", record.title);
+        assert_eq!(pin(&db, &record.ipfs_cid).status, pins::PINNED, "{}", record.title);
+    }
+    let missing = pin(&db, &lost.ipfs_cid);
+    assert_eq!(missing.status, pins::MISSING);
+    assert!(missing.error.unwrap().contains("missing blob"));
+    for record in [&orphan, &collected] {
+        assert!(!memory.is_pinned(&record.ipfs_cid), "{}", record.title);
+        assert_eq!(pin(&db, &record.ipfs_cid).status, pins::RELEASED, "{}", record.title);
+    }
+
+    // A second pass finds nothing to do but the missing blob, and leaves the
+    // rows of blobs whose state did not change alone
+    let before: Vec<BlobPin> = blob_pins::table.order(blob_pins::cid).load(&mut db.conn()).unwrap();
+    let report = pins::reconcile(&db.pool, &store).await.unwrap();
+    assert_eq!((report.checked, report.repinned, report.missing, report.released), (4, 0, 1, 0));
+    let after: Vec<BlobPin> = blob_pins::table.order(blob_pins::cid).load(&mut db.conn()).unwrap();
+    let updated = |pins: &[BlobPin]| pins.iter().map(|pin| (pin.cid.clone(), pin.updated_at)).collect::<Vec<_>>();
+    assert_eq!(updated(&after), updated(&before));
+
+    memory.set_offline(true);
+    assert!(pins::reconcile(&db.pool, &store).await.is_err());
+}
+
+#[actix_web::test]
+async fn reconcile_copies_blobs_back_to_replicas_that_lost_them() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let replicas = [MemoryBlobStore::new(), MemoryBlobStore::new(), MemoryBlobStore::new()];
+    let record = pinned_record(&db, &replicas[0], "replicated");
+    let content = replicas[0].get(&record.ipfs_cid).unwrap();
+    for replica in &replicas[1..] {
+        replica.put(content.clone());
+        replica.pin(&record.ipfs_cid).unwrap();
+    }
+    let named = replicas.iter().enumerate().map(|(index, replica)| (format!("memory-{}", index), BlobStore::Memory(replica.clone())));
+    let store = BlobStore::Replicated(Box::new(ReplicatedBlobStore::new(named.collect(), 2).unwrap()));
+    replicas[2].unpin(&record.ipfs_cid);
+
+    let report = pins::reconcile(&db.pool, &store).await.unwrap();
+    assert_eq!((report.checked, report.repinned, report.missing), (1, 1, 0));
+    assert_eq!(replicas[2].get(&record.ipfs_cid), Some(content));
+    assert!(replicas.iter().all(|replica| replica.is_pinned(&record.ipfs_cid)));
+    assert_eq!(pin(&db, &record.ipfs_cid).status, pins::PINNED);
+}
+
+#[actix_web::test]
+async fn repair_copies_lost_blobs_from_a_secondary_store() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let memory = MemoryBlobStore::new();
+    let backup = MemoryBlobStore::new();
+    let store = BlobStore::Memory(memory.clone());
+    let secondary = BlobStore::Memory(backup.clone());
+    let kept = pinned_record(&db, &memory, "kept");
+    let lost = pinned_record(&db, &memory, "lost");
+    let gone = pinned_record(&db, &memory, "gone");
+    backup.put(memory.get(&lost.ipfs_cid).unwrap());
+    memory.unpin(&lost.ipfs_cid);
+    memory.unpin(&gone.ipfs_cid);
+    pins::mark(&mut db.conn(), &lost.ipfs_cid, pins::MISSING, None).unwrap();
+
+    let report = pins::repair(&db.pool, &store, &secondary).await.unwrap();
+    assert_eq!((report.repaired, report.failed), (1, 1));
+    assert_eq!(memory.get(&lost.ipfs_cid), backup.get(&lost.ipfs_cid));
+    assert!(memory.is_pinned(&lost.ipfs_cid) && memory.is_pinned(&kept.ipfs_cid));
+    assert_eq!(pin(&db, &lost.ipfs_cid).status, pins::PINNED);
+    assert_eq!(pin(&db, &gone.ipfs_cid).status, pins::MISSING);
+    assert!(!memory.is_pinned(&gone.ipfs_cid));
+}
@@ -0,0 +1,77 @@
+// Route budgets, token buckets and account lockout of the rate limiter
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::test::{call_service, init_service, TestRequest};
+use actix_web::{middleware, web, App, HttpResponse};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use medirust::config::{BucketConfig, RateLimitConfig};
+use medirust::ratelimit::{self, Budget, RateLimiter};
+
+fn limiter() -> RateLimiter {
+    RateLimiter::new(RateLimitConfig {
+        record_write: BucketConfig { capacity: 2, refill_per_minute: 1 },
+        login: BucketConfig { capacity: 2, refill_per_minute: 1 },
+        ..RateLimitConfig::default()
+    })
+}
+
+#[test]
+fn record_writes_have_their_own_budget() {
+    assert_eq!(Budget::for_route(&Method::POST, "/patients/{patient_id}/records"), Some(Budget::RecordWrite));
+    assert_eq!(Budget::for_route(&Method::POST, "/patients/{patient_id}/records/upgrade-encryption"), Some(Budget::RecordWrite));
+    assert_eq!(Budget::for_route(&Method::GET, "/patients/{patient_id}/records"), Some(Budget::RecordRead));
+    assert_eq!(Budget::for_route(&Method::POST, "/patients/{patient_id}/login"), Some(Budget::Login));
+    assert_eq!(Budget::for_route(&Method::POST, "/patients/{patient_id}/recovery/requests"), Some(Budget::Recovery));
+    assert_eq!(Budget::for_route(&Method::POST, "/patients/{patient_id}/recovery/requests/{request_id}/shares"), Some(Budget::Recovery));
+    assert_eq!(Budget::for_route(&Method::GET, "/healthz"), None);
+}
+
+#[test]
+fn uploads_do_not_spend_the_login_allowance() {
+    let limiter = limiter();
+    let keys = vec!["principal:clinic-north".to_string()];
+    assert!(limiter.check(Budget::RecordWrite, &keys).is_ok());
+    assert!(limiter.check(Budget::RecordWrite, &keys).is_ok());
+    let wait = limiter.check(Budget::RecordWrite, &keys).err().unwrap();
+    assert!(wait.as_secs() > 0);
+
+    assert!(limiter.check(Budget::Login, &keys).is_ok());
+    assert!(limiter.check(Budget::Login, &keys).is_ok());
+}
+
+async fn failed_login() -> HttpResponse {
+    HttpResponse::Unauthorized().body("Invalid passphrase")
+}
+
+#[actix_web::test]
+async fn login_lockout_is_per_patient_and_client() {
+    let limiter = web::Data::new(RateLimiter::new(RateLimitConfig {
+        lockout_threshold: 3,
+        ..RateLimitConfig::default()
+    }));
+    let app = init_service(
+        App::new()
+            .wrap(middleware::from_fn(ratelimit::rate_limit))
+            .app_data(limiter)
+            .route("/patients/{patient_id}/login", web::post().to(failed_login)),
+    )
+    .await;
+    let patient = Uuid::new_v4();
+    let login = |patient: Uuid, ip: u8| {
+        TestRequest::post()
+            .uri(&format!("/patients/{}/login", patient))
+            .peer_addr(SocketAddr::from(([10, 0, 0, ip], 40000)))
+            .to_request()
+    };
+
+    for _ in 0..3 {
+        assert_eq!(call_service(&app, login(patient, 1)).await.status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(call_service(&app, login(patient, 1)).await.status(), StatusCode::TOO_MANY_REQUESTS);
+    // Failures from one address cannot lock the patient out elsewhere
+    assert_eq!(call_service(&app, login(patient, 2)).await.status(), StatusCode::UNAUTHORIZED);
+    // Other patients are unaffected, even from the locked address
+    assert_eq!(call_service(&app, login(Uuid::new_v4(), 1)).await.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,134 @@
+// Record content is bound to its row: key material or content moved between
+// two records no longer decrypts, and content failing its integrity check is
+// refused and audited
+
+mod common;
+
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use diesel::prelude::*;
+use diesel::sql_types::Binary;
+use diesel::PgConnection;
+use serde_json::Value;
+
+use medirust::audit;
+use medirust::blobstore::{BlobStore, MemoryBlobStore};
+use medirust::config::AppConfig;
+use medirust::crypto::KeyAlgorithm;
+use medirust::handlers;
+use medirust::models::{AuditEvent, HealthRecord};
+use medirust::schema::audit_events;
+
+use common::{escrow_private_key, insert_patient, insert_record, key_hierarchy, patient_uuid, TestDatabase};
+
+async fn app(
+    db: &TestDatabase,
+    memory: &MemoryBlobStore,
+) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+    test::init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .app_data(web::Data::new(BlobStore::Memory(memory.clone())))
+            .app_data(web::Data::new(AppConfig::default()))
+            .app_data(web::Data::new(Some(key_hierarchy())))
+            .route("/patients/{patient_id}/records", web::get().to(handlers::get_health_records_for_patient)),
+    )
+    .await
+}
+
+// Exchanges the given columns between two records
+fn swap(conn: &mut PgConnection, a: &HealthRecord, b: &HealthRecord, columns: &[&str]) {
+    let set = columns.iter().map(|column| format!("{0} = other.{0}", column)).collect::<Vec<_>>().join(", ");
+    diesel::sql_query(format!(
+        "UPDATE health_records SET {} FROM health_records other \
+         WHERE (health_records.id = $1 AND other.id = $2) OR (health_records.id = $2 AND other.id = $1)",
+        set
+    ))
+    .bind::<Binary, _>(&a.id)
+    .bind::<Binary, _>(&b.id)
+    .execute(conn)
+    .unwrap();
+}
+
+#[actix_web::test]
+async fn swapped_key_material_or_content_does_not_decrypt() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let memory = MemoryBlobStore::new();
+    let app = app(&db, &memory).await;
+    let (mut patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    escrow_private_key(&mut db.conn(), &key_hierarchy(), &mut patient, &private_key);
+    let first = insert_record(&mut db.conn(), &memory, &patient, "HbA1c", b"6.1%");
+    let second = insert_record(&mut db.conn(), &memory, &patient, "LDL", b"2.4 mmol/L");
+    let uri = format!("/patients/{}/records", patient_uuid(&patient));
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let records: Vec<Value> = test::call_and_read_body_json(&app, req).await;
+    let mut contents: Vec<&str> = records.iter().map(|record| record["content"].as_str().unwrap()).collect();
+    contents.sort();
+    assert_eq!(contents, ["2.4 mmol/L", "6.1%"]);
+
+    // The CID alone is caught by the stored hash before decryption; moved
+    // together with its hash it reaches decryption, which the record id in
+    // the associated data refuses. Moving everything fails the same way.
+    let cases: [(&[&str], StatusCode); 5] = [
+        (&["encrypted_aes_key"], StatusCode::INTERNAL_SERVER_ERROR),
+        (&["nonce"], StatusCode::INTERNAL_SERVER_ERROR),
+        (&["ipfs_cid"], StatusCode::BAD_GATEWAY),
+        (&["ipfs_cid", "content_sha256"], StatusCode::INTERNAL_SERVER_ERROR),
+        (&["ipfs_cid", "content_sha256", "encrypted_aes_key", "nonce"], StatusCode::INTERNAL_SERVER_ERROR),
+    ];
+    for (columns, expected) in cases {
+        swap(&mut db.conn(), &first, &second, columns);
+        let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), expected, "swapping {:?}", columns);
+        swap(&mut db.conn(), &first, &second, columns);
+    }
+
+    let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn content_failing_its_integrity_check_is_refused_and_audited() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let memory = MemoryBlobStore::new();
+    let app = app(&db, &memory).await;
+    let (mut patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    escrow_private_key(&mut db.conn(), &key_hierarchy(), &mut patient, &private_key);
+    let record = insert_record(&mut db.conn(), &memory, &patient, "HbA1c", b"6.1%");
+    let uri = format!("/patients/{}/records", patient_uuid(&patient));
+    let integrity_failures = || {
+        audit_events::table
+            .filter(audit_events::action.eq(audit::RECORD_INTEGRITY_FAILURE_ACTION))
+            .select(AuditEvent::as_select())
+            .load(&mut db.conn())
+            .unwrap()
+    };
+
+    // An outage is not tampering
+    memory.set_offline(true);
+    let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(integrity_failures().is_empty());
+    memory.set_offline(false);
+
+    memory.corrupt(&record.ipfs_cid);
+    let res = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body = test::read_body(res).await;
+    assert!(String::from_utf8_lossy(&body).contains(&record.ipfs_cid));
+
+    let events: Vec<AuditEvent> = integrity_failures();
+    assert_eq!(events.len(), 1);
+    let event = &events[0];
+    assert_eq!(event.status, 502);
+    assert_eq!(event.actor, audit::actor(None));
+    assert_eq!(event.patient_id.as_deref(), Some(&patient.id[..]));
+    assert_eq!(event.record_id.as_deref(), Some(&record.id[..]));
+    assert_eq!(event.record_cid.as_deref(), Some(record.ipfs_cid.as_str()));
+}
@@ -0,0 +1,385 @@
+// Social recovery: requests bound to a requester key, guardians proving they
+// hold their registered key, shares that never reach the server in plaintext,
+// and a rebuilt key only the requester can read
+
+mod common;
+
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, Responder};
+use diesel::prelude::*;
+use serde_json::{json, Value};
+
+use medirust::auth::{PatientSessions, Principal, PrincipalKind};
+use medirust::config::AppConfig;
+use medirust::crypto::{CryptoUtils, KeyAlgorithm, PatientPrivateKey};
+use medirust::keys::KeyHierarchy;
+use medirust::models::Patient;
+use medirust::recovery;
+use medirust::schema::{recovery_requests, recovery_submissions};
+
+use common::{insert_patient, patient_uuid, TestDatabase};
+
+async fn app(
+    db: &TestDatabase,
+    sessions: &web::Data<PatientSessions>,
+) -> impl Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error> {
+    test::init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .app_data(web::Data::new(AppConfig::default()))
+            .app_data(web::Data::new(None::<KeyHierarchy>))
+            .app_data(sessions.clone())
+            .route("/patients/{patient_id}/recovery", web::post().to(recovery::setup_recovery))
+            .route("/patients/{patient_id}/recovery/guardians/{guardian_id}/share", web::get().to(recovery::get_guardian_share))
+            .route("/patients/{patient_id}/recovery/guardians/{guardian_id}/challenge", web::post().to(recovery::create_guardian_challenge))
+            .route("/patients/{patient_id}/recovery/requests", web::post().to(recovery::open_recovery_request))
+            .route("/patients/{patient_id}/recovery/requests/{request_id}", web::get().to(recovery::get_recovery_request))
+            .route("/patients/{patient_id}/recovery/requests/{request_id}/shares", web::post().to(recovery::submit_share))
+            .route("/patients/{patient_id}/recovery/requests/{request_id}/complete", web::post().to(recovery::complete_recovery)),
+    )
+    .await
+}
+
+async fn send<S>(app: &S, req: test::TestRequest, token: Option<&str>) -> (StatusCode, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let req = match token {
+        Some(token) => req.insert_header(("Authorization", format!("Bearer {}", token))),
+        None => req,
+    };
+    let res = test::call_service(app, req.to_request()).await;
+    let status = res.status();
+    let body = test::read_body(res).await;
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+async fn post<S>(app: &S, uri: &str, token: Option<&str>, body: Value) -> (StatusCode, Value)
+where
+    S: Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    send(app, test::TestRequest::post().uri(uri).set_json(body), token).await
+}
+
+struct Guardian {
+    id: String,
+    key: PatientPrivateKey,
+    share: Vec<u8>,
+}
+
+// Sets up k-of-n recovery with one guardian per key, returning each
+// guardian's share as they would decrypt it
+async fn set_up<S>(app: &S, sessions: &PatientSessions, patient: &Patient, private_key: &PatientPrivateKey, threshold: u8, keys: Vec<PatientPrivateKey>) -> Vec<Guardian>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let setup = json!({
+        "threshold": threshold,
+        "guardians": keys.iter().enumerate()
+            .map(|(i, key)| json!({
+                "name": format!("guardian-{}", i),
+                "public_key_pem": key.public_key().to_pem().unwrap(),
+            }))
+            .collect::<Vec<_>>(),
+        "private_key_pem": private_key.to_pem().unwrap(),
+    });
+    let uri = format!("/patients/{}/recovery", patient_uuid(patient));
+    let (status, configured) = post(app, &uri, Some(&sessions.open(&patient.id)), setup).await;
+    assert_eq!(status, StatusCode::CREATED);
+    configured["guardians"].as_array().unwrap().iter()
+        .zip(keys)
+        .map(|(guardian, key)| {
+            let encrypted = CryptoUtils::decode_base64(guardian["encrypted_share"].as_str().unwrap()).unwrap();
+            Guardian {
+                id: guardian["id"].as_str().unwrap().to_string(),
+                share: key.unwrap_key(&encrypted).unwrap(),
+                key,
+            }
+        })
+        .collect()
+}
+
+struct OpenRequest {
+    uri: String,
+    token: String,
+    key: PatientPrivateKey,
+}
+
+// Opens a request and marks it verified, as an administrator would after
+// checking the patient's identity
+async fn open_verified<S>(app: &S, db: &TestDatabase, patient: &Patient) -> OpenRequest
+where
+    S: Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let key = PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap();
+    let base = format!("/patients/{}/recovery/requests", patient_uuid(patient));
+    let (status, opened) = post(app, &base, None, json!({ "recovery_public_key_pem": key.public_key().to_pem().unwrap() })).await;
+    assert_eq!(status, StatusCode::CREATED);
+    diesel::update(recovery_requests::table.find(uuid::Uuid::parse_str(opened["id"].as_str().unwrap()).unwrap().as_bytes().to_vec()))
+        .set(recovery_requests::status.eq(recovery::STATUS_VERIFIED))
+        .execute(&mut db.conn())
+        .unwrap();
+    OpenRequest {
+        uri: format!("{}/{}", base, opened["id"].as_str().unwrap()),
+        token: opened["access_token"].as_str().unwrap().to_string(),
+        key,
+    }
+}
+
+// Answers a guardian challenge and hands in `share` wrapped to the request's key
+async fn hand_in<S>(app: &S, patient: &Patient, request: &OpenRequest, guardian: &Guardian, share: &[u8]) -> StatusCode
+where
+    S: Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let challenge_uri = format!("/patients/{}/recovery/guardians/{}/challenge", patient_uuid(patient), guardian.id);
+    let (status, challenge) = post(app, &challenge_uri, None, json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let wrapped = CryptoUtils::decode_base64(challenge["wrapped_challenge"].as_str().unwrap()).unwrap();
+    let (status, _) = post(app, &format!("{}/shares", request.uri), None, json!({
+        "guardian_id": guardian.id,
+        "challenge_id": challenge["challenge_id"],
+        "challenge": CryptoUtils::encode_base64(&guardian.key.unwrap_key(&wrapped).unwrap()),
+        "wrapped_share": CryptoUtils::encode_base64(&request.key.public_key().wrap_key(share).unwrap()),
+    }))
+    .await;
+    status
+}
+
+// The handed-in shares, unwrapped with the request's key as the requester would
+async fn unwrapped_shares<S>(app: &S, request: &OpenRequest) -> Vec<Value>
+where
+    S: Service<actix_http::Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let (status, progress) = send(app, test::TestRequest::get().uri(&request.uri), Some(&request.token)).await;
+    assert_eq!(status, StatusCode::OK);
+    progress["shares"].as_array().unwrap().iter()
+        .map(|share| {
+            let wrapped = CryptoUtils::decode_base64(share["wrapped_share"].as_str().unwrap()).unwrap();
+            json!({
+                "guardian_id": share["guardian_id"],
+                "share": CryptoUtils::encode_base64(&request.key.unwrap_key(&wrapped).unwrap()),
+            })
+        })
+        .collect()
+}
+
+fn guardian_keys() -> Vec<PatientPrivateKey> {
+    vec![
+        PatientPrivateKey::generate(KeyAlgorithm::Rsa, 1024).unwrap(),
+        PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap(),
+        PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap(),
+    ]
+}
+
+#[actix_web::test]
+async fn recovered_keys_only_reach_the_requester() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let sessions = web::Data::new(PatientSessions::default());
+    let app = app(&db, &sessions).await;
+    let (patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let base = format!("/patients/{}/recovery", patient_uuid(&patient));
+
+    // Only the patient picks their guardians and fetches their shares
+    let (status, _) = post(&app, &base, None, json!({ "threshold": 2, "guardians": [] })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let guardians = set_up(&app, &sessions, &patient, &private_key, 2, guardian_keys()).await;
+    let share_uri = format!("{}/guardians/{}/share", base, guardians[1].id);
+    let (status, _) = send(&app, test::TestRequest::get().uri(&share_uri), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, fetched) = send(&app, test::TestRequest::get().uri(&share_uri), Some(&sessions.open(&patient.id))).await;
+    assert_eq!(status, StatusCode::OK);
+    let encrypted = CryptoUtils::decode_base64(fetched["encrypted_share"].as_str().unwrap()).unwrap();
+    assert_eq!(guardians[1].key.unwrap_key(&encrypted).unwrap(), guardians[1].share);
+
+    let (status, _) = post(&app, &format!("{}/requests", base), None, json!({ "recovery_public_key_pem": "not a key" })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let request = open_verified(&app, &db, &patient).await;
+    for guardian in &guardians[..2] {
+        assert_eq!(hand_in(&app, &patient, &request, guardian, &guardian.share).await, StatusCode::OK);
+    }
+
+    // Shares wait wrapped to the recovery key, not in plaintext
+    let stored: Vec<String> = recovery_submissions::table
+        .select(recovery_submissions::share)
+        .load(&mut db.conn())
+        .unwrap();
+    assert_eq!(stored.len(), 2);
+    for stored in &stored {
+        let stored = CryptoUtils::decode_base64(stored).unwrap();
+        for guardian in &guardians {
+            assert!(!stored.windows(guardian.share.len()).any(|window| window == guardian.share.as_slice()));
+        }
+    }
+
+    // Progress and completion need the token handed out with the request
+    let (status, _) = send(&app, test::TestRequest::get().uri(&request.uri), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = send(&app, test::TestRequest::get().uri(&request.uri), Some("forged")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let unwrapped = unwrapped_shares(&app, &request).await;
+    let complete_uri = format!("{}/complete", request.uri);
+    let token = Some(request.token.as_str());
+    let (status, _) = post(&app, &complete_uri, None, json!({ "shares": unwrapped })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(&app, &complete_uri, Some("forged"), json!({ "shares": unwrapped })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Completing needs the shares unwrapped with the recovery key
+    let (_, progress) = send(&app, test::TestRequest::get().uri(&request.uri), token).await;
+    let wrapped_back: Vec<Value> = progress["shares"].as_array().unwrap().iter()
+        .map(|share| json!({ "guardian_id": share["guardian_id"], "share": share["wrapped_share"] }))
+        .collect();
+    let (status, _) = post(&app, &complete_uri, token, json!({ "shares": wrapped_back })).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    // A genuine share that was never handed in for this request
+    let outsider = &guardians[2];
+    let mut with_outsider = unwrapped[..1].to_vec();
+    with_outsider.push(json!({ "guardian_id": outsider.id, "share": CryptoUtils::encode_base64(&outsider.share) }));
+    let (status, _) = post(&app, &complete_uri, token, json!({ "shares": with_outsider })).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    let (status, sealed) = post(&app, &complete_uri, token, json!({ "shares": unwrapped })).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(sealed.get("private_key_pem").is_none());
+    let envelope_key = request.key
+        .unwrap_key(&CryptoUtils::decode_base64(sealed["wrapped_key"].as_str().unwrap()).unwrap())
+        .unwrap();
+    let pem = CryptoUtils::decrypt_data(
+        &CryptoUtils::decode_base64(sealed["encrypted_private_key"].as_str().unwrap()).unwrap(),
+        &envelope_key,
+        &CryptoUtils::decode_base64(sealed["nonce"].as_str().unwrap()).unwrap(),
+    )
+    .unwrap();
+    let recovered = PatientPrivateKey::from_pem(KeyAlgorithm::X25519Hpke, &String::from_utf8(pem).unwrap()).unwrap();
+    assert!(recovered.public_key() == private_key.public_key());
+
+    let (status, _) = post(&app, &complete_uri, token, json!({ "shares": [] })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[actix_web::test]
+async fn guardians_prove_they_hold_their_registered_key() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let sessions = web::Data::new(PatientSessions::default());
+    let app = app(&db, &sessions).await;
+    let (patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let guardians = set_up(&app, &sessions, &patient, &private_key, 2, guardian_keys()).await;
+    let request = open_verified(&app, &db, &patient).await;
+    let shares_uri = format!("{}/shares", request.uri);
+    let challenge_uri = |guardian: &Guardian| format!("/patients/{}/recovery/guardians/{}/challenge", patient_uuid(&patient), guardian.id);
+    let submission = |guardian: &Guardian, challenge: &Value, answer: &[u8]| json!({
+        "guardian_id": guardian.id,
+        "challenge_id": challenge["challenge_id"],
+        "challenge": CryptoUtils::encode_base64(answer),
+        "wrapped_share": CryptoUtils::encode_base64(&request.key.public_key().wrap_key(&guardian.share).unwrap()),
+    });
+
+    // Knowing the share is not enough without the guardian's key
+    let (_, challenge) = post(&app, &challenge_uri(&guardians[0]), None, json!({})).await;
+    let (status, _) = post(&app, &shares_uri, None, submission(&guardians[0], &challenge, &[0u8; 32])).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // Nor is another guardian's challenge, and an answer only works once
+    let (_, challenge) = post(&app, &challenge_uri(&guardians[1]), None, json!({})).await;
+    let wrapped = CryptoUtils::decode_base64(challenge["wrapped_challenge"].as_str().unwrap()).unwrap();
+    let answer = guardians[1].key.unwrap_key(&wrapped).unwrap();
+    let (status, _) = post(&app, &shares_uri, None, submission(&guardians[0], &challenge, &answer)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = post(&app, &shares_uri, None, submission(&guardians[1], &challenge, &answer)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(recovery_submissions::table.count().get_result::<i64>(&mut db.conn()).unwrap(), 0);
+
+    let (_, challenge) = post(&app, &challenge_uri(&guardians[1]), None, json!({})).await;
+    let wrapped = CryptoUtils::decode_base64(challenge["wrapped_challenge"].as_str().unwrap()).unwrap();
+    let answer = guardians[1].key.unwrap_key(&wrapped).unwrap();
+    let (status, _) = post(&app, &shares_uri, None, submission(&guardians[1], &challenge, &answer)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = post(&app, &shares_uri, None, submission(&guardians[1], &challenge, &answer)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Guardian keys may be RSA or X25519, and the key must parse
+    let bad_setup = json!({
+        "threshold": 2,
+        "guardians": [
+            { "name": "a", "public_key_pem": guardians[0].key.public_key().to_pem().unwrap() },
+            { "name": "b", "public_key_pem": "not a key" },
+        ],
+        "private_key_pem": private_key.to_pem().unwrap(),
+    });
+    let uri = format!("/patients/{}/recovery", patient_uuid(&patient));
+    let (status, _) = post(&app, &uri, Some(&sessions.open(&patient.id)), bad_setup).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn recovery_needs_threshold_distinct_genuine_shares() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let sessions = web::Data::new(PatientSessions::default());
+    let app = app(&db, &sessions).await;
+    let (patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    let guardians = set_up(&app, &sessions, &patient, &private_key, 2, guardian_keys()).await;
+    let request = open_verified(&app, &db, &patient).await;
+    let complete_uri = format!("{}/complete", request.uri);
+    let token = Some(request.token.as_str());
+
+    // Threshold not met
+    assert_eq!(hand_in(&app, &patient, &request, &guardians[0], &guardians[0].share).await, StatusCode::OK);
+    let (status, _) = post(&app, &complete_uri, token, json!({ "shares": unwrapped_shares(&app, &request).await })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // A guardian handing in twice still counts once
+    assert_eq!(hand_in(&app, &patient, &request, &guardians[0], &guardians[0].share).await, StatusCode::OK);
+    let (_, progress) = send(&app, test::TestRequest::get().uri(&request.uri), token).await;
+    assert_eq!(progress["submitted_shares"], json!(1));
+    let mut duplicated = unwrapped_shares(&app, &request).await;
+    duplicated.push(duplicated[0].clone());
+    let (status, _) = post(&app, &complete_uri, token, json!({ "shares": duplicated })).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // A wrong share is caught once unwrapped, and the guardian can correct it
+    assert_eq!(hand_in(&app, &patient, &request, &guardians[1], &guardians[2].share).await, StatusCode::OK);
+    let (status, _) = post(&app, &complete_uri, token, json!({ "shares": unwrapped_shares(&app, &request).await })).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(hand_in(&app, &patient, &request, &guardians[1], &guardians[1].share).await, StatusCode::OK);
+    let (status, _) = post(&app, &complete_uri, token, json!({ "shares": unwrapped_shares(&app, &request).await })).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn only_administrators_verify_recovery_requests() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let sessions = web::Data::new(PatientSessions::default());
+    let app = app(&db, &sessions).await;
+    let (patient, private_key) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    set_up(&app, &sessions, &patient, &private_key, 2, guardian_keys()).await;
+    let recovery_key = PatientPrivateKey::generate(KeyAlgorithm::X25519Hpke, 2048).unwrap();
+    let uri = format!("/patients/{}/recovery/requests", patient_uuid(&patient));
+    let (_, opened) = post(&app, &uri, None, json!({ "recovery_public_key_pem": recovery_key.public_key().to_pem().unwrap() })).await;
+    let request_id = opened["id"].as_str().unwrap().to_string();
+
+    let verify = |kind: PrincipalKind| {
+        let pool = web::Data::new(db.pool.clone());
+        let path = web::Path::from((patient_uuid(&patient).to_string(), request_id.clone()));
+        async move {
+            let principal = Principal { name: "reviewer".to_string(), kind };
+            let res = recovery::verify_recovery_request(pool, principal, path).await;
+            res.respond_to(&test::TestRequest::default().to_http_request()).status()
+        }
+    };
+    assert_eq!(verify(PrincipalKind::Clinician).await, StatusCode::FORBIDDEN);
+    assert_eq!(verify(PrincipalKind::Admin).await, StatusCode::OK);
+    assert_eq!(verify(PrincipalKind::Admin).await, StatusCode::CONFLICT);
+    let verified_by: Option<String> = recovery_requests::table
+        .select(recovery_requests::verified_by)
+        .first(&mut db.conn())
+        .unwrap();
+    assert_eq!(verified_by.as_deref(), Some("reviewer"));
+}
@@ -0,0 +1,247 @@
+// Replicated blob storage over in-memory stores with injected failures
+
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+use medirust::blobstore::{self, BlobStore, FetchError, FsBlobStore, MemoryBlobStore};
+use medirust::replication::{ReplicaHealth, ReplicatedBlobStore};
+
+fn replicas(count: usize) -> Vec<MemoryBlobStore> {
+    (0..count).map(|_| MemoryBlobStore::new()).collect()
+}
+
+fn replicated(stores: &[MemoryBlobStore], write_quorum: usize) -> ReplicatedBlobStore {
+    let replicas = stores.iter()
+        .enumerate()
+        .map(|(index, store)| (format!("memory-{}", index), BlobStore::Memory(store.clone())))
+        .collect();
+    ReplicatedBlobStore::new(replicas, write_quorum).unwrap()
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn healthy(store: &ReplicatedBlobStore) -> Vec<bool> {
+    store.health().into_iter().map(|ReplicaHealth { healthy, .. }| healthy).collect()
+}
+
+#[tokio::test]
+async fn writes_reach_every_replica() {
+    let stores = replicas(3);
+    let store = replicated(&stores, 2);
+    let cid = store.put(b"encrypted record".to_vec()).await.unwrap();
+    assert_eq!(cid, blobstore::raw_cid(b"encrypted record"));
+    for replica in &stores {
+        assert_eq!(replica.get(&cid).as_deref(), Some(&b"encrypted record"[..]));
+    }
+    assert_eq!(healthy(&store), vec![true, true, true]);
+}
+
+#[tokio::test]
+async fn writes_need_a_quorum() {
+    let stores = replicas(3);
+    let store = replicated(&stores, 2);
+
+    stores[0].set_offline(true);
+    let cid = store.put(b"one replica down".to_vec()).await.unwrap();
+    assert!(stores[0].get(&cid).is_none());
+    assert_eq!(healthy(&store), vec![false, true, true]);
+
+    stores[1].set_offline(true);
+    let err = store.put(b"two replicas down".to_vec()).await.err().unwrap();
+    assert!(err.to_string().contains("Only 1 of 3 replicas"), "{:#}", err);
+
+    stores[0].set_offline(false);
+    stores[1].set_offline(false);
+    store.put(b"back up".to_vec()).await.unwrap();
+    assert_eq!(healthy(&store), vec![true, true, true]);
+}
+
+#[tokio::test]
+async fn reads_fall_back_past_failed_replicas() {
+    let stores = replicas(3);
+    let store = replicated(&stores, 2);
+    let cid = store.put(b"encrypted record".to_vec()).await.unwrap();
+
+    stores[0].set_offline(true);
+    assert_eq!(store.get(&cid, None).await.unwrap(), b"encrypted record");
+
+    stores[1].unpin(&cid);
+    assert_eq!(store.get(&cid, None).await.unwrap(), b"encrypted record");
+    assert_eq!(healthy(&store), vec![false, false, true]);
+
+    stores[2].set_offline(true);
+    let err = store.get(&cid, None).await.err().unwrap();
+    assert!(err.to_string().contains("No replica returned"), "{:#}", err);
+}
+
+#[tokio::test]
+async fn reads_reject_content_that_does_not_match_its_cid() {
+    let stores = replicas(2);
+    let store = replicated(&stores, 1);
+    let cid = store.put(b"encrypted record".to_vec()).await.unwrap();
+
+    stores[0].corrupt(&cid);
+    assert_eq!(store.get(&cid, None).await.unwrap(), b"encrypted record");
+    assert_eq!(healthy(&store), vec![false, true]);
+
+    stores[1].corrupt(&cid);
+    let err = store.get(&cid, None).await.err().unwrap();
+    assert!(err.to_string().contains("does not match"), "{:#}", err);
+}
+
+#[tokio::test]
+async fn reads_check_the_recorded_hash() {
+    let stores = replicas(2);
+    let store = replicated(&stores, 2);
+    // Past one chunk the CID names a dag-pb root, which the content alone
+    // cannot be checked against
+    let content = vec![0x5a; 1_500_000];
+    let sha256 = sha256_hex(&content);
+    let cid = store.put(content.clone()).await.unwrap();
+    assert_eq!(blobstore::matches_cid(&cid, &content), None);
+
+    // Without the hash no copy can be checked, and none is handed out
+    stores[0].corrupt(&cid);
+    assert!(matches!(store.get(&cid, None).await, Err(FetchError::Unverifiable(_))));
+    assert_eq!(healthy(&store), vec![true, true]);
+    assert_eq!(store.get(&cid, Some(&sha256)).await.unwrap(), content);
+    assert_eq!(healthy(&store), vec![false, true]);
+
+    stores[1].corrupt(&cid);
+    match store.get(&cid, Some(&sha256)).await {
+        Err(FetchError::Integrity(reason)) => assert!(reason.contains("expected"), "{}", reason),
+        other => panic!("expected an integrity failure, got {:?}", other.map(|_| ())),
+    }
+    // Through the blob store, the integrity failure is what gets reported
+    let store = BlobStore::Replicated(Box::new(store));
+    assert!(matches!(store.fetch_verified(&cid, Some(&sha256)).await, Err(FetchError::Integrity(_))));
+}
+
+#[tokio::test]
+async fn healthy_replicas_are_read_first() {
+    let stores = replicas(2);
+    let store = replicated(&stores, 1);
+    let cid = store.put(b"encrypted record".to_vec()).await.unwrap();
+
+    stores[0].set_offline(true);
+    store.get(&cid, None).await.unwrap();
+    // The first replica now serves corrupt data, but is only tried after the healthy one
+    stores[0].set_offline(false);
+    stores[0].corrupt(&cid);
+    assert_eq!(store.get(&cid, None).await.unwrap(), b"encrypted record");
+    assert_eq!(healthy(&store), vec![false, true]);
+}
+
+#[tokio::test]
+async fn pins_are_reconciled_across_replicas() {
+    let stores = replicas(3);
+    let store = BlobStore::Replicated(Box::new(replicated(&stores, 2)));
+    let kept = store.put(b"kept".to_vec()).await.unwrap();
+    let lost = store.put(b"lost".to_vec()).await.unwrap();
+    store.pin(&kept).await.unwrap();
+    store.pin(&lost).await.unwrap();
+
+    // A blob one replica lost no longer counts as pinned, and pinning it
+    // again copies it back
+    stores[2].unpin(&lost);
+    assert_eq!(store.pinned().await.unwrap(), HashSet::from([kept.clone()]));
+    store.pin(&lost).await.unwrap();
+    assert_eq!(stores[2].get(&lost).as_deref(), Some(&b"lost"[..]));
+    assert_eq!(store.pinned().await.unwrap(), HashSet::from([kept.clone(), lost.clone()]));
+    stores[2].unpin(&lost);
+
+    // An unreachable replica is left out while a quorum still answers
+    stores[2].set_offline(true);
+    assert_eq!(store.pinned().await.unwrap(), HashSet::from([kept.clone(), lost.clone()]));
+
+    stores[1].set_offline(true);
+    assert!(store.pinned().await.is_err());
+    assert!(store.pin(&kept).await.is_err());
+}
+
+#[tokio::test]
+async fn quorum_must_fit_the_replica_set() {
+    let stores = replicas(2);
+    let replicas = |stores: &[MemoryBlobStore]| stores.iter().map(|store| ("memory".to_string(), BlobStore::Memory(store.clone()))).collect();
+    assert!(ReplicatedBlobStore::new(replicas(&stores), 0).is_err());
+    assert!(ReplicatedBlobStore::new(replicas(&stores), 3).is_err());
+}
+
+#[tokio::test]
+async fn filesystem_replicas_agree_with_memory_replicas() {
+    let root = std::env::temp_dir().join(format!("medirust-blobs-{}", uuid::Uuid::new_v4()));
+    let store = ReplicatedBlobStore::new(
+        vec![
+            ("fs".to_string(), BlobStore::Fs(FsBlobStore::new(&root).unwrap())),
+            ("memory".to_string(), BlobStore::Memory(MemoryBlobStore::new())),
+        ],
+        2,
+    )
+    .unwrap();
+
+    let cid = store.put(vec![0x5a; 300_000]).await.unwrap();
+    assert_eq!(std::fs::read(root.join(&cid)).unwrap(), vec![0x5a; 300_000]);
+    store.pin(&cid).await.unwrap();
+    assert_eq!(store.pinned().await.unwrap(), HashSet::from([cid.clone()]));
+
+    // Content damaged on disk is skipped in favour of the intact replica
+    std::fs::write(root.join(&cid), b"damaged").unwrap();
+    assert_eq!(store.get(&cid, None).await.unwrap(), vec![0x5a; 300_000]);
+
+    // CIDs are checked before they become paths
+    let fs = FsBlobStore::new(&root).unwrap();
+    assert!(fs.get("../escape").await.is_err());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn blobs_over_one_chunk_get_the_same_cid_everywhere() {
+    let root = std::env::temp_dir().join(format!("medirust-blobs-{}", uuid::Uuid::new_v4()));
+    let memory = MemoryBlobStore::new();
+    let store = ReplicatedBlobStore::new(
+        vec![
+            ("fs".to_string(), BlobStore::Fs(FsBlobStore::new(&root).unwrap())),
+            ("memory".to_string(), BlobStore::Memory(memory.clone())),
+        ],
+        2,
+    )
+    .unwrap();
+
+    // Up to one chunk a blob is a single raw block; past it, IPFS links the
+    // chunks from a dag-pb root, and the other stores name it the same way
+    let chunk = 1_048_576;
+    assert_eq!(blobstore::replica_cid(&vec![1; chunk]), blobstore::raw_cid(&vec![1; chunk]));
+    let content: Vec<u8> = (0..chunk * 5 / 2).map(|i| (i % 251) as u8).collect();
+    let cid = store.put(content.clone()).await.unwrap();
+    assert_eq!(cid, blobstore::replica_cid(&content));
+    assert_ne!(cid, blobstore::raw_cid(&content));
+    assert!(cid.starts_with("bafybei"), "{}", cid);
+    assert_eq!(std::fs::read(root.join(&cid)).unwrap(), content);
+    assert_eq!(healthy(&store), vec![true, true]);
+    assert_eq!(store.get(&cid, Some(&sha256_hex(&content))).await.unwrap(), content);
+
+    // Every chunk counts towards the CID, as does a chunk boundary
+    let mut changed = content.clone();
+    changed[chunk * 2] ^= 1;
+    assert_ne!(blobstore::replica_cid(&changed), cid);
+    assert_ne!(blobstore::replica_cid(&content[..chunk * 2]), blobstore::replica_cid(&content[..chunk * 2 + 1]));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn copies_must_come_back_under_their_cid() {
+    let memory = MemoryBlobStore::new();
+    let store = BlobStore::Memory(memory.clone());
+    let cid = blobstore::raw_cid(b"encrypted record");
+    store.put_as(&cid, b"encrypted record".to_vec()).await.unwrap();
+    assert_eq!(memory.get(&cid).as_deref(), Some(&b"encrypted record"[..]));
+
+    // Content that lands elsewhere is not the blob asked for and is not kept
+    let err = store.put_as(&cid, b"something else".to_vec()).await.err().unwrap();
+    assert!(err.to_string().contains("does not reproduce"), "{:#}", err);
+    assert!(memory.get(&blobstore::raw_cid(b"something else")).is_none());
+}
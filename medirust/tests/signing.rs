@@ -0,0 +1,124 @@
+// Record signatures cover the record's id and creation time, so a signature
+// cannot be lifted onto another record with the same content
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use ed25519_dalek::pkcs8::EncodePublicKey;
+use ed25519_dalek::pkcs8::spki::der::pem::LineEnding;
+use ed25519_dalek::Signer;
+use uuid::Uuid;
+
+use medirust::crypto::{CryptoUtils, RECORD_AAD_VERSION};
+use medirust::models::{HealthRecord, NewHealthRecord, SigningKey};
+use medirust::signing::{self, SignatureStatus};
+
+const CONTENT: &[u8] = b"HbA1c 6.1%";
+
+fn signing_key() -> (ed25519_dalek::SigningKey, SigningKey) {
+    let key = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+    let public_key_pem = key.verifying_key().to_public_key_pem(LineEnding::LF).unwrap();
+    let stored = SigningKey {
+        id: "clinic-north-key".to_string(),
+        principal: "clinic-north".to_string(),
+        algorithm: "ed25519".to_string(),
+        public_key_pem,
+        created_at: Utc::now().naive_utc(),
+    };
+    (key, stored)
+}
+
+fn record(patient_id: &[u8], created_at: NaiveDateTime) -> HealthRecord {
+    HealthRecord {
+        id: Uuid::new_v4().as_bytes().to_vec(),
+        patient_id: patient_id.to_vec(),
+        ipfs_cid: "bafy-test".to_string(),
+        record_type: "lab".to_string(),
+        title: "HbA1c".to_string(),
+        encrypted_aes_key: String::new(),
+        nonce: String::new(),
+        created_at,
+        updated_at: created_at,
+        data_key_id: None,
+        key_fingerprint: None,
+        pre_capsule: None,
+        pre_wrapped_key: None,
+        signature: None,
+        signing_key_id: Some("clinic-north-key".to_string()),
+        aad_version: RECORD_AAD_VERSION,
+        content_sha256: None,
+        metadata_key_id: None,
+        sealed_record_type: None,
+        sealed_title: None,
+        record_type_index: None,
+    }
+}
+
+fn sign(key: &ed25519_dalek::SigningKey, record: &HealthRecord) -> String {
+    let payload = signing::record_signing_payload(
+        &record.id,
+        &record.patient_id,
+        record.created_at,
+        &record.record_type,
+        &record.title,
+        CONTENT,
+    );
+    CryptoUtils::encode_base64(&key.sign(&payload).to_bytes())
+}
+
+#[test]
+fn signatures_do_not_move_between_records() {
+    let (key, stored_key) = signing_key();
+    let patient_id = Uuid::new_v4().as_bytes().to_vec();
+    let now = signing::signed_creation_time(Utc::now().naive_utc()).unwrap();
+
+    let mut signed = record(&patient_id, now);
+    signed.signature = Some(sign(&key, &signed));
+    assert_eq!(signing::verify_record(&signed, Some(&stored_key), CONTENT).status, SignatureStatus::Valid);
+
+    // The same metadata and content under another id
+    let mut copy = record(&patient_id, now);
+    copy.signature = signed.signature.clone();
+    assert_eq!(signing::verify_record(&copy, Some(&stored_key), CONTENT).status, SignatureStatus::Invalid);
+
+    // The same record, backdated
+    let mut backdated = signed.clone();
+    backdated.created_at = now - Duration::days(30);
+    assert_eq!(signing::verify_record(&backdated, Some(&stored_key), CONTENT).status, SignatureStatus::Invalid);
+}
+
+#[test]
+fn new_records_are_checked_against_their_own_id_and_time() {
+    let (key, stored_key) = signing_key();
+    let patient_id = Uuid::new_v4().as_bytes().to_vec();
+    let created_at = signing::signed_creation_time(Utc::now().naive_utc()).unwrap();
+    let record = record(&patient_id, created_at);
+    let new_record = NewHealthRecord {
+        patient_id: patient_id.clone(),
+        record_type: record.record_type.clone(),
+        title: record.title.clone(),
+        content: String::from_utf8(CONTENT.to_vec()).unwrap(),
+        signature: Some(sign(&key, &record)),
+        signing_key_id: Some(stored_key.id.clone()),
+        id: Some(Uuid::from_slice(&record.id).unwrap()),
+        created_at: Some(created_at),
+    };
+    signing::verify_new_record(&stored_key, &new_record).unwrap();
+
+    let other_id = NewHealthRecord { id: Some(Uuid::new_v4()), ..new_record.clone() };
+    assert!(signing::verify_new_record(&stored_key, &other_id).is_err());
+    let other_time = NewHealthRecord { created_at: Some(created_at + Duration::seconds(1)), ..new_record.clone() };
+    assert!(signing::verify_new_record(&stored_key, &other_time).is_err());
+    let no_id = NewHealthRecord { id: None, ..new_record };
+    assert!(signing::verify_new_record(&stored_key, &no_id).is_err());
+}
+
+#[test]
+fn signed_creation_times_stay_near_the_server_clock() {
+    let now = Utc::now().naive_utc();
+    // Stored at the database's microsecond precision
+    let stored = signing::signed_creation_time(now).unwrap();
+    assert_eq!(stored.and_utc().timestamp_micros(), now.and_utc().timestamp_micros());
+    assert_eq!(stored.and_utc().timestamp_subsec_nanos() % 1000, 0);
+
+    assert!(signing::signed_creation_time(now - Duration::hours(1)).is_err());
+    assert!(signing::signed_creation_time(now + Duration::hours(1)).is_err());
+}
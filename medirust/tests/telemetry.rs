@@ -0,0 +1,118 @@
+// Correlation IDs and the Prometheus metrics the server exposes
+
+mod common;
+
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::test::{call_service, init_service, read_body, TestRequest};
+use actix_web::{middleware, web, App, HttpMessage, HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+use medirust::blobstore::MemoryBlobStore;
+use medirust::crypto::{KeyAlgorithm, RECORD_AAD_VERSION};
+use medirust::metrics::{self, METRICS};
+use medirust::telemetry::{self, RequestId, REQUEST_ID_HEADER};
+
+use common::{insert_patient, insert_record, TestDatabase};
+
+fn request_id<B>(res: &ServiceResponse<B>) -> String {
+    res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string()
+}
+
+// Answers with the correlation ID the middleware attached to the request
+async fn echo_request_id(req: HttpRequest) -> HttpResponse {
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.0.clone()).unwrap_or_default();
+    HttpResponse::Ok().body(request_id)
+}
+
+#[actix_web::test]
+async fn requests_carry_a_correlation_id() {
+    let app = init_service(
+        App::new()
+            .wrap(middleware::from_fn(telemetry::request_telemetry))
+            .route("/telemetry/{patient_id}", web::get().to(echo_request_id)),
+    )
+    .await;
+
+    // A well-formed caller ID is kept, for handlers and in the response
+    let res = call_service(&app, TestRequest::get().uri("/telemetry/p1").insert_header((REQUEST_ID_HEADER, "trace-42.a_b")).to_request()).await;
+    assert_eq!(request_id(&res), "trace-42.a_b");
+    assert_eq!(read_body(res).await, "trace-42.a_b");
+
+    // Missing, oversized or log-unsafe IDs are replaced with a fresh UUID
+    for supplied in [None, Some("x".repeat(129)), Some("id with spaces".to_string()), Some("a\"b".to_string())] {
+        let mut req = TestRequest::get().uri("/telemetry/p1");
+        if let Some(supplied) = &supplied {
+            req = req.insert_header((REQUEST_ID_HEADER, supplied.as_str()));
+        }
+        let res = call_service(&app, req.to_request()).await;
+        let assigned = request_id(&res);
+        assert!(Uuid::parse_str(&assigned).is_ok(), "{:?} became {}", supplied, assigned);
+        assert_eq!(read_body(res).await, assigned.as_bytes());
+    }
+}
+
+#[actix_web::test]
+async fn request_latency_is_labelled_by_route_pattern() {
+    let app = init_service(
+        App::new()
+            .wrap(middleware::from_fn(telemetry::request_telemetry))
+            .route("/metrics-test/{patient_id}", web::get().to(echo_request_id)),
+    )
+    .await;
+    let patient_id = Uuid::new_v4().to_string();
+    let res = call_service(&app, TestRequest::get().uri(&format!("/metrics-test/{}", patient_id)).to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = call_service(&app, TestRequest::get().uri("/not-routed").to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let rendered = METRICS.render().unwrap();
+    assert!(
+        rendered.contains(r#"medirust_http_request_duration_seconds_count{method="GET",route="/metrics-test/{patient_id}",status="200"} 1"#),
+        "{}",
+        rendered
+    );
+    assert!(rendered.contains(r#"route="unmatched",status="404""#), "{}", rendered);
+    // Patient IDs never become label values
+    assert!(!rendered.contains(&patient_id));
+}
+
+#[actix_web::test]
+async fn the_scrape_endpoint_reports_pool_and_operation_metrics() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    METRICS.time_crypto("scrape_test", || ());
+    METRICS.observe_ipfs("scrape_test", 0.01, false);
+    let app = init_service(
+        App::new()
+            .app_data(web::Data::new(db.pool.clone()))
+            .route("/metrics", web::get().to(metrics::metrics_endpoint)),
+    )
+    .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().get("content-type").unwrap().to_str().unwrap().starts_with("text/plain; version=0.0.4"));
+    let body = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+    assert!(body.contains("medirust_db_pool_max_size 4"), "{}", body);
+    assert!(body.contains(r#"medirust_crypto_operation_duration_seconds_count{operation="scrape_test"} 1"#), "{}", body);
+    assert!(body.contains(r#"medirust_ipfs_operation_failures_total{operation="scrape_test"} 1"#), "{}", body);
+}
+
+#[test]
+fn the_inventory_counts_what_the_vault_holds() {
+    let Some(db) = TestDatabase::create() else {
+        return;
+    };
+    let memory = MemoryBlobStore::new();
+    let (patient, _) = insert_patient(&mut db.conn(), KeyAlgorithm::X25519Hpke);
+    insert_record(&mut db.conn(), &memory, &patient, "HbA1c", b"6.1%");
+    insert_record(&mut db.conn(), &memory, &patient, "LDL", b"2.4 mmol/L");
+
+    let rendered = metrics::render_inventory(&mut db.conn()).unwrap();
+    assert!(rendered.contains(r#"medirust_vault_patients{key_algorithm="hpke-x25519-sha256-aes256gcm"} 1"#), "{}", rendered);
+    let records = format!(r#"medirust_vault_health_records{{aad_version="{}"}} 2"#, RECORD_AAD_VERSION);
+    assert!(rendered.contains(&records), "{}", rendered);
+    assert!(rendered.contains(r#"medirust_vault_devices{state="active"} 0"#), "{}", rendered);
+}
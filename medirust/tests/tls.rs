@@ -0,0 +1,211 @@
+// Mutual TLS with locally generated certificates: handshakes against the
+// server configuration and client certificates resolved to principals
+
+mod common;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConnection};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use uuid::Uuid;
+
+use medirust::auth::{Principal, PrincipalKind};
+use medirust::config::{AppConfig, TlsConfig};
+use medirust::models::AdminUser;
+use medirust::schema::admin_users;
+use medirust::tls::{self, ClientCertificate};
+
+struct Issued {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+fn ca(name: &str) -> Issued {
+    let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+    let key = KeyPair::generate().unwrap();
+    Issued { cert: params.self_signed(&key).unwrap(), key }
+}
+
+fn leaf(ca: &Issued, name: &str, usage: ExtendedKeyUsagePurpose) -> Issued {
+    let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.extended_key_usages = vec![usage];
+    let key = KeyPair::generate().unwrap();
+    Issued { cert: params.signed_by(&key, &ca.cert, &ca.key).unwrap(), key }
+}
+
+// A CA, a server certificate for localhost and a clinician certificate it issued
+struct Pki {
+    dir: PathBuf,
+    client: Issued,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("medirust-tls-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = ca("MediRust test CA");
+        let server = leaf(&ca, "localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let client = leaf(&ca, "clinic-north", ExtendedKeyUsagePurpose::ClientAuth);
+        std::fs::write(dir.join("ca.pem"), ca.cert.pem()).unwrap();
+        std::fs::write(dir.join("server.pem"), server.cert.pem()).unwrap();
+        std::fs::write(dir.join("server.key"), server.key.serialize_pem()).unwrap();
+        Pki { dir, client }
+    }
+
+    fn tls_config(&self, require_client_cert: bool) -> TlsConfig {
+        TlsConfig {
+            enabled: true,
+            cert_path: Some(self.dir.join("server.pem")),
+            key_path: Some(self.dir.join("server.key")),
+            client_ca_path: Some(self.dir.join("ca.pem")),
+            require_client_cert,
+            client_principals: Default::default(),
+        }
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn client_config(ca_pem: &Path, identity: Option<&Issued>) -> ClientConfig {
+    let mut roots = RootCertStore::empty();
+    let ca = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(ca_pem).unwrap()))
+        .next()
+        .unwrap()
+        .unwrap();
+    roots.add(ca).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    match identity {
+        Some(identity) => {
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(identity.key.serialize_der()));
+            builder.with_client_auth_cert(vec![identity.cert.der().clone()], key).unwrap()
+        }
+        None => builder.with_no_client_auth(),
+    }
+}
+
+// Runs a handshake in memory, returning the client certificate the server verified
+fn handshake(server_config: rustls::ServerConfig, client_config: ClientConfig) -> Result<Option<ClientCertificate>, rustls::Error> {
+    let mut server = ServerConnection::new(Arc::new(server_config))?;
+    let mut client = ClientConnection::new(Arc::new(client_config), ServerName::try_from("localhost").unwrap())?;
+    loop {
+        let mut progressed = false;
+        while client.wants_write() {
+            let mut flight = Vec::new();
+            client.write_tls(&mut flight).unwrap();
+            server.read_tls(&mut flight.as_slice()).unwrap();
+            server.process_new_packets()?;
+            progressed = true;
+        }
+        while server.wants_write() {
+            let mut flight = Vec::new();
+            server.write_tls(&mut flight).unwrap();
+            client.read_tls(&mut flight.as_slice()).unwrap();
+            client.process_new_packets()?;
+            progressed = true;
+        }
+        if !client.is_handshaking() && !server.is_handshaking() && !client.wants_write() {
+            let leaf = server.peer_certificates().and_then(|certs| certs.first()).map(|cert| cert.to_vec());
+            return Ok(leaf.map(ClientCertificate));
+        }
+        assert!(progressed, "handshake stalled");
+    }
+}
+
+#[test]
+fn client_certificates_from_the_configured_ca_are_accepted() {
+    let pki = Pki::new();
+    let server_config = tls::load_server_config(&pki.tls_config(true)).unwrap();
+    let presented = handshake(server_config, client_config(&pki.dir.join("ca.pem"), Some(&pki.client)))
+        .unwrap()
+        .expect("server saw the client certificate");
+    assert_eq!(presented.0, pki.client.cert.der().to_vec());
+}
+
+#[test]
+fn handshakes_without_an_acceptable_client_certificate_fail() {
+    let pki = Pki::new();
+    let ca_pem = pki.dir.join("ca.pem");
+
+    let server_config = tls::load_server_config(&pki.tls_config(true)).unwrap();
+    assert!(handshake(server_config, client_config(&ca_pem, None)).is_err());
+
+    // Issued by a CA the server does not trust
+    let other_ca = ca("Other CA");
+    let stranger = leaf(&other_ca, "stranger", ExtendedKeyUsagePurpose::ClientAuth);
+    let server_config = tls::load_server_config(&pki.tls_config(true)).unwrap();
+    assert!(handshake(server_config, client_config(&ca_pem, Some(&stranger))).is_err());
+
+    // Optional client authentication lets anonymous clients in
+    let server_config = tls::load_server_config(&pki.tls_config(false)).unwrap();
+    assert!(handshake(server_config, client_config(&ca_pem, None)).unwrap().is_none());
+}
+
+#[test]
+fn client_certificates_resolve_through_client_principals() {
+    let pki = Pki::new();
+    let presented = handshake(
+        tls::load_server_config(&pki.tls_config(true)).unwrap(),
+        client_config(&pki.dir.join("ca.pem"), Some(&pki.client)),
+    )
+    .unwrap()
+    .unwrap();
+
+    let mut config = AppConfig::default();
+    assert_eq!(Principal::from_client_certificate(&config, &presented), None);
+
+    // Fingerprints are matched regardless of case
+    config.tls.client_principals.insert(presented.fingerprint().to_ascii_uppercase(), "clinic-north".to_string());
+    assert_eq!(
+        Principal::from_client_certificate(&config, &presented),
+        Some(Principal { name: "clinic-north".to_string(), kind: PrincipalKind::Clinician }),
+    );
+}
+
+#[test]
+fn client_certificates_resolve_to_enabled_admin_users() {
+    let Some(db) = common::TestDatabase::create() else {
+        return;
+    };
+    let mut conn = db.conn();
+    let pki = Pki::new();
+    let presented = handshake(
+        tls::load_server_config(&pki.tls_config(true)).unwrap(),
+        client_config(&pki.dir.join("ca.pem"), Some(&pki.client)),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(Principal::admin_from_client_certificate(&mut conn, &presented).unwrap(), None);
+
+    let admin = AdminUser {
+        id: Uuid::new_v4().as_bytes().to_vec(),
+        name: "operator".to_string(),
+        certificate_fingerprint: presented.fingerprint(),
+        created_at: Utc::now().naive_utc(),
+        disabled_at: None,
+    };
+    diesel::insert_into(admin_users::table).values(&admin).execute(&mut conn).unwrap();
+    assert_eq!(
+        Principal::admin_from_client_certificate(&mut conn, &presented).unwrap(),
+        Some(Principal { name: "operator".to_string(), kind: PrincipalKind::Admin }),
+    );
+
+    diesel::update(admin_users::table.find(&admin.id))
+        .set(admin_users::disabled_at.eq(Some(Utc::now().naive_utc())))
+        .execute(&mut conn)
+        .unwrap();
+    assert_eq!(Principal::admin_from_client_certificate(&mut conn, &presented).unwrap(), None);
+}
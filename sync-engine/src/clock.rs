@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Vector clock over device actors. Each edit increments the editing device's
+// counter; two revisions neither of which descends from the other are
+// concurrent edits and become a conflict.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+// How two clocks are causally related
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Causality {
+    Equal,
+    // The left clock happened before the right one
+    Before,
+    After,
+    Concurrent,
+}
+
+impl VectorClock {
+    pub fn get(&self, actor: &str) -> u64 {
+        self.0.get(actor).copied().unwrap_or_default()
+    }
+
+    // The clock of an edit by `actor` that follows this one
+    pub fn incremented(&self, actor: &str) -> Self {
+        let mut next = self.clone();
+        *next.0.entry(actor.to_string()).or_default() += 1;
+        next
+    }
+
+    // Pointwise maximum, the smallest clock descending from both
+    pub fn merged(&self, other: &Self) -> Self {
+        let mut merged = self.clone();
+        for (actor, &counter) in &other.0 {
+            let entry = merged.0.entry(actor.clone()).or_default();
+            *entry = (*entry).max(counter);
+        }
+        merged
+    }
+
+    pub fn compare(&self, other: &Self) -> Causality {
+        let actors = self.0.keys().chain(other.0.keys());
+        let (mut less, mut greater) = (false, false);
+        for actor in actors {
+            match self.get(actor).cmp(&other.get(actor)) {
+                std::cmp::Ordering::Less => less = true,
+                std::cmp::Ordering::Greater => greater = true,
+                std::cmp::Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => Causality::Equal,
+            (true, false) => Causality::Before,
+            (false, true) => Causality::After,
+            (true, true) => Causality::Concurrent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(entries: &[(&str, u64)]) -> VectorClock {
+        VectorClock(entries.iter().map(|(actor, counter)| (actor.to_string(), *counter)).collect())
+    }
+
+    #[test]
+    fn compare_orders_causally_related_clocks() {
+        let empty = VectorClock::default();
+        let a1 = empty.incremented("a");
+        let a2 = a1.incremented("a");
+        assert_eq!(empty.compare(&empty), Causality::Equal);
+        assert_eq!(empty.compare(&a1), Causality::Before);
+        assert_eq!(a2.compare(&a1), Causality::After);
+        assert_eq!(a1.compare(&a2), Causality::Before);
+
+        // Missing actors count as zero
+        assert_eq!(clock(&[("a", 1), ("b", 0)]).compare(&clock(&[("a", 1)])), Causality::Equal);
+        assert_eq!(clock(&[("a", 1)]).compare(&clock(&[("a", 1), ("b", 1)])), Causality::Before);
+    }
+
+    #[test]
+    fn compare_detects_concurrent_edits() {
+        let base = VectorClock::default().incremented("a");
+        let left = base.incremented("a");
+        let right = base.incremented("b");
+        assert_eq!(left.compare(&right), Causality::Concurrent);
+        assert_eq!(right.compare(&left), Causality::Concurrent);
+        assert_eq!(clock(&[("a", 2), ("b", 1)]).compare(&clock(&[("a", 1), ("b", 2)])), Causality::Concurrent);
+    }
+
+    #[test]
+    fn merged_descends_from_both_sides() {
+        let left = clock(&[("a", 3), ("b", 1)]);
+        let right = clock(&[("b", 2), ("c", 1)]);
+        let merged = left.merged(&right);
+        assert_eq!(merged, clock(&[("a", 3), ("b", 2), ("c", 1)]));
+        assert_eq!(merged, right.merged(&left));
+        assert_eq!(left.compare(&merged), Causality::Before);
+        assert_eq!(right.compare(&merged), Causality::Before);
+        assert_eq!(merged.merged(&merged), merged);
+
+        // Merging a clock it already descends from changes nothing
+        assert_eq!(merged.merged(&left), merged);
+        // The resolving edit supersedes both sides
+        let resolved = merged.incremented("a");
+        assert_eq!(resolved.compare(&left), Causality::After);
+        assert_eq!(resolved.compare(&right), Causality::After);
+    }
+}
@@ -0,0 +1,61 @@
+use medirust_core::{aead, hpke, open_record, KeyAlgorithm, PatientPrivateKey, RecordBinding, SealedRecord};
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+// Client side of the server's record encryption. Key and record formats come
+// from medirust-core, so they match the server by construction.
+
+const RSA_KEY_BITS: usize = 2048;
+
+// A device's private key; the public half is registered with the server
+pub struct DeviceKey(PatientPrivateKey);
+
+impl DeviceKey {
+    pub fn generate_x25519() -> Self {
+        DeviceKey(PatientPrivateKey::X25519(hpke::generate_key_pair().0))
+    }
+
+    pub fn generate_rsa() -> Result<Self> {
+        PatientPrivateKey::generate(KeyAlgorithm::Rsa, RSA_KEY_BITS).map(DeviceKey)
+    }
+
+    // RSA keys are PKCS1 PEM, X25519 keys PKCS8 PEM
+    pub fn from_pem(pem_str: &str) -> Result<Self> {
+        PatientPrivateKey::from_pem(KeyAlgorithm::detect(pem_str), pem_str).map(DeviceKey)
+    }
+
+    pub fn to_pem(&self) -> Result<Zeroizing<String>> {
+        self.0.to_pem().map(Zeroizing::new)
+    }
+
+    // Public key PEM to register the device with
+    pub fn public_key_pem(&self) -> Result<String> {
+        self.0.public_key().to_pem()
+    }
+
+    // Unwraps a record AES key the server wrapped to this device
+    pub fn unwrap_key(&self, wrapped_key: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        self.0.unwrap_key(wrapped_key).map(Zeroizing::new)
+    }
+
+    // Decrypts record content whose key is wrapped to this device
+    pub fn open_record(&self, binding: &RecordBinding<'_>, sealed: &SealedRecord) -> Result<Zeroizing<Vec<u8>>> {
+        open_record(&self.0, binding, sealed)
+    }
+}
+
+// Encrypts under a fresh random nonce, returning nonce || ciphertext
+pub fn seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let (ciphertext, mut sealed) = aead::encrypt(plaintext, key, aad)?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+// Opens nonce || ciphertext produced by `seal`
+pub fn open(key: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < aead::NONCE_SIZE {
+        return Err(anyhow!("Sealed data is too short"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(aead::NONCE_SIZE);
+    aead::decrypt(ciphertext, key, nonce, aad)
+}
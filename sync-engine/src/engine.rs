@@ -0,0 +1,333 @@
+use uuid::Uuid;
+use anyhow::{Result, anyhow};
+
+use crate::clock::{Causality, VectorClock};
+use crate::replica::{Replica, Revision, LOCAL_REVISION_PREFIX};
+use crate::transport::{Transport, TransportError};
+
+// Offline-first document store on top of a replica and a transport. Edits
+// are recorded locally and queued; `sync` pushes the queue and pulls the
+// change feed. A document's current versions are its heads, the revisions no
+// other revision descends from: one head means it is consistent, several
+// mean concurrent edits that stay visible until `resolve` merges them.
+
+// A document with its current versions
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub doc_id: Uuid,
+    pub heads: Vec<Revision>,
+}
+
+impl Document {
+    pub fn is_conflicted(&self) -> bool {
+        self.heads.len() > 1
+    }
+
+    // The single current version, None while conflicted
+    pub fn current(&self) -> Option<&Revision> {
+        match self.heads.as_slice() {
+            [head] => Some(head),
+            _ => None,
+        }
+    }
+}
+
+// Outcome of one `sync` call
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    pub pushed: usize,
+    pub pulled: usize,
+    // Revisions still queued, non-zero when the server could not be reached
+    pub queued: usize,
+    pub offline: bool,
+    pub conflicts: Vec<Uuid>,
+}
+
+// Revisions not superseded by another revision, with duplicates folded
+fn heads(revisions: Vec<Revision>) -> Vec<Revision> {
+    let mut heads: Vec<Revision> = Vec::new();
+    for revision in &revisions {
+        let superseded = revisions.iter().any(|other| revision.clock.compare(&other.clock) == Causality::Before);
+        if !superseded && !heads.iter().any(|head| head.same_version(revision)) {
+            heads.push(revision.clone());
+        }
+    }
+    heads
+}
+
+pub struct SyncEngine<T: Transport> {
+    replica: Replica,
+    transport: T,
+    // This device's entry in vector clocks
+    actor: String,
+}
+
+impl<T: Transport> SyncEngine<T> {
+    pub fn new(replica: Replica, transport: T, actor: impl Into<String>) -> Self {
+        SyncEngine { replica, transport, actor: actor.into() }
+    }
+
+    fn record_local(&mut self, doc_id: Uuid, record_type: String, title: String, body: String, clock: VectorClock) -> Result<()> {
+        let revision = Revision {
+            revision_id: format!("{}{}", LOCAL_REVISION_PREFIX, Uuid::new_v4()),
+            doc_id,
+            record_type,
+            title,
+            body,
+            clock,
+        };
+        self.replica.insert_local(&revision)
+    }
+
+    pub fn create(&mut self, record_type: &str, title: &str, body: &str) -> Result<Uuid> {
+        let doc_id = Uuid::new_v4();
+        let clock = VectorClock::default().incremented(&self.actor);
+        self.record_local(doc_id, record_type.to_string(), title.to_string(), body.to_string(), clock)?;
+        Ok(doc_id)
+    }
+
+    // Records a new version of a document; fails while the document is conflicted
+    pub fn edit(&mut self, doc_id: Uuid, title: &str, body: &str) -> Result<()> {
+        let document = self.document(doc_id)?.ok_or_else(|| anyhow!("Document {} not found", doc_id))?;
+        let head = document
+            .current()
+            .ok_or_else(|| anyhow!("Document {} has conflicting versions; resolve them first", doc_id))?;
+        let clock = head.clock.incremented(&self.actor);
+        self.record_local(doc_id, head.record_type.clone(), title.to_string(), body.to_string(), clock)
+    }
+
+    // Replaces all current versions of a document with one that supersedes them
+    pub fn resolve(&mut self, doc_id: Uuid, title: &str, body: &str) -> Result<()> {
+        let document = self.document(doc_id)?.ok_or_else(|| anyhow!("Document {} not found", doc_id))?;
+        let clock = document
+            .heads
+            .iter()
+            .fold(VectorClock::default(), |clock, head| clock.merged(&head.clock))
+            .incremented(&self.actor);
+        let record_type = document.heads[0].record_type.clone();
+        self.record_local(doc_id, record_type, title.to_string(), body.to_string(), clock)
+    }
+
+    pub fn document(&self, doc_id: Uuid) -> Result<Option<Document>> {
+        let revisions = self.replica.revisions(&doc_id)?;
+        if revisions.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Document { doc_id, heads: heads(revisions) }))
+    }
+
+    pub fn documents(&self) -> Result<Vec<Document>> {
+        self.replica
+            .doc_ids()?
+            .into_iter()
+            .map(|doc_id| Ok(Document { doc_id, heads: heads(self.replica.revisions(&doc_id)?) }))
+            .collect()
+    }
+
+    pub fn conflicts(&self) -> Result<Vec<Document>> {
+        Ok(self.documents()?.into_iter().filter(Document::is_conflicted).collect())
+    }
+
+    // Pushes queued revisions in order, then pulls the change feed. Being
+    // offline is not an error: the report says so and the queue is kept.
+    pub fn sync(&mut self) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        for revision in self.replica.outbox()? {
+            match self.transport.push(&revision) {
+                Ok(server_id) => {
+                    self.replica.mark_pushed(&revision.revision_id, &server_id)?;
+                    report.pushed += 1;
+                }
+                Err(TransportError::Offline(_)) => {
+                    report.offline = true;
+                    break;
+                }
+                Err(e) => return Err(anyhow!("Pushing revision {} failed: {}", revision.revision_id, e)),
+            }
+        }
+
+        while !report.offline {
+            let cursor = self.replica.cursor()?;
+            match self.transport.pull(cursor.as_deref()) {
+                Ok(page) => {
+                    report.pulled += self.replica.apply_pull(&page.revisions, page.cursor.as_deref())?;
+                    if !page.has_more {
+                        break;
+                    }
+                }
+                Err(TransportError::Offline(_)) => report.offline = true,
+                Err(e) => return Err(anyhow!("Pulling changes failed: {}", e)),
+            }
+        }
+
+        report.queued = self.replica.outbox()?.len();
+        report.conflicts = self.conflicts()?.into_iter().map(|document| document.doc_id).collect();
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use zeroize::Zeroizing;
+
+    use crate::transport::ChangePage;
+
+    // In-memory stand-in for the server, shared by the devices under test
+    #[derive(Default)]
+    struct FakeServer {
+        records: Vec<Revision>,
+        offline: bool,
+        // Pushes accepted before the connection drops
+        pushes_before_outage: Option<usize>,
+    }
+
+    struct FakeTransport(Rc<RefCell<FakeServer>>);
+
+    impl Transport for FakeTransport {
+        fn push(&mut self, revision: &Revision) -> Result<String, TransportError> {
+            let mut server = self.0.borrow_mut();
+            match server.pushes_before_outage {
+                Some(0) => server.offline = true,
+                Some(left) => server.pushes_before_outage = Some(left - 1),
+                None => {}
+            }
+            if server.offline {
+                return Err(TransportError::Offline("connection refused".to_string()));
+            }
+            let record = Revision { revision_id: Uuid::new_v4().to_string(), ..revision.clone() };
+            server.records.push(record.clone());
+            Ok(record.revision_id)
+        }
+
+        // The cursor is the number of records already seen
+        fn pull(&mut self, since: Option<&str>) -> Result<ChangePage, TransportError> {
+            let server = self.0.borrow();
+            if server.offline {
+                return Err(TransportError::Offline("connection refused".to_string()));
+            }
+            let seen = since.map(|cursor| cursor.parse().unwrap()).unwrap_or(0);
+            Ok(ChangePage {
+                revisions: server.records[seen..].to_vec(),
+                cursor: Some(server.records.len().to_string()),
+                has_more: false,
+            })
+        }
+    }
+
+    fn engine(server: &Rc<RefCell<FakeServer>>, actor: &str) -> SyncEngine<FakeTransport> {
+        let replica = Replica::open_in_memory(Zeroizing::new([7u8; 32])).unwrap();
+        SyncEngine::new(replica, FakeTransport(server.clone()), actor)
+    }
+
+    fn revision(revision_id: &str, doc_id: Uuid, body: &str, clock: &[(&str, u64)]) -> Revision {
+        let clock = clock
+            .iter()
+            .fold(VectorClock::default(), |clock, (actor, count)| (0..*count).fold(clock, |clock, _| clock.incremented(actor)));
+        Revision {
+            revision_id: revision_id.to_string(),
+            doc_id,
+            record_type: "note".to_string(),
+            title: "Note".to_string(),
+            body: body.to_string(),
+            clock,
+        }
+    }
+
+    #[test]
+    fn heads_keep_only_unsuperseded_revisions() {
+        let doc_id = Uuid::new_v4();
+        let first = revision("r1", doc_id, "v1", &[("a", 1)]);
+        let second = revision("r2", doc_id, "v2", &[("a", 2)]);
+        assert_eq!(heads(vec![first.clone(), second.clone()]), vec![second.clone()]);
+        assert_eq!(heads(vec![second.clone(), first.clone()]), vec![second.clone()]);
+
+        // The same version under two ids, e.g. pushed twice, is one head
+        let duplicate = Revision { revision_id: "r2-again".to_string(), ..second.clone() };
+        assert_eq!(heads(vec![first.clone(), second.clone(), duplicate]), vec![second.clone()]);
+
+        // Concurrent edits both stay current
+        let concurrent = revision("r3", doc_id, "v2 elsewhere", &[("a", 1), ("b", 1)]);
+        let current = heads(vec![first, second.clone(), concurrent.clone()]);
+        assert_eq!(current, vec![second, concurrent]);
+        assert!(Document { doc_id, heads: current }.is_conflicted());
+    }
+
+    #[test]
+    fn concurrent_edits_conflict_until_resolved() {
+        let server = Rc::new(RefCell::new(FakeServer::default()));
+        let mut phone = engine(&server, "phone");
+        let mut laptop = engine(&server, "laptop");
+        let doc_id = phone.create("note", "Allergies", "penicillin").unwrap();
+        phone.sync().unwrap();
+        laptop.sync().unwrap();
+        assert_eq!(laptop.document(doc_id).unwrap().unwrap().current().unwrap().body, "penicillin");
+
+        // Both edit the same version
+        phone.edit(doc_id, "Allergies", "penicillin, latex").unwrap();
+        laptop.edit(doc_id, "Allergies", "penicillin, peanuts").unwrap();
+        phone.sync().unwrap();
+        let report = laptop.sync().unwrap();
+        assert_eq!(report.conflicts, vec![doc_id]);
+        let document = laptop.document(doc_id).unwrap().unwrap();
+        assert!(document.is_conflicted() && document.current().is_none());
+        let mut bodies: Vec<&str> = document.heads.iter().map(|head| head.body.as_str()).collect();
+        bodies.sort();
+        assert_eq!(bodies, ["penicillin, latex", "penicillin, peanuts"]);
+
+        // Editing one side would silently drop the other
+        let err = laptop.edit(doc_id, "Allergies", "penicillin").unwrap_err();
+        assert!(err.to_string().contains("resolve"), "{}", err);
+
+        laptop.resolve(doc_id, "Allergies", "penicillin, latex, peanuts").unwrap();
+        let resolved = laptop.document(doc_id).unwrap().unwrap();
+        let head = resolved.current().unwrap();
+        assert_eq!(head.body, "penicillin, latex, peanuts");
+        for side in &document.heads {
+            assert_eq!(side.clock.compare(&head.clock), Causality::Before);
+        }
+        assert!(laptop.conflicts().unwrap().is_empty());
+
+        // The resolution reaches the other device, which can edit again
+        laptop.sync().unwrap();
+        let report = phone.sync().unwrap();
+        assert!(report.conflicts.is_empty());
+        assert_eq!(phone.document(doc_id).unwrap().unwrap().current().unwrap().body, "penicillin, latex, peanuts");
+        phone.edit(doc_id, "Allergies", "none known").unwrap();
+    }
+
+    #[test]
+    fn going_offline_mid_push_keeps_the_rest_queued() {
+        let server = Rc::new(RefCell::new(FakeServer { pushes_before_outage: Some(1), ..FakeServer::default() }));
+        let mut phone = engine(&server, "phone");
+        let first = phone.create("note", "First", "1").unwrap();
+        let second = phone.create("note", "Second", "2").unwrap();
+        phone.edit(second, "Second", "2b").unwrap();
+        let queued: Vec<String> = phone.replica.outbox().unwrap().iter().map(|r| r.revision_id.clone()).collect();
+
+        let report = phone.sync().unwrap();
+        assert!(report.offline);
+        assert_eq!((report.pushed, report.pulled, report.queued), (1, 0, 2));
+        // The rest stays queued in order, under its local ids
+        let outbox = phone.replica.outbox().unwrap();
+        assert_eq!(outbox.iter().map(|r| r.revision_id.clone()).collect::<Vec<_>>(), queued[1..]);
+        assert!(outbox.iter().all(Revision::is_local));
+        assert!(!phone.document(first).unwrap().unwrap().current().unwrap().is_local());
+        assert_eq!(server.borrow().records.len(), 1);
+
+        // Nothing is lost or sent twice once the server is back
+        {
+            let mut server = server.borrow_mut();
+            server.offline = false;
+            server.pushes_before_outage = None;
+        }
+        let report = phone.sync().unwrap();
+        assert!(!report.offline);
+        assert_eq!((report.pushed, report.queued), (2, 0));
+        assert_eq!(server.borrow().records.len(), 3);
+        assert_eq!(phone.document(second).unwrap().unwrap().current().unwrap().body, "2b");
+        assert!(phone.documents().unwrap().iter().all(|document| !document.is_conflicted()));
+    }
+}
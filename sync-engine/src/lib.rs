@@ -0,0 +1,16 @@
+// Offline-first sync client for MediRust. Keeps an encrypted SQLite replica
+// of a patient's records on a device, queues creates and edits while offline
+// and reconciles concurrent edits with vector clocks, surfacing conflicts
+// instead of dropping either side.
+
+pub mod clock;
+pub mod crypto;
+pub mod replica;
+pub mod transport;
+pub mod engine;
+
+pub use clock::{Causality, VectorClock};
+pub use crypto::DeviceKey;
+pub use engine::{Document, SyncEngine, SyncReport};
+pub use replica::{Replica, Revision};
+pub use transport::{ChangePage, HttpTransport, Transport, TransportError};
@@ -0,0 +1,222 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use anyhow::{Result, anyhow};
+
+use crate::clock::VectorClock;
+use crate::crypto;
+
+// Local SQLite replica of a patient's records. Every revision is sealed with
+// AES-256-GCM under the replica key and bound to its revision id, so the file
+// reveals only how many revisions each document has.
+
+// Prefix of revision ids assigned before the server has accepted the revision
+pub const LOCAL_REVISION_PREFIX: &str = "local:";
+const CURSOR_KEY: &str = "cursor";
+const KEY_CHECK_KEY: &str = "key_check";
+const KEY_CHECK_PLAINTEXT: &[u8] = b"medirust-replica-v1";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS revisions (
+    revision_id TEXT PRIMARY KEY NOT NULL,
+    doc_id TEXT NOT NULL,
+    sealed BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS revisions_doc_idx ON revisions (doc_id);
+CREATE TABLE IF NOT EXISTS outbox (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    revision_id TEXT NOT NULL UNIQUE
+);
+CREATE TABLE IF NOT EXISTS sync_state (
+    key TEXT PRIMARY KEY NOT NULL,
+    value BLOB NOT NULL
+);
+";
+
+// One version of a document. Server records are revisions too; an edit is a
+// new revision whose clock descends from the one it replaces.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    // Server record id, or LOCAL_REVISION_PREFIX + uuid until pushed
+    pub revision_id: String,
+    pub doc_id: Uuid,
+    pub record_type: String,
+    pub title: String,
+    pub body: String,
+    pub clock: VectorClock,
+}
+
+impl Revision {
+    pub fn is_local(&self) -> bool {
+        self.revision_id.starts_with(LOCAL_REVISION_PREFIX)
+    }
+
+    // True if both carry the same clock and content, e.g. a revision pushed
+    // twice after a crash before the first push was acknowledged
+    pub fn same_version(&self, other: &Revision) -> bool {
+        self.doc_id == other.doc_id
+            && self.clock == other.clock
+            && self.record_type == other.record_type
+            && self.title == other.title
+            && self.body == other.body
+    }
+}
+
+pub struct Replica {
+    conn: Connection,
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl Replica {
+    pub fn open(path: impl AsRef<Path>, key: Zeroizing<[u8; 32]>) -> Result<Self> {
+        Self::init(Connection::open(path)?, key)
+    }
+
+    pub fn open_in_memory(key: Zeroizing<[u8; 32]>) -> Result<Self> {
+        Self::init(Connection::open_in_memory()?, key)
+    }
+
+    fn init(conn: Connection, key: Zeroizing<[u8; 32]>) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        let replica = Replica { conn, key };
+        // A replica opened with the wrong key must fail here, not on first read
+        match replica.state(KEY_CHECK_KEY)? {
+            Some(check) => {
+                crypto::open(replica.key.as_slice(), &check, KEY_CHECK_KEY.as_bytes())
+                    .map_err(|_| anyhow!("Replica key does not match this replica"))?;
+            }
+            None => {
+                let check = crypto::seal(replica.key.as_slice(), KEY_CHECK_PLAINTEXT, KEY_CHECK_KEY.as_bytes())?;
+                replica.set_state(&replica.conn, KEY_CHECK_KEY, &check)?;
+            }
+        }
+        Ok(replica)
+    }
+
+    fn state(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.conn
+            .query_row("SELECT value FROM sync_state WHERE key = ?1", params![key], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn set_state(&self, conn: &Connection, key: &str, value: &[u8]) -> Result<()> {
+        conn.execute(
+            "INSERT INTO sync_state (key, value) VALUES (?1, ?2) ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    fn seal(&self, revision: &Revision) -> Result<Vec<u8>> {
+        let plaintext = Zeroizing::new(serde_json::to_vec(revision)?);
+        crypto::seal(self.key.as_slice(), &plaintext, revision.revision_id.as_bytes())
+    }
+
+    fn unseal(&self, revision_id: &str, sealed: &[u8]) -> Result<Revision> {
+        let plaintext = Zeroizing::new(crypto::open(self.key.as_slice(), sealed, revision_id.as_bytes())?);
+        let revision: Revision = serde_json::from_slice(&plaintext)?;
+        if revision.revision_id != revision_id {
+            return Err(anyhow!("Revision {} is stored under the wrong id", revision_id));
+        }
+        Ok(revision)
+    }
+
+    fn insert_with(&self, conn: &Connection, revision: &Revision) -> Result<bool> {
+        let inserted = conn.execute(
+            "INSERT INTO revisions (revision_id, doc_id, sealed) VALUES (?1, ?2, ?3) ON CONFLICT (revision_id) DO NOTHING",
+            params![revision.revision_id, revision.doc_id.to_string(), self.seal(revision)?],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    // Stores a local revision and queues it for pushing
+    pub fn insert_local(&mut self, revision: &Revision) -> Result<()> {
+        if !revision.is_local() {
+            return Err(anyhow!("Local revisions need a {} id", LOCAL_REVISION_PREFIX));
+        }
+        let tx = self.conn.unchecked_transaction()?;
+        self.insert_with(&tx, revision)?;
+        tx.execute("INSERT INTO outbox (revision_id) VALUES (?1)", params![revision.revision_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Stores pulled revisions and advances the cursor atomically, returning
+    // how many revisions were new
+    pub fn apply_pull(&mut self, revisions: &[Revision], cursor: Option<&str>) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let mut inserted = 0;
+        for revision in revisions {
+            if self.insert_with(&tx, revision)? {
+                inserted += 1;
+            }
+        }
+        if let Some(cursor) = cursor {
+            self.set_state(&tx, CURSOR_KEY, cursor.as_bytes())?;
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    // Renames a pushed revision to its server record id and dequeues it
+    pub fn mark_pushed(&mut self, local_id: &str, server_id: &str) -> Result<()> {
+        let sealed: Vec<u8> = self.conn.query_row(
+            "SELECT sealed FROM revisions WHERE revision_id = ?1",
+            params![local_id],
+            |row| row.get(0),
+        )?;
+        let mut revision = self.unseal(local_id, &sealed)?;
+        revision.revision_id = server_id.to_string();
+        let resealed = self.seal(&revision)?;
+
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM revisions WHERE revision_id = ?1", params![local_id])?;
+        tx.execute(
+            "INSERT INTO revisions (revision_id, doc_id, sealed) VALUES (?1, ?2, ?3) ON CONFLICT (revision_id) DO NOTHING",
+            params![server_id, revision.doc_id.to_string(), resealed],
+        )?;
+        tx.execute("DELETE FROM outbox WHERE revision_id = ?1", params![local_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn load(&self, sql: &str, param: Option<&str>) -> Result<Vec<Revision>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = match param {
+            Some(param) => stmt.query_map(params![param], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+            None => stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+        };
+        rows.iter().map(|(revision_id, sealed)| self.unseal(revision_id, sealed)).collect()
+    }
+
+    pub fn revisions(&self, doc_id: &Uuid) -> Result<Vec<Revision>> {
+        self.load(
+            "SELECT revision_id, sealed FROM revisions WHERE doc_id = ?1 ORDER BY revision_id",
+            Some(&doc_id.to_string()),
+        )
+    }
+
+    pub fn doc_ids(&self) -> Result<Vec<Uuid>> {
+        let mut stmt = self.conn.prepare("SELECT DISTINCT doc_id FROM revisions ORDER BY doc_id")?;
+        let ids = stmt.query_map([], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+        ids.iter().map(|id| Uuid::parse_str(id).map_err(|e| anyhow!("Corrupt document id {}: {}", id, e))).collect()
+    }
+
+    // Revisions waiting to be pushed, oldest first
+    pub fn outbox(&self) -> Result<Vec<Revision>> {
+        self.load(
+            "SELECT r.revision_id, r.sealed FROM outbox o JOIN revisions r ON r.revision_id = o.revision_id ORDER BY o.seq",
+            None,
+        )
+    }
+
+    pub fn cursor(&self) -> Result<Option<String>> {
+        self.state(CURSOR_KEY)?
+            .map(|cursor| String::from_utf8(cursor).map_err(|_| anyhow!("Corrupt sync cursor")))
+            .transpose()
+    }
+}
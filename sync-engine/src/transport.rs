@@ -0,0 +1,265 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use hyper::{body, client::HttpConnector, Body, Client, Method, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use uuid::Uuid;
+use medirust_core::{RecordBinding, SealedRecord};
+use anyhow::anyhow;
+
+use crate::clock::VectorClock;
+use crate::crypto::DeviceKey;
+use crate::replica::Revision;
+
+// Version tag of the envelope the engine stores as record content
+const ENVELOPE_VERSION: u32 = 1;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CHANGE_PAGE_SIZE: usize = 100;
+
+// Why a push or pull did not complete
+#[derive(Debug)]
+pub enum TransportError {
+    // The server or IPFS gateway is unreachable or temporarily unavailable;
+    // queued changes stay queued and the call can be retried later
+    Offline(String),
+    // The server refused the request
+    Rejected { status: u16, body: String },
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Offline(reason) => write!(f, "offline: {}", reason),
+            TransportError::Rejected { status, body } => write!(f, "request rejected with HTTP {}: {}", status, body),
+            TransportError::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<anyhow::Error> for TransportError {
+    fn from(e: anyhow::Error) -> Self {
+        TransportError::Other(e)
+    }
+}
+
+// One page of the server's change feed
+#[derive(Debug, Default)]
+pub struct ChangePage {
+    pub revisions: Vec<Revision>,
+    pub cursor: Option<String>,
+    pub has_more: bool,
+}
+
+// How the engine reaches the server; HttpTransport talks to the MediRust API
+pub trait Transport {
+    // Stores a revision as a new server record, returning the record id
+    fn push(&mut self, revision: &Revision) -> Result<String, TransportError>;
+    fn pull(&mut self, since: Option<&str>) -> Result<ChangePage, TransportError>;
+}
+
+// Record content written by the engine: the document body plus the sync
+// metadata needed to order revisions
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    medirust_sync: u32,
+    doc_id: Uuid,
+    clock: VectorClock,
+    body: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Change {
+    record_id: Uuid,
+    ipfs_cid: String,
+    record_type: String,
+    title: String,
+    nonce: String,
+    aad_version: i32,
+    wrapped_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangeFeed {
+    changes: Vec<Change>,
+    cursor: Option<String>,
+    has_more: bool,
+}
+
+// Blocking client for the MediRust REST API and an IPFS gateway
+pub struct HttpTransport {
+    api_url: String,
+    ipfs_gateway_url: String,
+    patient_id: Uuid,
+    device_id: Option<Uuid>,
+    device_key: DeviceKey,
+    // Patient session token, sent as a bearer token
+    session_token: Option<String>,
+    client: Client<HttpConnector>,
+    runtime: Runtime,
+}
+
+impl HttpTransport {
+    pub fn new(api_url: &str, ipfs_gateway_url: &str, patient_id: Uuid, device_key: DeviceKey) -> anyhow::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        Ok(HttpTransport {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            ipfs_gateway_url: ipfs_gateway_url.trim_end_matches('/').to_string(),
+            patient_id,
+            device_id: None,
+            device_key,
+            session_token: None,
+            client: Client::new(),
+            runtime,
+        })
+    }
+
+    // Uses a device registered earlier
+    pub fn with_device(mut self, device_id: Uuid) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    // Acts under a patient session, from a passphrase login or a session
+    // challenge; registering the device needs one
+    pub fn with_session(mut self, token: impl Into<String>) -> Self {
+        self.session_token = Some(token.into());
+        self
+    }
+
+    // Registers this device's public key with the server and uses it
+    pub fn register_device(&mut self, name: &str) -> Result<Uuid, TransportError> {
+        let payload = json!({ "name": name, "public_key_pem": self.device_key.public_key_pem()? });
+        let url = format!("{}/patients/{}/devices", self.api_url, self.patient_id);
+        let response: Value = serde_json::from_slice(&self.send(Method::POST, &url, Some(payload))?)
+            .map_err(|e| anyhow!("Invalid device response: {}", e))?;
+        let device_id = response["id"]
+            .as_str()
+            .and_then(|id| Uuid::parse_str(id).ok())
+            .ok_or_else(|| anyhow!("Device response has no id"))?;
+        self.device_id = Some(device_id);
+        Ok(device_id)
+    }
+
+    fn send(&self, method: Method, url: &str, payload: Option<Value>) -> Result<Vec<u8>, TransportError> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(url)
+            .header("content-type", "application/json");
+        if let Some(token) = &self.session_token {
+            request = request.header("authorization", format!("Bearer {}", token));
+        }
+        let request = request
+            .body(payload.map(|p| Body::from(p.to_string())).unwrap_or_else(Body::empty))
+            .map_err(|e| anyhow!("Invalid request to {}: {}", url, e))?;
+        self.runtime.block_on(async {
+            let exchange = async {
+                let response = self.client.request(request).await?;
+                let status = response.status();
+                Ok::<_, hyper::Error>((status, body::to_bytes(response.into_body()).await?))
+            };
+            let (status, bytes) = match tokio::time::timeout(REQUEST_TIMEOUT, exchange).await {
+                Ok(Ok(exchanged)) => exchanged,
+                Ok(Err(e)) if e.is_connect() || e.is_closed() || e.is_incomplete_message() => {
+                    return Err(TransportError::Offline(e.to_string()));
+                }
+                Ok(Err(e)) => return Err(TransportError::Other(e.into())),
+                Err(_) => return Err(TransportError::Offline(format!("no response from {} within {:?}", url, REQUEST_TIMEOUT))),
+            };
+            match status {
+                status if status.is_success() => Ok(bytes.to_vec()),
+                StatusCode::TOO_MANY_REQUESTS | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+                    Err(TransportError::Offline(format!("{} returned HTTP {}", url, status)))
+                }
+                status => Err(TransportError::Rejected {
+                    status: status.as_u16(),
+                    body: String::from_utf8_lossy(&bytes).into_owned(),
+                }),
+            }
+        })
+    }
+
+    // Fetches and decrypts a record's content, or None if no key is wrapped to
+    // this device yet (the feed redelivers the record once one is)
+    fn open_change(&self, change: &Change) -> Result<Option<Revision>, TransportError> {
+        let Some(wrapped_key) = &change.wrapped_key else {
+            return Ok(None);
+        };
+        let ciphertext = self.send(Method::GET, &format!("{}/ipfs/{}", self.ipfs_gateway_url, change.ipfs_cid), None)?;
+        let sealed = SealedRecord {
+            ciphertext,
+            nonce: STANDARD.decode(&change.nonce).map_err(anyhow::Error::from)?,
+            wrapped_key: STANDARD.decode(wrapped_key).map_err(anyhow::Error::from)?,
+        };
+        let binding = RecordBinding {
+            record_id: change.record_id.as_bytes(),
+            patient_id: self.patient_id.as_bytes(),
+            record_type: &change.record_type,
+            aad_version: change.aad_version,
+        };
+        let content = self.device_key.open_record(&binding, &sealed)?;
+        let content = String::from_utf8(content.to_vec()).map_err(|_| anyhow!("Record {} is not UTF-8", change.record_id))?;
+
+        // Records created outside the engine become single-revision documents
+        let (doc_id, clock, body) = match serde_json::from_str::<Envelope>(&content) {
+            Ok(envelope) if envelope.medirust_sync == ENVELOPE_VERSION => (envelope.doc_id, envelope.clock, envelope.body),
+            _ => (change.record_id, VectorClock::default(), content),
+        };
+        Ok(Some(Revision {
+            revision_id: change.record_id.to_string(),
+            doc_id,
+            record_type: change.record_type.clone(),
+            title: change.title.clone(),
+            body,
+            clock,
+        }))
+    }
+}
+
+impl Transport for HttpTransport {
+    fn push(&mut self, revision: &Revision) -> Result<String, TransportError> {
+        let envelope = Envelope {
+            medirust_sync: ENVELOPE_VERSION,
+            doc_id: revision.doc_id,
+            clock: revision.clock.clone(),
+            body: revision.body.clone(),
+        };
+        let payload = json!({
+            "patient_id": self.patient_id.as_bytes().to_vec(),
+            "record_type": revision.record_type,
+            "title": revision.title,
+            "content": serde_json::to_string(&envelope).map_err(anyhow::Error::from)?,
+        });
+        let url = format!("{}/patients/{}/records", self.api_url, self.patient_id);
+        let record: Value = serde_json::from_slice(&self.send(Method::POST, &url, Some(payload))?)
+            .map_err(|e| anyhow!("Invalid record response: {}", e))?;
+        // The server returns record ids as byte arrays
+        let id: Vec<u8> = serde_json::from_value(record["id"].clone()).map_err(|e| anyhow!("Record response has no id: {}", e))?;
+        Ok(Uuid::from_slice(&id).map_err(anyhow::Error::from)?.to_string())
+    }
+
+    fn pull(&mut self, since: Option<&str>) -> Result<ChangePage, TransportError> {
+        let device_id = self.device_id.ok_or_else(|| anyhow!("Register the device before pulling"))?;
+        let mut url = format!(
+            "{}/patients/{}/devices/{}/changes?limit={}",
+            self.api_url, self.patient_id, device_id, CHANGE_PAGE_SIZE
+        );
+        // Cursors are unpadded base64url, safe in a query string
+        if let Some(since) = since {
+            url.push_str(&format!("&since={}", since));
+        }
+        let feed: ChangeFeed = serde_json::from_slice(&self.send(Method::GET, &url, None)?)
+            .map_err(|e| anyhow!("Invalid change feed: {}", e))?;
+        let mut revisions = Vec::with_capacity(feed.changes.len());
+        for change in &feed.changes {
+            if let Some(revision) = self.open_change(change)? {
+                revisions.push(revision);
+            }
+        }
+        Ok(ChangePage { revisions, cursor: feed.cursor, has_more: feed.has_more })
+    }
+}